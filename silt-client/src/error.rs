@@ -0,0 +1,33 @@
+use reqwest::StatusCode;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The proxy could not be reached, or the connection dropped. Calls are
+    /// retried with the same idempotency key before this is returned.
+    #[error("request to silt failed: {0}")]
+    Network(#[from] reqwest::Error),
+    /// The proxy answered with an error status.
+    #[error("silt returned {status}: {message}")]
+    Api { status: StatusCode, message: String },
+    /// The request was accepted but ended `failed`, including when it was
+    /// cancelled.
+    #[error("request {id} failed: {message}")]
+    Failed { id: String, message: String },
+}
+
+impl Error {
+    /// Whether retrying the same call could succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Network(source) => {
+                source.is_timeout() || source.is_connect() || source.is_request() || source.is_body()
+            }
+            Error::Api { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::Failed { .. } => false,
+        }
+    }
+}
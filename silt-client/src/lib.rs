@@ -0,0 +1,285 @@
+//! Client for the silt batching proxy.
+//!
+//! ```no_run
+//! use silt_client::{Client, Request};
+//! use serde_json::json;
+//!
+//! # async fn run() -> silt_client::Result<()> {
+//! let client = Client::new("http://localhost:8080", "sk-...");
+//! let request = Request::new(json!({
+//!     "model": "gpt-4o-mini",
+//!     "messages": [{"role": "user", "content": "Hello!"}],
+//! }));
+//!
+//! // Hold a connection open until the batch completes...
+//! let completion = client.complete(&request).await?;
+//!
+//! // ...or submit now and collect the result later
+//! let submitted = client.submit(&request).await?;
+//! let completion = client.wait(&submitted.id).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod error;
+mod types;
+
+pub use error::{Error, Result};
+pub use types::{Completion, Priority, Request, RequestStatus, Status, StatusTransition};
+
+use futures_util::stream::{self, Stream, StreamExt};
+use reqwest::{Method, RequestBuilder, Response};
+use std::time::Duration;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_RETRIES: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    auth_token: Option<String>,
+    organization: Option<String>,
+    project: Option<String>,
+    poll_interval: Duration,
+    max_retries: u32,
+}
+
+impl Client {
+    /// A client for the proxy at `base_url`, authenticating with `api_key`
+    /// (an upstream key or a silt virtual key).
+    pub fn new(base_url: impl Into<String>, api_key: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: api_key.into(),
+            auth_token: None,
+            organization: None,
+            project: None,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+
+    /// Sends `x-silt-auth-token` for proxies with `SILT_AUTH_TOKEN` set.
+    pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Sends `OpenAI-Organization`, which silt forwards on the upstream calls
+    /// made for this client's requests.
+    pub fn with_organization(mut self, organization: impl Into<String>) -> Self {
+        self.organization = Some(organization.into());
+        self
+    }
+
+    /// Sends `OpenAI-Project`, which silt forwards like the organization.
+    pub fn with_project(mut self, project: impl Into<String>) -> Self {
+        self.project = Some(project.into());
+        self
+    }
+
+    /// Uses a preconfigured HTTP client, e.g. one with a request timeout.
+    /// Synchronous completions can take hours, so leave it unset or long.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// How often `wait` and `status_stream` poll (default 30 seconds).
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Retries of a call after a dropped connection or a 429/5xx, with
+    /// exponential backoff (default 3).
+    pub fn with_max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Submits a request and holds the connection open until its result is
+    /// ready. If the connection drops, it is resubmitted under the same
+    /// idempotency key and picks up the original.
+    pub async fn complete(&self, request: &Request) -> Result<Completion> {
+        let response = self.send(|| self.submission(request)).await?;
+        completion(response).await
+    }
+
+    /// Submits a request and returns as soon as it is queued.
+    pub async fn submit(&self, request: &Request) -> Result<RequestStatus> {
+        let response = self
+            .send(|| self.submission(request).header("prefer", "respond-async"))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// Submits every request, `concurrency` at a time, returning outcomes in
+    /// the order given. A failed submission can be retried with the same
+    /// `Request` without risk of queueing it twice.
+    pub async fn submit_all(&self, requests: &[Request], concurrency: usize) -> Vec<Result<RequestStatus>> {
+        stream::iter(requests)
+            .map(|request| self.submit(request))
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    pub async fn status(&self, request_id: &str) -> Result<RequestStatus> {
+        let path = format!("/v1/requests/{}", request_id);
+        let response = self.send(|| self.request(Method::GET, &path)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// The result of a completed request. Returns an `Api` error with status
+    /// 409 while it is still pending.
+    pub async fn result(&self, request_id: &str) -> Result<Completion> {
+        let path = format!("/v1/requests/{}/result", request_id);
+        let response = self.send(|| self.request(Method::GET, &path)).await?;
+        completion(response).await
+    }
+
+    /// Polls a submitted request until it finishes and returns its result.
+    pub async fn wait(&self, request_id: &str) -> Result<Completion> {
+        loop {
+            let status = self.status(request_id).await?;
+            match status.status {
+                Status::Complete => return self.result(request_id).await,
+                Status::Failed => return Err(failed(status)),
+                _ => tokio::time::sleep(self.poll_interval).await,
+            }
+        }
+    }
+
+    /// Cancels a request that has not been dispatched yet. Returns an `Api`
+    /// error with status 409 once it is part of a batch.
+    pub async fn cancel(&self, request_id: &str) -> Result<RequestStatus> {
+        let path = format!("/v1/requests/{}", request_id);
+        let response = self.send(|| self.request(Method::DELETE, &path)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Polls a request, yielding its status whenever it moves to a new stage,
+    /// and ends once it has finished. Estimates are refreshed with every
+    /// yield but do not trigger one themselves.
+    pub fn status_stream<'a>(&'a self, request_id: &'a str) -> impl Stream<Item = Result<RequestStatus>> + 'a {
+        stream::unfold(Some(None::<Status>), move |last| async move {
+            let last = last?;
+            loop {
+                if last.is_some() {
+                    tokio::time::sleep(self.poll_interval).await;
+                }
+                match self.status(request_id).await {
+                    Ok(status) if last.as_ref() == Some(&status.status) => {}
+                    Ok(status) => {
+                        let next = (!status.status.is_finished()).then(|| Some(status.status.clone()));
+                        return Some((Ok(status), next));
+                    }
+                    Err(e) => return Some((Err(e), None)),
+                }
+            }
+        })
+    }
+
+    fn submission(&self, request: &Request) -> RequestBuilder {
+        let mut builder = self
+            .request(Method::POST, "/v1/chat/completions")
+            .header("idempotency-key", &request.idempotency_key)
+            .json(&request.body);
+        if let Some(priority) = request.priority {
+            builder = builder.header("x-silt-priority", priority.as_str());
+        }
+        if let Some(deadline) = request.deadline {
+            builder = builder.header("x-silt-deadline-secs", deadline.as_secs().max(1).to_string());
+        }
+        if let Some(urgency) = &request.urgency {
+            builder = builder.header("x-silt-urgency", urgency);
+        }
+        if !request.metadata.is_empty() {
+            // A map of strings always serializes
+            let metadata = serde_json::to_string(&request.metadata).unwrap_or_default();
+            builder = builder.header("x-silt-metadata", metadata);
+        }
+        builder
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let mut builder = self
+            .http
+            .request(method, format!("{}{}", self.base_url, path))
+            .bearer_auth(&self.api_key);
+        if let Some(token) = &self.auth_token {
+            builder = builder.header("x-silt-auth-token", token);
+        }
+        if let Some(organization) = &self.organization {
+            builder = builder.header("openai-organization", organization);
+        }
+        if let Some(project) = &self.project {
+            builder = builder.header("openai-project", project);
+        }
+        builder
+    }
+
+    /// Sends a request, retrying transient failures. Every call the client
+    /// makes is idempotent, submissions by way of their idempotency key.
+    async fn send(&self, build: impl Fn() -> RequestBuilder) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let outcome = match build().send().await {
+                Ok(response) => check_status(response).await,
+                Err(e) => Err(Error::Network(e)),
+            };
+            match outcome {
+                Err(e) if e.is_transient() && attempt < self.max_retries => {
+                    attempt += 1;
+                    tokio::time::sleep(Duration::from_secs(1 << attempt.min(6))).await;
+                }
+                outcome => return outcome,
+            }
+        }
+    }
+}
+
+/// Turns an error status into an `Api` error carrying the proxy's message.
+async fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|json| json["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or(body);
+    Err(Error::Api { status, message })
+}
+
+async fn completion(response: Response) -> Result<Completion> {
+    let batch_id = response
+        .headers()
+        .get("x-silt-batch-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    // Quarantined requests come back 202 with their status, not a result
+    if response.status() == reqwest::StatusCode::ACCEPTED {
+        let status: RequestStatus = response.json().await?;
+        return Err(Error::Api {
+            status: reqwest::StatusCode::ACCEPTED,
+            message: format!("Request {} is {:?}", status.id, status.status),
+        });
+    }
+    Ok(Completion {
+        response: response.json().await?,
+        batch_id,
+    })
+}
+
+fn failed(status: RequestStatus) -> Error {
+    Error::Failed {
+        message: status.error.unwrap_or_else(|| "Unknown error".to_string()),
+        id: status.id,
+    }
+}
@@ -0,0 +1,150 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Dispatch lane for a request, sent as `x-silt-priority`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Low,
+}
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Low => "low",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Queued,
+    Batching,
+    Processing,
+    Complete,
+    Failed,
+    /// Flagged by moderation and held until an admin releases or rejects it
+    Quarantined,
+}
+
+impl Status {
+    /// Whether the request will not change status again.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Status::Complete | Status::Failed)
+    }
+}
+
+/// A chat completion to submit, with the idempotency key that identifies it
+/// to the proxy. The key is generated once, so resubmitting the same
+/// `Request` (or retrying after a dropped connection) attaches to the
+/// original instead of queueing it twice.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub body: serde_json::Value,
+    pub idempotency_key: String,
+    pub priority: Option<Priority>,
+    pub deadline: Option<Duration>,
+    pub urgency: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Request {
+    /// A request for an OpenAI chat completions body, with a fresh
+    /// idempotency key.
+    pub fn new(body: serde_json::Value) -> Self {
+        Self {
+            body,
+            idempotency_key: Uuid::new_v4().to_string(),
+            priority: None,
+            deadline: None,
+            urgency: None,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// Uses a key of the caller's choosing, e.g. one derived from their own
+    /// record id, so a restarted process finds its earlier submissions.
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = key.into();
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Falls back to a realtime call if the batch has not answered in time
+    /// (`x-silt-deadline-secs`, whole seconds).
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Batches the request with others of one of the proxy's urgency classes,
+    /// e.g. `hour` (`x-silt-urgency`).
+    pub fn with_urgency(mut self, urgency: impl Into<String>) -> Self {
+        self.urgency = Some(urgency.into());
+        self
+    }
+
+    /// Adds a pair to the batch metadata sent upstream (`x-silt-metadata`).
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// `GET /v1/requests/{id}`: where a request is, without its result.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RequestStatus {
+    pub id: String,
+    pub status: Status,
+    pub priority: Priority,
+    pub batch_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub dispatched_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+    /// Why the request failed, e.g. `batch_expired` or `cancelled`
+    #[serde(default)]
+    pub error_kind: Option<String>,
+    #[serde(default)]
+    pub estimated_prompt_tokens: Option<u64>,
+    #[serde(default)]
+    pub urgency: Option<String>,
+    #[serde(default)]
+    pub estimated_dispatch_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub estimated_completion_at: Option<DateTime<Utc>>,
+    /// Every status the request has been through, oldest first
+    #[serde(default)]
+    pub history: Vec<StatusTransition>,
+    pub status_url: String,
+    pub result_url: String,
+}
+
+/// One status change of a request, e.g. when it joined a batch.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatusTransition {
+    pub at: DateTime<Utc>,
+    pub status: Status,
+    #[serde(default)]
+    pub batch_id: Option<String>,
+    pub cause: String,
+}
+
+/// A finished chat completion, as the upstream returned it.
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub response: serde_json::Value,
+    /// Upstream batch that served it, absent when no batch did
+    pub batch_id: Option<String>,
+}
@@ -0,0 +1,1500 @@
+use crate::archive::ArchiveFilter;
+use crate::audit::AuditQuery;
+use crate::auth::{self, mapping_id};
+use crate::config::TenantSettings;
+use crate::crypto::key_fingerprint;
+use crate::events::EventReader;
+use crate::handlers::{bearer_token, ApiError, AppState};
+use crate::models::{
+    Analytics, BatchProgress, CompletionRequest, KeyMapping, KeyRotation, ModerationPolicy, Priority, PurgeRecord,
+    RequestState, RetentionPolicy, SystemPrompt, TenantQuota, TenantUsage, UsageCounts,
+};
+use crate::runtime::RuntimeSettings;
+use crate::state::{rotated_key, StateManager, ANALYTICS_RETENTION_DAYS, DEFAULT_TENANT};
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// A key mapping as shown by the admin API. The upstream key is masked and
+/// the client token is only returned once, when the mapping is created.
+#[derive(Serialize)]
+struct KeyMappingView {
+    id: String,
+    source: &'static str,
+    upstream_key: String,
+    upstream_key_fingerprint: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    tenant: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_models: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_requests_per_minute: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    batch_window_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_prompt: Option<SystemPrompt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_urls: Option<Vec<String>>,
+}
+
+impl KeyMappingView {
+    fn new(id: String, source: &'static str, mapping: KeyMapping) -> Self {
+        Self {
+            id,
+            source,
+            upstream_key: mask_key(&mapping.upstream_key),
+            upstream_key_fingerprint: key_fingerprint(&mapping.upstream_key),
+            token: None,
+            tenant: mapping.tenant.unwrap_or_else(|| DEFAULT_TENANT.to_string()),
+            allowed_models: mapping.allowed_models,
+            max_requests_per_minute: mapping.max_requests_per_minute,
+            batch_window_secs: mapping.batch_window_secs,
+            system_prompt: mapping.system_prompt,
+            upstream_urls: mapping.upstream_urls,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    tenant: Option<String>,
+}
+
+/// `GET /admin/keys` - mappings from Redis and `SILT_VIRTUAL_KEYS_FILE`,
+/// optionally only those of one tenant (`?tenant=`).
+pub async fn list_key_mappings(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let mut views: Vec<KeyMappingView> = app_state
+        .state_manager
+        .list_key_mappings()
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?
+        .into_iter()
+        .map(|(id, mapping)| KeyMappingView::new(id, "redis", mapping))
+        .collect();
+    views.extend(
+        app_state
+            .key_resolver
+            .file_mappings()
+            .iter()
+            .map(|(id, mapping)| KeyMappingView::new(id.clone(), "file", mapping.clone())),
+    );
+    if let Some(tenant) = &query.tenant {
+        views.retain(|view| view.tenant == *tenant);
+    }
+    views.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(Json(serde_json::json!({ "object": "list", "data": views })).into_response())
+}
+
+/// `POST /admin/keys` - issues a new client token for an upstream key.
+pub async fn create_key_mapping(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(mapping): Json<KeyMapping>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    validate_mapping(&mapping)?;
+
+    let token = auth::generate_token();
+    let id = mapping_id(&token);
+    app_state
+        .state_manager
+        .put_key_mapping(&id, &mapping)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    info!("Created key mapping {}", id);
+
+    let mut view = KeyMappingView::new(id, "redis", mapping);
+    view.token = Some(token);
+    Ok((StatusCode::CREATED, Json(view)).into_response())
+}
+
+/// `GET /admin/keys/:id`
+pub async fn get_key_mapping(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let view = match load_redis_mapping(&app_state, &id).await? {
+        Some(mapping) => KeyMappingView::new(id, "redis", mapping),
+        None => match app_state.key_resolver.file_mappings().get(&id) {
+            Some(mapping) => KeyMappingView::new(id, "file", mapping.clone()),
+            None => return Err(not_found(&id)),
+        },
+    };
+    Ok(Json(view).into_response())
+}
+
+/// `PUT /admin/keys/:id` - replaces the upstream key and policies of an
+/// existing mapping; the client token stays the same.
+pub async fn update_key_mapping(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(mapping): Json<KeyMapping>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    validate_mapping(&mapping)?;
+
+    if load_redis_mapping(&app_state, &id).await?.is_none() {
+        return Err(not_found(&id));
+    }
+
+    app_state
+        .state_manager
+        .put_key_mapping(&id, &mapping)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    info!("Updated key mapping {}", id);
+
+    Ok(Json(KeyMappingView::new(id, "redis", mapping)).into_response())
+}
+
+/// `DELETE /admin/keys/:id` - revokes a client token.
+pub async fn delete_key_mapping(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let removed = app_state
+        .state_manager
+        .delete_key_mapping(&id)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    if !removed {
+        return Err(not_found(&id));
+    }
+    info!("Deleted key mapping {}", id);
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// A key rotation as shown by the admin API, with both keys masked.
+#[derive(Serialize)]
+struct KeyRotationView {
+    old_key: String,
+    old_key_fingerprint: String,
+    new_key: String,
+    new_key_fingerprint: String,
+    created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retired_at: Option<DateTime<Utc>>,
+    /// Batches still in flight on the old key, across tenants
+    inflight_batches: usize,
+}
+
+async fn key_rotation_view(app_state: &AppState, rotation: KeyRotation) -> Result<KeyRotationView, ApiError> {
+    let (inflight_batches, _) = app_state
+        .state_manager
+        .inflight_batches(&rotation.old_key)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    Ok(KeyRotationView {
+        old_key: mask_key(&rotation.old_key),
+        old_key_fingerprint: key_fingerprint(&rotation.old_key),
+        new_key: mask_key(&rotation.new_key),
+        new_key_fingerprint: key_fingerprint(&rotation.new_key),
+        created_at: rotation.created_at,
+        retired_at: rotation.retired_at,
+        inflight_batches,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RotateKeyRequest {
+    old_key: String,
+    new_key: String,
+}
+
+/// `GET /admin/key-rotations` - registered rotations, with how many batches
+/// each old key still has in flight.
+pub async fn list_key_rotations(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let rotations = app_state
+        .state_manager
+        .list_key_rotations()
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    let mut views = Vec::with_capacity(rotations.len());
+    for rotation in rotations.into_values() {
+        views.push(key_rotation_view(&app_state, rotation).await?);
+    }
+    views.sort_by_key(|view| view.created_at);
+
+    Ok(Json(serde_json::json!({ "object": "list", "data": views })).into_response())
+}
+
+/// `POST /admin/key-rotations` - registers a replacement for an upstream key.
+/// Requests are sent with the new key from then on, including those already
+/// queued, while batches in flight keep polling with the key they went out on.
+pub async fn create_key_rotation(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<RotateKeyRequest>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    if request.old_key.trim().is_empty() || request.new_key.trim().is_empty() {
+        return Err(ApiError::InvalidRequest("old_key and new_key must not be empty".to_string()));
+    }
+    if request.old_key == request.new_key {
+        return Err(ApiError::InvalidRequest("new_key must differ from old_key".to_string()));
+    }
+    let rotations = app_state
+        .state_manager
+        .list_key_rotations()
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    if rotated_key(&rotations, &request.new_key) == request.old_key {
+        return Err(ApiError::InvalidRequest(
+            "new_key is rotated back to old_key; retire or replace that rotation first".to_string(),
+        ));
+    }
+
+    let rotation = KeyRotation {
+        old_key: request.old_key,
+        new_key: request.new_key,
+        created_at: Utc::now(),
+        retired_at: None,
+    };
+    app_state
+        .state_manager
+        .put_key_rotation(&rotation)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    info!(
+        "Rotating upstream key {} to {}",
+        key_fingerprint(&rotation.old_key),
+        key_fingerprint(&rotation.new_key)
+    );
+
+    let view = key_rotation_view(&app_state, rotation).await?;
+    Ok((StatusCode::CREATED, Json(view)).into_response())
+}
+
+/// `POST /admin/key-rotations/:fingerprint/retire` - marks a rotated key as
+/// retired once none of its batches are in flight, so it can be revoked
+/// upstream. The file sweeper stops using it.
+pub async fn retire_rotated_key(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(fingerprint): Path<String>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let mut rotation = app_state
+        .state_manager
+        .get_key_rotation(&fingerprint)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?
+        .ok_or_else(|| ApiError::NotFound(format!("No key rotation found for key {}", fingerprint)))?;
+
+    if rotation.retired_at.is_none() {
+        let (inflight, _) = app_state
+            .state_manager
+            .inflight_batches(&rotation.old_key)
+            .await
+            .map_err(|e| ApiError::InternalError(e.to_string()))?;
+        if inflight > 0 {
+            return Err(ApiError::Conflict(format!(
+                "{} batch(es) are still in flight on key {}; retire it once they complete",
+                inflight, fingerprint
+            )));
+        }
+
+        rotation.retired_at = Some(Utc::now());
+        app_state
+            .state_manager
+            .put_key_rotation(&rotation)
+            .await
+            .map_err(|e| ApiError::InternalError(e.to_string()))?;
+        app_state
+            .state_manager
+            .forget_upload_key(&fingerprint)
+            .await
+            .map_err(|e| ApiError::InternalError(e.to_string()))?;
+        info!("Retired upstream key {}", fingerprint);
+    }
+
+    Ok(Json(key_rotation_view(&app_state, rotation).await?).into_response())
+}
+
+#[derive(Serialize)]
+struct TenantView {
+    tenant: String,
+    quota: Option<TenantQuota>,
+    retention: Option<RetentionPolicy>,
+    moderation: Option<ModerationPolicy>,
+    usage_today: TenantUsage,
+}
+
+async fn tenant_view(
+    app_state: &AppState,
+    tenant: String,
+    quota: Option<TenantQuota>,
+) -> Result<TenantView, ApiError> {
+    let internal = |e: anyhow::Error| ApiError::InternalError(e.to_string());
+    let retention = app_state
+        .state_manager
+        .get_retention_policy(&tenant)
+        .await
+        .map_err(internal)?;
+    let moderation = app_state
+        .state_manager
+        .get_moderation_policy(&tenant)
+        .await
+        .map_err(internal)?;
+    let usage_today = app_state
+        .state_manager
+        .for_tenant(&tenant)
+        .daily_usage()
+        .await
+        .map_err(internal)?;
+    Ok(TenantView {
+        tenant,
+        quota,
+        retention,
+        moderation,
+        usage_today,
+    })
+}
+
+/// `GET /admin/tenants` - known tenants with their quotas, retention and
+/// moderation policies, and today's usage.
+pub async fn list_tenants(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    let internal = |e: anyhow::Error| ApiError::InternalError(e.to_string());
+
+    let mut quotas: BTreeMap<String, TenantQuota> = app_state
+        .state_manager
+        .list_tenant_quotas()
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .collect();
+    let mut tenants: BTreeSet<String> = app_state
+        .state_manager
+        .list_tenants()
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .collect();
+    tenants.extend(quotas.keys().cloned());
+    tenants.extend(
+        app_state
+            .state_manager
+            .list_retention_policies()
+            .await
+            .map_err(internal)?
+            .into_iter()
+            .map(|(tenant, _)| tenant),
+    );
+    tenants.extend(
+        app_state
+            .state_manager
+            .list_moderation_policies()
+            .await
+            .map_err(internal)?
+            .into_iter()
+            .map(|(tenant, _)| tenant),
+    );
+
+    let mut views = Vec::new();
+    for tenant in tenants {
+        let quota = quotas.remove(&tenant);
+        views.push(tenant_view(&app_state, tenant, quota).await?);
+    }
+
+    Ok(Json(serde_json::json!({ "object": "list", "data": views })).into_response())
+}
+
+/// `GET /admin/tenants/:tenant/quota`
+pub async fn get_tenant_quota(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    validate_tenant(&tenant)?;
+
+    let quota = app_state
+        .state_manager
+        .get_tenant_quota(&tenant)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    Ok(Json(tenant_view(&app_state, tenant, quota).await?).into_response())
+}
+
+/// Applies the config file's `tenants` table at startup, replacing the
+/// policies it sets for each tenant and leaving the others alone.
+pub async fn apply_tenant_settings(
+    state_manager: &StateManager,
+    tenants: &HashMap<String, TenantSettings>,
+) -> anyhow::Result<()> {
+    for (tenant, settings) in tenants {
+        if !auth::is_valid_tenant(tenant) {
+            anyhow::bail!("SILT_TENANTS contains an invalid tenant '{}'", tenant);
+        }
+        if let Some(quota) = &settings.quota {
+            state_manager.put_tenant_quota(tenant, quota).await?;
+        }
+        if let Some(policy) = &settings.retention {
+            state_manager.put_retention_policy(tenant, policy).await?;
+        }
+        if let Some(action) = settings.moderation {
+            state_manager
+                .put_moderation_policy(tenant, &ModerationPolicy { action })
+                .await?;
+        }
+        info!("Applied configured policies for tenant {}", tenant);
+    }
+    Ok(())
+}
+
+/// `PUT /admin/tenants/:tenant/quota` - sets (replaces) a tenant's limits.
+/// Omitted limits are unlimited.
+pub async fn update_tenant_quota(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    Json(quota): Json<TenantQuota>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    validate_tenant(&tenant)?;
+
+    app_state
+        .state_manager
+        .put_tenant_quota(&tenant, &quota)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    info!("Updated quota for tenant {}", tenant);
+
+    Ok(Json(tenant_view(&app_state, tenant, Some(quota)).await?).into_response())
+}
+
+/// `DELETE /admin/tenants/:tenant/quota` - removes all limits.
+pub async fn delete_tenant_quota(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let removed = app_state
+        .state_manager
+        .delete_tenant_quota(&tenant)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    if !removed {
+        return Err(ApiError::NotFound(format!("No quota set for tenant {}", tenant)));
+    }
+    info!("Deleted quota for tenant {}", tenant);
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// `PUT /admin/tenants/:tenant/retention` - sets (replaces) how long a
+/// tenant's finished requests are kept.
+pub async fn update_retention_policy(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    Json(policy): Json<RetentionPolicy>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    validate_tenant(&tenant)?;
+    if policy.max_age_secs == Some(0) || policy.result_ttl_after_retrieval_secs == Some(0) {
+        return Err(ApiError::InvalidRequest(
+            "retention periods must be positive; omit them to keep requests for 48 hours".to_string(),
+        ));
+    }
+
+    app_state
+        .state_manager
+        .put_retention_policy(&tenant, &policy)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    info!("Updated retention policy for tenant {}", tenant);
+
+    let quota = app_state
+        .state_manager
+        .get_tenant_quota(&tenant)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    Ok(Json(tenant_view(&app_state, tenant, quota).await?).into_response())
+}
+
+/// `DELETE /admin/tenants/:tenant/retention` - back to the default 48 hours.
+pub async fn delete_retention_policy(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let removed = app_state
+        .state_manager
+        .delete_retention_policy(&tenant)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    if !removed {
+        return Err(ApiError::NotFound(format!("No retention policy set for tenant {}", tenant)));
+    }
+    info!("Deleted retention policy for tenant {}", tenant);
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// `PUT /admin/tenants/:tenant/moderation` - sets (replaces) what happens to
+/// the tenant's flagged requests, overriding `SILT_MODERATION_ACTION`.
+pub async fn update_moderation_policy(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+    Json(policy): Json<ModerationPolicy>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    validate_tenant(&tenant)?;
+
+    app_state
+        .state_manager
+        .put_moderation_policy(&tenant, &policy)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    info!("Updated moderation policy for tenant {}", tenant);
+
+    let quota = app_state
+        .state_manager
+        .get_tenant_quota(&tenant)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    Ok(Json(tenant_view(&app_state, tenant, quota).await?).into_response())
+}
+
+/// `DELETE /admin/tenants/:tenant/moderation` - back to `SILT_MODERATION_ACTION`.
+pub async fn delete_moderation_policy(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let removed = app_state
+        .state_manager
+        .delete_moderation_policy(&tenant)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    if !removed {
+        return Err(ApiError::NotFound(format!("No moderation policy set for tenant {}", tenant)));
+    }
+    info!("Deleted moderation policy for tenant {}", tenant);
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// A quarantined request as shown for review, including its messages.
+#[derive(Serialize)]
+struct QuarantinedView {
+    request_id: String,
+    tenant: String,
+    api_key_fingerprint: String,
+    flagged_categories: Vec<String>,
+    created_at: chrono::DateTime<Utc>,
+    request: CompletionRequest,
+}
+
+impl QuarantinedView {
+    fn new(tenant: &str, state: RequestState) -> Self {
+        Self {
+            request_id: state.request_id,
+            tenant: tenant.to_string(),
+            api_key_fingerprint: key_fingerprint(&state.api_key),
+            flagged_categories: state.flagged_categories.unwrap_or_default(),
+            created_at: state.created_at,
+            request: state.request,
+        }
+    }
+}
+
+/// `GET /admin/quarantine` - requests held by moderation, for one tenant
+/// (`?tenant=`) or all of them.
+pub async fn list_quarantined(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    let internal = |e: anyhow::Error| ApiError::InternalError(e.to_string());
+
+    let tenants = match query.tenant {
+        Some(tenant) => {
+            validate_tenant(&tenant)?;
+            vec![tenant]
+        }
+        None => app_state.state_manager.list_tenants().await.map_err(internal)?,
+    };
+
+    let mut views = Vec::new();
+    for tenant in tenants {
+        let states = app_state
+            .state_manager
+            .for_tenant(&tenant)
+            .list_quarantined()
+            .await
+            .map_err(internal)?;
+        views.extend(states.into_iter().map(|state| QuarantinedView::new(&tenant, state)));
+    }
+
+    Ok(Json(serde_json::json!({ "object": "list", "data": views })).into_response())
+}
+
+/// `POST /admin/quarantine/:id/release?tenant=` - queues a quarantined
+/// request for batching as if it had passed moderation.
+pub async fn release_quarantined(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(request_id): Path<String>,
+    Query(query): Query<ListQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    let tenant = query.tenant.unwrap_or_else(|| DEFAULT_TENANT.to_string());
+    validate_tenant(&tenant)?;
+
+    let state = app_state
+        .state_manager
+        .for_tenant(&tenant)
+        .release_quarantined(&request_id)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?
+        .ok_or_else(|| ApiError::NotFound(format!("No quarantined request found with id {}", request_id)))?;
+    info!("Released quarantined request {} (tenant {})", request_id, tenant);
+
+    Ok(Json(QuarantinedView::new(&tenant, state)).into_response())
+}
+
+/// `DELETE /admin/quarantine/:id?tenant=` - fails a quarantined request with
+/// "Rejected by moderation".
+pub async fn reject_quarantined(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(request_id): Path<String>,
+    Query(query): Query<ListQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    let tenant = query.tenant.unwrap_or_else(|| DEFAULT_TENANT.to_string());
+    validate_tenant(&tenant)?;
+
+    let rejected = app_state
+        .state_manager
+        .for_tenant(&tenant)
+        .reject_quarantined(&request_id)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    if !rejected {
+        return Err(ApiError::NotFound(format!("No quarantined request found with id {}", request_id)));
+    }
+    info!("Rejected quarantined request {} (tenant {})", request_id, tenant);
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Deserialize)]
+pub struct AnalyticsQuery {
+    tenant: Option<String>,
+    days: Option<i64>,
+}
+
+/// Analytics counters with the rates derived from them.
+#[derive(Serialize)]
+struct AnalyticsView {
+    #[serde(flatten)]
+    counters: Analytics,
+    failure_rate: f64,
+    average_batch_duration_secs: Option<f64>,
+}
+
+impl From<Analytics> for AnalyticsView {
+    fn from(counters: Analytics) -> Self {
+        Self {
+            failure_rate: counters.failure_rate(),
+            average_batch_duration_secs: counters.average_batch_duration_secs(),
+            counters,
+        }
+    }
+}
+
+/// `GET /admin/analytics` - daily rollups for the last `days` UTC days
+/// (default 7) and their totals, for one tenant (`?tenant=`) or summed over
+/// all of them.
+pub async fn get_analytics(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<AnalyticsQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let daily = daily_analytics(&app_state, &query).await?;
+    let mut totals = Analytics::default();
+    for day in &daily {
+        totals.add(day);
+    }
+
+    Ok(Json(serde_json::json!({
+        "tenant": query.tenant,
+        "days": daily.into_iter().map(AnalyticsView::from).collect::<Vec<_>>(),
+        "totals": AnalyticsView::from(totals),
+    }))
+    .into_response())
+}
+
+/// `GET /admin/usage` - tokens used per model on each of the last `days` UTC
+/// days (default 7) and over the whole range, from the usage upstream
+/// returned with each completed request. Takes the same `?tenant=` as
+/// analytics.
+pub async fn get_usage(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<AnalyticsQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let daily = daily_analytics(&app_state, &query).await?;
+    let mut totals: BTreeMap<String, UsageCounts> = BTreeMap::new();
+    for day in &daily {
+        for (model, counts) in &day.models {
+            totals.entry(model.clone()).or_default().add(counts);
+        }
+    }
+
+    Ok(Json(serde_json::json!({
+        "tenant": query.tenant,
+        "days": daily
+            .into_iter()
+            .map(|day| serde_json::json!({ "date": day.date, "models": day.models }))
+            .collect::<Vec<_>>(),
+        "totals": totals,
+    }))
+    .into_response())
+}
+
+/// Analytics for each of the last `days` UTC days, oldest first, summed over
+/// the tenants the query covers.
+async fn daily_analytics(app_state: &AppState, query: &AnalyticsQuery) -> Result<Vec<Analytics>, ApiError> {
+    let days = query.days.unwrap_or(7);
+    if !(1..=ANALYTICS_RETENTION_DAYS).contains(&days) {
+        return Err(ApiError::InvalidRequest(format!(
+            "days must be between 1 and {}",
+            ANALYTICS_RETENTION_DAYS
+        )));
+    }
+    let tenants = analytics_tenants(app_state, query.tenant.as_deref()).await?;
+
+    let today = Utc::now().date_naive();
+    let mut daily = Vec::new();
+    for offset in (0..days).rev() {
+        let date = today - chrono::Duration::days(offset);
+        let day = tenants_analytics(&app_state.state_manager, &tenants, date)
+            .await
+            .map_err(|e| ApiError::InternalError(e.to_string()))?;
+        daily.push(day);
+    }
+    Ok(daily)
+}
+
+/// The tenant asked for, or every tenant.
+async fn analytics_tenants(app_state: &AppState, tenant: Option<&str>) -> Result<Vec<String>, ApiError> {
+    match tenant {
+        Some(tenant) => {
+            validate_tenant(tenant)?;
+            Ok(vec![tenant.to_string()])
+        }
+        None => app_state
+            .state_manager
+            .list_tenants()
+            .await
+            .map_err(|e| ApiError::InternalError(e.to_string())),
+    }
+}
+
+/// One day's analytics summed over `tenants`.
+async fn tenants_analytics(
+    state_manager: &StateManager,
+    tenants: &[String],
+    date: NaiveDate,
+) -> anyhow::Result<Analytics> {
+    let mut day = Analytics {
+        date: Some(date),
+        ..Default::default()
+    };
+    for tenant in tenants {
+        day.add(&state_manager.for_tenant(tenant).analytics(date).await?);
+    }
+    Ok(day)
+}
+
+#[derive(Deserialize)]
+pub struct UsageExportQuery {
+    tenant: Option<String>,
+    /// First UTC day, inclusive; a week before `to` by default
+    from: Option<NaiveDate>,
+    /// Last UTC day, inclusive; today by default
+    to: Option<NaiveDate>,
+    #[serde(default)]
+    group_by: UsageGrouping,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageGrouping {
+    Key,
+    #[default]
+    Model,
+}
+
+/// Latency percentiles reported in usage exports.
+const EXPORT_PERCENTILES: [f64; 3] = [50.0, 90.0, 99.0];
+
+/// `GET /admin/usage/export` - a CSV with a row per UTC day from `from` to
+/// `to` and model or API key fingerprint (`group_by=model|key`): request
+/// counts, tokens, estimated cost and latency percentiles. Rows are streamed
+/// a day at a time as the counters are read.
+pub async fn export_usage(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<UsageExportQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let today = Utc::now().date_naive();
+    let to = query.to.unwrap_or(today);
+    let from = query.from.unwrap_or(to - chrono::Duration::days(6));
+    if from > to {
+        return Err(ApiError::InvalidRequest("from must not be after to".to_string()));
+    }
+    if to > today || from <= today - chrono::Duration::days(ANALYTICS_RETENTION_DAYS) {
+        return Err(ApiError::InvalidRequest(format!(
+            "from and to must be within the last {} days",
+            ANALYTICS_RETENTION_DAYS
+        )));
+    }
+    let tenants = analytics_tenants(&app_state, query.tenant.as_deref()).await?;
+
+    let group_by = query.group_by;
+    let mut columns = String::from("date,");
+    columns.push_str(match group_by {
+        UsageGrouping::Key => "api_key_fingerprint",
+        UsageGrouping::Model => "model",
+    });
+    columns.push_str(",requests_completed,requests_failed,prompt_tokens,completion_tokens,total_tokens");
+    columns.push_str(",estimated_cost_usd");
+    for p in EXPORT_PERCENTILES {
+        columns.push_str(&format!(",latency_p{}_secs", p));
+    }
+    columns.push('\n');
+
+    let state_manager = app_state.state_manager.clone();
+    let days = from.iter_days().take_while(move |date| *date <= to);
+    let rows = stream::iter(days).then(move |date| {
+        let state_manager = state_manager.clone();
+        let tenants = tenants.clone();
+        async move {
+            let day = tenants_analytics(&state_manager, &tenants, date).await?;
+            let groups = match group_by {
+                UsageGrouping::Key => day.keys,
+                UsageGrouping::Model => day.models,
+            };
+            let mut rows = String::new();
+            for (group, counts) in groups {
+                rows.push_str(&usage_row(date, &group, &counts));
+            }
+            Ok::<_, anyhow::Error>(rows)
+        }
+    });
+    let body = Body::from_stream(stream::once(async move { Ok(columns) }).chain(rows));
+
+    let filename = format!("silt-usage-{}-{}.csv", from, to);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+fn usage_row(date: NaiveDate, group: &str, counts: &UsageCounts) -> String {
+    let mut row = format!(
+        "{},{},{},{},{},{},{},{:.6}",
+        date,
+        csv_field(group),
+        counts.completed,
+        counts.failed,
+        counts.prompt_tokens,
+        counts.completion_tokens,
+        counts.tokens,
+        counts.cost_micros as f64 / 1_000_000.0
+    );
+    for p in EXPORT_PERCENTILES {
+        row.push(',');
+        if let Some(secs) = counts.latency_percentile(p) {
+            row.push_str(&secs.to_string());
+        }
+    }
+    row.push('\n');
+    row
+}
+
+/// Quotes a CSV field holding a comma, quote or line break. Model names come
+/// from clients, so may hold anything; one a spreadsheet would read as a
+/// formula is prefixed with `'` so it opens as text.
+fn csv_field(value: &str) -> String {
+    if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("\"'{}\"", value.replace('"', "\"\""))
+    } else if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// `GET /admin/audit` - request lifecycle events, newest first, filtered by
+/// `tenant`, `request_id` or `api_key_fingerprint`. Page with `before=` set
+/// to the last event's `id`.
+pub async fn list_audit_events(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let events = app_state
+        .state_manager
+        .audit_events(&query)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?
+        .ok_or_else(|| ApiError::NotFound("Audit log is not stored in Redis (SILT_AUDIT_LOG=redis)".to_string()))?;
+
+    Ok(Json(serde_json::json!({ "object": "list", "data": events })).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct EventStreamQuery {
+    tenant: Option<String>,
+    /// Stream entry id to resume after, as sent in `Last-Event-ID`
+    after: Option<String>,
+}
+
+/// `GET /admin/events` - an SSE feed of operator events (requests enqueued,
+/// requeued, completed or failed, batches created and changing status) as
+/// every replica publishes them. Reconnecting with `Last-Event-ID` resumes
+/// where the feed left off; filter with `tenant=`.
+pub async fn stream_events(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<EventStreamQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    if app_state.config.event_stream_max_len == 0 {
+        return Err(ApiError::NotFound("Event stream is disabled (SILT_EVENT_STREAM_MAX_LEN=0)".to_string()));
+    }
+
+    let after = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or(query.after);
+    let reader = app_state
+        .state_manager
+        .read_events(after)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+
+    let tenant = query.tenant;
+    let events = stream::unfold(Some(reader), |reader: Option<EventReader>| async move {
+        let mut reader = reader?;
+        match reader.next().await {
+            Ok(events) => Some((events, Some(reader))),
+            Err(e) => {
+                warn!("Event stream subscriber stopped: {}", e);
+                None
+            }
+        }
+    })
+    .flat_map(stream::iter)
+    .filter(move |event| {
+        let matches = tenant.as_ref().is_none_or(|tenant| *tenant == event.tenant);
+        async move { matches }
+    })
+    .map(|event| {
+        let mut sse = SseEvent::default().event(event.event.as_str());
+        if let Some(id) = &event.id {
+            sse = sse.id(id);
+        }
+        Ok::<_, Infallible>(sse.json_data(&event).unwrap_or_default())
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct PurgeQuery {
+    tenant: Option<String>,
+    api_key_fingerprint: Option<String>,
+}
+
+/// `DELETE /admin/data` - erases stored prompts, results and key mappings for
+/// an API key (`?api_key_fingerprint=`), a tenant (`?tenant=`), or an API key
+/// within a tenant, and records the purge in the audit log.
+pub async fn purge_data(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<PurgeQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    if query.tenant.is_none() && query.api_key_fingerprint.is_none() {
+        return Err(ApiError::InvalidRequest(
+            "tenant or api_key_fingerprint is required".to_string(),
+        ));
+    }
+    if let Some(tenant) = &query.tenant {
+        validate_tenant(tenant)?;
+    }
+    let internal = |e: anyhow::Error| ApiError::InternalError(e.to_string());
+    let matches_key = |api_key: &str| {
+        query
+            .api_key_fingerprint
+            .as_deref()
+            .is_none_or(|fingerprint| key_fingerprint(api_key) == fingerprint)
+    };
+
+    let tenants = match &query.tenant {
+        Some(tenant) => vec![tenant.clone()],
+        None => app_state.state_manager.list_tenants().await.map_err(internal)?,
+    };
+    let mut requests_purged = 0;
+    for tenant in tenants {
+        let state_manager = app_state.state_manager.for_tenant(&tenant);
+        for request_id in state_manager.list_request_ids().await.map_err(internal)? {
+            let Some(state) = state_manager.get_request(&request_id).await.map_err(internal)? else {
+                continue;
+            };
+            if matches_key(&state.api_key) && state_manager.purge_request(&request_id).await.map_err(internal)? {
+                requests_purged += 1;
+            }
+        }
+        // Failures of requests that have already expired are still listed
+        state_manager
+            .purge_failures(query.api_key_fingerprint.as_deref())
+            .await
+            .map_err(internal)?;
+    }
+
+    let mut key_mappings_purged = 0;
+    for (id, mapping) in app_state.state_manager.list_key_mappings().await.map_err(internal)? {
+        let tenant = mapping.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+        if query.tenant.as_deref().is_some_and(|t| t != tenant) || !matches_key(&mapping.upstream_key) {
+            continue;
+        }
+        if app_state.state_manager.delete_key_mapping(&id).await.map_err(internal)? {
+            key_mappings_purged += 1;
+        }
+    }
+
+    // The file sweeper keeps the upstream key itself; other tenants may still
+    // use it, so it is only dropped when purging the key everywhere
+    if let (None, Some(fingerprint)) = (&query.tenant, &query.api_key_fingerprint) {
+        app_state.state_manager.forget_upload_key(fingerprint).await.map_err(internal)?;
+    }
+
+    let archived_rows_purged = purge_archive(
+        &app_state,
+        ArchiveFilter {
+            tenant: query.tenant.as_deref(),
+            api_key_fingerprint: query.api_key_fingerprint.as_deref(),
+            request_id: None,
+        },
+    )
+    .await?;
+
+    let record = PurgeRecord {
+        purged_at: Utc::now(),
+        tenant: query.tenant.clone(),
+        api_key_fingerprint: query.api_key_fingerprint.clone(),
+        request_id: None,
+        requests_purged,
+        key_mappings_purged,
+        archived_rows_purged,
+    };
+    app_state.state_manager.record_purge(&record).await.map_err(internal)?;
+    info!(
+        "Purged {} request(s), {} key mapping(s) and {} archived row(s) (tenant: {:?}, api key: {:?})",
+        requests_purged, key_mappings_purged, archived_rows_purged, record.tenant, record.api_key_fingerprint
+    );
+
+    Ok(Json(record).into_response())
+}
+
+/// `DELETE /admin/requests/:id` - erases a single request (`?tenant=` for
+/// requests outside the default tenant).
+pub async fn purge_request(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(request_id): Path<String>,
+    Query(query): Query<ListQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    let tenant = query.tenant.unwrap_or_else(|| DEFAULT_TENANT.to_string());
+    validate_tenant(&tenant)?;
+    let internal = |e: anyhow::Error| ApiError::InternalError(e.to_string());
+
+    let purged = app_state
+        .state_manager
+        .for_tenant(&tenant)
+        .purge_request(&request_id)
+        .await
+        .map_err(internal)?;
+    let archived_rows_purged = purge_archive(
+        &app_state,
+        ArchiveFilter {
+            tenant: Some(&tenant),
+            api_key_fingerprint: None,
+            request_id: Some(&request_id),
+        },
+    )
+    .await?;
+    if !purged && archived_rows_purged == 0 {
+        return Err(ApiError::NotFound(format!("No request found with id {}", request_id)));
+    }
+
+    let record = PurgeRecord {
+        purged_at: Utc::now(),
+        tenant: Some(tenant),
+        api_key_fingerprint: None,
+        request_id: Some(request_id),
+        requests_purged: usize::from(purged),
+        key_mappings_purged: 0,
+        archived_rows_purged,
+    };
+    app_state.state_manager.record_purge(&record).await.map_err(internal)?;
+    info!("Purged request {:?}", record.request_id);
+
+    Ok(Json(record).into_response())
+}
+
+/// Removes matching rows from the S3 archive, if one is configured.
+async fn purge_archive(app_state: &AppState, filter: ArchiveFilter<'_>) -> Result<usize, ApiError> {
+    match &app_state.archiver {
+        Some(archiver) => archiver
+            .purge(&filter)
+            .await
+            .map_err(|e| ApiError::InternalError(format!("Failed to purge archive: {}", e))),
+        None => Ok(0),
+    }
+}
+
+/// Runtime settings as stored, and the values currently in effect once the
+/// startup configuration fills the gaps.
+#[derive(Serialize)]
+struct RuntimeView {
+    settings: RuntimeSettings,
+    effective: EffectiveSettings,
+}
+
+#[derive(Serialize)]
+struct EffectiveSettings {
+    batch_window_secs: u64,
+    batch_high_priority_window_secs: u64,
+    batch_poll_interval_secs: u64,
+    batch_poll_max_interval_secs: u64,
+    paused: bool,
+}
+
+fn runtime_view(app_state: &AppState) -> RuntimeView {
+    let runtime = &app_state.runtime;
+    RuntimeView {
+        settings: runtime.settings(),
+        effective: EffectiveSettings {
+            batch_window_secs: runtime.lane_window(Priority::Low).as_secs(),
+            batch_high_priority_window_secs: runtime.lane_window(Priority::High).as_secs(),
+            batch_poll_interval_secs: runtime.poll_interval_secs(),
+            batch_poll_max_interval_secs: runtime.poll_max_interval_secs(),
+            paused: runtime.is_paused(),
+        },
+    }
+}
+
+/// `GET /admin/runtime` - settings changed at runtime and those in effect.
+pub async fn get_runtime_settings(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    Ok(Json(runtime_view(&app_state)).into_response())
+}
+
+/// `PUT /admin/runtime` - sets (replaces) the runtime settings. Every
+/// instance picks them up within a few seconds, without a restart.
+pub async fn update_runtime_settings(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(settings): Json<RuntimeSettings>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    app_state.runtime.validate(&settings).map_err(ApiError::InvalidRequest)?;
+
+    app_state
+        .state_manager
+        .put_runtime_settings(&settings)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    app_state.runtime.apply(settings);
+    info!("Updated runtime settings");
+
+    Ok(Json(runtime_view(&app_state)).into_response())
+}
+
+/// `DELETE /admin/runtime` - back to the startup configuration.
+pub async fn delete_runtime_settings(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+
+    let removed = app_state
+        .state_manager
+        .delete_runtime_settings()
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    if !removed {
+        return Err(ApiError::NotFound("No runtime settings set".to_string()));
+    }
+    app_state.runtime.apply(RuntimeSettings::default());
+    info!("Deleted runtime settings");
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Serialize)]
+struct QueueView {
+    tenant: String,
+    queued_high: usize,
+    queued_low: usize,
+}
+
+#[derive(Serialize)]
+struct BatchView {
+    id: String,
+    tenant: String,
+    requests: usize,
+    /// Base URL the batch was created on, absent for the configured upstream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream: Option<String>,
+    /// Absent until the batch has been polled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<BatchProgress>,
+}
+
+/// `GET /admin/queues` - queue depths per tenant and the batches in flight
+/// upstream, with their progress as of the last poll.
+pub async fn get_queues(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    let internal = |e: anyhow::Error| ApiError::InternalError(e.to_string());
+
+    let mut queues = Vec::new();
+    let mut batches = Vec::new();
+    for tenant in app_state.state_manager.list_tenants().await.map_err(internal)? {
+        let state_manager = app_state.state_manager.for_tenant(&tenant);
+        queues.push(QueueView {
+            tenant: tenant.clone(),
+            queued_high: state_manager.queued_count(Priority::High).await.map_err(internal)?,
+            queued_low: state_manager.queued_count(Priority::Low).await.map_err(internal)?,
+        });
+        for batch_id in state_manager.get_processing_batches().await.map_err(internal)? {
+            batches.push(BatchView {
+                requests: state_manager.get_batch_requests(&batch_id).await.map_err(internal)?.len(),
+                progress: state_manager.get_batch_progress(&batch_id).await.map_err(internal)?,
+                upstream: state_manager.get_batch_upstream(&batch_id).await.map_err(internal)?,
+                tenant: tenant.clone(),
+                id: batch_id,
+            });
+        }
+    }
+
+    Ok(Json(serde_json::json!({
+        "paused": app_state.runtime.is_paused(),
+        "queues": queues,
+        "batches": batches,
+    }))
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct FailuresQuery {
+    limit: Option<usize>,
+    tenant: Option<String>,
+}
+
+/// `GET /admin/failures` - the most recent failed requests across tenants,
+/// or of one (`?tenant=`), newest first (`?limit=`, default and at most 100).
+pub async fn list_failures(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<FailuresQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&app_state, &headers)?;
+    let internal = |e: anyhow::Error| ApiError::InternalError(e.to_string());
+    let limit = query.limit.unwrap_or(100).clamp(1, 100);
+
+    let tenants = match query.tenant {
+        Some(tenant) => {
+            validate_tenant(&tenant)?;
+            vec![tenant]
+        }
+        None => app_state.state_manager.list_tenants().await.map_err(internal)?,
+    };
+    let mut failures = Vec::new();
+    for tenant in tenants {
+        let state_manager = app_state.state_manager.for_tenant(&tenant);
+        failures.extend(state_manager.recent_failures(limit).await.map_err(internal)?);
+    }
+    failures.sort_by_key(|failure| std::cmp::Reverse(failure.failed_at));
+    failures.truncate(limit);
+
+    Ok(Json(serde_json::json!({ "object": "list", "data": failures })).into_response())
+}
+
+/// `GET /admin/ui` - a static page that asks for the admin token and renders
+/// the admin JSON endpoints. It holds no data itself, so it is served
+/// without the token, but only while the admin API is enabled.
+pub async fn dashboard(State(app_state): State<Arc<AppState>>) -> Result<Response, ApiError> {
+    if app_state.config.admin_token.is_none() {
+        return Err(ApiError::NotFound("Admin API is disabled".to_string()));
+    }
+    Ok(Html(include_str!("dashboard.html")).into_response())
+}
+
+/// The admin API is disabled unless `SILT_ADMIN_TOKEN` is set, and then requires
+/// it as the bearer token.
+pub fn require_admin(app_state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(admin_token) = &app_state.config.admin_token else {
+        return Err(ApiError::NotFound("Admin API is disabled".to_string()));
+    };
+    let token = bearer_token(headers).ok_or(ApiError::MissingApiKey)?;
+
+    if !auth::constant_time_eq(token.as_bytes(), admin_token.as_bytes()) {
+        return Err(ApiError::InvalidApiKey);
+    }
+    Ok(())
+}
+
+async fn load_redis_mapping(app_state: &AppState, id: &str) -> Result<Option<KeyMapping>, ApiError> {
+    app_state
+        .state_manager
+        .get_key_mapping(id)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))
+}
+
+fn validate_mapping(mapping: &KeyMapping) -> Result<(), ApiError> {
+    if mapping.upstream_key.trim().is_empty() {
+        return Err(ApiError::InvalidRequest("upstream_key must not be empty".to_string()));
+    }
+    if mapping.max_requests_per_minute == Some(0) {
+        return Err(ApiError::InvalidRequest(
+            "max_requests_per_minute must be positive; omit it for no limit".to_string(),
+        ));
+    }
+    if mapping
+        .system_prompt
+        .as_ref()
+        .is_some_and(|prompt| prompt.content.trim().is_empty())
+    {
+        return Err(ApiError::InvalidRequest(
+            "system_prompt.content must not be empty; omit system_prompt for none".to_string(),
+        ));
+    }
+    if let Some(tenant) = &mapping.tenant {
+        validate_tenant(tenant)?;
+    }
+    Ok(())
+}
+
+fn validate_tenant(tenant: &str) -> Result<(), ApiError> {
+    if !auth::is_valid_tenant(tenant) {
+        return Err(ApiError::InvalidRequest(
+            "tenant must be 1-64 characters of letters, digits, '-' or '_'".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn not_found(id: &str) -> ApiError {
+    ApiError::NotFound(format!("No key mapping found with id {}", id))
+}
+
+/// Shows only enough of an upstream key to tell keys apart.
+fn mask_key(key: &str) -> String {
+    let suffix: String = key.chars().rev().take(4).collect::<Vec<_>>().into_iter().rev().collect();
+    format!("...{}", suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("gpt-4o-mini"), "gpt-4o-mini");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn csv_fields_that_look_like_formulas_open_as_text() {
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "\"'+1\"");
+        assert_eq!(csv_field("-1"), "\"'-1\"");
+        assert_eq!(csv_field("@SUM(A1)"), "\"'@SUM(A1)\"");
+        assert_eq!(csv_field("\tcmd"), "\"'\tcmd\"");
+        assert_eq!(csv_field("\rcmd"), "\"'\rcmd\"");
+        assert_eq!(csv_field("a=b"), "a=b");
+    }
+}
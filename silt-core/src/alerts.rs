@@ -0,0 +1,349 @@
+use crate::config::Config;
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use reqwest::Client;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Payload sent by the generic webhook when `SILT_ALERT_WEBHOOK_TEMPLATE` is
+/// unset; Slack's incoming webhook format.
+const DEFAULT_TEMPLATE: &str = r#"{"text": "{{message}}"}"#;
+
+static ALERTER: OnceLock<Alerter> = OnceLock::new();
+
+/// Process-wide alerter, disabled until `init_alerts` configures a channel.
+pub fn alerts() -> &'static Alerter {
+    ALERTER.get_or_init(Alerter::disabled)
+}
+
+pub fn init_alerts(config: &Config) -> Result<()> {
+    let alerter = Alerter::from_config(config)?;
+    for (name, channel) in &alerter.channels {
+        info!("Sending alerts to {} ({})", name, channel.describe());
+    }
+    let _ = ALERTER.set(alerter);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    BatchFailed,
+    QueueAge,
+    CircuitOpen,
+    SlaBreach,
+}
+
+impl AlertKind {
+    const ALL: [AlertKind; 4] = [
+        AlertKind::BatchFailed,
+        AlertKind::QueueAge,
+        AlertKind::CircuitOpen,
+        AlertKind::SlaBreach,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertKind::BatchFailed => "batch_failed",
+            AlertKind::QueueAge => "queue_age",
+            AlertKind::CircuitOpen => "circuit_open",
+            AlertKind::SlaBreach => "sla_breach",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == value)
+    }
+
+    /// PagerDuty severity for the event class.
+    fn severity(self) -> &'static str {
+        match self {
+            AlertKind::CircuitOpen => "critical",
+            AlertKind::BatchFailed => "error",
+            AlertKind::QueueAge | AlertKind::SlaBreach => "warning",
+        }
+    }
+}
+
+/// One operational event. `subject` identifies what it is about (a batch id,
+/// a tenant lane, the upstream) and is used for de-duplication.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub subject: String,
+    pub message: String,
+}
+
+/// A channel alerts can be delivered to.
+pub trait Notifier: Send + Sync {
+    /// Where alerts go, safe to log.
+    fn describe(&self) -> String;
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Posts `{"text": message}` to a Slack incoming webhook.
+pub struct SlackNotifier {
+    client: Client,
+    webhook_url: String,
+}
+
+impl Notifier for SlackNotifier {
+    fn describe(&self) -> String {
+        redact_url(&self.webhook_url)
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let body = json!({ "text": alert.message });
+            post_json(&self.client, &self.webhook_url, body.to_string()).await
+        })
+    }
+}
+
+/// Triggers incidents through the PagerDuty Events API v2. Repeats of the
+/// same event and subject share a dedup key, so they update one incident.
+pub struct PagerDutyNotifier {
+    client: Client,
+    events_url: String,
+    routing_key: String,
+}
+
+impl Notifier for PagerDutyNotifier {
+    fn describe(&self) -> String {
+        redact_url(&self.events_url)
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let body = json!({
+                "routing_key": self.routing_key,
+                "event_action": "trigger",
+                "dedup_key": format!("silt:{}:{}", alert.kind.as_str(), alert.subject),
+                "payload": {
+                    "summary": alert.message,
+                    "source": "silt",
+                    "severity": alert.kind.severity(),
+                    "class": alert.kind.as_str(),
+                },
+            });
+            post_json(&self.client, &self.events_url, body.to_string()).await
+        })
+    }
+}
+
+/// Posts a JSON body rendered from `SILT_ALERT_WEBHOOK_TEMPLATE`.
+pub struct WebhookNotifier {
+    client: Client,
+    url: String,
+    template: String,
+}
+
+impl Notifier for WebhookNotifier {
+    fn describe(&self) -> String {
+        redact_url(&self.url)
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let body = render(&self.template, alert.kind, &alert.message);
+            post_json(&self.client, &self.url, body).await
+        })
+    }
+}
+
+/// Delivers operational alerts to the configured channels.
+///
+/// `SILT_ALERT_ROUTES` decides which event classes go to which channels; without
+/// it every alert goes to every channel. Alerts with the same `kind` and
+/// `subject` are sent at most once per `SILT_ALERT_COOLDOWN_SECS`, so a persistent
+/// condition doesn't flood the channel. Delivery happens in the background and
+/// failures are only logged.
+pub struct Alerter {
+    channels: Vec<(String, Arc<dyn Notifier>)>,
+    routes: HashMap<AlertKind, Vec<Arc<dyn Notifier>>>,
+    cooldown: Duration,
+    last_sent: Mutex<HashMap<String, Instant>>,
+}
+
+impl Alerter {
+    fn disabled() -> Self {
+        Self {
+            channels: Vec::new(),
+            routes: HashMap::new(),
+            cooldown: Duration::ZERO,
+            last_sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn from_config(config: &Config) -> Result<Self> {
+        let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
+        let mut channels: Vec<(String, Arc<dyn Notifier>)> = Vec::new();
+
+        if let Some(webhook_url) = &config.alert_slack_webhook_url {
+            channels.push((
+                "slack".to_string(),
+                Arc::new(SlackNotifier {
+                    client: client.clone(),
+                    webhook_url: webhook_url.clone(),
+                }),
+            ));
+        }
+        if let Some(routing_key) = &config.alert_pagerduty_routing_key {
+            channels.push((
+                "pagerduty".to_string(),
+                Arc::new(PagerDutyNotifier {
+                    client: client.clone(),
+                    events_url: config.alert_pagerduty_events_url.clone(),
+                    routing_key: routing_key.clone(),
+                }),
+            ));
+        }
+        if let Some(url) = &config.alert_webhook_url {
+            let template = config
+                .alert_webhook_template
+                .clone()
+                .unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+            serde_json::from_str::<serde_json::Value>(&render(&template, AlertKind::BatchFailed, "test"))
+                .map_err(|e| anyhow::anyhow!("SILT_ALERT_WEBHOOK_TEMPLATE must render to JSON: {}", e))?;
+            channels.push((
+                "webhook".to_string(),
+                Arc::new(WebhookNotifier {
+                    client: client.clone(),
+                    url: url.clone(),
+                    template,
+                }),
+            ));
+        }
+
+        let routes = parse_routes(&config.alert_routes, &channels)?;
+        Ok(Self {
+            channels,
+            routes,
+            cooldown: Duration::from_secs(config.alert_cooldown_secs),
+            last_sent: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn send(&self, kind: AlertKind, subject: &str, message: String) {
+        let Some(channels) = self.routes.get(&kind).filter(|channels| !channels.is_empty()) else {
+            return;
+        };
+
+        {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            let key = format!("{}:{}", kind.as_str(), subject);
+            if last_sent.get(&key).is_some_and(|sent| sent.elapsed() < self.cooldown) {
+                return;
+            }
+            // Subjects name batches and requests, so forget those past their cooldown
+            last_sent.retain(|_, sent| sent.elapsed() < self.cooldown);
+            last_sent.insert(key, Instant::now());
+        }
+
+        let alert = Arc::new(Alert {
+            kind,
+            subject: subject.to_string(),
+            message,
+        });
+        for channel in channels {
+            let channel = channel.clone();
+            let alert = alert.clone();
+            tokio::spawn(async move {
+                if let Err(e) = channel.notify(&alert).await {
+                    warn!("Failed to send {} alert to {}: {}", kind.as_str(), channel.describe(), e);
+                }
+            });
+        }
+    }
+}
+
+/// Parses `event=channel+channel` rules. `*` matches every event without a
+/// rule of its own; with no rules at all, every event goes to every channel.
+fn parse_routes(
+    rules: &[String],
+    channels: &[(String, Arc<dyn Notifier>)],
+) -> Result<HashMap<AlertKind, Vec<Arc<dyn Notifier>>>> {
+    let all: Vec<_> = channels.iter().map(|(_, channel)| channel.clone()).collect();
+    if rules.is_empty() {
+        return Ok(AlertKind::ALL.into_iter().map(|kind| (kind, all.clone())).collect());
+    }
+
+    let mut routes: HashMap<AlertKind, Vec<Arc<dyn Notifier>>> = HashMap::new();
+    let mut fallback = None;
+    for rule in rules {
+        let (event, names) = rule
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Invalid SILT_ALERT_ROUTES rule '{}': expected event=channel", rule))?;
+
+        let mut targets = Vec::new();
+        for name in names.split('+').map(str::trim).filter(|name| !name.is_empty()) {
+            let channel = channels
+                .iter()
+                .find(|(channel, _)| channel == name)
+                .map(|(_, channel)| channel.clone())
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "SILT_ALERT_ROUTES sends to '{}', which is not configured (expected slack, pagerduty or webhook)",
+                        name
+                    )
+                })?;
+            targets.push(channel);
+        }
+
+        match event.trim() {
+            "*" => fallback = Some(targets),
+            event => {
+                let kind = AlertKind::parse(event).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Invalid SILT_ALERT_ROUTES event '{}': expected batch_failed, queue_age, circuit_open or *",
+                        event
+                    )
+                })?;
+                routes.entry(kind).or_default().extend(targets);
+            }
+        }
+    }
+
+    if let Some(fallback) = fallback {
+        for kind in AlertKind::ALL {
+            routes.entry(kind).or_insert_with(|| fallback.clone());
+        }
+    }
+    Ok(routes)
+}
+
+async fn post_json(client: &Client, url: &str, body: String) -> Result<()> {
+    client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Fills `{{event}}` and `{{message}}` in the template, escaped for use
+/// inside JSON strings.
+fn render(template: &str, kind: AlertKind, message: &str) -> String {
+    template
+        .replace("{{event}}", &json_escape(kind.as_str()))
+        .replace("{{message}}", &json_escape(message))
+}
+
+fn json_escape(value: &str) -> String {
+    serde_json::to_string(value)
+        .map(|quoted| quoted[1..quoted.len() - 1].to_string())
+        .unwrap_or_default()
+}
+
+/// Webhook URLs embed their secret in the path; only log the host.
+fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => format!("{}://{}/...", url.scheme(), url.host_str().unwrap_or_default()),
+        Err(_) => "(invalid URL)".to_string(),
+    }
+}
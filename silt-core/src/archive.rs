@@ -0,0 +1,227 @@
+use crate::config::Config;
+use crate::crypto::{key_fingerprint, Cipher};
+use crate::models::RequestState;
+use anyhow::{Context, Result};
+use arrow_array::cast::AsArray;
+use arrow_array::{ArrayRef, BooleanArray, RecordBatch, StringArray, TimestampMicrosecondArray, UInt32Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use object_store::aws::AmazonS3Builder;
+use object_store::path::Path;
+use object_store::{ObjectStore, PutPayload};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::sync::Arc;
+
+/// Writes finished requests from completed batches to Parquet files in S3,
+/// one file per batch under `{prefix}tenant={tenant}/date={YYYY-MM-DD}/`.
+///
+/// The bucket is set by `SILT_ARCHIVE_S3_BUCKET`; region, endpoint and credentials
+/// come from the standard `AWS_*` environment variables. With
+/// `SILT_ENCRYPT_PAYLOADS` the request, response and error columns are
+/// encrypted as they are in Redis.
+pub struct Archiver {
+    store: Arc<dyn ObjectStore>,
+    prefix: String,
+    cipher: Option<Cipher>,
+}
+
+/// Which archived rows a purge removes. Unset fields match everything.
+#[derive(Debug, Default)]
+pub struct ArchiveFilter<'a> {
+    pub tenant: Option<&'a str>,
+    pub api_key_fingerprint: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+}
+
+impl Archiver {
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let Some(bucket) = &config.archive_s3_bucket else {
+            return Ok(None);
+        };
+        let store = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()
+            .context("Failed to configure the S3 archive")?;
+
+        let mut prefix = config.archive_s3_prefix.trim_matches('/').to_string();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        let cipher = Cipher::from_config(config)?.filter(Cipher::encrypts_payloads);
+        Ok(Some(Self {
+            store: Arc::new(store),
+            prefix,
+            cipher,
+        }))
+    }
+
+    /// Uploads a batch's finished requests; returns the object path.
+    pub async fn archive_batch(&self, tenant: &str, batch_id: &str, states: &[RequestState]) -> Result<String> {
+        let batch = to_record_batch(tenant, states, self.cipher.as_ref())?;
+        let path = format!(
+            "{}tenant={}/date={}/{}.parquet",
+            self.prefix,
+            tenant,
+            Utc::now().format("%Y-%m-%d"),
+            batch_id
+        );
+        self.store
+            .put(&Path::from(path.as_str()), write_parquet(&[batch])?.into())
+            .await?;
+        Ok(path)
+    }
+
+    /// Removes matching rows from archived files, rewriting or deleting the
+    /// files they were in. Returns the number of rows removed.
+    pub async fn purge(&self, filter: &ArchiveFilter<'_>) -> Result<usize> {
+        let prefix = match filter.tenant {
+            Some(tenant) => format!("{}tenant={}/", self.prefix, tenant),
+            None => self.prefix.clone(),
+        };
+        let objects: Vec<_> = self
+            .store
+            .list(Some(&Path::from(prefix.as_str())))
+            .try_collect()
+            .await?;
+
+        let mut removed = 0;
+        for object in objects {
+            if !object.location.as_ref().ends_with(".parquet") {
+                continue;
+            }
+            let bytes = self.store.get(&object.location).await?.bytes().await?;
+            let reader = ParquetRecordBatchReaderBuilder::try_new(bytes)?.build()?;
+
+            let mut kept = Vec::new();
+            let mut file_removed = 0;
+            for batch in reader {
+                let batch = batch?;
+                let keep = keep_mask(&batch, filter)?;
+                file_removed += keep.false_count();
+                kept.push(arrow_select::filter::filter_record_batch(&batch, &keep)?);
+            }
+            if file_removed == 0 {
+                continue;
+            }
+
+            removed += file_removed;
+            if kept.iter().all(|batch| batch.num_rows() == 0) {
+                self.store.delete(&object.location).await?;
+            } else {
+                let payload: PutPayload = write_parquet(&kept)?.into();
+                self.store.put(&object.location, payload).await?;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn schema() -> SchemaRef {
+    let timestamp = DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()));
+    Arc::new(Schema::new(vec![
+        Field::new("request_id", DataType::Utf8, false),
+        Field::new("tenant", DataType::Utf8, false),
+        Field::new("batch_id", DataType::Utf8, true),
+        Field::new("api_key_fingerprint", DataType::Utf8, false),
+        Field::new("model", DataType::Utf8, false),
+        Field::new("status", DataType::Utf8, false),
+        Field::new("request", DataType::Utf8, false),
+        Field::new("response", DataType::Utf8, true),
+        Field::new("error", DataType::Utf8, true),
+        Field::new("prompt_tokens", DataType::UInt32, true),
+        Field::new("completion_tokens", DataType::UInt32, true),
+        Field::new("total_tokens", DataType::UInt32, true),
+        Field::new("created_at", timestamp.clone(), false),
+        Field::new("dispatched_at", timestamp.clone(), true),
+        Field::new("completed_at", timestamp, true),
+    ]))
+}
+
+fn to_record_batch(tenant: &str, states: &[RequestState], cipher: Option<&Cipher>) -> Result<RecordBatch> {
+    let strings = |value: fn(&RequestState) -> Option<String>| -> ArrayRef {
+        Arc::new(states.iter().map(value).collect::<StringArray>())
+    };
+    let payloads = |value: fn(&RequestState) -> Option<String>| -> Result<ArrayRef> {
+        let values = states
+            .iter()
+            .map(|state| match (value(state), cipher) {
+                (Some(payload), Some(cipher)) => cipher.encrypt(&payload).map(Some),
+                (payload, _) => Ok(payload),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Arc::new(StringArray::from(values)))
+    };
+    let tokens = |value: fn(&crate::models::Usage) -> u32| -> ArrayRef {
+        Arc::new(
+            states
+                .iter()
+                .map(|state| state.result.as_ref().map(|result| value(&result.usage)))
+                .collect::<UInt32Array>(),
+        )
+    };
+    let timestamps = |value: fn(&RequestState) -> Option<DateTime<Utc>>| -> ArrayRef {
+        Arc::new(
+            states
+                .iter()
+                .map(|state| value(state).map(|at| at.timestamp_micros()))
+                .collect::<TimestampMicrosecondArray>()
+                .with_timezone("UTC"),
+        )
+    };
+
+    let columns = vec![
+        strings(|state| Some(state.request_id.clone())),
+        Arc::new(StringArray::from(vec![tenant; states.len()])) as ArrayRef,
+        strings(|state| state.batch_id.clone()),
+        strings(|state| Some(key_fingerprint(&state.api_key))),
+        strings(|state| Some(state.request.model.clone())),
+        strings(|state| serde_json::to_value(&state.status).ok()?.as_str().map(str::to_string)),
+        payloads(|state| serde_json::to_string(&state.request).ok())?,
+        payloads(|state| state.result.as_ref().and_then(|result| serde_json::to_string(result).ok()))?,
+        payloads(|state| state.error.as_ref().map(|error| error.message.clone()))?,
+        tokens(|usage| usage.prompt_tokens),
+        tokens(|usage| usage.completion_tokens),
+        tokens(|usage| usage.total_tokens),
+        timestamps(|state| Some(state.created_at)),
+        timestamps(|state| state.dispatched_at),
+        timestamps(|state| state.completed_at),
+    ];
+    Ok(RecordBatch::try_new(schema(), columns)?)
+}
+
+fn write_parquet(batches: &[RecordBatch]) -> Result<Vec<u8>> {
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(Vec::new(), schema(), Some(properties))?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+/// True for rows that don't match the purge filter.
+fn keep_mask(batch: &RecordBatch, filter: &ArchiveFilter<'_>) -> Result<BooleanArray> {
+    let column = |name: &str| {
+        batch
+            .column_by_name(name)
+            .and_then(|column| column.as_string_opt::<i32>())
+            .ok_or_else(|| anyhow::anyhow!("Archived file is missing the {} column", name))
+    };
+    let fingerprints = column("api_key_fingerprint")?;
+    let request_ids = column("request_id")?;
+
+    Ok((0..batch.num_rows())
+        .map(|row| {
+            let matches = filter
+                .api_key_fingerprint
+                .is_none_or(|fingerprint| fingerprints.value(row) == fingerprint)
+                && filter.request_id.is_none_or(|id| request_ids.value(row) == id);
+            Some(!matches)
+        })
+        .collect())
+}
@@ -0,0 +1,194 @@
+use crate::config::Config;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use redis::streams::{StreamMaxlen, StreamRangeReply};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// Redis stream holding audit events when `SILT_AUDIT_LOG=redis`.
+const AUDIT_STREAM: &str = "audit_log";
+
+/// How far back an admin query scans the stream for matching events.
+const QUERY_SCAN_LIMIT: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    Submitted,
+    Dispatched,
+    Requeued,
+    Completed,
+    Failed,
+    Retrieved,
+    Purged,
+    Quarantined,
+    Released,
+    /// The upstream returned more than one result line for the request
+    DuplicateResult,
+}
+
+/// One step in a request's lifecycle. `api_key_fingerprint` identifies who
+/// submitted or retrieved the request without recording the key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// Stream entry id, set when read back from Redis
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub at: DateTime<Utc>,
+    pub event: AuditEventKind,
+    pub tenant: String,
+    pub request_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(event: AuditEventKind, tenant: &str, request_id: &str) -> Self {
+        Self {
+            id: None,
+            at: Utc::now(),
+            event,
+            tenant: tenant.to_string(),
+            request_id: request_id.to_string(),
+            api_key_fingerprint: None,
+            batch_id: None,
+            detail: None,
+        }
+    }
+}
+
+/// Filters for reading events back through the admin API.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub tenant: Option<String>,
+    pub request_id: Option<String>,
+    pub api_key_fingerprint: Option<String>,
+    /// Only events older than this stream entry id, for paging
+    pub before: Option<String>,
+    pub limit: Option<usize>,
+}
+
+enum AuditSink {
+    Disabled,
+    Redis { max_len: usize },
+    File(Mutex<tokio::fs::File>),
+}
+
+/// Append-only trail of request lifecycle events, written to a Redis stream
+/// or a JSON lines file (`SILT_AUDIT_LOG`).
+///
+/// Recording never fails the operation being audited; sink errors are
+/// logged instead.
+pub struct AuditLog {
+    sink: AuditSink,
+}
+
+impl AuditLog {
+    pub async fn from_config(config: &Config) -> Result<Self> {
+        let sink = match config.audit_log.as_str() {
+            "off" => AuditSink::Disabled,
+            "redis" => AuditSink::Redis {
+                max_len: config.audit_log_max_len,
+            },
+            "file" => {
+                let path = config
+                    .audit_log_file
+                    .as_deref()
+                    .ok_or_else(|| anyhow::anyhow!("SILT_AUDIT_LOG=file requires SILT_AUDIT_LOG_FILE"))?;
+                let file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("Failed to open SILT_AUDIT_LOG_FILE {}", path))?;
+                AuditSink::File(Mutex::new(file))
+            }
+            other => anyhow::bail!("Invalid SILT_AUDIT_LOG '{}': expected off, redis or file", other),
+        };
+        Ok(Self { sink })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self.sink, AuditSink::Disabled)
+    }
+
+    /// Whether events can be read back through the admin API.
+    pub fn is_queryable(&self) -> bool {
+        matches!(self.sink, AuditSink::Redis { .. })
+    }
+
+    pub async fn record(&self, redis: &redis::aio::ConnectionManager, event: AuditEvent) {
+        if let Err(e) = self.write(redis, &event).await {
+            warn!("Failed to record {:?} audit event for {}: {}", event.event, event.request_id, e);
+        }
+    }
+
+    async fn write(&self, redis: &redis::aio::ConnectionManager, event: &AuditEvent) -> Result<()> {
+        match &self.sink {
+            AuditSink::Disabled => {}
+            AuditSink::Redis { max_len } => {
+                let mut conn = redis.clone();
+                let json = serde_json::to_string(event)?;
+                conn.xadd_maxlen::<_, _, _, _, ()>(
+                    AUDIT_STREAM,
+                    StreamMaxlen::Approx(*max_len),
+                    "*",
+                    &[("event", json)],
+                )
+                .await?;
+            }
+            AuditSink::File(file) => {
+                let mut line = serde_json::to_vec(event)?;
+                line.push(b'\n');
+                file.lock().await.write_all(&line).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Matching events from the Redis stream, newest first.
+    pub async fn query(
+        &self,
+        redis: &redis::aio::ConnectionManager,
+        query: &AuditQuery,
+    ) -> Result<Vec<AuditEvent>> {
+        let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+        let mut conn = redis.clone();
+        let end = match &query.before {
+            Some(before) => format!("({}", before),
+            None => "+".to_string(),
+        };
+        let reply: StreamRangeReply = conn
+            .xrevrange_count(AUDIT_STREAM, end, "-", QUERY_SCAN_LIMIT)
+            .await?;
+
+        let mut events = Vec::new();
+        for entry in reply.ids {
+            let Some(json) = entry.get::<String>("event") else {
+                continue;
+            };
+            let mut event: AuditEvent = serde_json::from_str(&json)?;
+            let matches = query.tenant.as_ref().is_none_or(|tenant| *tenant == event.tenant)
+                && query.request_id.as_ref().is_none_or(|id| *id == event.request_id)
+                && query
+                    .api_key_fingerprint
+                    .as_ref()
+                    .is_none_or(|fingerprint| event.api_key_fingerprint.as_ref() == Some(fingerprint));
+            if matches {
+                event.id = Some(entry.id);
+                events.push(event);
+                if events.len() == limit {
+                    break;
+                }
+            }
+        }
+        Ok(events)
+    }
+}
@@ -0,0 +1,190 @@
+use crate::config::Config;
+use crate::handlers::{ApiError, AppState};
+use crate::models::KeyMapping;
+use crate::state::{StateManager, DEFAULT_TENANT};
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Header carrying the proxy-level credential, separate from the
+/// `Authorization` header that carries the upstream key.
+const PROXY_AUTH_HEADER: &str = "x-silt-auth-token";
+
+/// A client token resolved to the upstream key and policies to apply.
+#[derive(Debug, Clone)]
+pub struct ResolvedKey {
+    /// Identifier of the key mapping, or `None` for a passed-through
+    /// upstream key
+    pub mapping_id: Option<String>,
+    pub mapping: KeyMapping,
+}
+
+impl ResolvedKey {
+    pub fn tenant(&self) -> &str {
+        self.mapping.tenant.as_deref().unwrap_or(DEFAULT_TENANT)
+    }
+}
+
+/// Resolves the bearer token a client presents to the upstream API key used
+/// on its behalf.
+///
+/// Tokens are looked up in the Redis mapping table (managed through the admin
+/// API) and then in `SILT_VIRTUAL_KEYS_FILE` and `SILT_VIRTUAL_KEYS`. Unknown tokens are
+/// passed through as upstream keys unless `SILT_REQUIRE_VIRTUAL_KEYS` is set or
+/// static keys are configured, in which case they are rejected.
+pub struct KeyResolver {
+    file_mappings: HashMap<String, KeyMapping>,
+    require_mapping: bool,
+    state_manager: StateManager,
+}
+
+impl KeyResolver {
+    pub fn new(config: &Config, state_manager: StateManager) -> Result<Self> {
+        let mut file_mappings = match &config.virtual_keys_file {
+            Some(path) => load_virtual_keys(path)?,
+            None => HashMap::new(),
+        };
+        if let Some(json) = &config.virtual_keys {
+            file_mappings.extend(parse_virtual_keys("SILT_VIRTUAL_KEYS", json)?);
+        }
+        Ok(Self {
+            file_mappings,
+            require_mapping: config.require_virtual_keys
+                || config.virtual_keys_file.is_some()
+                || config.virtual_keys.is_some(),
+            state_manager,
+        })
+    }
+
+    /// The upstream key and policies for a client token, or `None` if the
+    /// token is not accepted.
+    pub async fn resolve(&self, token: &str) -> Result<Option<ResolvedKey>> {
+        let mapping_id = mapping_id(token);
+
+        let mapping = match self.state_manager.get_key_mapping(&mapping_id).await? {
+            Some(mapping) => Some(mapping),
+            None => self.file_mappings.get(&mapping_id).cloned(),
+        };
+
+        Ok(match mapping {
+            Some(mapping) => Some(ResolvedKey {
+                mapping_id: Some(mapping_id),
+                mapping,
+            }),
+            None if self.require_mapping => None,
+            None => Some(ResolvedKey {
+                mapping_id: None,
+                mapping: KeyMapping::passthrough(token.to_string()),
+            }),
+        })
+    }
+
+    /// Mappings loaded from `SILT_VIRTUAL_KEYS_FILE`, by mapping id. These are
+    /// read-only through the admin API.
+    pub fn file_mappings(&self) -> &HashMap<String, KeyMapping> {
+        &self.file_mappings
+    }
+
+    pub fn requires_mapping(&self) -> bool {
+        self.require_mapping
+    }
+}
+
+/// Middleware for the `/v1` routes: with `SILT_AUTH_TOKEN` set, callers must
+/// present one of the configured tokens in `x-silt-auth-token` before their
+/// upstream key is even looked at.
+pub async fn require_proxy_auth(
+    State(app_state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let tokens = &app_state.config.silt_auth_tokens;
+    if tokens.is_empty() {
+        return next.run(request).await;
+    }
+
+    let presented = request
+        .headers()
+        .get(PROXY_AUTH_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match presented {
+        Some(presented) if tokens.iter().any(|token| constant_time_eq(presented.as_bytes(), token.as_bytes())) => {
+            next.run(request).await
+        }
+        Some(_) => ApiError::InvalidProxyToken.into_response(),
+        None => ApiError::MissingProxyToken.into_response(),
+    }
+}
+
+/// Compares secrets without returning early on the first differing byte.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Mapping ids are the SHA-256 of the token so tokens are never stored.
+pub fn mapping_id(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Tenant ids become part of Redis keys, so they are kept to a safe charset.
+pub fn is_valid_tenant(tenant: &str) -> bool {
+    (1..=64).contains(&tenant.len())
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// A new random client token.
+pub fn generate_token() -> String {
+    format!("silt-{}", hex::encode(rand::random::<[u8; 24]>()))
+}
+
+/// Entries in the keys file are either a bare upstream key or a mapping with
+/// policies.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileEntry {
+    Key(String),
+    Mapping(KeyMapping),
+}
+
+/// Reads a JSON object mapping silt-issued tokens to upstream API keys, e.g. a
+/// mounted Kubernetes secret.
+fn load_virtual_keys(path: &str) -> Result<HashMap<String, KeyMapping>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read SILT_VIRTUAL_KEYS_FILE {}", path))?;
+    parse_virtual_keys(&format!("SILT_VIRTUAL_KEYS_FILE {}", path), &contents)
+}
+
+/// Parses keys in the `SILT_VIRTUAL_KEYS_FILE` format; `source` names where they
+/// came from in errors.
+fn parse_virtual_keys(source: &str, contents: &str) -> Result<HashMap<String, KeyMapping>> {
+    let entries: HashMap<String, FileEntry> = serde_json::from_str(contents).with_context(|| {
+        format!("{} must be a JSON object of token -> upstream key or mapping", source)
+    })?;
+
+    let mut mappings = HashMap::new();
+    for (token, entry) in entries {
+        let mapping = match entry {
+            FileEntry::Key(upstream_key) => KeyMapping::passthrough(upstream_key),
+            FileEntry::Mapping(mapping) => mapping,
+        };
+        if token.is_empty() || mapping.upstream_key.is_empty() {
+            anyhow::bail!("{} contains an empty token or key", source);
+        }
+        if let Some(tenant) = &mapping.tenant {
+            if !is_valid_tenant(tenant) {
+                anyhow::bail!("{} contains an invalid tenant '{}'", source, tenant);
+            }
+        }
+        mappings.insert(mapping_id(&token), mapping);
+    }
+    Ok(mappings)
+}
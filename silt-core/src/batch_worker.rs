@@ -0,0 +1,2159 @@
+use crate::alerts::{alerts, AlertKind};
+use crate::archive::Archiver;
+use crate::chaos::chaos;
+use crate::config::{Config, DispatchMode};
+use crate::crypto::key_fingerprint;
+use crate::hooks::{HookContext, Hooks};
+use crate::maintenance::pause_end;
+use crate::metrics::metrics;
+use crate::models::{
+    BatchLine, BatchProgress, BatchResponse, CompletionRequest, CompletionResponse, Priority, RequestError,
+    RequestErrorKind, RequestState, RequestStatus, RetentionPolicy, TraceContext, UpstreamScope,
+};
+use crate::openai_client::{
+    BatchResult, OpenAIClient, UpstreamError, DEFAULT_COMPLETION_WINDOW, UPLOAD_FILENAME_PREFIX,
+};
+use crate::runtime::Runtime;
+use crate::state::{rotated_key, StateManager, FLUSH_PREFIX};
+use crate::supervisor::supervise;
+use crate::tokens;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, Stream, StreamExt};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, interval_at, sleep, Duration};
+use tracing::{debug, error, info, warn};
+
+/// How often the deadline watcher looks for requests past their deadline.
+const DEADLINE_CHECK_INTERVAL_SECS: u64 = 5;
+
+/// How often tenants' retention policies are applied.
+const RETENTION_SWEEP_INTERVAL_SECS: u64 = 60;
+
+/// How often request ages are checked against `SILT_SLA_QUEUED_SECS` and
+/// `SILT_SLA_PROCESSING_SECS`.
+const SLA_CHECK_INTERVAL_SECS: u64 = 60;
+
+/// How often the busiest API keys are re-ranked for the per-key metrics'
+/// `key` label.
+const KEY_RANK_INTERVAL_SECS: u64 = 60;
+
+/// How often requests scheduled for a requeue are put back on the queue.
+const REQUEUE_CHECK_INTERVAL_SECS: u64 = 10;
+
+/// Joins a request id and choice index into the custom_id of a fanned out
+/// batch line (`SILT_BATCH_FAN_OUT_CHOICES`).
+const FAN_OUT_SEPARATOR: &str = "#choice-";
+
+/// Characters of an unreadable result line included in the log.
+const MALFORMED_LINE_PREVIEW_CHARS: usize = 1000;
+
+/// Requests read from Redis per round trip while loading the queue and while
+/// streaming a batch input file to the upstream.
+const LOAD_CHUNK_SIZE: usize = 500;
+
+/// Requests that can go out in the same upstream batch. Groups are keyed by
+/// the API key's fingerprint; the key itself is only carried to dispatch.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct GroupKey {
+    fingerprint: String,
+    metadata: Option<BTreeMap<String, String>>,
+    upstream_url: Option<String>,
+    scope: UpstreamScope,
+    /// `SILT_URGENCY_CLASSES` class, which sets the completion window
+    urgency: Option<String>,
+}
+
+/// Group fingerprint for requests sent with the tenant's key pool, whose key
+/// is picked per batch at dispatch.
+const KEY_POOL_GROUP: &str = "pool";
+
+struct DispatchGroup {
+    api_key: String,
+    requests: Vec<(String, CompletionRequest)>,
+    oldest: DateTime<Utc>,
+}
+
+/// An API key's requests sent as realtime calls in one dispatch.
+struct RealtimeGroup {
+    client: OpenAIClient,
+    api_key: String,
+    requests: Vec<(String, CompletionRequest)>,
+}
+
+pub struct BatchWorker {
+    config: Arc<Config>,
+    runtime: Arc<Runtime>,
+    state: StateManager,
+    openai_client: OpenAIClient,
+    /// Fingerprints of API keys the upstream has rate limited, with the
+    /// earliest time the next dispatch for that key may be attempted.
+    dispatch_backoff: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Consecutive failed dispatches to each routed upstream, with the time
+    /// of the last, for failing over to a route's fallback.
+    upstream_failures: Arc<Mutex<HashMap<String, (u32, Instant)>>>,
+    archiver: Option<Arc<Archiver>>,
+    hooks: Arc<Hooks>,
+}
+
+impl BatchWorker {
+    pub fn new(
+        config: Arc<Config>,
+        runtime: Arc<Runtime>,
+        state: StateManager,
+        openai_client: OpenAIClient,
+        archiver: Option<Arc<Archiver>>,
+        hooks: Arc<Hooks>,
+    ) -> Self {
+        Self {
+            config,
+            runtime,
+            state,
+            openai_client,
+            dispatch_backoff: Arc::new(Mutex::new(HashMap::new())),
+            upstream_failures: Arc::new(Mutex::new(HashMap::new())),
+            archiver,
+            hooks,
+        }
+    }
+
+    /// Starts the dispatcher, the poller for batches already upstream, the
+    /// deadline watcher and the retention and file sweepers in the background.
+    pub fn spawn(self) -> Vec<JoinHandle<()>> {
+        let worker = Arc::new(self);
+        let mut handles = Vec::new();
+
+        // Start batch dispatcher
+        let dispatcher_worker = Arc::clone(&worker);
+        handles.push(tokio::spawn(supervise("dispatcher", move || {
+            let worker = Arc::clone(&dispatcher_worker);
+            async move { worker.start_dispatcher().await }
+        })));
+        info!("Batch dispatcher started");
+
+        // Start existing batch poller
+        let poller_worker = Arc::clone(&worker);
+        handles.push(tokio::spawn(supervise("poller", move || {
+            let worker = Arc::clone(&poller_worker);
+            async move { worker.start_poller().await }
+        })));
+        info!("Batch poller started");
+
+        // Start deadline watcher for realtime fallbacks
+        let deadline_worker = Arc::clone(&worker);
+        handles.push(tokio::spawn(supervise("deadline_watcher", move || {
+            let worker = Arc::clone(&deadline_worker);
+            async move { worker.start_deadline_watcher().await }
+        })));
+        info!("Deadline watcher started");
+
+        // Start sweeper for batch files orphaned between upload and batch creation
+        if worker.config.file_sweep_interval_secs > 0 {
+            let file_worker = Arc::clone(&worker);
+            handles.push(tokio::spawn(supervise("file_sweeper", move || {
+                let worker = Arc::clone(&file_worker);
+                async move { worker.start_file_sweeper().await }
+            })));
+            info!("File sweeper started");
+        }
+
+        // Start SLA monitor for requests stuck queued or in flight
+        if worker.config.sla_queued_secs > 0 || worker.config.sla_processing_secs > 0 {
+            let sla_worker = Arc::clone(&worker);
+            handles.push(tokio::spawn(supervise("sla_monitor", move || {
+                let worker = Arc::clone(&sla_worker);
+                async move { worker.start_sla_monitor().await }
+            })));
+            info!("SLA monitor started");
+        }
+
+        // Start requeue scheduler for requests that failed transiently
+        if worker.config.requeue_max_attempts > 0 {
+            let requeue_worker = Arc::clone(&worker);
+            handles.push(tokio::spawn(supervise("requeue_scheduler", move || {
+                let worker = Arc::clone(&requeue_worker);
+                async move { worker.start_requeue_scheduler().await }
+            })));
+            info!("Requeue scheduler started");
+        }
+
+        // Start ranker for the API keys labelled in the per-key metrics
+        if worker.config.metrics_max_keys > 0 {
+            let ranker_worker = Arc::clone(&worker);
+            handles.push(tokio::spawn(supervise("key_ranker", move || {
+                let worker = Arc::clone(&ranker_worker);
+                async move { worker.start_key_ranker().await }
+            })));
+            info!("Key ranker started");
+        }
+
+        // Start retention sweeper for tenants' data retention policies
+        handles.push(tokio::spawn(supervise("retention_sweeper", move || {
+            let worker = Arc::clone(&worker);
+            async move { worker.start_retention_sweeper().await }
+        })));
+
+        handles
+    }
+
+    pub async fn start_dispatcher(&self) {
+        // Each lane runs its own window so urgent requests are not held up by
+        // the slower, larger low priority batches
+        tokio::join!(self.run_lane(Priority::High), self.run_lane(Priority::Low));
+    }
+
+    async fn run_lane(&self, priority: Priority) {
+        let mut ticker = interval(self.runtime.lane_window(priority));
+        let mut settings = self.runtime.subscribe();
+        let mut triggers = None;
+        // A lane on a schedule dispatches at its times instead of every window
+        let schedule = self.config.lane_schedule(priority);
+        let mut last_scheduled = Utc::now();
+        if schedule.is_some() {
+            self.publish_next_dispatch(priority).await;
+        }
+
+        loop {
+            let mut flush = false;
+            // Subscribed even without an early dispatch threshold, for `silt flush`
+            if triggers.is_none() {
+                triggers = match self.state.subscribe_to_dispatch_triggers().await {
+                    Ok(stream) => Some(stream),
+                    Err(e) => {
+                        warn!("Failed to subscribe to dispatch triggers: {}", e);
+                        None
+                    }
+                };
+            }
+
+            let next_scheduled = schedule.and_then(|schedule| schedule.next_after(last_scheduled.max(Utc::now())));
+            tokio::select! {
+                _ = ticker.tick(), if schedule.is_none() => {}
+                _ = sleep_until_scheduled(next_scheduled), if schedule.is_some() => {
+                    last_scheduled = next_scheduled.unwrap_or(last_scheduled);
+                }
+                Ok(()) = settings.changed() => {
+                    if schedule.is_some() {
+                        continue;
+                    }
+                    // Restart the window so a shortened one takes effect now
+                    // rather than after the current, longer one
+                    let window = self.runtime.lane_window(priority);
+                    if window != ticker.period() {
+                        info!("{} priority batch window changed to {:?}", priority.as_str(), window);
+                        ticker = interval_at(tokio::time::Instant::now() + window, window);
+                        self.publish_next_dispatch(priority).await;
+                    }
+                    continue;
+                }
+                message = next_trigger(&mut triggers) => {
+                    let Some(message) = message else {
+                        warn!("Dispatch trigger stream ended, resubscribing");
+                        triggers = None;
+                        continue;
+                    };
+
+                    let payload = message.get_payload::<String>().unwrap_or_default();
+                    if payload.strip_prefix(FLUSH_PREFIX) == Some(priority.as_str()) {
+                        info!("Flush requested, dispatching {} priority requests now", priority.as_str());
+                        flush = true;
+                        ticker.reset();
+                    } else if payload == priority.as_str() && self.config.batch_max_queue_size > 0 && schedule.is_none() {
+                        // Several submissions may have crossed the threshold while the
+                        // previous dispatch ran; only dispatch if the queue is still full
+                        match self.largest_queue(priority).await {
+                            Ok(count) if count >= self.config.batch_max_queue_size => {
+                                info!("{} priority queue reached {} requests, dispatching early", priority.as_str(), count);
+                                ticker.reset();
+                            }
+                            Ok(_) => continue,
+                            Err(e) => {
+                                error!("Failed to read queue size: {}", e);
+                                continue;
+                            }
+                        }
+                    } else {
+                        continue;
+                    }
+                }
+            }
+
+            if let Err(e) = self.dispatch_batch(priority, flush).await {
+                error!("Error dispatching {} priority batch: {}", priority.as_str(), e);
+            }
+            self.publish_next_dispatch(priority).await;
+        }
+    }
+
+    /// Publishes the next tick so the API can estimate dispatch times.
+    async fn publish_next_dispatch(&self, priority: Priority) {
+        let now = Utc::now();
+        let schedule = self.config.lane_schedule(priority);
+        let next = match schedule {
+            Some(schedule) => schedule.next_after(now),
+            None => Some(now + chrono::Duration::from_std(self.runtime.lane_window(priority)).unwrap_or_default()),
+        };
+        // Nothing goes out in a pause window, so the lane's first tick after it
+        let next = next.and_then(|next| match (pause_end(&self.config.pause_windows, next), schedule) {
+            (Some(resumes), Some(schedule)) => schedule.next_after(resumes - chrono::Duration::minutes(1)),
+            (Some(resumes), None) => Some(resumes),
+            (None, _) => Some(next),
+        });
+        let Some(next) = next else {
+            return;
+        };
+        let ttl_secs = (next - now).num_seconds().max(1) as u64 * 2;
+        if let Err(e) = self.state.set_next_dispatch(priority, next, ttl_secs).await {
+            warn!("Failed to record next dispatch time: {}", e);
+        }
+    }
+
+    /// Dispatches every tenant's queue for the lane. `flush` also sends
+    /// groups that would be held back for being below `SILT_BATCH_MIN_SIZE`.
+    async fn dispatch_batch(&self, priority: Priority, flush: bool) -> Result<()> {
+        if self.runtime.is_paused() {
+            info!("Dispatch is paused, leaving {} priority requests queued", priority.as_str());
+            return Ok(());
+        }
+        if let Some(resumes) = pause_end(&self.config.pause_windows, Utc::now()) {
+            info!(
+                "Dispatch is paused for a maintenance window until {}, leaving {} priority requests queued",
+                resumes.to_rfc3339(),
+                priority.as_str()
+            );
+            return Ok(());
+        }
+
+        // One tenant's failure shouldn't hold up the others
+        for tenant in self.state.list_tenants().await? {
+            if let Err(e) = self.for_tenant(&tenant).dispatch_tenant_batch(priority, flush).await {
+                error!("Error dispatching {} priority batch for tenant {}: {}", priority.as_str(), tenant, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Size of the fullest queue for a lane across tenants.
+    async fn largest_queue(&self, priority: Priority) -> Result<usize> {
+        let mut largest = 0;
+        for tenant in self.state.list_tenants().await? {
+            largest = largest.max(self.state.for_tenant(&tenant).queued_count(priority).await?);
+        }
+        Ok(largest)
+    }
+
+    async fn dispatch_tenant_batch(&self, priority: Priority, flush: bool) -> Result<()> {
+        // Get all queued requests
+        let request_ids = self.state.get_queued_requests(priority).await?;
+
+        metrics()
+            .queued_requests
+            .with_label_values(&[self.state.tenant(), priority.as_str()])
+            .set(request_ids.len() as i64);
+
+        if request_ids.is_empty() {
+            debug!("No {} priority requests queued for batching", priority.as_str());
+            return Ok(());
+        }
+
+        info!(
+            "Dispatching batches for {} queued {} priority requests (tenant {})",
+            request_ids.len(),
+            priority.as_str(),
+            self.state.tenant()
+        );
+
+        // A handful of requests isn't worth a multi-hour batch wait for the discount
+        let service_tier = (self.config.dispatch_mode == DispatchMode::ServiceTier)
+            .then_some(self.config.service_tier.as_str());
+        let use_realtime = service_tier.is_some() || request_ids.len() < self.config.realtime_threshold;
+
+        // Load requests oldest first so per-key caps keep the longest waiting
+        // ones. Batched prompts are dropped once counted and read again while
+        // the batch file streams out, so the queue is never held in memory whole.
+        let now = Utc::now();
+        let rotations = self.state.list_key_rotations().await?;
+        let mut states = Vec::with_capacity(request_ids.len());
+        for ids in request_ids.chunks(LOAD_CHUNK_SIZE) {
+            for mut state in self.state.get_requests(ids).await?.into_iter().flatten() {
+                // Still inside its key mapping's minimum batch window
+                if state.not_before.is_some_and(|not_before| not_before > now) {
+                    continue;
+                }
+                // Requests queued before their key was rotated go out on the new one
+                state.api_key = rotated_key(&rotations, &state.api_key).to_string();
+                self.route(&mut state);
+                if !use_realtime {
+                    // Requests stored before token counting was added are counted now
+                    let tokens = state
+                        .estimated_prompt_tokens
+                        .unwrap_or_else(|| tokens::count_prompt_tokens(&self.outgoing_request(&state)));
+                    state.estimated_prompt_tokens = Some(tokens);
+                    state.request.messages = Vec::new();
+                }
+                states.push(state);
+            }
+        }
+        states.sort_by_key(|state| state.created_at);
+        self.check_queue_age(priority, states.first().map(|state| state.created_at));
+
+        // Group them into batches that can share an upload
+        let mut groups: HashMap<GroupKey, DispatchGroup> = HashMap::new();
+        let mut prompt_tokens: HashMap<String, u64> = HashMap::new();
+        let mut trace_contexts: HashMap<String, TraceContext> = HashMap::new();
+        let key_pool = self.key_pool();
+        for state in states {
+            // Mapped requests for the configured upstream share the tenant's key
+            // pool; passed-through keys never spend it
+            let pooled = !key_pool.is_empty() && state.mapped && state.upstream_url.is_none();
+            let key = GroupKey {
+                fingerprint: if pooled {
+                    KEY_POOL_GROUP.to_string()
+                } else {
+                    key_fingerprint(&state.api_key)
+                },
+                metadata: state.metadata.clone(),
+                upstream_url: state.upstream_url.clone(),
+                scope: state.scope.clone(),
+                urgency: state.urgency.clone(),
+            };
+            let group = groups.entry(key).or_insert_with(|| DispatchGroup {
+                api_key: state.api_key.clone(),
+                requests: Vec::new(),
+                oldest: state.created_at,
+            });
+            let request = self.outgoing_request(&state);
+            // Requests stored before token counting was added are counted now
+            let mut tokens = state
+                .estimated_prompt_tokens
+                .unwrap_or_else(|| tokens::count_prompt_tokens(&request));
+            if self.config.batch_fan_out_choices {
+                tokens *= u64::from(request.n.unwrap_or(1).max(1));
+            }
+            prompt_tokens.insert(state.request_id.clone(), tokens);
+            if let Some(trace_context) = state.trace_context {
+                trace_contexts.insert(state.request_id.clone(), trace_context);
+            }
+            group.requests.push((state.request_id, request));
+        }
+
+        if groups.is_empty() {
+            warn!("No valid requests found in queue");
+            return Ok(());
+        }
+
+        if let Some(tier) = service_tier {
+            info!("Sending {} request(s) to the realtime API with service_tier={}", request_ids.len(), tier);
+        } else if use_realtime {
+            info!(
+                "Only {} request(s) queued (below SILT_REALTIME_THRESHOLD), sending them to the realtime API",
+                request_ids.len()
+            );
+        } else {
+            info!("Creating {} batch(es) grouped by API key and metadata", groups.len());
+        }
+
+        // Keys that have waited longest go first, and no key takes more than
+        // its share of the window, so one key's bulk load can't starve the rest
+        let mut groups: Vec<(GroupKey, DispatchGroup)> = groups.into_iter().collect();
+        groups.sort_by_key(|(_, group)| group.oldest);
+        let mut dispatched_per_key: HashMap<String, usize> = HashMap::new();
+        let mut realtime_groups = Vec::new();
+
+        // Process each group's batch
+        for (key, DispatchGroup { api_key, mut requests, oldest }) in groups {
+            // Pool keys are paused and backed off individually when picked
+            let pooled = key.fingerprint == KEY_POOL_GROUP;
+            if !pooled && self.runtime.is_key_paused(&key.fingerprint) {
+                info!(
+                    "Dispatch is paused for API key {}, leaving {} request(s) queued",
+                    key.fingerprint,
+                    requests.len()
+                );
+                continue;
+            }
+
+            // Groups for other upstreams, or failed over, can still go out
+            let client = self
+                .openai_client
+                .for_upstream(key.upstream_url.as_deref())
+                .with_scope(&key.scope);
+            if let Some(remaining) = client.circuit_breaker().remaining_cooldown() {
+                warn!(
+                    "Circuit breaker for {} is open, deferring {} request(s) ({:?} remaining)",
+                    key.upstream_url.as_deref().unwrap_or("the upstream"),
+                    requests.len(),
+                    remaining
+                );
+                continue;
+            }
+
+            if let Some(remaining) = self.backoff_remaining(&key.fingerprint).filter(|_| !pooled) {
+                info!(
+                    "Deferring {} request(s) for rate limited API key {} ({:?} remaining)",
+                    requests.len(),
+                    key.fingerprint,
+                    remaining
+                );
+                continue;
+            }
+
+            let per_key_cap = if pooled {
+                self.runtime.max_requests_per_key(KEY_POOL_GROUP) * key_pool.len()
+            } else {
+                self.runtime.max_requests_per_key(&key.fingerprint)
+            };
+            if per_key_cap > 0 {
+                let dispatched = dispatched_per_key.entry(key.fingerprint.clone()).or_default();
+                let allowed = per_key_cap.saturating_sub(*dispatched);
+                if requests.len() > allowed {
+                    info!(
+                        "Leaving {} request(s) queued for API key {} over SILT_DISPATCH_MAX_REQUESTS_PER_KEY",
+                        requests.len() - allowed,
+                        key.fingerprint
+                    );
+                    requests.truncate(allowed);
+                }
+                *dispatched += requests.len();
+                if requests.is_empty() {
+                    continue;
+                }
+            }
+
+            if use_realtime {
+                let api_key = if pooled {
+                    match self.pick_pool_key(key_pool).await? {
+                        Some(pool_key) => pool_key.clone(),
+                        None => continue,
+                    }
+                } else {
+                    api_key
+                };
+                realtime_groups.push(RealtimeGroup {
+                    client,
+                    api_key,
+                    requests,
+                });
+                continue;
+            }
+
+            if priority == Priority::Low && !flush && self.should_hold_back(requests.len(), oldest) {
+                info!(
+                    "Holding back {} request(s) below SILT_BATCH_MIN_SIZE for a later window",
+                    requests.len()
+                );
+                continue;
+            }
+
+            // High priority traffic goes out in smaller batches that complete sooner
+            let max_size = match priority {
+                Priority::High if self.config.batch_high_priority_max_size > 0 => {
+                    self.config.batch_high_priority_max_size
+                }
+                _ => requests.len(),
+            };
+            // Shards of a large group finish at different times, releasing
+            // their waiters without holding them for the slowest request
+            let urgency = key.urgency.as_deref().and_then(|name| self.config.urgency_class(name));
+            let max_size = match urgency.and_then(|class| class.shard_size).unwrap_or(self.config.batch_shard_size) {
+                0 => max_size,
+                shard_size => max_size.min(shard_size),
+            };
+
+            let max_size = max_size.max(1);
+            for (index, chunk) in requests.chunks(max_size).enumerate() {
+                // Each of a pooled group's batches goes out on whichever key
+                // has the most room, spreading a large load across the pool
+                let (api_key, key) = if pooled {
+                    match self.pick_pool_key(key_pool).await? {
+                        Some(pool_key) => (
+                            pool_key.clone(),
+                            GroupKey {
+                                fingerprint: key_fingerprint(pool_key),
+                                ..key.clone()
+                            },
+                        ),
+                        None => {
+                            info!(
+                                "Every key in the pool is paused, backing off or at its in-flight limit, leaving {} request(s) queued",
+                                requests.len() - index * max_size
+                            );
+                            break;
+                        }
+                    }
+                } else {
+                    (api_key.clone(), key.clone())
+                };
+                let chunk = self.fit_token_limits(&api_key, &key.fingerprint, chunk, &prompt_tokens).await?;
+                if chunk.is_empty() {
+                    continue;
+                }
+                let mut model_tokens: HashMap<String, u64> = HashMap::new();
+                for (id, request) in &chunk {
+                    *model_tokens.entry(request.model.clone()).or_default() +=
+                        prompt_tokens.get(id).copied().unwrap_or(0);
+                }
+                let estimated_tokens = model_tokens.values().sum();
+                if self.inflight_limit_reached(&api_key, &key.fingerprint, estimated_tokens).await? {
+                    info!(
+                        "Holding back {} request(s) until earlier batches for API key {} complete",
+                        requests.len() - index * max_size,
+                        key.fingerprint
+                    );
+                    break;
+                }
+                if self.daily_batch_limit_reached(&api_key).await? {
+                    info!(
+                        "API key {} reached SILT_MAX_BATCHES_PER_KEY_PER_DAY, leaving {} request(s) queued until tomorrow (UTC)",
+                        key.fingerprint,
+                        requests.len() - index * max_size
+                    );
+                    break;
+                }
+
+                let batch_request_ids: Vec<String> = chunk.into_iter().map(|(id, _)| id).collect();
+                // A batch can only join one trace, so it joins that of its
+                // oldest request sent with one
+                let trace_context = batch_request_ids.iter().find_map(|id| trace_contexts.get(id));
+                let batch_client = client.with_trace_context(trace_context);
+                self.dispatch_batch_for_key(&batch_client, &key, &api_key, batch_request_ids, priority, &model_tokens)
+                    .await?;
+            }
+        }
+
+        if !realtime_groups.is_empty() {
+            self.dispatch_realtime(realtime_groups, priority, service_tier, &trace_contexts)
+                .await;
+        }
+
+        Ok(())
+    }
+
+    /// Runs queued requests as concurrent realtime calls instead of a batch,
+    /// optionally on a discounted service tier. At most
+    /// `SILT_REALTIME_CONCURRENCY` run at once, taken from each API key's
+    /// group in turn so every key makes progress.
+    async fn dispatch_realtime(
+        &self,
+        groups: Vec<RealtimeGroup>,
+        priority: Priority,
+        service_tier: Option<&str>,
+        trace_contexts: &HashMap<String, TraceContext>,
+    ) {
+        let mut pending: Vec<_> = groups
+            .iter()
+            .enumerate()
+            .map(|(index, group)| (index, group.requests.iter()))
+            .collect();
+        let mut requests = Vec::new();
+        while !pending.is_empty() {
+            pending.retain_mut(|(index, group)| match group.next() {
+                Some((request_id, request)) => {
+                    requests.push((*index, request_id, request.clone()));
+                    true
+                }
+                None => false,
+            });
+        }
+
+        let groups = &groups;
+        futures_util::stream::iter(requests)
+            .for_each_concurrent(self.config.realtime_concurrency.max(1), |(index, request_id, mut request)| async move {
+                let RealtimeGroup { client, api_key, .. } = &groups[index];
+                // An explicit service_tier from the client wins
+                if let Some(tier) = service_tier {
+                    request
+                        .extra
+                        .entry("service_tier".to_string())
+                        .or_insert_with(|| serde_json::Value::String(tier.to_string()));
+                }
+
+                let client = client.with_trace_context(trace_contexts.get(request_id));
+                if let Err(e) = self.run_realtime(&client, api_key, request_id, &request, priority).await {
+                    error!("Realtime dispatch failed for {}: {}", request_id, e);
+                }
+            })
+            .await;
+    }
+
+    async fn run_realtime(
+        &self,
+        client: &OpenAIClient,
+        api_key: &str,
+        request_id: &str,
+        request: &CompletionRequest,
+        priority: Priority,
+    ) -> Result<()> {
+        // Claim it from the queue so a concurrent dispatch can't also send it
+        if !self.state.remove_from_queue(request_id, priority).await? {
+            return Ok(());
+        }
+
+        self.state
+            .update_status(request_id, RequestStatus::Processing, None)
+            .await?;
+
+        match client.create_chat_completion(api_key, request).await {
+            Ok(response) => self.complete_request(request_id, response, None).await,
+            Err(e) if is_transient(&e) => {
+                warn!("Realtime request {} failed, requeueing: {}", request_id, e);
+                self.state
+                    .update_status(request_id, RequestStatus::Queued, None)
+                    .await?;
+                self.state.requeue(request_id, priority).await
+            }
+            Err(e) => {
+                let error = RequestError::new(RequestErrorKind::Realtime, format!("Realtime request failed: {}", e))
+                    .with_upstream_status(upstream_status(&e));
+                self.state.fail_request(request_id, error).await
+            }
+        }
+    }
+
+    async fn dispatch_batch_for_key(
+        &self,
+        client: &OpenAIClient,
+        key: &GroupKey,
+        api_key: &str,
+        mut request_ids: Vec<String>,
+        priority: Priority,
+        model_tokens: &HashMap<String, u64>,
+    ) -> Result<()> {
+        // OpenAI rejects the whole batch if a custom_id repeats, which can
+        // happen if a request was queued twice
+        let mut seen = HashSet::new();
+        request_ids.retain(|request_id| {
+            let first = seen.insert(request_id.clone());
+            if !first {
+                warn!("Dropping duplicate custom_id {} from batch file", request_id);
+            }
+            first
+        });
+        // Claim them first, so a request cancelled since the queue was read is
+        // left out and one claimed here can't be cancelled while it goes out
+        let request_ids = self.state.claim_for_dispatch(&request_ids, priority).await?;
+        if request_ids.is_empty() {
+            return Ok(());
+        }
+        info!("Dispatching batch with {} requests for API key {}", request_ids.len(), key.fingerprint);
+
+        // The file sweeper only cleans up the configured upstream
+        if key.upstream_url.is_none() {
+            if let Err(e) = self.state.record_upload_key(api_key).await {
+                warn!("Failed to record upload key for the file sweeper: {}", e);
+            }
+        }
+
+        // Upload batch file - don't fail requests on transient errors, let them retry
+        let upload_started_at = Utc::now();
+        let file_id = match client
+            .upload_batch_file(api_key, || self.batch_file(&request_ids))
+            .await
+        {
+            Ok(id) => id,
+            Err(e) => {
+                error!("Failed to upload batch file (will retry next window): {}", e);
+                self.record_rate_limit(&key.fingerprint, &e);
+                self.record_dispatch_outcome(key.upstream_url.as_deref(), false);
+                // Leave requests in queue for retry
+                self.release_dispatch_claims(&request_ids).await;
+                return Ok(());
+            }
+        };
+
+        info!("Uploaded batch file: {}", file_id);
+
+        // Create batch - don't fail requests on transient errors, let them retry
+        let completion_window = key
+            .urgency
+            .as_deref()
+            .and_then(|name| self.config.urgency_class(name))
+            .map_or(DEFAULT_COMPLETION_WINDOW, |class| class.completion_window.as_str());
+        let batch = match client
+            .create_batch(api_key, file_id, completion_window, key.metadata.clone())
+            .await
+        {
+            Ok(batch) => batch,
+            Err(e) => {
+                error!("Failed to create batch (will retry next window): {}", e);
+                self.record_rate_limit(&key.fingerprint, &e);
+                self.record_dispatch_outcome(key.upstream_url.as_deref(), false);
+                // Leave requests in queue for retry
+                self.release_dispatch_claims(&request_ids).await;
+                return Ok(());
+            }
+        };
+
+        match &key.upstream_url {
+            Some(upstream_url) => info!("Created batch: {} on {}", batch.id, upstream_url),
+            None => info!("Created batch: {}", batch.id),
+        }
+        self.record_dispatch_outcome(key.upstream_url.as_deref(), true);
+
+        // Update state
+        self.state
+            .move_to_batching(
+                &request_ids,
+                &batch.id,
+                api_key,
+                key.upstream_url.as_deref(),
+                &key.scope,
+                key.urgency.as_deref(),
+                priority,
+                model_tokens,
+                upload_started_at,
+            )
+            .await?;
+
+        // Start polling for this batch
+        self.spawn_poll(batch.id.clone());
+
+        Ok(())
+    }
+
+    async fn release_dispatch_claims(&self, request_ids: &[String]) {
+        if let Err(e) = self.state.release_dispatch_claims(request_ids).await {
+            warn!("Failed to release dispatch claims: {}", e);
+        }
+    }
+
+    /// The JSONL input file for a batch, reading the requests back from Redis
+    /// a chunk at a time so the batch's size doesn't bound memory. Requests
+    /// that disappeared since they were grouped are left out.
+    fn batch_file(&self, request_ids: &[String]) -> impl Stream<Item = Result<Vec<u8>>> + Send + 'static {
+        let worker = self.clone();
+        let chunks: Vec<Vec<String>> = request_ids.chunks(LOAD_CHUNK_SIZE).map(<[String]>::to_vec).collect();
+        stream::iter(chunks).then(move |ids| {
+            let worker = worker.clone();
+            async move {
+                let mut content = Vec::new();
+                for state in worker.state.get_requests(&ids).await?.into_iter().flatten() {
+                    let request = vec![(state.request_id.clone(), worker.outgoing_request(&state))];
+                    let lines = if worker.config.batch_fan_out_choices {
+                        fan_out_choices(request)
+                    } else {
+                        request
+                    };
+                    for (custom_id, body) in lines {
+                        serde_json::to_writer(&mut content, &BatchLine::chat_completion(custom_id, body))?;
+                        content.push(b'\n');
+                    }
+                }
+                Ok(content)
+            }
+        })
+    }
+
+    /// Small groups are held for later windows in the hope of merging with
+    /// more requests, until their oldest request has waited `batch_max_wait_secs`.
+    fn should_hold_back(&self, group_size: usize, oldest: DateTime<Utc>) -> bool {
+        if group_size >= self.config.batch_min_size {
+            return false;
+        }
+
+        let waited = (Utc::now() - oldest).num_seconds().max(0) as u64;
+        waited < self.config.batch_max_wait_secs
+    }
+
+    /// Whether another batch of `estimated_tokens` would exceed the API key's
+    /// in-flight batch or token cap. A key with nothing in flight may always
+    /// dispatch, so a single oversized batch can't wedge it.
+    async fn inflight_limit_reached(&self, api_key: &str, fingerprint: &str, estimated_tokens: u64) -> Result<bool> {
+        let max_batches = self.runtime.max_inflight_batches(fingerprint);
+        let max_tokens = self.runtime.max_inflight_tokens(fingerprint);
+        if max_batches == 0 && max_tokens == 0 {
+            return Ok(false);
+        }
+
+        let (batches, tokens) = self.state.inflight_batches(api_key).await?;
+        if batches == 0 {
+            return Ok(false);
+        }
+        Ok((max_batches > 0 && batches >= max_batches)
+            || (max_tokens > 0 && tokens + estimated_tokens > max_tokens))
+    }
+
+    /// Leaves out requests that would take their model past its
+    /// `SILT_MAX_INFLIGHT_TOKENS_PER_MODEL` limit for the API key, or the key
+    /// past its in-flight token cap, counting batches already in flight, so
+    /// the upstream doesn't reject the batch for exceeding its enqueued token
+    /// quota and the cap holds back only what doesn't fit. They stay queued
+    /// for a later window. A model with nothing in flight always takes its
+    /// first request, and a key with nothing in flight isn't capped, so one
+    /// oversized request can't wedge the queue.
+    async fn fit_token_limits(
+        &self,
+        api_key: &str,
+        fingerprint: &str,
+        chunk: &[(String, CompletionRequest)],
+        prompt_tokens: &HashMap<String, u64>,
+    ) -> Result<Vec<(String, CompletionRequest)>> {
+        let limits = &self.config.max_inflight_tokens_per_model;
+        let max_key_tokens = self.runtime.max_inflight_tokens(fingerprint);
+        let (key_batches, mut key_tokens) = match max_key_tokens {
+            0 => (0, 0),
+            _ => self.state.inflight_batches(api_key).await?,
+        };
+        if limits.is_empty() && key_batches == 0 {
+            return Ok(chunk.to_vec());
+        }
+
+        let mut inflight = if limits.is_empty() {
+            HashMap::new()
+        } else {
+            self.state.inflight_model_tokens(api_key).await?
+        };
+        let mut kept = Vec::with_capacity(chunk.len());
+        let mut deferred: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut over_key_cap = 0;
+        for (id, request) in chunk {
+            let tokens = prompt_tokens.get(id).copied().unwrap_or(0);
+            if key_batches > 0 && key_tokens + tokens > max_key_tokens {
+                over_key_cap += 1;
+                continue;
+            }
+            let used = inflight.entry(request.model.clone()).or_default();
+            let fits = match limits.get(&request.model).or_else(|| limits.get("*")) {
+                Some(limit) => *used == 0 || *used + tokens <= *limit,
+                None => true,
+            };
+            if fits {
+                *used += tokens;
+                key_tokens += tokens;
+                kept.push((id.clone(), request.clone()));
+            } else {
+                *deferred.entry(request.model.as_str()).or_default() += 1;
+            }
+        }
+
+        for (model, count) in deferred {
+            info!(
+                "Leaving {} {} request(s) queued for API key {} over SILT_MAX_INFLIGHT_TOKENS_PER_MODEL",
+                count, model, fingerprint
+            );
+        }
+        if over_key_cap > 0 {
+            info!(
+                "Leaving {} request(s) queued for API key {} over its in-flight token cap",
+                over_key_cap, fingerprint
+            );
+        }
+        Ok(kept)
+    }
+
+    async fn daily_batch_limit_reached(&self, api_key: &str) -> Result<bool> {
+        let max_batches = self.config.max_batches_per_key_per_day;
+        if max_batches == 0 {
+            return Ok(false);
+        }
+        Ok(self.state.daily_batch_count(api_key).await? >= max_batches)
+    }
+
+    /// Defers further dispatches for this key if the upstream rate limited us
+    /// and told us how long to wait.
+    fn record_rate_limit(&self, fingerprint: &str, error: &anyhow::Error) {
+        let Some(retry_after) = error
+            .downcast_ref::<UpstreamError>()
+            .filter(|e| e.is_rate_limited())
+            .and_then(UpstreamError::retry_after)
+        else {
+            return;
+        };
+
+        warn!("Upstream rate limited API key {}, backing off for {:?}", fingerprint, retry_after);
+        let mut backoff = self.dispatch_backoff.lock().unwrap();
+        backoff.insert(fingerprint.to_string(), Instant::now() + retry_after);
+        update_backoff_metrics(&mut backoff);
+    }
+
+    /// Time left before this key may be dispatched again, if it is backing off.
+    fn backoff_remaining(&self, fingerprint: &str) -> Option<Duration> {
+        let mut backoff = self.dispatch_backoff.lock().unwrap();
+        update_backoff_metrics(&mut backoff);
+        backoff
+            .get(fingerprint)
+            .map(|until| until.saturating_duration_since(Instant::now()))
+    }
+
+    async fn poll_batch(&self, batch_id: &str) -> Result<()> {
+        info!("Starting to poll batch: {}", batch_id);
+
+        // Get API key for this batch
+        let api_key = match self.state.get_batch_api_key(batch_id).await? {
+            Some(key) => key,
+            None => {
+                error!("No API key found for batch {}", batch_id);
+                return Err(anyhow::anyhow!("No API key found for batch"));
+            }
+        };
+        let client = self
+            .openai_client
+            .for_upstream(self.state.get_batch_upstream(batch_id).await?.as_deref())
+            .with_scope(&self.state.get_batch_scope(batch_id).await?);
+        // Urgent classes are polled at their own fixed interval
+        let urgency_poll_interval = self
+            .state
+            .get_batch_urgency(batch_id)
+            .await?
+            .and_then(|name| self.config.urgency_class(&name))
+            .and_then(|class| class.poll_interval_secs)
+            .map(Duration::from_secs);
+
+        let mut delay = Duration::ZERO;
+
+        loop {
+            sleep(delay).await;
+            if let Some(extra) = chaos().poll_delay() {
+                sleep(extra).await;
+            }
+            // Failed polls retry at the base interval; successful ones adapt below
+            delay = urgency_poll_interval.unwrap_or_else(|| Duration::from_secs(self.runtime.poll_interval_secs()));
+
+            if !client.circuit_breaker().allow_request() {
+                info!("Upstream circuit breaker is open, skipping poll for batch {}", batch_id);
+                continue;
+            }
+
+            // Try to get batch status, but don't fail the whole polling loop on transient errors
+            let batch = match client.get_batch_status(&api_key, batch_id).await {
+                Ok(b) => b,
+                Err(e) => {
+                    warn!("Failed to get batch status for {}, will retry: {}", batch_id, e);
+                    continue;  // Retry on next tick
+                }
+            };
+
+            info!("Batch {} status: {}", batch_id, batch.status);
+            let progress = BatchProgress {
+                status: batch.status.clone(),
+                counts: batch.request_counts.unwrap_or_default(),
+                polled_at: Utc::now(),
+            };
+            if let Err(e) = self.state.set_batch_progress(batch_id, &progress).await {
+                warn!("Failed to record progress of batch {}: {}", batch_id, e);
+            }
+
+            // Update request statuses to processing
+            let request_ids = self.state.get_batch_requests(batch_id).await?;
+            for request_id in &request_ids {
+                if let Some(state) = self.state.get_request(request_id).await? {
+                    if state.status == RequestStatus::Batching {
+                        self.state
+                            .update_status(request_id, RequestStatus::Processing, Some(batch_id.to_string()))
+                            .await?;
+                    }
+                }
+            }
+
+            match batch.status.as_str() {
+                "completed" => {
+                    info!("Batch {} completed!", batch_id);
+                    let duration_secs = batch.completed_at.map(|completed_at| completed_at - batch.created_at);
+                    if let Err(e) = self.state.record_batch_outcome(true, duration_secs).await {
+                        warn!("Failed to record analytics for batch {}: {}", batch_id, e);
+                    }
+                    if let Some(completed_at) = batch.completed_at {
+                        self.record_batch_duration(&request_ids, completed_at - batch.created_at)
+                            .await;
+                    }
+                    if batch.output_file_id.is_none() {
+                        warn!("Batch completed but no output file");
+                    }
+                    self.process_batch_results(&client, &api_key, &batch, &result_files(&batch))
+                        .await?;
+                    self.state.remove_processing_batch(batch_id, &api_key).await?;
+                    self.archive_batch(batch_id, &request_ids).await;
+                    break;
+                }
+                "failed" | "expired" | "cancelled" => {
+                    error!("Batch {} failed with status: {}", batch_id, batch.status);
+                    if let Err(e) = self.state.record_batch_outcome(false, None).await {
+                        warn!("Failed to record analytics for batch {}: {}", batch_id, e);
+                    }
+                    // An expired or cancelled batch may have finished some of its requests
+                    self.process_batch_results(&client, &api_key, &batch, &result_files(&batch))
+                        .await?;
+
+                    // Requeue or fail the rest
+                    let request_ids = self.state.get_batch_requests(batch_id).await?;
+                    alerts().send(
+                        AlertKind::BatchFailed,
+                        batch_id,
+                        format!(
+                            "Batch {} for tenant {} ended {} with {} request(s)",
+                            batch_id,
+                            self.state.tenant(),
+                            batch.status,
+                            request_ids.len()
+                        ),
+                    );
+                    for request_id in request_ids {
+                        // Skip requests finished, or already requeued, by their result line
+                        let still_in_batch = self
+                            .state
+                            .get_request(&request_id)
+                            .await?
+                            .is_some_and(|state| {
+                                state.batch_id.as_deref() == Some(batch_id)
+                                    && !matches!(state.status, RequestStatus::Complete | RequestStatus::Failed)
+                            });
+                        if !still_in_batch {
+                            continue;
+                        }
+                        let kind = match batch.status.as_str() {
+                            "expired" => RequestErrorKind::BatchExpired,
+                            "cancelled" => RequestErrorKind::BatchCancelled,
+                            _ => RequestErrorKind::BatchFailed,
+                        };
+                        let error = RequestError::new(kind, format!("Batch {}", batch.status))
+                            .retryable(batch.is_transient_failure());
+                        self.fail_or_requeue(&request_id, error).await?;
+                    }
+                    self.state.remove_processing_batch(batch_id, &api_key).await?;
+                    break;
+                }
+                _ => {
+                    // Still processing
+                    delay = urgency_poll_interval.unwrap_or_else(|| self.poll_delay(&batch.status, batch.created_at));
+                    debug!("Next poll for batch {} in {:?}", batch_id, delay);
+                    continue;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The request to send upstream, with the key mapping's or the global
+    /// system prompt applied. The stored request is left untouched.
+    fn outgoing_request(&self, state: &RequestState) -> CompletionRequest {
+        match state.system_prompt.as_ref().or(self.config.system_prompt.as_ref()) {
+            Some(prompt) => prompt.apply(&state.request),
+            None => state.request.clone(),
+        }
+    }
+
+    /// The tenant's pool of upstream keys from the config file, empty when
+    /// requests are sent with their own key.
+    fn key_pool(&self) -> &[String] {
+        self.config
+            .tenants
+            .get(self.state.tenant())
+            .map(|settings| settings.key_pool.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// The key in the pool with the fewest batches (then tokens) in flight,
+    /// skipping keys that are paused, rate limited, at their in-flight limit
+    /// or out of batches for the day.
+    async fn pick_pool_key<'a>(&self, key_pool: &'a [String]) -> Result<Option<&'a String>> {
+        let mut best = None;
+        for api_key in key_pool {
+            let fingerprint = key_fingerprint(api_key);
+            if self.runtime.is_key_paused(&fingerprint)
+                || self.backoff_remaining(&fingerprint).is_some()
+                || self.inflight_limit_reached(api_key, &fingerprint, 0).await?
+                || self.daily_batch_limit_reached(api_key).await?
+            {
+                continue;
+            }
+            let load = self.state.inflight_batches(api_key).await?;
+            if best.as_ref().is_none_or(|(_, best_load)| load < *best_load) {
+                best = Some((api_key, load));
+            }
+        }
+        Ok(best.map(|(api_key, _)| api_key))
+    }
+
+    /// Points a request at the upstream `SILT_UPSTREAM_ROUTES` sends its model
+    /// to, unless it picked one with `x-silt-upstream-url`.
+    fn route(&self, state: &mut RequestState) {
+        if state.upstream_url.is_some() {
+            return;
+        }
+        if let Some(route) = self.config.upstream_route(&state.request.model) {
+            let (base_url, api_key) = match &route.fallback_base_url {
+                Some(fallback) if self.is_failing(&route.base_url) => {
+                    (fallback, route.fallback_api_key.as_ref().or(route.api_key.as_ref()))
+                }
+                _ => (&route.base_url, route.api_key.as_ref()),
+            };
+            state.upstream_url = Some(base_url.clone());
+            if let Some(api_key) = api_key {
+                state.api_key = api_key.clone();
+            }
+        }
+    }
+
+    /// Whether new batches for a routed upstream should go to the route's
+    /// fallback: its circuit breaker is open, or its last
+    /// `upstream_failover_threshold` dispatches failed within the circuit
+    /// cooldown.
+    fn is_failing(&self, base_url: &str) -> bool {
+        if !self.openai_client.for_upstream(Some(base_url)).circuit_breaker().allow_request() {
+            return true;
+        }
+        let threshold = self.config.upstream_failover_threshold;
+        let cooldown = Duration::from_secs(self.config.upstream_circuit_cooldown_secs);
+        self.upstream_failures
+            .lock()
+            .unwrap()
+            .get(base_url)
+            .is_some_and(|(failures, last)| threshold > 0 && *failures >= threshold && last.elapsed() < cooldown)
+    }
+
+    fn record_dispatch_outcome(&self, upstream_url: Option<&str>, succeeded: bool) {
+        let Some(upstream_url) = upstream_url else {
+            return;
+        };
+        let mut failures = self.upstream_failures.lock().unwrap();
+        if succeeded {
+            failures.remove(upstream_url);
+            return;
+        }
+        let (count, last) = failures.entry(upstream_url.to_string()).or_insert((0, Instant::now()));
+        *count += 1;
+        *last = Instant::now();
+        if *count == self.config.upstream_failover_threshold {
+            warn!(
+                "{} consecutive dispatches to {} failed, routes with a fallback will use it",
+                count, upstream_url
+            );
+        }
+    }
+
+    /// Alerts when the oldest dispatchable request has waited longer than
+    /// `alert_queue_age_secs`.
+    fn check_queue_age(&self, priority: Priority, oldest: Option<DateTime<Utc>>) {
+        let threshold = self.config.alert_queue_age_secs;
+        let Some(oldest) = oldest else {
+            return;
+        };
+        let age_secs = (Utc::now() - oldest).num_seconds().max(0) as u64;
+        if threshold == 0 || age_secs < threshold {
+            return;
+        }
+        let tenant = self.state.tenant();
+        alerts().send(
+            AlertKind::QueueAge,
+            &format!("{}:{}", tenant, priority.as_str()),
+            format!(
+                "Oldest queued {} priority request for tenant {} has waited {}s (threshold {}s)",
+                priority.as_str(),
+                tenant,
+                age_secs,
+                threshold
+            ),
+        );
+    }
+
+    /// Periodically measures how long every tenant's requests have been queued
+    /// and in flight, alerting when any exceed their SLA.
+    pub async fn start_sla_monitor(&self) {
+        let mut ticker = interval(Duration::from_secs(SLA_CHECK_INTERVAL_SECS));
+
+        loop {
+            ticker.tick().await;
+
+            let tenants = match self.state.list_tenants().await {
+                Ok(tenants) => tenants,
+                Err(e) => {
+                    error!("Failed to list tenants for the SLA check: {}", e);
+                    continue;
+                }
+            };
+            for tenant in tenants {
+                if let Err(e) = self.for_tenant(&tenant).check_slas().await {
+                    error!("SLA check failed for tenant {}: {}", tenant, e);
+                }
+            }
+        }
+    }
+
+    /// Queued requests are aged from submission and in-flight ones from
+    /// dispatch, which every request in a batch shares.
+    async fn check_slas(&self) -> Result<()> {
+        let now = Utc::now();
+        let age_secs = |since: DateTime<Utc>| (now - since).num_seconds().max(0) as u64;
+
+        if self.config.sla_queued_secs > 0 {
+            let sla = chrono::Duration::seconds(self.config.sla_queued_secs as i64);
+            let (oldest, breached) = self.state.queued_since(now - sla).await?;
+            self.report_sla("queued", self.config.sla_queued_secs, oldest.map_or(0, age_secs), breached);
+        }
+
+        if self.config.sla_processing_secs > 0 {
+            let (mut oldest, mut breached) = (0, 0);
+            for batch_id in self.state.get_processing_batches().await? {
+                let request_ids = self.state.get_batch_requests(&batch_id).await?;
+                let Some(first) = request_ids.first() else {
+                    continue;
+                };
+                let Some(dispatched_at) = self.state.get_request(first).await?.and_then(|state| state.dispatched_at)
+                else {
+                    continue;
+                };
+                let age = age_secs(dispatched_at);
+                oldest = oldest.max(age);
+                if age > self.config.sla_processing_secs {
+                    breached += request_ids.len();
+                }
+            }
+            self.report_sla("processing", self.config.sla_processing_secs, oldest, breached);
+        }
+
+        Ok(())
+    }
+
+    fn report_sla(&self, status: &str, sla_secs: u64, oldest: u64, breached: usize) {
+        let tenant = self.state.tenant();
+        metrics()
+            .oldest_request_age_seconds
+            .with_label_values(&[tenant, status])
+            .set(oldest as i64);
+        metrics()
+            .sla_breached_requests
+            .with_label_values(&[tenant, status])
+            .set(breached as i64);
+        if breached > 0 {
+            alerts().send(
+                AlertKind::SlaBreach,
+                &format!("{}:{}", tenant, status),
+                format!(
+                    "{} {} request(s) for tenant {} exceeded the {}s SLA; the oldest has been {} for {}s",
+                    breached, status, tenant, sla_secs, status, oldest
+                ),
+            );
+        }
+    }
+
+    /// Poll frequently while a batch is young or about to finish, and back
+    /// off exponentially (doubling every `batch_poll_backoff_step_secs` of
+    /// age) while it sits in progress for hours.
+    fn poll_delay(&self, status: &str, created_at: i64) -> Duration {
+        let min_secs = self.runtime.poll_interval_secs();
+        let max_secs = self.runtime.poll_max_interval_secs().max(min_secs);
+
+        if status == "finalizing" || self.config.batch_poll_backoff_step_secs == 0 {
+            return Duration::from_secs(min_secs);
+        }
+
+        let age_secs = (Utc::now().timestamp() - created_at).max(0) as u64;
+        let steps = (age_secs / self.config.batch_poll_backoff_step_secs).min(32) as u32;
+        let delay_secs = min_secs.saturating_mul(2u64.saturating_pow(steps)).min(max_secs);
+
+        Duration::from_secs(delay_secs)
+    }
+
+    /// Stores each result as its line of the output and error files arrives,
+    /// so waiters are released while the rest is still downloading.
+    async fn process_batch_results(
+        &self,
+        client: &OpenAIClient,
+        api_key: &str,
+        batch: &BatchResponse,
+        file_ids: &[String],
+    ) -> Result<()> {
+        let batch_id = batch.id.as_str();
+        info!("Processing results for batch: {}", batch_id);
+
+        // Batches that ended early have no completion time, so they count
+        // from when silt saw them end
+        let finished_at = batch
+            .completed_at
+            .and_then(|completed_at| DateTime::from_timestamp(completed_at, 0))
+            .unwrap_or_else(Utc::now);
+
+        // Fanned out choices can only be merged once every line is in
+        let mut fanned_out = HashMap::new();
+        let mut malformed_choices = HashSet::new();
+        // Whether each custom_id's line succeeded, to settle duplicates
+        let mut seen: HashMap<String, bool> = HashMap::new();
+        let mut retrieved = 0;
+        for file_id in file_ids {
+            let mut results = std::pin::pin!(client.batch_results(api_key, file_id));
+            while let Some(result) = results.next().await {
+                retrieved += 1;
+                let result = result?;
+                let (custom_id, succeeded) = match &result {
+                    BatchResult::Parsed(line) => {
+                        (Some(&line.custom_id), (200..300).contains(&line.response.status_code))
+                    }
+                    BatchResult::Malformed { custom_id, .. } => (custom_id.as_ref(), false),
+                    BatchResult::Failed { custom_id, .. } => (Some(custom_id), false),
+                };
+
+                // The first line wins unless a later one succeeds where it failed
+                let mut replaces_failure = false;
+                if let Some(custom_id) = custom_id {
+                    if let Some(&earlier) = seen.get(custom_id) {
+                        replaces_failure = succeeded && !earlier;
+                        self.record_duplicate_result(batch_id, custom_id, replaces_failure).await;
+                        if !replaces_failure {
+                            continue;
+                        }
+                    }
+                    seen.insert(custom_id.clone(), succeeded);
+                }
+
+                let line = match result {
+                    BatchResult::Parsed(line) => line,
+                    BatchResult::Malformed { custom_id, line, error } => {
+                        let preview: String = line.chars().take(MALFORMED_LINE_PREVIEW_CHARS).collect();
+                        error!("Unreadable result line in batch {} ({}): {}", batch_id, error, preview);
+                        match custom_id {
+                            Some(custom_id) if custom_id.contains(FAN_OUT_SEPARATOR) => {
+                                malformed_choices.insert(custom_id);
+                            }
+                            Some(request_id) => self.fail_malformed_result(&request_id, &error).await?,
+                            None => {}
+                        }
+                        continue;
+                    }
+                    BatchResult::Failed { custom_id, status_code, code, message } => {
+                        if custom_id.contains(FAN_OUT_SEPARATOR) {
+                            malformed_choices.insert(custom_id);
+                        } else {
+                            self.fail_result_line(&custom_id, status_code, code.as_deref(), &message)
+                                .await?;
+                        }
+                        continue;
+                    }
+                };
+                if line.custom_id.contains(FAN_OUT_SEPARATOR) {
+                    malformed_choices.remove(&line.custom_id);
+                    fanned_out.insert(line.custom_id, line.response.body);
+                } else if replaces_failure {
+                    // The earlier line failed the request, so it counts as finished
+                    self.complete_request(&line.custom_id, line.response.body, Some(finished_at)).await?;
+                } else {
+                    self.store_batch_result(&line.custom_id, line.response.body, finished_at).await?;
+                }
+            }
+        }
+
+        info!("Retrieved {} results", retrieved);
+
+        let mut reassembled = HashSet::new();
+        for (request_id, response) in reassemble_choices(fanned_out) {
+            self.store_batch_result(&request_id, response, finished_at).await?;
+            reassembled.insert(request_id);
+        }
+        // Requests whose every choice was unreadable have nothing to return
+        for custom_id in malformed_choices {
+            let request_id = custom_id_request(&custom_id);
+            if reassembled.insert(request_id.to_string()) {
+                self.fail_malformed_result(request_id, "no choice could be read").await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts and audits a second result line for the same custom_id.
+    async fn record_duplicate_result(&self, batch_id: &str, custom_id: &str, replaced: bool) {
+        let kept = if replaced { "later, successful" } else { "first" };
+        warn!("Batch {} returned more than one result for {}, keeping the {} one", batch_id, custom_id, kept);
+        metrics()
+            .duplicate_results_total
+            .with_label_values(&[self.state.tenant()])
+            .inc();
+        let detail = format!("duplicate result line for {} in batch {}, kept the {} one", custom_id, batch_id, kept);
+        if let Err(e) = self.state.record_duplicate_result(custom_id_request(custom_id), detail).await {
+            warn!("Failed to audit duplicate result for {}: {}", custom_id, e);
+        }
+    }
+
+    async fn fail_malformed_result(&self, request_id: &str, error: impl std::fmt::Display) -> Result<()> {
+        if self.finished_out_of_band(request_id).await? {
+            return Ok(());
+        }
+        let message = format!("Could not parse batch result: {}", error);
+        self.state
+            .fail_request(request_id, RequestError::new(RequestErrorKind::MalformedResult, message))
+            .await
+    }
+
+    async fn fail_result_line(
+        &self,
+        request_id: &str,
+        status_code: Option<u16>,
+        code: Option<&str>,
+        message: &str,
+    ) -> Result<()> {
+        if self.finished_out_of_band(request_id).await? {
+            return Ok(());
+        }
+        let message = match (status_code, code) {
+            (Some(status), _) => format!("Upstream returned {}: {}", status, message),
+            (None, Some(code)) => format!("Upstream failed the request ({}): {}", code, message),
+            (None, None) => format!("Upstream failed the request: {}", message),
+        };
+        let error = RequestError::new(RequestErrorKind::ResultLine, message)
+            .with_upstream_status(status_code)
+            .retryable(BatchResult::is_transient_failure(status_code, code));
+        self.fail_or_requeue(request_id, error).await
+    }
+
+    /// Fails a request, unless the error is retryable and it has requeues
+    /// left under `SILT_REQUEUE_MAX_ATTEMPTS`, in which case it goes back on
+    /// the queue after an exponential backoff.
+    async fn fail_or_requeue(&self, request_id: &str, error: RequestError) -> Result<()> {
+        if error.retryable {
+            if let Some(state) = self.state.get_request(request_id).await? {
+                if state.requeues < self.config.requeue_max_attempts {
+                    let delay_secs = self
+                        .config
+                        .requeue_base_delay_secs
+                        .saturating_mul(2u64.saturating_pow(state.requeues))
+                        .min(self.config.requeue_max_delay_secs);
+                    let retry_at = Utc::now() + chrono::Duration::seconds(delay_secs as i64);
+                    warn!("Request {} failed transiently, requeueing in {}s: {}", request_id, delay_secs, error);
+                    if self.state.schedule_requeue(request_id, retry_at, &error.message).await? {
+                        metrics()
+                            .requests_requeued_total
+                            .with_label_values(&[self.state.tenant()])
+                            .inc();
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        self.state.fail_request(request_id, error).await
+    }
+
+    async fn store_batch_result(
+        &self,
+        request_id: &str,
+        response: CompletionResponse,
+        finished_at: DateTime<Utc>,
+    ) -> Result<()> {
+        if self.finished_out_of_band(request_id).await? {
+            info!("Discarding late batch result for {}, already completed", request_id);
+            return Ok(());
+        }
+        self.complete_request(request_id, response, Some(finished_at)).await
+    }
+
+    /// Stores a result once `SILT_POST_RESULT_HOOKS` have run over it. A hook
+    /// rejecting the result fails the request instead.
+    async fn complete_request(
+        &self,
+        request_id: &str,
+        mut response: CompletionResponse,
+        upstream_completed_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        if self.hooks.has_post_result() {
+            if let Some(state) = self.state.get_request(request_id).await? {
+                let context = HookContext {
+                    tenant: self.state.tenant(),
+                    request_id,
+                };
+                if let Err(e) = self.hooks.after_result(context, &state.request, &mut response).await {
+                    warn!("Result for {} failed a post-result hook: {:#}", request_id, e);
+                    let error = RequestError::new(RequestErrorKind::PostResultHook, format!("{:#}", e));
+                    return self.state.fail_request(request_id, error).await;
+                }
+            }
+        }
+        self.state.complete_request(request_id, response, upstream_completed_at).await
+    }
+
+    /// Copies a completed batch's finished requests to the archive, if one is
+    /// configured. Failures are logged; the requests stay in Redis either way.
+    async fn archive_batch(&self, batch_id: &str, request_ids: &[String]) {
+        let Some(archiver) = &self.archiver else {
+            return;
+        };
+
+        let mut states = Vec::with_capacity(request_ids.len());
+        for request_id in request_ids {
+            match self.state.get_request(request_id).await {
+                Ok(Some(mut state)) if matches!(state.status, RequestStatus::Complete | RequestStatus::Failed) => {
+                    if let Err(e) = self.state.load_result(&mut state).await {
+                        warn!("Failed to load the result of {} for archiving: {}", request_id, e);
+                    }
+                    states.push(state)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to load {} for archiving: {}", request_id, e),
+            }
+        }
+        if states.is_empty() {
+            return;
+        }
+
+        match archiver.archive_batch(self.state.tenant(), batch_id, &states).await {
+            Ok(path) => info!("Archived {} request(s) from batch {} to {}", states.len(), batch_id, path),
+            Err(e) => error!("Failed to archive batch {}: {}", batch_id, e),
+        }
+    }
+
+    /// Feeds the per-model duration history used for ETA estimates.
+    async fn record_batch_duration(&self, request_ids: &[String], duration_secs: i64) {
+        let mut models = std::collections::BTreeSet::new();
+        for request_id in request_ids {
+            if let Ok(Some(state)) = self.state.get_request(request_id).await {
+                models.insert(state.request.model);
+            }
+        }
+
+        for model in models {
+            if let Err(e) = self.state.record_batch_duration(&model, duration_secs).await {
+                warn!("Failed to record batch duration for {}: {}", model, e);
+            }
+        }
+    }
+
+    /// Whether a request already reached a final state outside its batch,
+    /// e.g. through the deadline fallback.
+    async fn finished_out_of_band(&self, request_id: &str) -> Result<bool> {
+        Ok(self
+            .state
+            .get_request(request_id)
+            .await?
+            .is_some_and(|state| matches!(state.status, RequestStatus::Complete | RequestStatus::Failed)))
+    }
+
+    /// Periodically re-runs requests that passed their `x-silt-deadline-secs`
+    /// deadline against the realtime endpoint.
+    pub async fn start_deadline_watcher(&self) {
+        let mut ticker = interval(Duration::from_secs(DEADLINE_CHECK_INTERVAL_SECS));
+
+        loop {
+            ticker.tick().await;
+
+            let tenants = match self.state.list_tenants().await {
+                Ok(tenants) => tenants,
+                Err(e) => {
+                    error!("Failed to list tenants: {}", e);
+                    continue;
+                }
+            };
+            for tenant in tenants {
+                self.for_tenant(&tenant).run_expired_deadlines().await;
+            }
+        }
+    }
+
+    /// Periodically puts requests that failed transiently back on their
+    /// tenant's queue once their backoff has passed.
+    pub async fn start_requeue_scheduler(&self) {
+        let mut ticker = interval(Duration::from_secs(REQUEUE_CHECK_INTERVAL_SECS));
+
+        loop {
+            ticker.tick().await;
+
+            let tenants = match self.state.list_tenants().await {
+                Ok(tenants) => tenants,
+                Err(e) => {
+                    error!("Failed to list tenants for requeueing: {}", e);
+                    continue;
+                }
+            };
+            for tenant in tenants {
+                match self.state.for_tenant(&tenant).requeue_due().await {
+                    Ok(0) => {}
+                    Ok(requeued) => info!("Requeued {} request(s) for tenant {}", requeued, tenant),
+                    Err(e) => error!("Requeue failed for tenant {}: {}", tenant, e),
+                }
+            }
+        }
+    }
+
+    /// Labels the `SILT_METRICS_MAX_KEYS` API keys with the most requests
+    /// finished today across tenants in the per-key metrics, so the keys
+    /// worth watching get their own series whichever this replica saw first.
+    pub async fn start_key_ranker(&self) {
+        let mut ticker = interval(Duration::from_secs(KEY_RANK_INTERVAL_SECS));
+
+        loop {
+            ticker.tick().await;
+
+            match self.rank_keys().await {
+                Ok(keys) => metrics().set_labelled_keys(keys),
+                Err(e) => error!("Failed to rank API keys for the metrics: {}", e),
+            }
+        }
+    }
+
+    async fn rank_keys(&self) -> Result<HashSet<String>> {
+        let today = Utc::now().date_naive();
+        let mut volumes: HashMap<String, u64> = HashMap::new();
+        for tenant in self.state.list_tenants().await? {
+            let analytics = self.state.for_tenant(&tenant).analytics(today).await?;
+            for (fingerprint, counts) in analytics.keys {
+                *volumes.entry(fingerprint).or_default() += counts.completed + counts.failed;
+            }
+        }
+
+        let mut ranked: Vec<(String, u64)> = volumes.into_iter().collect();
+        ranked.sort_by_key(|(_, volume)| std::cmp::Reverse(*volume));
+        Ok(ranked
+            .into_iter()
+            .take(self.config.metrics_max_keys)
+            .map(|(fingerprint, _)| fingerprint)
+            .collect())
+    }
+
+    /// Erases or trims finished requests according to each tenant's
+    /// retention policy.
+    pub async fn start_retention_sweeper(&self) {
+        let mut ticker = interval(Duration::from_secs(RETENTION_SWEEP_INTERVAL_SECS));
+
+        loop {
+            ticker.tick().await;
+
+            let policies = match self.state.list_retention_policies().await {
+                Ok(policies) => policies,
+                Err(e) => {
+                    error!("Failed to load retention policies: {}", e);
+                    continue;
+                }
+            };
+            for (tenant, policy) in policies {
+                if let Err(e) = self.for_tenant(&tenant).sweep_retention(&policy).await {
+                    error!("Retention sweep failed for tenant {}: {}", tenant, e);
+                }
+            }
+        }
+    }
+
+    /// Deletes old batch input files silt uploaded, for every upstream key it
+    /// has uploaded with. Inputs of batches that were created go too, once
+    /// past `SILT_FILE_SWEEP_MIN_AGE_SECS`; the upstream no longer needs them.
+    pub async fn start_file_sweeper(&self) {
+        let interval_secs = self.config.file_sweep_interval_secs;
+        let mut ticker = interval(Duration::from_secs(interval_secs));
+
+        loop {
+            ticker.tick().await;
+
+            // One worker sweeps per interval; the rest skip it
+            match self.state.claim_file_sweep(interval_secs).await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    error!("Failed to claim the file sweep: {}", e);
+                    continue;
+                }
+            }
+            let api_keys = match self.state.list_upload_keys().await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    error!("Failed to load upload keys: {}", e);
+                    continue;
+                }
+            };
+            for api_key in api_keys {
+                if let Err(e) = self.sweep_files(&api_key).await {
+                    error!("File sweep failed for API key {}: {}", key_fingerprint(&api_key), e);
+                }
+            }
+        }
+    }
+
+    async fn sweep_files(&self, api_key: &str) -> Result<()> {
+        let fingerprint = key_fingerprint(api_key);
+        let files = match self.openai_client.list_batch_files(api_key).await {
+            Ok(files) => files,
+            Err(e) if is_unauthorized(&e) => {
+                info!("Upstream no longer accepts API key {}, no longer sweeping its files", fingerprint);
+                self.state.forget_upload_key(&fingerprint).await?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let cutoff = Utc::now().timestamp() - self.config.file_sweep_min_age_secs as i64;
+        let mut deleted = 0;
+        for file in files
+            .iter()
+            .filter(|file| file.filename.starts_with(UPLOAD_FILENAME_PREFIX) && file.created_at <= cutoff)
+        {
+            match self.openai_client.delete_file(api_key, &file.id).await {
+                Ok(()) => {
+                    deleted += 1;
+                    metrics().upstream_files_swept_total.inc();
+                }
+                Err(e) => warn!("Failed to delete file {}: {}", file.id, e),
+            }
+        }
+
+        if deleted > 0 {
+            info!("Deleted {} old batch file(s) for API key {}", deleted, fingerprint);
+        }
+        Ok(())
+    }
+
+    async fn sweep_retention(&self, policy: &RetentionPolicy) -> Result<()> {
+        let seconds = |secs: u64| chrono::Duration::seconds(secs as i64);
+        let (mut purged, mut trimmed) = (0, 0);
+
+        for request_id in self.state.list_finished_requests().await? {
+            let Some(state) = self.state.get_request(&request_id).await? else {
+                continue;
+            };
+            if !matches!(state.status, RequestStatus::Complete | RequestStatus::Failed) {
+                continue;
+            }
+
+            let now = Utc::now();
+            let expired = policy
+                .max_age_secs
+                .is_some_and(|max_age| now - state.created_at >= seconds(max_age))
+                || policy
+                    .result_ttl_after_retrieval_secs
+                    .zip(state.retrieved_at)
+                    .is_some_and(|(ttl, retrieved_at)| now - retrieved_at >= seconds(ttl));
+            if expired {
+                if self.state.purge_request(&request_id).await? {
+                    purged += 1;
+                }
+            } else if policy.drop_prompts_on_completion && !state.request.messages.is_empty() {
+                self.state.drop_prompt(&request_id).await?;
+                trimmed += 1;
+            }
+        }
+
+        if purged > 0 || trimmed > 0 {
+            info!(
+                "Retention sweep for tenant {}: erased {} request(s), dropped {} prompt(s)",
+                self.state.tenant(),
+                purged,
+                trimmed
+            );
+        }
+        Ok(())
+    }
+
+    /// Claims and runs fallbacks for this tenant's requests past their deadline.
+    async fn run_expired_deadlines(&self) {
+        let request_ids = match self.state.get_expired_deadlines().await {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("Failed to read request deadlines: {}", e);
+                return;
+            }
+        };
+
+        for request_id in request_ids {
+            match self.state.claim_deadline(&request_id).await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    error!("Failed to claim deadline for {}: {}", request_id, e);
+                    continue;
+                }
+            }
+
+            let worker = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = worker.run_deadline_fallback(&request_id).await {
+                    error!("Deadline fallback failed for {}: {}", request_id, e);
+                }
+            });
+        }
+    }
+
+    async fn run_deadline_fallback(&self, request_id: &str) -> Result<()> {
+        let Some(mut state) = self.state.get_request(request_id).await? else {
+            return Ok(());
+        };
+        state.api_key = self.state.upstream_key(&state.api_key).await?;
+        self.route(&mut state);
+
+        if matches!(state.status, RequestStatus::Complete | RequestStatus::Failed) {
+            return Ok(());
+        }
+
+        info!(
+            "Request {} missed its deadline while {:?}, falling back to realtime",
+            request_id, state.status
+        );
+
+        // Keep it out of any future batch; if it is already in one, that
+        // result is discarded when it arrives
+        self.state.remove_from_queue(request_id, state.priority).await?;
+
+        match self
+            .openai_client
+            .for_upstream(state.upstream_url.as_deref())
+            .with_scope(&state.scope)
+            .with_trace_context(state.trace_context.as_ref())
+            .create_chat_completion(&state.api_key, &self.outgoing_request(&state))
+            .await
+        {
+            Ok(response) => self.complete_request(request_id, response, None).await,
+            Err(e) => {
+                let error = RequestError::new(RequestErrorKind::DeadlineFallback, format!("Realtime fallback failed: {}", e))
+                    .with_upstream_status(upstream_status(&e));
+                self.state.fail_request(request_id, error).await
+            }
+        }
+    }
+
+    fn clone(&self) -> Self {
+        self.for_tenant(self.state.tenant())
+    }
+
+    /// A worker operating on one tenant's state, sharing the upstream client
+    /// and rate limit backoff.
+    fn for_tenant(&self, tenant: &str) -> Self {
+        Self {
+            config: Arc::clone(&self.config),
+            runtime: Arc::clone(&self.runtime),
+            state: self.state.for_tenant(tenant),
+            openai_client: self.openai_client.clone(),
+            dispatch_backoff: Arc::clone(&self.dispatch_backoff),
+            upstream_failures: Arc::clone(&self.upstream_failures),
+            archiver: self.archiver.clone(),
+            hooks: Arc::clone(&self.hooks),
+        }
+    }
+
+    /// Polls a batch in the background until it finishes, restarting the
+    /// poll if it panics.
+    fn spawn_poll(&self, batch_id: String) {
+        let worker = self.clone();
+        tokio::spawn(supervise("batch_poller", move || {
+            let worker = worker.clone();
+            let batch_id = batch_id.clone();
+            async move {
+                if let Err(e) = worker.poll_batch(&batch_id).await {
+                    error!("Error polling batch {}: {}", batch_id, e);
+                }
+            }
+        }));
+    }
+
+    pub async fn start_poller(&self) {
+        let tenants = match self.state.list_tenants().await {
+            Ok(tenants) => tenants,
+            Err(e) => {
+                error!("Failed to list tenants: {}", e);
+                return;
+            }
+        };
+
+        // Poll existing batches on startup
+        for tenant in tenants {
+            let tenant_worker = self.for_tenant(&tenant);
+            if let Ok(batch_ids) = tenant_worker.state.get_processing_batches().await {
+                for batch_id in batch_ids {
+                    tenant_worker.spawn_poll(batch_id);
+                }
+            }
+        }
+    }
+}
+
+/// Splits each request with `n` > 1 into `n` single-choice lines.
+fn fan_out_choices(requests: Vec<(String, CompletionRequest)>) -> Vec<(String, CompletionRequest)> {
+    requests
+        .into_iter()
+        .flat_map(|(request_id, request)| match request.n.filter(|n| *n > 1) {
+            Some(n) => (0..n)
+                .map(|index| {
+                    let mut line = request.clone();
+                    line.n = None;
+                    (format!("{}{}{}", request_id, FAN_OUT_SEPARATOR, index), line)
+                })
+                .collect(),
+            None => vec![(request_id, request)],
+        })
+        .collect::<Vec<_>>()
+}
+
+/// The request a batch line's custom_id belongs to, without any fan out suffix.
+fn custom_id_request(custom_id: &str) -> &str {
+    custom_id
+        .rsplit_once(FAN_OUT_SEPARATOR)
+        .map_or(custom_id, |(request_id, _)| request_id)
+}
+
+/// A finished batch's output file, then its error file, whichever it has.
+fn result_files(batch: &BatchResponse) -> Vec<String> {
+    batch.output_file_id.iter().chain(&batch.error_file_id).cloned().collect()
+}
+
+/// Merges fanned out lines back into one response per request, with each
+/// line's choice at its own index and usage summed. Lines that failed are
+/// simply missing, so the response keeps the choices that succeeded.
+fn reassemble_choices(results: HashMap<String, CompletionResponse>) -> Vec<(String, CompletionResponse)> {
+    let mut responses = Vec::new();
+    let mut fanned_out: BTreeMap<String, Vec<(u32, CompletionResponse)>> = BTreeMap::new();
+    for (custom_id, response) in results {
+        let part = custom_id
+            .rsplit_once(FAN_OUT_SEPARATOR)
+            .and_then(|(request_id, index)| Some((request_id, index.parse::<u32>().ok()?)));
+        match part {
+            Some((request_id, index)) => fanned_out.entry(request_id.to_string()).or_default().push((index, response)),
+            None => responses.push((custom_id, response)),
+        }
+    }
+
+    for (request_id, mut parts) in fanned_out {
+        parts.sort_by_key(|(index, _)| *index);
+        let mut parts = parts.into_iter();
+        let Some((first_index, mut merged)) = parts.next() else {
+            continue;
+        };
+        for choice in &mut merged.choices {
+            choice.index = first_index;
+        }
+        for (index, part) in parts {
+            merged.usage.prompt_tokens += part.usage.prompt_tokens;
+            merged.usage.completion_tokens += part.usage.completion_tokens;
+            merged.usage.total_tokens += part.usage.total_tokens;
+            merged.choices.extend(part.choices.into_iter().map(|mut choice| {
+                choice.index = index;
+                choice
+            }));
+        }
+        responses.push((request_id, merged));
+    }
+    responses
+}
+
+fn is_transient(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<UpstreamError>()
+        .is_some_and(|e| e.is_transient() || matches!(e, UpstreamError::CircuitOpen))
+}
+
+/// The status an upstream call failed with, if it got a response.
+fn upstream_status(error: &anyhow::Error) -> Option<u16> {
+    match error.downcast_ref::<UpstreamError>() {
+        Some(UpstreamError::Status { status, .. }) => Some(status.as_u16()),
+        _ => None,
+    }
+}
+
+fn is_unauthorized(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<UpstreamError>(),
+        Some(UpstreamError::Status { status, .. }) if *status == reqwest::StatusCode::UNAUTHORIZED
+    )
+}
+
+/// Waits until a lane's next scheduled dispatch, or forever if it has none.
+async fn sleep_until_scheduled(at: Option<DateTime<Utc>>) {
+    match at {
+        Some(at) => tokio::time::sleep((at - Utc::now()).to_std().unwrap_or_default()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next early-dispatch trigger, or forever if not subscribed.
+async fn next_trigger(triggers: &mut Option<redis::aio::PubSubStream>) -> Option<redis::Msg> {
+    match triggers {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Drops expired backoffs and refreshes the backoff gauges.
+fn update_backoff_metrics(backoff: &mut HashMap<String, Instant>) {
+    let now = Instant::now();
+    backoff.retain(|_, until| *until > now);
+
+    let longest = backoff
+        .values()
+        .map(|until| until.saturating_duration_since(now))
+        .max()
+        .unwrap_or_default();
+
+    metrics().dispatch_backoff_keys.set(backoff.len() as i64);
+    metrics().dispatch_backoff_seconds.set(longest.as_secs_f64());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(content: &str, completion_tokens: u32) -> CompletionResponse {
+        serde_json::from_value(serde_json::json!({
+            "id": format!("chatcmpl-{}", content),
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "gpt-4o-mini",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": content}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 10, "completion_tokens": completion_tokens, "total_tokens": 10 + completion_tokens}
+        }))
+        .unwrap()
+    }
+
+    fn fanned_out(request_id: &str, index: u32) -> String {
+        format!("{}{}{}", request_id, FAN_OUT_SEPARATOR, index)
+    }
+
+    fn contents(response: &CompletionResponse) -> Vec<(u32, String)> {
+        response
+            .choices
+            .iter()
+            .map(|choice| {
+                (
+                    choice.index,
+                    choice.message.content.as_ref().unwrap().text().into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merges_fanned_out_choices_in_index_order() {
+        let results = HashMap::from([
+            (fanned_out("req-1", 2), response("c", 3)),
+            (fanned_out("req-1", 0), response("a", 1)),
+            (fanned_out("req-1", 1), response("b", 2)),
+        ]);
+        let reassembled = reassemble_choices(results);
+
+        assert_eq!(reassembled.len(), 1);
+        let (request_id, merged) = &reassembled[0];
+        assert_eq!(request_id, "req-1");
+        assert_eq!(
+            contents(merged),
+            vec![(0, "a".into()), (1, "b".into()), (2, "c".into())]
+        );
+        assert_eq!(merged.id, "chatcmpl-a");
+        assert_eq!(merged.usage.prompt_tokens, 30);
+        assert_eq!(merged.usage.completion_tokens, 6);
+        assert_eq!(merged.usage.total_tokens, 36);
+    }
+
+    #[test]
+    fn keeps_the_choices_that_succeeded() {
+        let results = HashMap::from([
+            (fanned_out("req-1", 1), response("b", 2)),
+            (fanned_out("req-1", 3), response("d", 4)),
+        ]);
+        let reassembled = reassemble_choices(results);
+
+        assert_eq!(reassembled.len(), 1);
+        assert_eq!(contents(&reassembled[0].1), vec![(1, "b".into()), (3, "d".into())]);
+        assert_eq!(reassembled[0].1.usage.completion_tokens, 6);
+    }
+
+    #[test]
+    fn passes_through_requests_that_were_not_fanned_out() {
+        let results = HashMap::from([
+            ("req-1".to_string(), response("a", 1)),
+            (fanned_out("req-2", 0), response("b", 2)),
+            // A suffix that isn't an index is part of the request id
+            (format!("req-3{}x", FAN_OUT_SEPARATOR), response("c", 3)),
+        ]);
+        let mut reassembled = reassemble_choices(results);
+        reassembled.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let ids: Vec<&str> = reassembled.iter().map(|(request_id, _)| request_id.as_str()).collect();
+        assert_eq!(ids, vec!["req-1", "req-2", "req-3#choice-x"]);
+        assert_eq!(contents(&reassembled[0].1), vec![(0, "a".into())]);
+        assert_eq!(custom_id_request(&fanned_out("req-2", 0)), "req-2");
+    }
+}
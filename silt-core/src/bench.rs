@@ -0,0 +1,260 @@
+use crate::config::{BenchArgs, Config};
+use crate::server::connect_state;
+use anyhow::{Context, Result};
+use futures_util::stream::{self, StreamExt};
+use reqwest::Client;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How often `/metrics` is scraped while the benchmark runs.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The figures the benchmark reads from the instance's `/metrics`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Sample {
+    submitted: f64,
+    waiting: f64,
+    resident_bytes: f64,
+}
+
+#[derive(Debug, Default)]
+struct Peaks {
+    waiting: f64,
+    resident_bytes: f64,
+    /// When the instance had counted every benchmark request as submitted.
+    enqueued_after: Option<Duration>,
+}
+
+/// `silt bench`: sends `--requests` synthetic chat completions to a running
+/// instance, `--concurrency` at a time, each waiting for its result. Meant to
+/// be pointed at an instance running the mock upstream. Enqueue throughput
+/// and waiter memory are read from the instance's `/metrics` (so other
+/// traffic skews them), Redis load from `INFO stats` on `SILT_REDIS_URL`.
+pub async fn run(config: &Config, args: &BenchArgs) -> Result<()> {
+    anyhow::ensure!(
+        args.requests > 0 && args.concurrency > 0,
+        "--requests and --concurrency must be at least 1"
+    );
+    let url = args
+        .url
+        .clone()
+        .unwrap_or_else(|| format!("http://127.0.0.1:{}", config.server_port));
+    let url = url.trim_end_matches('/').to_string();
+    let client = Client::new();
+
+    let baseline = scrape(&client, &url)
+        .await
+        .with_context(|| format!("Could not read {}/metrics", url))?;
+    let state = match connect_state(config).await {
+        Ok(state) => Some(state),
+        Err(e) => {
+            warn!("Not reporting Redis load: {:#}", e);
+            None
+        }
+    };
+    let commands_before = match &state {
+        Some(state) => state.commands_processed().await.ok(),
+        None => None,
+    };
+
+    println!(
+        "Sending {} requests to {}, {} at a time",
+        args.requests, url, args.concurrency
+    );
+    let run_id = uuid::Uuid::new_v4().simple().to_string();
+    let started = Instant::now();
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = tokio::spawn(sample(
+        client.clone(),
+        url.clone(),
+        baseline,
+        args.requests,
+        started,
+        Arc::clone(&done),
+    ));
+
+    let outcomes: Vec<Result<Duration, String>> = stream::iter(0..args.requests)
+        .map(|index| send(&client, &url, args, &run_id, index))
+        .buffer_unordered(args.concurrency)
+        .collect()
+        .await;
+    let elapsed = started.elapsed();
+    done.store(true, Ordering::Relaxed);
+    let peaks = sampler.await?;
+
+    let commands_after = match &state {
+        Some(state) => state.commands_processed().await.ok(),
+        None => None,
+    };
+
+    let mut latencies = Vec::new();
+    let mut errors = BTreeMap::<String, usize>::new();
+    for outcome in outcomes {
+        match outcome {
+            Ok(latency) => latencies.push(latency),
+            Err(error) => *errors.entry(error).or_default() += 1,
+        }
+    }
+    latencies.sort();
+
+    let failed: usize = errors.values().sum();
+    let failures = errors
+        .iter()
+        .map(|(error, count)| format!("{}: {}", error, count))
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!();
+    println!(
+        "{:<10} {} sent, {} succeeded, {} failed{}",
+        "Requests",
+        args.requests,
+        latencies.len(),
+        failed,
+        if failures.is_empty() { String::new() } else { format!(" ({})", failures) }
+    );
+    println!("{:<10} {:.1}s", "Duration", elapsed.as_secs_f64());
+
+    match peaks.enqueued_after {
+        Some(after) => println!(
+            "{:<10} {} requests in {:.2}s ({:.0} req/s)",
+            "Enqueue",
+            args.requests,
+            after.as_secs_f64(),
+            args.requests as f64 / after.as_secs_f64().max(f64::EPSILON)
+        ),
+        None => println!("{:<10} n/a (not every request was counted as submitted)", "Enqueue"),
+    }
+
+    if peaks.resident_bytes > 0.0 && peaks.waiting > 0.0 {
+        let growth = (peaks.resident_bytes - baseline.resident_bytes).max(0.0);
+        println!(
+            "{:<10} peak {:.0} waiting, resident memory +{:.1} MiB ({:.1} KiB per waiter)",
+            "Waiters",
+            peaks.waiting,
+            growth / (1024.0 * 1024.0),
+            growth / peaks.waiting / 1024.0
+        );
+    } else {
+        println!("{:<10} peak {:.0} waiting, memory not reported by the instance", "Waiters", peaks.waiting);
+    }
+
+    match (commands_before, commands_after) {
+        (Some(before), Some(after)) => println!(
+            "{:<10} {:.0} ops/s ({} commands)",
+            "Redis",
+            after.saturating_sub(before) as f64 / elapsed.as_secs_f64(),
+            after.saturating_sub(before)
+        ),
+        _ => println!("{:<10} n/a", "Redis"),
+    }
+
+    if latencies.is_empty() {
+        println!("{:<10} n/a (no request succeeded)", "Latency");
+    } else {
+        println!(
+            "{:<10} p50 {:.2}s  p90 {:.2}s  p99 {:.2}s  max {:.2}s",
+            "Latency",
+            percentile(&latencies, 50.0).as_secs_f64(),
+            percentile(&latencies, 90.0).as_secs_f64(),
+            percentile(&latencies, 99.0).as_secs_f64(),
+            percentile(&latencies, 100.0).as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+/// Sends one synthetic request and waits for its result. Every prompt is
+/// unique so identical-request de-duplication does not short-circuit it.
+async fn send(client: &Client, url: &str, args: &BenchArgs, run_id: &str, index: usize) -> Result<Duration, String> {
+    let body = json!({
+        "model": args.model,
+        "messages": [{
+            "role": "user",
+            "content": format!("Benchmark request {} of run {}", index, run_id),
+        }],
+    });
+    let mut request = client
+        .post(format!("{}/v1/chat/completions", url))
+        .bearer_auth(&args.api_key)
+        .header("idempotency-key", format!("bench-{}-{}", run_id, index))
+        .json(&body);
+    if let Some(priority) = args.priority {
+        request = request.header("x-silt-priority", priority.as_str());
+    }
+
+    let started = Instant::now();
+    let response = request.send().await.map_err(|e| {
+        if e.is_timeout() {
+            "timeout".to_string()
+        } else {
+            "connection error".to_string()
+        }
+    })?;
+    let status = response.status();
+    // Latency covers the whole response, as a client would see it
+    response.bytes().await.map_err(|_| "connection error".to_string())?;
+    if !status.is_success() {
+        return Err(status.to_string());
+    }
+    Ok(started.elapsed())
+}
+
+async fn sample(
+    client: Client,
+    url: String,
+    baseline: Sample,
+    requests: usize,
+    started: Instant,
+    done: Arc<AtomicBool>,
+) -> Peaks {
+    let mut peaks = Peaks::default();
+    while !done.load(Ordering::Relaxed) {
+        if let Ok(sample) = scrape(&client, &url).await {
+            peaks.waiting = peaks.waiting.max(sample.waiting);
+            peaks.resident_bytes = peaks.resident_bytes.max(sample.resident_bytes);
+            if peaks.enqueued_after.is_none() && sample.submitted - baseline.submitted >= requests as f64 {
+                peaks.enqueued_after = Some(started.elapsed());
+            }
+        }
+        tokio::time::sleep(SAMPLE_INTERVAL).await;
+    }
+    peaks
+}
+
+async fn scrape(client: &Client, url: &str) -> Result<Sample> {
+    let text = client
+        .get(format!("{}/metrics", url))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    let mut sample = Sample::default();
+    for line in text.lines().filter(|line| !line.starts_with('#')) {
+        let Some((series, value)) = line.rsplit_once(' ') else {
+            continue;
+        };
+        let Ok(value) = value.parse::<f64>() else {
+            continue;
+        };
+        let name = series.split('{').next().unwrap_or(series);
+        match name {
+            "silt_requests_submitted_total" => sample.submitted += value,
+            "silt_waiting_requests" => sample.waiting = value,
+            "silt_process_resident_memory_bytes" => sample.resident_bytes = value,
+            _ => {}
+        }
+    }
+    Ok(sample)
+}
+
+/// The `p`th percentile of sorted, non-empty `values`.
+fn percentile(values: &[Duration], p: f64) -> Duration {
+    let index = ((p / 100.0) * (values.len() - 1) as f64).round() as usize;
+    values[index.min(values.len() - 1)]
+}
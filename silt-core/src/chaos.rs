@@ -0,0 +1,112 @@
+use crate::config::Config;
+use crate::metrics::metrics;
+use crate::openai_client::UpstreamError;
+use reqwest::StatusCode;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::warn;
+
+/// Retry-After sent with injected 429s, so dispatch backoff is exercised too.
+const INJECTED_RETRY_AFTER: Duration = Duration::from_secs(2);
+
+static CHAOS: OnceLock<Chaos> = OnceLock::new();
+
+/// Process-wide fault injector, inert until `init_chaos` sees `SILT_CHAOS`.
+pub fn chaos() -> &'static Chaos {
+    CHAOS.get_or_init(Chaos::disabled)
+}
+
+pub fn init_chaos(config: &Config) {
+    if !config.chaos_enabled {
+        return;
+    }
+    warn!(
+        "Chaos mode is on: failing {:.0}% of Redis writes, delaying {:.0}% of polls by up to {}s, \
+         injecting {:.0}% upstream 429s and {:.0}% upstream 500s",
+        config.chaos_redis_drop_rate * 100.0,
+        config.chaos_poll_delay_rate * 100.0,
+        config.chaos_poll_delay_max_secs,
+        config.chaos_upstream_429_rate * 100.0,
+        config.chaos_upstream_500_rate * 100.0,
+    );
+    let _ = CHAOS.set(Chaos {
+        redis_drop_rate: config.chaos_redis_drop_rate,
+        poll_delay_rate: config.chaos_poll_delay_rate,
+        poll_delay_max: Duration::from_secs(config.chaos_poll_delay_max_secs),
+        upstream_429_rate: config.chaos_upstream_429_rate,
+        upstream_500_rate: config.chaos_upstream_500_rate,
+    });
+}
+
+/// Random faults injected at the Redis, polling and upstream boundaries, for
+/// checking that retries, requeues and the deadline fallback recover from
+/// them. Every injected fault is logged and counted in
+/// `silt_chaos_faults_total`.
+pub struct Chaos {
+    redis_drop_rate: f64,
+    poll_delay_rate: f64,
+    poll_delay_max: Duration,
+    upstream_429_rate: f64,
+    upstream_500_rate: f64,
+}
+
+impl Chaos {
+    fn disabled() -> Self {
+        Self {
+            redis_drop_rate: 0.0,
+            poll_delay_rate: 0.0,
+            poll_delay_max: Duration::ZERO,
+            upstream_429_rate: 0.0,
+            upstream_500_rate: 0.0,
+        }
+    }
+
+    /// Fails a Redis write before it is sent, as a dropped connection would.
+    pub fn redis_write(&self, operation: &str) -> anyhow::Result<()> {
+        if !roll(self.redis_drop_rate) {
+            return Ok(());
+        }
+        record("redis_write");
+        warn!("Chaos: dropping Redis write ({})", operation);
+        anyhow::bail!("Chaos: dropped Redis write ({})", operation)
+    }
+
+    /// Extra time to wait before the next status poll of a batch, if any.
+    pub fn poll_delay(&self) -> Option<Duration> {
+        if !roll(self.poll_delay_rate) || self.poll_delay_max.is_zero() {
+            return None;
+        }
+        record("poll_delay");
+        let delay = self.poll_delay_max.mul_f64(rand::random::<f64>());
+        warn!("Chaos: delaying poll by {:.1}s", delay.as_secs_f64());
+        Some(delay)
+    }
+
+    /// Fails an upstream call with a 429 or 500 before it is sent, so it
+    /// goes through the same retry and circuit breaker paths as a real one.
+    pub fn upstream_fault(&self, operation: &'static str) -> Result<(), UpstreamError> {
+        let (status, retry_after) = if roll(self.upstream_429_rate) {
+            (StatusCode::TOO_MANY_REQUESTS, Some(INJECTED_RETRY_AFTER))
+        } else if roll(self.upstream_500_rate) {
+            (StatusCode::INTERNAL_SERVER_ERROR, None)
+        } else {
+            return Ok(());
+        };
+        record(if retry_after.is_some() { "upstream_429" } else { "upstream_500" });
+        warn!("Chaos: failing {} with {}", operation, status);
+        Err(UpstreamError::Status {
+            operation,
+            status,
+            body: "Injected by chaos mode".to_string(),
+            retry_after,
+        })
+    }
+}
+
+fn roll(rate: f64) -> bool {
+    rate > 0.0 && rand::random::<f64>() < rate
+}
+
+fn record(kind: &str) {
+    metrics().chaos_faults_total.with_label_values(&[kind]).inc();
+}
@@ -0,0 +1,146 @@
+use crate::alerts::{alerts, AlertKind};
+use crate::metrics::metrics;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    fn as_gauge(self) -> i64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open => 2,
+        }
+    }
+}
+
+struct Inner {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// Stops upstream traffic after repeated consecutive failures. Once the
+/// cooldown elapses the breaker is half-open: calls are let through again and
+/// the first success closes it, while another failure reopens it.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    /// Base URL of a routed upstream; unset for the configured one, which is
+    /// the only breaker reported in `upstream_circuit_state`
+    upstream: Option<String>,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    /// A `failure_threshold` of 0 disables the breaker.
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            upstream: None,
+            inner: Mutex::new(Inner {
+                consecutive_failures: 0,
+                opened_at: None,
+            }),
+        }
+    }
+
+    /// A breaker for an upstream requests were routed to, named in its logs
+    /// and alerts.
+    pub fn for_upstream(failure_threshold: u32, cooldown: Duration, base_url: &str) -> Self {
+        Self {
+            upstream: Some(base_url.to_string()),
+            ..Self::new(failure_threshold, cooldown)
+        }
+    }
+
+    fn report_state(&self, state: BreakerState) {
+        if self.upstream.is_none() {
+            metrics().upstream_circuit_state.set(state.as_gauge());
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        let inner = self.inner.lock().unwrap();
+        let state = self.state_of(&inner);
+        self.report_state(state);
+        state
+    }
+
+    fn state_of(&self, inner: &Inner) -> BreakerState {
+        match inner.opened_at {
+            None => BreakerState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether an upstream call may be attempted right now.
+    pub fn allow_request(&self) -> bool {
+        self.state() != BreakerState::Open
+    }
+
+    /// Time until the breaker moves to half-open, if it is open.
+    pub fn remaining_cooldown(&self) -> Option<Duration> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .opened_at
+            .map(|opened_at| self.cooldown.saturating_sub(opened_at.elapsed()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.opened_at.is_some() {
+            info!("Upstream call succeeded, closing circuit breaker");
+        }
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        self.report_state(BreakerState::Closed);
+    }
+
+    pub fn record_failure(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+
+        let should_open = match self.state_of(&inner) {
+            BreakerState::Closed => inner.consecutive_failures >= self.failure_threshold,
+            // A failed probe while half-open restarts the cooldown
+            BreakerState::HalfOpen => true,
+            BreakerState::Open => false,
+        };
+
+        if should_open {
+            let upstream = self.upstream.as_deref().unwrap_or("upstream");
+            warn!(
+                "Opening {} circuit breaker after {} consecutive failures (cooldown {:?})",
+                upstream, inner.consecutive_failures, self.cooldown
+            );
+            inner.opened_at = Some(Instant::now());
+            metrics().upstream_circuit_opened_total.inc();
+            alerts().send(
+                AlertKind::CircuitOpen,
+                upstream,
+                format!(
+                    "Circuit breaker for {} opened after {} consecutive failures; pausing calls to it for {:?}",
+                    upstream, inner.consecutive_failures, self.cooldown
+                ),
+            );
+        }
+
+        self.report_state(self.state_of(&inner));
+    }
+}
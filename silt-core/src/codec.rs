@@ -0,0 +1,148 @@
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::str::FromStr;
+
+/// First byte of a MessagePack value. 0xc1 is never used by MessagePack and
+/// can't begin UTF-8 text, so it also tells these values apart from JSON.
+const MSGPACK_TAG: u8 = 0xc1;
+
+/// How request state and batch mappings are written to Redis
+/// (`SILT_REDIS_CODEC`). Values are read back in either format whatever the
+/// setting, so switching codecs needs no migration: existing values stay
+/// readable and are rewritten in the new format as they next change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RedisCodec {
+    /// Plain JSON, readable with `redis-cli` (the default)
+    #[default]
+    Json,
+    /// Tagged MessagePack, smaller and cheaper to parse
+    MessagePack,
+}
+
+impl RedisCodec {
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            RedisCodec::Json => Ok(serde_json::to_vec(value)?),
+            RedisCodec::MessagePack => {
+                let mut bytes = vec![MSGPACK_TAG];
+                // Named fields, as flattened and untagged fields need a map
+                rmp_serde::encode::write_named(&mut bytes, value)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Reads a value written with either codec.
+    pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        match bytes.split_first() {
+            Some((&MSGPACK_TAG, packed)) => Ok(rmp_serde::from_slice(packed)?),
+            _ => Ok(serde_json::from_slice(bytes)?),
+        }
+    }
+}
+
+impl FromStr for RedisCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(RedisCodec::Json),
+            "msgpack" | "messagepack" => Ok(RedisCodec::MessagePack),
+            other => Err(anyhow::anyhow!("Invalid SILT_REDIS_CODEC '{}': expected 'json' or 'msgpack'", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Content {
+        Text(String),
+        Parts(Vec<String>),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        role: String,
+        content: Content,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        #[serde(flatten)]
+        extra: HashMap<String, serde_json::Value>,
+    }
+
+    fn message() -> Message {
+        Message {
+            role: "user".to_string(),
+            content: Content::Parts(vec!["hello".to_string(), "world".to_string()]),
+            name: None,
+            extra: HashMap::from([("seed".to_string(), serde_json::json!(42))]),
+        }
+    }
+
+    #[test]
+    fn round_trips_with_both_codecs() {
+        for codec in [RedisCodec::Json, RedisCodec::MessagePack] {
+            let bytes = codec.encode(&message()).unwrap();
+            assert_eq!(RedisCodec::decode::<Message>(&bytes).unwrap(), message(), "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn round_trips_request_state() {
+        use crate::models::{CompletionRequest, Priority, RequestState};
+
+        let request: CompletionRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o-mini",
+            "messages": [{"role": "user", "content": [{"type": "text", "text": "hi"}]}],
+            "temperature": 0.5,
+            "logit_bias": {"50256": -100}
+        }))
+        .unwrap();
+        let state = RequestState::new("req-1".to_string(), request, "sk-test".to_string(), Priority::Low);
+
+        let bytes = RedisCodec::MessagePack.encode(&state).unwrap();
+        let decoded: RequestState = RedisCodec::decode(&bytes).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&state).unwrap()
+        );
+    }
+
+    #[test]
+    fn message_pack_is_tagged_and_json_is_plain() {
+        let packed = RedisCodec::MessagePack.encode(&message()).unwrap();
+        assert_eq!(packed[0], MSGPACK_TAG);
+        let json = RedisCodec::Json.encode(&message()).unwrap();
+        assert_eq!(json[0], b'{');
+        assert!(std::str::from_utf8(&packed).is_err());
+    }
+
+    #[test]
+    fn decodes_json_written_before_the_codec_existed() {
+        let stored = br#"{"role":"user","content":"hi","seed":1}"#;
+        let decoded: Message = RedisCodec::decode(stored).unwrap();
+        assert_eq!(decoded.content, Content::Text("hi".to_string()));
+        assert_eq!(decoded.extra["seed"], serde_json::json!(1));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(RedisCodec::decode::<Message>(b"").is_err());
+        assert!(RedisCodec::decode::<Message>(&[MSGPACK_TAG, 0xff]).is_err());
+    }
+
+    #[test]
+    fn parses_the_setting() {
+        assert_eq!(" JSON ".parse::<RedisCodec>().unwrap(), RedisCodec::Json);
+        assert_eq!("msgpack".parse::<RedisCodec>().unwrap(), RedisCodec::MessagePack);
+        assert_eq!("MessagePack".parse::<RedisCodec>().unwrap(), RedisCodec::MessagePack);
+        assert!("cbor".parse::<RedisCodec>().is_err());
+    }
+}
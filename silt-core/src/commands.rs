@@ -0,0 +1,62 @@
+use crate::models::Priority;
+use crate::state::StateManager;
+use anyhow::Result;
+
+/// `silt flush`: asks the running dispatchers to send queued requests now.
+/// Dispatching stays with them, so the resulting batches are polled by a
+/// long-running process rather than this one.
+pub async fn flush(state: &StateManager, priority: Option<Priority>) -> Result<()> {
+    let lanes = match priority {
+        Some(priority) => vec![priority],
+        None => vec![Priority::High, Priority::Low],
+    };
+
+    for priority in lanes {
+        let dispatchers = state.request_flush(priority).await?;
+        if dispatchers == 0 {
+            anyhow::bail!("No running dispatcher received the flush; start `silt serve` or `silt worker`");
+        }
+        println!("Flushing {} priority queues ({} dispatcher(s))", priority.as_str(), dispatchers);
+    }
+    Ok(())
+}
+
+/// `silt status`: queue depths and in-flight batches per tenant, read
+/// straight from Redis.
+pub async fn status(state: &StateManager) -> Result<()> {
+    if let Some(settings) = state.get_runtime_settings().await? {
+        if settings.paused {
+            println!("Dispatch is paused");
+        }
+        for (fingerprint, _) in settings.keys.iter().filter(|(_, key)| key.paused) {
+            println!("Dispatch is paused for API key {}", fingerprint);
+        }
+    }
+
+    let mut batches = Vec::new();
+    println!("{:<24} {:>12} {:>12} {:>10}", "TENANT", "QUEUED HIGH", "QUEUED LOW", "IN FLIGHT");
+    for tenant in state.list_tenants().await? {
+        let tenant_state = state.for_tenant(&tenant);
+        let batch_ids = tenant_state.get_processing_batches().await?;
+        println!(
+            "{:<24} {:>12} {:>12} {:>10}",
+            tenant,
+            tenant_state.queued_count(Priority::High).await?,
+            tenant_state.queued_count(Priority::Low).await?,
+            batch_ids.len()
+        );
+        for batch_id in batch_ids {
+            let requests = tenant_state.get_batch_requests(&batch_id).await?.len();
+            batches.push((tenant.clone(), batch_id, requests));
+        }
+    }
+
+    if !batches.is_empty() {
+        println!();
+        println!("{:<40} {:<24} {:>10}", "BATCH", "TENANT", "REQUESTS");
+        for (tenant, batch_id, requests) in batches {
+            println!("{:<40} {:<24} {:>10}", batch_id, tenant, requests);
+        }
+    }
+    Ok(())
+}
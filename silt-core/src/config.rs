@@ -0,0 +1,1053 @@
+use crate::codec::RedisCodec;
+use crate::ip_filter::parse_network;
+use crate::models::{ModerationAction, Priority, RetentionPolicy, SystemPrompt, SystemPromptMode, TenantQuota};
+use crate::maintenance::pause_end;
+use crate::schedule::DispatchSchedule;
+use crate::state::DEFAULT_TENANT;
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::warn;
+
+/// Prefix of every setting's env var.
+const ENV_PREFIX: &str = "SILT_";
+
+/// Settings that were already prefixed before the prefix became the rule, and
+/// so have no unprefixed legacy name.
+const ALWAYS_PREFIXED: [&str; 1] = ["SILT_AUTH_TOKEN"];
+
+/// Headers silt sends upstream itself, which `SILT_PASSTHROUGH_HEADERS` can't
+/// override.
+const RESERVED_HEADERS: [&str; 10] = [
+    "authorization",
+    "content-type",
+    "content-length",
+    "host",
+    "idempotency-key",
+    "openai-organization",
+    "openai-project",
+    "traceparent",
+    "tracestate",
+    "transfer-encoding",
+];
+
+/// Config file settings holding nested data, passed on as JSON rather than as
+/// a `name=value` list.
+const JSON_SETTINGS: [&str; 5] = ["virtual_keys", "tenants", "upstream_routes", "urgency_classes", "model_prices"];
+
+/// Command line flags. Settings are layered: flags override environment
+/// variables (and `.env`), which override the config file. Embedders without
+/// a command line of their own load from `Cli::default()`.
+#[derive(Debug, Default, Parser)]
+#[command(name = "silt", version, about = "Batching proxy for the OpenAI API")]
+pub struct Cli {
+    /// What to run; `serve` when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML or YAML file of settings, keyed by env var name in lowercase
+    /// without the `SILT_` prefix
+    #[arg(long, global = true, env = "SILT_CONFIG_FILE", value_name = "PATH")]
+    pub config: Option<String>,
+
+    #[arg(long, global = true, value_name = "HOST")]
+    pub server_host: Option<String>,
+
+    #[arg(long, global = true, value_name = "PORT")]
+    pub server_port: Option<u16>,
+
+    #[arg(long, global = true, value_name = "URL")]
+    pub redis_url: Option<String>,
+
+    #[arg(long, global = true, value_name = "URL")]
+    pub upstream_base_url: Option<String>,
+
+    /// Any other setting, e.g. `--set batch_window_secs=30`; repeatable
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE")]
+    pub settings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Run the API server along with the batch dispatcher and poller
+    Serve,
+    /// Run only the batch dispatcher and poller, without the API
+    Worker,
+    /// Make the running dispatchers send queued requests now rather than at
+    /// the end of the window
+    Flush {
+        /// Only flush this lane (high or low)
+        #[arg(long, value_name = "LANE", value_parser = parse_priority)]
+        priority: Option<Priority>,
+    },
+    /// Print queue depths and in-flight batches from Redis
+    Status,
+    /// Fire synthetic requests at a running instance and report throughput,
+    /// memory, Redis load and latency
+    Bench(BenchArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct BenchArgs {
+    /// Base URL of the instance; defaults to this host on SILT_SERVER_PORT
+    #[arg(long, value_name = "URL")]
+    pub url: Option<String>,
+
+    /// Requests to send in total
+    #[arg(long, short = 'n', default_value_t = 1000)]
+    pub requests: usize,
+
+    /// Requests waiting on results at once
+    #[arg(long, short = 'c', default_value_t = 100)]
+    pub concurrency: usize,
+
+    /// Bearer token to send; any token works against the mock upstream
+    #[arg(long, default_value = "sk-bench")]
+    pub api_key: String,
+
+    #[arg(long, default_value = "gpt-4o-mini")]
+    pub model: String,
+
+    /// Lane to submit on (high or low)
+    #[arg(long, value_name = "LANE", value_parser = parse_priority)]
+    pub priority: Option<Priority>,
+}
+
+fn parse_priority(value: &str) -> Result<Priority, String> {
+    Priority::parse(value).ok_or_else(|| format!("expected high or low, got '{}'", value))
+}
+
+/// Policies for one tenant from the config file's `tenants` table, applied
+/// at startup over whatever was set through the admin API.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantSettings {
+    #[serde(default)]
+    pub quota: Option<TenantQuota>,
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    #[serde(default)]
+    pub moderation: Option<ModerationAction>,
+    /// Upstream keys the tenant's batches are spread across, in place of
+    /// each request's own key
+    #[serde(default)]
+    pub key_pool: Vec<String>,
+}
+
+/// Sends requests for matching models to another upstream, from
+/// `SILT_UPSTREAM_ROUTES`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamRoute {
+    /// A model name, or a prefix ending in `*` such as `claude-*`
+    pub model: String,
+    pub base_url: String,
+    /// Key for the route's upstream, sent instead of the request's own
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Upstream new batches go to while the primary is failing
+    #[serde(default)]
+    pub fallback_base_url: Option<String>,
+    /// Key for the fallback; `api_key` (or the request's own) when unset
+    #[serde(default)]
+    pub fallback_api_key: Option<String>,
+}
+
+impl UpstreamRoute {
+    pub fn matches(&self, model: &str) -> bool {
+        match self.model.strip_suffix('*') {
+            Some(prefix) => model.starts_with(prefix),
+            None => self.model == model,
+        }
+    }
+}
+
+/// A class of request urgency clients pick with `x-silt-urgency`, from
+/// `SILT_URGENCY_CLASSES`. Requests of each class are batched separately,
+/// with the class's completion window.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UrgencyClass {
+    /// Value of `x-silt-urgency`, e.g. `hour` or `overnight`
+    pub name: String,
+    /// `completion_window` of the class's batches, e.g. `1h`
+    pub completion_window: String,
+    /// Largest batch for the class, instead of `SILT_BATCH_SHARD_SIZE`
+    #[serde(default)]
+    pub shard_size: Option<usize>,
+    /// Fixed interval the class's batches are polled at, instead of backing
+    /// off from `SILT_BATCH_POLL_INTERVAL_SECS`
+    #[serde(default)]
+    pub poll_interval_secs: Option<u64>,
+}
+
+/// What a model costs, in USD per million tokens, from `SILT_MODEL_PRICES`.
+/// Used to estimate spend in usage reports, so it should be the batch price
+/// when requests go out as batches.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl ModelPrice {
+    /// Cost of a request's tokens in millionths of a USD, which at a price
+    /// per million tokens is the tokens times the price.
+    pub fn cost_micros(&self, prompt_tokens: u32, completion_tokens: u32) -> u64 {
+        (f64::from(prompt_tokens) * self.prompt + f64::from(completion_tokens) * self.completion).round() as u64
+    }
+}
+
+/// How the dispatcher sends queued requests upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DispatchMode {
+    /// Upload a JSONL file and create an OpenAI batch (the default)
+    Batch,
+    /// Send each request to the realtime endpoint with a discounted
+    /// `service_tier` (e.g. "flex"), still aggregated per window
+    ServiceTier,
+}
+
+impl FromStr for DispatchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "batch" => Ok(DispatchMode::Batch),
+            "service_tier" => Ok(DispatchMode::ServiceTier),
+            other => Err(anyhow::anyhow!(
+                "Invalid SILT_DISPATCH_MODE '{}': expected 'batch' or 'service_tier'",
+                other
+            )),
+        }
+    }
+}
+
+/// Which parts of silt `silt serve` runs, so the API can scale separately
+/// from the dispatcher and poller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The API along with the dispatcher and poller (the default)
+    All,
+    /// Only the API; worker processes dispatch and poll the batches
+    Api,
+    /// Only the dispatcher and poller, as `silt worker` runs
+    Worker,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "all" => Ok(Role::All),
+            "api" => Ok(Role::Api),
+            "worker" => Ok(Role::Worker),
+            other => Err(anyhow::anyhow!("Invalid SILT_ROLE '{}': expected 'api', 'worker' or 'all'", other)),
+        }
+    }
+}
+
+/// Where upstream calls go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamMode {
+    /// The OpenAI API, or whatever `SILT_UPSTREAM_BASE_URL` points at (the default)
+    OpenAI,
+    /// An in-process simulation of the files and batches endpoints, for
+    /// development without an OpenAI key
+    Mock,
+}
+
+impl FromStr for UpstreamMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "openai" => Ok(UpstreamMode::OpenAI),
+            "mock" => Ok(UpstreamMode::Mock),
+            other => Err(anyhow::anyhow!("Invalid SILT_UPSTREAM_MODE '{}': expected 'openai' or 'mock'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub role: Role,
+    pub upstream_base_url: Option<String>,
+    /// Base URLs a request may pick with `x-silt-upstream-url`
+    pub upstream_url_allowlist: Vec<String>,
+    pub upstream_routes: Vec<UpstreamRoute>,
+    pub upstream_failover_threshold: u32,
+    pub urgency_classes: Vec<UrgencyClass>,
+    /// Prices by model, for the estimated cost in usage reports
+    pub model_prices: HashMap<String, ModelPrice>,
+    /// Client request headers stored with a request and replayed upstream,
+    /// lowercased
+    pub passthrough_headers: Vec<String>,
+    pub redis_url: String,
+    pub redis_codec: RedisCodec,
+    pub result_ttl_secs: u64,
+    pub virtual_keys_file: Option<String>,
+    /// Inline key mappings in the `SILT_VIRTUAL_KEYS_FILE` format, as JSON
+    pub virtual_keys: Option<String>,
+    pub tenants: HashMap<String, TenantSettings>,
+    pub require_virtual_keys: bool,
+    pub filter_models_by_allowlist: bool,
+    pub model_aliases: HashMap<String, String>,
+    pub rewrite_rules_file: Option<String>,
+    pub default_model: Option<String>,
+    pub default_max_tokens: Option<u32>,
+    pub max_tokens_cap: Option<u32>,
+    pub default_temperature: Option<f32>,
+    pub max_response_schema_bytes: usize,
+    pub model_context_windows: HashMap<String, u64>,
+    pub system_prompt: Option<SystemPrompt>,
+    pub moderation_action: ModerationAction,
+    pub moderation_url: Option<String>,
+    pub moderation_model: String,
+    pub moderation_api_key: Option<String>,
+    pub pre_enqueue_hooks: Vec<String>,
+    pub post_result_hooks: Vec<String>,
+    pub admin_token: Option<String>,
+    /// Addresses allowed to reach the admin API; empty allows any
+    pub admin_allowed_ips: Vec<IpNet>,
+    /// Addresses allowed to reach anything but the health checks; empty allows any
+    pub allowed_ips: Vec<IpNet>,
+    pub denied_ips: Vec<IpNet>,
+    /// Proxies whose `X-Forwarded-For` is believed
+    pub trusted_proxies: Vec<IpNet>,
+    pub silt_auth_tokens: Vec<String>,
+    pub encryption_key: Option<String>,
+    pub encryption_key_file: Option<String>,
+    pub encryption_previous_keys: Vec<String>,
+    pub encrypt_payloads: bool,
+    pub key_fingerprint_secret: Option<String>,
+    pub audit_log: String,
+    pub audit_log_file: Option<String>,
+    pub audit_log_max_len: usize,
+    pub event_stream_max_len: usize,
+    pub archive_s3_bucket: Option<String>,
+    pub archive_s3_prefix: String,
+    pub alert_slack_webhook_url: Option<String>,
+    pub alert_pagerduty_routing_key: Option<String>,
+    pub alert_pagerduty_events_url: String,
+    pub alert_webhook_url: Option<String>,
+    pub alert_webhook_template: Option<String>,
+    pub alert_queue_age_secs: u64,
+    /// Longest a request should stay queued; 0 disables the check
+    pub sla_queued_secs: u64,
+    /// Longest a request should stay in flight after dispatch; 0 disables
+    /// the check
+    pub sla_processing_secs: u64,
+    pub alert_cooldown_secs: u64,
+    pub alert_routes: Vec<String>,
+    pub batch_window_secs: u64,
+    pub batch_high_priority_window_secs: u64,
+    /// Cron schedules the lanes dispatch on, instead of every window
+    pub batch_schedule: Option<DispatchSchedule>,
+    pub batch_high_priority_schedule: Option<DispatchSchedule>,
+    /// Recurring windows in which the dispatcher holds the queue
+    pub pause_windows: Vec<DispatchSchedule>,
+    pub batch_high_priority_max_size: usize,
+    pub batch_shard_size: usize,
+    pub batch_max_queue_size: usize,
+    pub batch_min_size: usize,
+    pub batch_max_wait_secs: u64,
+    pub dispatch_max_requests_per_key: usize,
+    pub batch_fan_out_choices: bool,
+    pub max_inflight_batches_per_key: usize,
+    pub max_inflight_tokens_per_key: u64,
+    pub max_inflight_tokens_per_model: HashMap<String, u64>,
+    pub max_batches_per_key_per_day: u64,
+    pub dispatch_mode: DispatchMode,
+    pub service_tier: String,
+    pub realtime_threshold: usize,
+    pub realtime_concurrency: usize,
+    pub dedupe_identical_requests: bool,
+    pub cancel_on_disconnect: bool,
+    /// Longest a synchronous request is held open; 0 waits indefinitely
+    pub max_wait_secs: u64,
+    /// Status returned when a wait runs out, 504 or 202
+    pub wait_timeout_status: u16,
+    /// Most submissions handled at once, waits included; 0 is unlimited
+    pub submit_concurrency_limit: usize,
+    /// Most status, result and model list calls handled at once; 0 is unlimited
+    pub status_concurrency_limit: usize,
+    /// Most admin API calls handled at once; 0 is unlimited
+    pub admin_concurrency_limit: usize,
+    pub result_cache_ttl_secs: u64,
+    pub batch_poll_interval_secs: u64,
+    pub batch_poll_max_interval_secs: u64,
+    pub batch_poll_backoff_step_secs: u64,
+    pub file_sweep_interval_secs: u64,
+    pub file_sweep_min_age_secs: u64,
+    pub server_host: String,
+    pub server_port: u16,
+    pub tcp_keepalive_secs: u64,
+    /// Bind with SO_REUSEPORT, so a replacement process can bind alongside
+    pub reuse_port: bool,
+    /// Longest to wait for open connections on shutdown; 0 waits for all
+    pub drain_timeout_secs: u64,
+    /// API keys given their own `key` label in per-key metrics before the
+    /// rest are counted as `other`; 0 turns the per-key metrics off
+    pub metrics_max_keys: usize,
+    pub upstream_ca_certs: Vec<String>,
+    pub upstream_cert_pins: Vec<String>,
+    pub upstream_pool_max_idle_per_host: usize,
+    pub upstream_pool_idle_timeout_secs: u64,
+    pub upstream_request_timeout_secs: u64,
+    pub upstream_connect_timeout_secs: u64,
+    pub upstream_retry_max_attempts: u32,
+    pub upstream_retry_base_delay_ms: u64,
+    pub upstream_retry_max_delay_ms: u64,
+    pub upstream_retry_jitter: f64,
+    pub requeue_max_attempts: u32,
+    pub requeue_base_delay_secs: u64,
+    pub requeue_max_delay_secs: u64,
+    pub upstream_circuit_failure_threshold: u32,
+    pub upstream_circuit_cooldown_secs: u64,
+    pub upstream_mode: UpstreamMode,
+    pub mock_latency_ms: u64,
+    pub mock_batch_latency_secs: u64,
+    pub mock_error_rate: f64,
+    pub mock_batch_failure_rate: f64,
+    pub chaos_enabled: bool,
+    pub chaos_redis_drop_rate: f64,
+    pub chaos_poll_delay_rate: f64,
+    pub chaos_poll_delay_max_secs: u64,
+    pub chaos_upstream_429_rate: f64,
+    pub chaos_upstream_500_rate: f64,
+}
+
+impl Config {
+    /// Loads settings from the command line, the environment (including
+    /// `.env`) and the config file, in that order of precedence.
+    pub fn load(cli: &Cli) -> anyhow::Result<Self> {
+        let mut overrides = Vec::new();
+        for (name, value) in [
+            ("SILT_SERVER_HOST", cli.server_host.clone()),
+            ("SILT_SERVER_PORT", cli.server_port.map(|port| port.to_string())),
+            ("SILT_REDIS_URL", cli.redis_url.clone()),
+            ("SILT_UPSTREAM_BASE_URL", cli.upstream_base_url.clone()),
+        ] {
+            if let Some(value) = value {
+                overrides.push((name.to_string(), value));
+            }
+        }
+        for setting in &cli.settings {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Invalid --set '{}': expected KEY=VALUE", setting))?;
+            overrides.push((setting_name(key), value.to_string()));
+        }
+        for (name, value) in overrides {
+            env::set_var(name, value);
+        }
+
+        // Neither .env nor the config file replace variables that are already set.
+        // `SILT_CONFIG_FILE` is looked up again once `.env` is loaded, as it may be set there
+        dotenv::dotenv().ok();
+        if let Some(path) = cli.config.clone().or_else(|| var("SILT_CONFIG_FILE").ok()) {
+            for (name, value) in read_config_file(&path)? {
+                if !is_set(&name) {
+                    env::set_var(name, value);
+                }
+            }
+        }
+
+        let config = Self::from_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects values that parse but make no sense, listing every problem at
+    /// once so a bad deployment fails at startup rather than at first use.
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+
+        if self.server_port == 0 {
+            problems.push("SILT_SERVER_PORT must be between 1 and 65535".to_string());
+        }
+        // Fingerprints name Redis keys and appear in analytics, metrics and
+        // exports, so an unkeyed hash would let anyone match them to keys
+        if self.key_fingerprint_secret.as_deref().is_none_or(|secret| secret.trim().is_empty()) {
+            problems.push("SILT_KEY_FINGERPRINT_SECRET must be set, the same on every replica".to_string());
+        }
+        for (name, url, schemes) in [
+            ("SILT_REDIS_URL", Some(&self.redis_url), &["redis", "rediss", "unix", "redis+unix"][..]),
+            ("SILT_UPSTREAM_BASE_URL", self.upstream_base_url.as_ref(), &["http", "https"]),
+            ("SILT_MODERATION_URL", self.moderation_url.as_ref(), &["http", "https"]),
+            ("SILT_ALERT_SLACK_WEBHOOK_URL", self.alert_slack_webhook_url.as_ref(), &["http", "https"]),
+            ("SILT_ALERT_PAGERDUTY_EVENTS_URL", Some(&self.alert_pagerduty_events_url), &["http", "https"]),
+            ("SILT_ALERT_WEBHOOK_URL", self.alert_webhook_url.as_ref(), &["http", "https"]),
+        ] {
+            let Some(url) = url else {
+                continue;
+            };
+            match reqwest::Url::parse(url) {
+                Ok(parsed) if schemes.contains(&parsed.scheme()) => {}
+                Ok(parsed) => problems.push(format!(
+                    "{} has scheme '{}', expected {}",
+                    name,
+                    parsed.scheme(),
+                    schemes.join(" or ")
+                )),
+                Err(e) => problems.push(format!("{} is not a valid URL: {}", name, e)),
+            }
+        }
+
+        for url in &self.upstream_url_allowlist {
+            match reqwest::Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                _ => problems.push(format!("SILT_UPSTREAM_URL_ALLOWLIST entry '{}' is not an http(s) URL", url)),
+            }
+        }
+        for route in &self.upstream_routes {
+            for url in std::iter::once(&route.base_url).chain(&route.fallback_base_url) {
+                match reqwest::Url::parse(url) {
+                    Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                    _ => problems.push(format!(
+                        "SILT_UPSTREAM_ROUTES URL '{}' for {} is not an http(s) URL",
+                        url, route.model
+                    )),
+                }
+            }
+        }
+
+        let mut urgency_names = std::collections::HashSet::new();
+        for class in &self.urgency_classes {
+            if class.name.trim().is_empty() {
+                problems.push("SILT_URGENCY_CLASSES has a class with no name".to_string());
+            } else if !urgency_names.insert(class.name.as_str()) {
+                problems.push(format!("SILT_URGENCY_CLASSES has more than one class named {}", class.name));
+            }
+            if class.completion_window.trim().is_empty() {
+                problems.push(format!("SILT_URGENCY_CLASSES class {} has no completion_window", class.name));
+            }
+            if class.shard_size == Some(0) || class.poll_interval_secs == Some(0) {
+                problems.push(format!(
+                    "SILT_URGENCY_CLASSES class {} must have a shard_size and poll_interval_secs of at least 1",
+                    class.name
+                ));
+            }
+        }
+        for (model, price) in &self.model_prices {
+            let valid = |price: f64| price.is_finite() && price >= 0.0;
+            if !(valid(price.prompt) && valid(price.completion)) {
+                problems.push(format!("SILT_MODEL_PRICES prices for {} must be non-negative numbers", model));
+            }
+        }
+
+        for (tenant, settings) in &self.tenants {
+            if settings.key_pool.iter().any(|api_key| api_key.trim().is_empty()) {
+                problems.push(format!("SILT_TENANTS key_pool for {} has an empty key", tenant));
+            }
+            // Unknown tokens land in the default tenant, so its pool needs callers authenticated.
+            // A keys file requires mappings just as SILT_REQUIRE_VIRTUAL_KEYS does
+            let requires_mapping =
+                self.require_virtual_keys || self.virtual_keys_file.is_some() || self.virtual_keys.is_some();
+            let open_to_anyone = !requires_mapping && self.silt_auth_tokens.is_empty();
+            if tenant == DEFAULT_TENANT && !settings.key_pool.is_empty() && open_to_anyone {
+                problems.push(format!(
+                    "SILT_TENANTS key_pool for {} needs SILT_REQUIRE_VIRTUAL_KEYS or SILT_AUTH_TOKEN set",
+                    tenant
+                ));
+            }
+        }
+
+        for name in &self.passthrough_headers {
+            if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                problems.push(format!("SILT_PASSTHROUGH_HEADERS entry '{}' is not a valid header name", name));
+            } else if RESERVED_HEADERS.contains(&name.as_str()) || name.starts_with("x-silt-") {
+                problems.push(format!("SILT_PASSTHROUGH_HEADERS cannot include '{}', which silt sets itself", name));
+            }
+        }
+
+        if self.batch_window_secs == 0 {
+            problems.push("SILT_BATCH_WINDOW_SECS must be at least 1".to_string());
+        }
+        if self.batch_high_priority_window_secs == 0 {
+            problems.push("SILT_BATCH_HIGH_PRIORITY_WINDOW_SECS must be at least 1".to_string());
+        }
+        if self.batch_high_priority_window_secs > self.batch_window_secs {
+            problems.push(format!(
+                "SILT_BATCH_HIGH_PRIORITY_WINDOW_SECS ({}) is longer than SILT_BATCH_WINDOW_SECS ({})",
+                self.batch_high_priority_window_secs, self.batch_window_secs
+            ));
+        }
+        for (name, schedule) in [
+            ("SILT_BATCH_SCHEDULE", &self.batch_schedule),
+            ("SILT_BATCH_HIGH_PRIORITY_SCHEDULE", &self.batch_high_priority_schedule),
+        ] {
+            if schedule.as_ref().is_some_and(|schedule| schedule.next_after(chrono::Utc::now()).is_none()) {
+                problems.push(format!("{} never comes round", name));
+            }
+        }
+        if let Some(end) = pause_end(&self.pause_windows, chrono::Utc::now()) {
+            if pause_end(&self.pause_windows, end).is_some() {
+                problems.push("SILT_PAUSE_WINDOWS pause dispatch for over a year".to_string());
+            }
+        }
+        if self.batch_poll_interval_secs == 0 {
+            problems.push("SILT_BATCH_POLL_INTERVAL_SECS must be at least 1".to_string());
+        }
+        if self.batch_poll_max_interval_secs < self.batch_poll_interval_secs {
+            problems.push(format!(
+                "SILT_BATCH_POLL_MAX_INTERVAL_SECS ({}) is shorter than SILT_BATCH_POLL_INTERVAL_SECS ({})",
+                self.batch_poll_max_interval_secs, self.batch_poll_interval_secs
+            ));
+        }
+        // Batch input must outlive the 24 hour completion window
+        if self.file_sweep_interval_secs > 0 && self.file_sweep_min_age_secs < 86_400 {
+            problems.push("SILT_FILE_SWEEP_MIN_AGE_SECS must be at least 86400 (24 hours)".to_string());
+        }
+        if self.result_ttl_secs == 0 {
+            problems.push("SILT_RESULT_TTL_SECS must be at least 1".to_string());
+        }
+        if self.upstream_retry_max_attempts == 0 {
+            problems.push("SILT_UPSTREAM_RETRY_MAX_ATTEMPTS must be at least 1".to_string());
+        }
+        if self.upstream_retry_max_delay_ms < self.upstream_retry_base_delay_ms {
+            problems.push(format!(
+                "SILT_UPSTREAM_RETRY_MAX_DELAY_MS ({}) is shorter than SILT_UPSTREAM_RETRY_BASE_DELAY_MS ({})",
+                self.upstream_retry_max_delay_ms, self.upstream_retry_base_delay_ms
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.upstream_retry_jitter) {
+            problems.push("SILT_UPSTREAM_RETRY_JITTER must be between 0 and 1".to_string());
+        }
+        if self.requeue_max_attempts > 0 && self.requeue_base_delay_secs == 0 {
+            problems.push("SILT_REQUEUE_BASE_DELAY_SECS must be at least 1".to_string());
+        }
+        if self.requeue_max_delay_secs < self.requeue_base_delay_secs {
+            problems.push(format!(
+                "SILT_REQUEUE_MAX_DELAY_SECS ({}) is shorter than SILT_REQUEUE_BASE_DELAY_SECS ({})",
+                self.requeue_max_delay_secs, self.requeue_base_delay_secs
+            ));
+        }
+        for (name, rate) in [
+            ("SILT_MOCK_ERROR_RATE", self.mock_error_rate),
+            ("SILT_MOCK_BATCH_FAILURE_RATE", self.mock_batch_failure_rate),
+            ("SILT_CHAOS_REDIS_DROP_RATE", self.chaos_redis_drop_rate),
+            ("SILT_CHAOS_POLL_DELAY_RATE", self.chaos_poll_delay_rate),
+            ("SILT_CHAOS_UPSTREAM_429_RATE", self.chaos_upstream_429_rate),
+            ("SILT_CHAOS_UPSTREAM_500_RATE", self.chaos_upstream_500_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                problems.push(format!("{} must be between 0 and 1", name));
+            }
+        }
+        if self.upstream_circuit_failure_threshold == 0 {
+            problems.push("SILT_UPSTREAM_CIRCUIT_FAILURE_THRESHOLD must be at least 1".to_string());
+        }
+        if !matches!(self.wait_timeout_status, 202 | 504) {
+            problems.push("SILT_WAIT_TIMEOUT_STATUS must be 504 or 202".to_string());
+        }
+        if let Some(temperature) = self.default_temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                problems.push("SILT_DEFAULT_TEMPERATURE must be between 0 and 2".to_string());
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        anyhow::bail!("Invalid configuration:\n  {}", problems.join("\n  "))
+    }
+
+    /// The cron schedule a lane dispatches on, if it has one.
+    pub fn lane_schedule(&self, priority: Priority) -> Option<&DispatchSchedule> {
+        match priority {
+            Priority::High => self.batch_high_priority_schedule.as_ref(),
+            Priority::Low => self.batch_schedule.as_ref(),
+        }
+    }
+
+    pub fn urgency_class(&self, name: &str) -> Option<&UrgencyClass> {
+        self.urgency_classes.iter().find(|class| class.name == name)
+    }
+
+    /// The first of `SILT_UPSTREAM_ROUTES` matching the model.
+    pub fn upstream_route(&self, model: &str) -> Option<&UpstreamRoute> {
+        self.upstream_routes.iter().find(|route| route.matches(model))
+    }
+
+    /// The configuration with secrets masked, for logging.
+    pub fn masked(&self) -> Self {
+        let secret = |value: &Option<String>| value.as_ref().map(|_| "***".to_string());
+        let url_with_secret_path = |value: &Option<String>| {
+            value.as_ref().map(|url| match reqwest::Url::parse(url) {
+                Ok(url) => format!("{}://{}/***", url.scheme(), url.host_str().unwrap_or_default()),
+                Err(_) => "***".to_string(),
+            })
+        };
+
+        let mut config = self.clone();
+        config.redis_url = mask_password(&self.redis_url);
+        config.upstream_base_url = self.upstream_base_url.as_deref().map(mask_password);
+        config.upstream_url_allowlist = self.upstream_url_allowlist.iter().map(|url| mask_password(url)).collect();
+        for route in &mut config.upstream_routes {
+            route.base_url = mask_password(&route.base_url);
+            route.api_key = secret(&route.api_key);
+            route.fallback_base_url = route.fallback_base_url.as_deref().map(mask_password);
+            route.fallback_api_key = secret(&route.fallback_api_key);
+        }
+        for settings in config.tenants.values_mut() {
+            settings.key_pool = vec!["***".to_string(); settings.key_pool.len()];
+        }
+        config.virtual_keys = secret(&self.virtual_keys);
+        config.moderation_api_key = secret(&self.moderation_api_key);
+        config.admin_token = secret(&self.admin_token);
+        config.silt_auth_tokens = vec!["***".to_string(); self.silt_auth_tokens.len()];
+        config.encryption_key = secret(&self.encryption_key);
+        config.encryption_previous_keys = vec!["***".to_string(); self.encryption_previous_keys.len()];
+        config.key_fingerprint_secret = secret(&self.key_fingerprint_secret);
+        config.alert_slack_webhook_url = url_with_secret_path(&self.alert_slack_webhook_url);
+        config.alert_pagerduty_routing_key = secret(&self.alert_pagerduty_routing_key);
+        config.alert_webhook_url = url_with_secret_path(&self.alert_webhook_url);
+        config
+    }
+
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            upstream_base_url: var("SILT_UPSTREAM_BASE_URL").ok(),
+            upstream_url_allowlist: env_list("SILT_UPSTREAM_URL_ALLOWLIST")
+                .into_iter()
+                .map(|url| url.trim_end_matches('/').to_string())
+                .collect(),
+            passthrough_headers: env_list("SILT_PASSTHROUGH_HEADERS")
+                .into_iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            upstream_routes: match var("SILT_UPSTREAM_ROUTES").ok().filter(|json| !json.trim().is_empty()) {
+                Some(json) => serde_json::from_str::<Vec<UpstreamRoute>>(&json)
+                    .context("SILT_UPSTREAM_ROUTES must be a JSON array of {model, base_url, api_key, fallback_base_url, fallback_api_key}")?
+                    .into_iter()
+                    .map(|route| UpstreamRoute {
+                        base_url: route.base_url.trim_end_matches('/').to_string(),
+                        fallback_base_url: route
+                            .fallback_base_url
+                            .map(|url| url.trim_end_matches('/').to_string()),
+                        ..route
+                    })
+                    .collect(),
+                None => Vec::new(),
+            },
+            upstream_failover_threshold: env_or("SILT_UPSTREAM_FAILOVER_THRESHOLD", "3")?,
+            urgency_classes: match var("SILT_URGENCY_CLASSES").ok().filter(|json| !json.trim().is_empty()) {
+                Some(json) => serde_json::from_str(&json).context(
+                    "SILT_URGENCY_CLASSES must be a JSON array of {name, completion_window, shard_size, poll_interval_secs}",
+                )?,
+                None => Vec::new(),
+            },
+            model_prices: match var("SILT_MODEL_PRICES").ok().filter(|json| !json.trim().is_empty()) {
+                Some(json) => serde_json::from_str(&json)
+                    .context("SILT_MODEL_PRICES must be a JSON object of model names to {prompt, completion}")?,
+                None => HashMap::new(),
+            },
+            redis_url: var("SILT_REDIS_URL")
+                .unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string()),
+            redis_codec: env_or("SILT_REDIS_CODEC", "json")?,
+            result_ttl_secs: env_or("SILT_RESULT_TTL_SECS", "172800")?,
+            virtual_keys_file: var("SILT_VIRTUAL_KEYS_FILE").ok(),
+            virtual_keys: var("SILT_VIRTUAL_KEYS").ok().filter(|json| !json.trim().is_empty()),
+            tenants: match var("SILT_TENANTS").ok().filter(|json| !json.trim().is_empty()) {
+                Some(json) => serde_json::from_str(&json)
+                    .context("SILT_TENANTS must be a JSON object of tenant -> {quota, retention, moderation, key_pool}")?,
+                None => HashMap::new(),
+            },
+            require_virtual_keys: env_or("SILT_REQUIRE_VIRTUAL_KEYS", "false")?,
+            filter_models_by_allowlist: env_or("SILT_FILTER_MODELS_BY_ALLOWLIST", "false")?,
+            model_aliases: env_map("SILT_MODEL_ALIASES")?,
+            rewrite_rules_file: var("SILT_REWRITE_RULES_FILE").ok().filter(|path| !path.is_empty()),
+            default_model: var("SILT_DEFAULT_MODEL").ok().filter(|model| !model.is_empty()),
+            default_max_tokens: env_optional("SILT_DEFAULT_MAX_TOKENS")?,
+            max_tokens_cap: env_optional("SILT_MAX_TOKENS_CAP")?,
+            default_temperature: env_optional("SILT_DEFAULT_TEMPERATURE")?,
+            max_response_schema_bytes: env_or("SILT_MAX_RESPONSE_SCHEMA_BYTES", "65536")?,
+            model_context_windows: env_map("SILT_MODEL_CONTEXT_WINDOWS")?
+                .into_iter()
+                .map(|(model, tokens)| Ok((model, tokens.parse()?)))
+                .collect::<anyhow::Result<_>>()?,
+            system_prompt: match var("SILT_SYSTEM_PROMPT").ok().filter(|prompt| !prompt.trim().is_empty()) {
+                Some(content) => Some(SystemPrompt {
+                    content,
+                    mode: env_or::<SystemPromptMode>("SILT_SYSTEM_PROMPT_MODE", "prepend")?,
+                }),
+                None => None,
+            },
+            moderation_action: env_or("SILT_MODERATION_ACTION", "off")?,
+            moderation_url: var("SILT_MODERATION_URL").ok().filter(|url| !url.is_empty()),
+            moderation_model: var("SILT_MODERATION_MODEL")
+                .unwrap_or_else(|_| "omni-moderation-latest".to_string()),
+            moderation_api_key: var("SILT_MODERATION_API_KEY").ok().filter(|key| !key.is_empty()),
+            pre_enqueue_hooks: env_list("SILT_PRE_ENQUEUE_HOOKS"),
+            post_result_hooks: env_list("SILT_POST_RESULT_HOOKS"),
+            admin_token: var("SILT_ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            admin_allowed_ips: env_networks("SILT_ADMIN_ALLOWED_IPS")?,
+            allowed_ips: env_networks("SILT_ALLOWED_IPS")?,
+            denied_ips: env_networks("SILT_DENIED_IPS")?,
+            trusted_proxies: env_networks("SILT_TRUSTED_PROXIES")?,
+            silt_auth_tokens: env_list("SILT_AUTH_TOKEN"),
+            encryption_key: var("SILT_ENCRYPTION_KEY").ok().filter(|key| !key.is_empty()),
+            encryption_key_file: var("SILT_ENCRYPTION_KEY_FILE").ok(),
+            encryption_previous_keys: env_list("SILT_ENCRYPTION_PREVIOUS_KEYS"),
+            encrypt_payloads: env_or("SILT_ENCRYPT_PAYLOADS", "false")?,
+            key_fingerprint_secret: var("SILT_KEY_FINGERPRINT_SECRET").ok(),
+            audit_log: var("SILT_AUDIT_LOG").unwrap_or_else(|_| "off".to_string()),
+            audit_log_file: var("SILT_AUDIT_LOG_FILE").ok(),
+            audit_log_max_len: env_or("SILT_AUDIT_LOG_MAX_LEN", "100000")?,
+            event_stream_max_len: env_or("SILT_EVENT_STREAM_MAX_LEN", "10000")?,
+            archive_s3_bucket: var("SILT_ARCHIVE_S3_BUCKET").ok().filter(|bucket| !bucket.is_empty()),
+            archive_s3_prefix: var("SILT_ARCHIVE_S3_PREFIX").unwrap_or_else(|_| "silt".to_string()),
+            alert_slack_webhook_url: var("SILT_ALERT_SLACK_WEBHOOK_URL").ok().filter(|url| !url.is_empty()),
+            alert_pagerduty_routing_key: var("SILT_ALERT_PAGERDUTY_ROUTING_KEY")
+                .ok()
+                .filter(|key| !key.is_empty()),
+            alert_pagerduty_events_url: var("SILT_ALERT_PAGERDUTY_EVENTS_URL")
+                .unwrap_or_else(|_| "https://events.pagerduty.com/v2/enqueue".to_string()),
+            alert_webhook_url: var("SILT_ALERT_WEBHOOK_URL").ok().filter(|url| !url.is_empty()),
+            alert_webhook_template: var("SILT_ALERT_WEBHOOK_TEMPLATE").ok(),
+            alert_queue_age_secs: env_or("SILT_ALERT_QUEUE_AGE_SECS", "0")?,
+            sla_queued_secs: env_or("SILT_SLA_QUEUED_SECS", "0")?,
+            sla_processing_secs: env_or("SILT_SLA_PROCESSING_SECS", "0")?,
+            alert_cooldown_secs: env_or("SILT_ALERT_COOLDOWN_SECS", "900")?,
+            alert_routes: env_list("SILT_ALERT_ROUTES"),
+            batch_window_secs: env_or("SILT_BATCH_WINDOW_SECS", "60")?,
+            batch_high_priority_window_secs: env_or("SILT_BATCH_HIGH_PRIORITY_WINDOW_SECS", "10")?,
+            batch_schedule: env_optional("SILT_BATCH_SCHEDULE")?,
+            batch_high_priority_schedule: env_optional("SILT_BATCH_HIGH_PRIORITY_SCHEDULE")?,
+            pause_windows: env_schedules("SILT_PAUSE_WINDOWS")?,
+            batch_high_priority_max_size: env_or("SILT_BATCH_HIGH_PRIORITY_MAX_SIZE", "100")?,
+            batch_shard_size: env_or("SILT_BATCH_SHARD_SIZE", "0")?,
+            batch_max_queue_size: env_or("SILT_BATCH_MAX_QUEUE_SIZE", "0")?,
+            batch_min_size: env_or("SILT_BATCH_MIN_SIZE", "1")?,
+            batch_max_wait_secs: env_or("SILT_BATCH_MAX_WAIT_SECS", "0")?,
+            dispatch_max_requests_per_key: env_or("SILT_DISPATCH_MAX_REQUESTS_PER_KEY", "0")?,
+            batch_fan_out_choices: env_or("SILT_BATCH_FAN_OUT_CHOICES", "false")?,
+            max_inflight_batches_per_key: env_or("SILT_MAX_INFLIGHT_BATCHES_PER_KEY", "0")?,
+            max_inflight_tokens_per_key: env_or("SILT_MAX_INFLIGHT_TOKENS_PER_KEY", "0")?,
+            max_inflight_tokens_per_model: env_map("SILT_MAX_INFLIGHT_TOKENS_PER_MODEL")?
+                .into_iter()
+                .map(|(model, tokens)| Ok((model, tokens.parse()?)))
+                .collect::<anyhow::Result<_>>()?,
+            max_batches_per_key_per_day: env_or("SILT_MAX_BATCHES_PER_KEY_PER_DAY", "0")?,
+            dispatch_mode: env_or("SILT_DISPATCH_MODE", "batch")?,
+            service_tier: var("SILT_SERVICE_TIER")
+                .unwrap_or_else(|_| "flex".to_string()),
+            realtime_threshold: env_or("SILT_REALTIME_THRESHOLD", "0")?,
+            realtime_concurrency: env_or("SILT_REALTIME_CONCURRENCY", "8")?,
+            dedupe_identical_requests: env_or("SILT_DEDUPE_IDENTICAL_REQUESTS", "false")?,
+            cancel_on_disconnect: env_or("SILT_CANCEL_ON_DISCONNECT", "false")?,
+            max_wait_secs: env_or("SILT_MAX_WAIT_SECS", "0")?,
+            wait_timeout_status: env_or("SILT_WAIT_TIMEOUT_STATUS", "504")?,
+            submit_concurrency_limit: env_or("SILT_SUBMIT_CONCURRENCY_LIMIT", "0")?,
+            status_concurrency_limit: env_or("SILT_STATUS_CONCURRENCY_LIMIT", "0")?,
+            admin_concurrency_limit: env_or("SILT_ADMIN_CONCURRENCY_LIMIT", "0")?,
+            result_cache_ttl_secs: env_or("SILT_RESULT_CACHE_TTL_SECS", "0")?,
+            batch_poll_interval_secs: env_or("SILT_BATCH_POLL_INTERVAL_SECS", "60")?,
+            batch_poll_max_interval_secs: env_or("SILT_BATCH_POLL_MAX_INTERVAL_SECS", "900")?,
+            batch_poll_backoff_step_secs: env_or("SILT_BATCH_POLL_BACKOFF_STEP_SECS", "1800")?,
+            file_sweep_interval_secs: env_or("SILT_FILE_SWEEP_INTERVAL_SECS", "3600")?,
+            file_sweep_min_age_secs: env_or("SILT_FILE_SWEEP_MIN_AGE_SECS", "172800")?,
+            server_host: var("SILT_SERVER_HOST")
+                .unwrap_or_else(|_| "0.0.0.0".to_string()),
+            server_port: env_or("SILT_SERVER_PORT", "8080")?,
+            role: env_or("SILT_ROLE", "all")?,
+            tcp_keepalive_secs: env_or("SILT_TCP_KEEPALIVE_SECS", "60")?,
+            reuse_port: env_or("SILT_REUSE_PORT", "false")?,
+            drain_timeout_secs: env_or("SILT_DRAIN_TIMEOUT_SECS", "0")?,
+            metrics_max_keys: env_or("SILT_METRICS_MAX_KEYS", "20")?,
+            upstream_ca_certs: env_list("SILT_UPSTREAM_CA_CERTS"),
+            upstream_cert_pins: env_list("SILT_UPSTREAM_CERT_PINS"),
+            upstream_pool_max_idle_per_host: env_or("SILT_UPSTREAM_POOL_MAX_IDLE_PER_HOST", "32")?,
+            upstream_pool_idle_timeout_secs: env_or("SILT_UPSTREAM_POOL_IDLE_TIMEOUT_SECS", "90")?,
+            // Result file downloads for large batches can take several minutes
+            upstream_request_timeout_secs: env_or("SILT_UPSTREAM_REQUEST_TIMEOUT_SECS", "600")?,
+            upstream_connect_timeout_secs: env_or("SILT_UPSTREAM_CONNECT_TIMEOUT_SECS", "30")?,
+            upstream_retry_max_attempts: env_or("SILT_UPSTREAM_RETRY_MAX_ATTEMPTS", "4")?,
+            upstream_retry_base_delay_ms: env_or("SILT_UPSTREAM_RETRY_BASE_DELAY_MS", "500")?,
+            upstream_retry_max_delay_ms: env_or("SILT_UPSTREAM_RETRY_MAX_DELAY_MS", "30000")?,
+            upstream_retry_jitter: env_or("SILT_UPSTREAM_RETRY_JITTER", "0.5")?,
+            requeue_max_attempts: env_or("SILT_REQUEUE_MAX_ATTEMPTS", "3")?,
+            requeue_base_delay_secs: env_or("SILT_REQUEUE_BASE_DELAY_SECS", "60")?,
+            requeue_max_delay_secs: env_or("SILT_REQUEUE_MAX_DELAY_SECS", "3600")?,
+            upstream_circuit_failure_threshold: env_or("SILT_UPSTREAM_CIRCUIT_FAILURE_THRESHOLD", "5")?,
+            upstream_circuit_cooldown_secs: env_or("SILT_UPSTREAM_CIRCUIT_COOLDOWN_SECS", "300")?,
+            upstream_mode: env_or("SILT_UPSTREAM_MODE", "openai")?,
+            mock_latency_ms: env_or("SILT_MOCK_LATENCY_MS", "0")?,
+            mock_batch_latency_secs: env_or("SILT_MOCK_BATCH_LATENCY_SECS", "30")?,
+            mock_error_rate: env_or("SILT_MOCK_ERROR_RATE", "0")?,
+            mock_batch_failure_rate: env_or("SILT_MOCK_BATCH_FAILURE_RATE", "0")?,
+            chaos_enabled: env_or("SILT_CHAOS", "false")?,
+            chaos_redis_drop_rate: env_or("SILT_CHAOS_REDIS_DROP_RATE", "0")?,
+            chaos_poll_delay_rate: env_or("SILT_CHAOS_POLL_DELAY_RATE", "0")?,
+            chaos_poll_delay_max_secs: env_or("SILT_CHAOS_POLL_DELAY_MAX_SECS", "60")?,
+            chaos_upstream_429_rate: env_or("SILT_CHAOS_UPSTREAM_429_RATE", "0")?,
+            chaos_upstream_500_rate: env_or("SILT_CHAOS_UPSTREAM_500_RATE", "0")?,
+        })
+    }
+}
+
+/// The env var a config file key or `--set` key stands for:
+/// `batch_window_secs` and `batch-window-secs` are both
+/// `SILT_BATCH_WINDOW_SECS`.
+fn setting_name(key: &str) -> String {
+    let name = key.trim().replace('-', "_").to_ascii_uppercase();
+    if name.starts_with(ENV_PREFIX) {
+        name
+    } else {
+        format!("{}{}", ENV_PREFIX, name)
+    }
+}
+
+/// The name a setting's env var had before the `SILT_` prefix.
+fn legacy_name(name: &str) -> Option<&str> {
+    name.strip_prefix(ENV_PREFIX).filter(|_| !ALWAYS_PREFIXED.contains(&name))
+}
+
+fn is_set(name: &str) -> bool {
+    env::var_os(name).is_some() || legacy_name(name).is_some_and(|legacy| env::var_os(legacy).is_some())
+}
+
+/// Reads a setting's env var, falling back (with a warning) to the
+/// unprefixed name older deployments use.
+fn var(name: &str) -> Result<String, env::VarError> {
+    env::var(name).or_else(|e| {
+        let legacy = legacy_name(name).ok_or(e.clone())?;
+        let value = env::var(legacy).map_err(|_| e)?;
+        warn!("{} is deprecated, set {} instead", legacy, name);
+        Ok(value)
+    })
+}
+
+/// Parses a setting, or its default when unset.
+fn env_or<T>(name: &str, default: &str) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = var(name).unwrap_or_else(|_| default.to_string());
+    value
+        .trim()
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid {} '{}': {}", name, value, e))
+}
+
+/// A URL with any password replaced by `***`.
+fn mask_password(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("***"));
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Reads a TOML or YAML config file into env var names and values. Lists
+/// become comma-separated values and tables `name=value` lists, the forms
+/// the env vars take; `virtual_keys` and `tenants` are passed on as JSON.
+fn read_config_file(path: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let contents = std::fs::read_to_string(path).with_context(|| format!("Failed to read config file {}", path))?;
+    let settings: BTreeMap<String, serde_json::Value> = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&contents).with_context(|| format!("Invalid TOML in config file {}", path))?,
+        Some("yaml" | "yml") => {
+            serde_yaml::from_str(&contents).with_context(|| format!("Invalid YAML in config file {}", path))?
+        }
+        _ => anyhow::bail!("Config file {} must end in .toml, .yaml or .yml", path),
+    };
+
+    let scalar = |key: &str, value: &serde_json::Value| match value {
+        serde_json::Value::String(value) => Ok(value.clone()),
+        serde_json::Value::Number(_) | serde_json::Value::Bool(_) => Ok(value.to_string()),
+        _ => Err(anyhow::anyhow!("Config file setting {} may only hold plain values", key)),
+    };
+
+    let mut vars = Vec::new();
+    for (key, value) in settings {
+        let value = match &value {
+            serde_json::Value::Null => continue,
+            _ if JSON_SETTINGS.contains(&key.as_str()) => value.to_string(),
+            serde_json::Value::Array(items) => items
+                .iter()
+                .map(|item| scalar(&key, item))
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .join(","),
+            serde_json::Value::Object(entries) => entries
+                .iter()
+                .map(|(name, item)| Ok(format!("{}={}", name, scalar(&key, item)?)))
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .join(","),
+            _ => scalar(&key, &value)?,
+        };
+        vars.push((setting_name(&key), value));
+    }
+    Ok(vars)
+}
+
+/// Reads a comma-separated env var into a list, skipping empty entries.
+fn env_list(name: &str) -> Vec<String> {
+    var(name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads a comma-separated list of IP addresses and CIDR networks.
+fn env_networks(name: &str) -> anyhow::Result<Vec<IpNet>> {
+    env_list(name)
+        .iter()
+        .map(|item| parse_network(item).map_err(|e| anyhow::anyhow!("Invalid {}: {}", name, e)))
+        .collect()
+}
+
+/// Reads a `;`-separated list of cron expressions, as the expressions
+/// themselves use commas.
+fn env_schedules(name: &str) -> anyhow::Result<Vec<DispatchSchedule>> {
+    var(name)
+        .unwrap_or_default()
+        .split(';')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().map_err(|e| anyhow::anyhow!("Invalid {}: {}", name, e)))
+        .collect()
+}
+
+/// Reads a comma-separated list of `name=value` pairs into a map.
+fn env_map(name: &str) -> anyhow::Result<HashMap<String, String>> {
+    env_list(name)
+        .into_iter()
+        .map(|item| match item.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() && !value.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.trim().to_string()))
+            }
+            _ => Err(anyhow::anyhow!("Invalid {} entry '{}': expected name=value", name, item)),
+        })
+        .collect()
+}
+
+/// Parses an env var that is unset (or empty) by default.
+fn env_optional<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .map(|value| value.trim().parse())
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Invalid {}: {}", name, e))
+}
@@ -0,0 +1,132 @@
+use crate::config::Config;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+/// Prefix marking an encrypted value, followed by `{key_id}:{base64}`.
+pub const ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+static FINGERPRINT_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// AES-256-GCM encryption for secrets stored in Redis.
+///
+/// Values are encrypted with the current key and tagged with its id, so keys
+/// can be rotated: older keys listed in `SILT_ENCRYPTION_PREVIOUS_KEYS` still
+/// decrypt existing values. Values without the prefix are returned as-is,
+/// which keeps state written before encryption was enabled readable.
+pub struct Cipher {
+    current: (String, Aes256Gcm),
+    previous: Vec<(String, Aes256Gcm)>,
+    encrypt_payloads: bool,
+}
+
+impl Cipher {
+    /// Builds the cipher from `SILT_ENCRYPTION_KEY` or `SILT_ENCRYPTION_KEY_FILE`, or
+    /// returns `None` if neither is set.
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let current = match (&config.encryption_key, &config.encryption_key_file) {
+            (Some(key), _) => key.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read SILT_ENCRYPTION_KEY_FILE {}", path))?
+                .trim()
+                .to_string(),
+            (None, None) if config.encrypt_payloads => {
+                anyhow::bail!("SILT_ENCRYPT_PAYLOADS requires SILT_ENCRYPTION_KEY or SILT_ENCRYPTION_KEY_FILE")
+            }
+            (None, None) => return Ok(None),
+        };
+
+        let previous = config
+            .encryption_previous_keys
+            .iter()
+            .map(|key| parse_key(key))
+            .collect::<Result<_>>()?;
+
+        Ok(Some(Self {
+            current: parse_key(&current)?,
+            previous,
+            encrypt_payloads: config.encrypt_payloads,
+        }))
+    }
+
+    /// Whether prompts and completions are encrypted as well as API keys.
+    pub fn encrypts_payloads(&self) -> bool {
+        self.encrypt_payloads
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let (key_id, cipher) = &self.current;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}:{}", ENCRYPTED_PREFIX, key_id, BASE64.encode(payload)))
+    }
+
+    pub fn decrypt(&self, value: &str) -> Result<String> {
+        let Some(encrypted) = value.strip_prefix(ENCRYPTED_PREFIX) else {
+            return Ok(value.to_string());
+        };
+        let (key_id, payload) = encrypted
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Malformed encrypted value"))?;
+
+        let cipher = std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|(id, _)| id == key_id)
+            .map(|(_, cipher)| cipher)
+            .ok_or_else(|| anyhow::anyhow!("Value was encrypted with unknown key {}", key_id))?;
+
+        let payload = BASE64.decode(payload).context("Malformed encrypted value")?;
+        if payload.len() < 12 {
+            anyhow::bail!("Malformed encrypted value");
+        }
+        let (nonce, ciphertext) = payload.split_at(12);
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt value with key {}", key_id))?;
+
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+/// Keys are 32 bytes, base64 encoded (e.g. `openssl rand -base64 32`). The key
+/// id is a short hash of the key so it can be recognised on decryption.
+fn parse_key(encoded: &str) -> Result<(String, Aes256Gcm)> {
+    let bytes = BASE64
+        .decode(encoded.trim())
+        .context("Encryption keys must be base64 encoded")?;
+    if bytes.len() != 32 {
+        anyhow::bail!("Encryption keys must be 32 bytes, got {}", bytes.len());
+    }
+
+    let key_id = hex::encode(&Sha256::digest(&bytes)[..4]);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes));
+    Ok((key_id, cipher))
+}
+
+/// Sets the HMAC secret used by `key_fingerprint`. Called once at startup;
+/// fingerprints must stay stable across restarts and replicas since they are
+/// part of Redis keys, so `Config::validate` refuses to start without
+/// `SILT_KEY_FINGERPRINT_SECRET` rather than fall back to an unkeyed hash.
+pub fn init_key_fingerprints(config: &Config) {
+    let secret = config.key_fingerprint_secret.clone().unwrap_or_default();
+    let _ = FINGERPRINT_SECRET.set(secret.into_bytes());
+}
+
+/// A stable, non-reversible identifier for an upstream API key, used wherever
+/// keys are grouped, counted, logged or labelled instead of the key itself.
+pub fn key_fingerprint(api_key: &str) -> String {
+    let secret = FINGERPRINT_SECRET.get().map(Vec::as_slice).unwrap_or_default();
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(api_key.as_bytes());
+    hex::encode(&mac.finalize().into_bytes()[..16])
+}
@@ -0,0 +1,45 @@
+use crate::models::{RequestState, RequestStatus};
+use crate::state::StateManager;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Estimates {
+    /// When the request is expected to leave the queue (next window tick)
+    pub estimated_dispatch_at: Option<DateTime<Utc>>,
+    /// When the result is expected, based on recent batch durations for the model
+    pub estimated_completion_at: Option<DateTime<Utc>>,
+}
+
+/// Estimates when a request will be dispatched and completed. Estimates are
+/// best effort and absent when there is no history to base them on.
+pub async fn estimate(state_manager: &StateManager, state: &RequestState) -> Result<Estimates> {
+    let dispatched_at = match state.status {
+        RequestStatus::Complete | RequestStatus::Failed | RequestStatus::Quarantined => {
+            return Ok(Estimates::default())
+        }
+        RequestStatus::Queued => {
+            let now = Utc::now();
+            state_manager
+                .get_next_dispatch(state.priority)
+                .await?
+                .map(|next| next.max(now).max(state.not_before.unwrap_or(now)))
+        }
+        RequestStatus::Batching | RequestStatus::Processing => state.dispatched_at,
+    };
+
+    let typical_duration = state_manager
+        .typical_batch_duration(&state.request.model)
+        .await?;
+
+    let completion_at = match (dispatched_at, typical_duration) {
+        (Some(dispatched_at), Some(secs)) => Some((dispatched_at + Duration::seconds(secs)).max(Utc::now())),
+        _ => None,
+    };
+
+    Ok(Estimates {
+        estimated_dispatch_at: dispatched_at.filter(|_| state.status == RequestStatus::Queued),
+        estimated_completion_at: completion_at,
+    })
+}
@@ -0,0 +1,143 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use redis::streams::{StreamMaxlen, StreamRangeReply, StreamReadOptions, StreamReadReply};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+/// Redis stream every replica publishes operator events to. Shared by all
+/// tenants, like the audit log.
+const EVENT_STREAM: &str = "events";
+
+/// How long a read waits for new events before returning none, so a
+/// disconnected subscriber is noticed.
+const READ_BLOCK_MS: usize = 15_000;
+
+/// Most events returned by one read.
+const READ_COUNT: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Enqueued,
+    Requeued,
+    BatchCreated,
+    /// A batch's upstream status changed, e.g. to `in_progress`
+    BatchStatus,
+    Completed,
+    Failed,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Enqueued => "enqueued",
+            EventKind::Requeued => "requeued",
+            EventKind::BatchCreated => "batch_created",
+            EventKind::BatchStatus => "batch_status",
+            EventKind::Completed => "completed",
+            EventKind::Failed => "failed",
+        }
+    }
+}
+
+/// Something that happened in silt, for external systems to react to. Unlike
+/// audit events these cover batches as well as requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    /// Stream entry id, set when read back from Redis
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub at: DateTime<Utc>,
+    pub event: EventKind,
+    pub tenant: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Event {
+    pub fn new(event: EventKind, tenant: &str) -> Self {
+        Self {
+            id: None,
+            at: Utc::now(),
+            event,
+            tenant: tenant.to_string(),
+            request_id: None,
+            batch_id: None,
+            detail: None,
+        }
+    }
+
+    pub fn request(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_string());
+        self
+    }
+
+    pub fn batch(mut self, batch_id: Option<&str>) -> Self {
+        self.batch_id = batch_id.map(str::to_string);
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Appends an event to the stream, keeping roughly `max_len` of them.
+pub async fn publish(redis: &redis::aio::ConnectionManager, max_len: usize, event: &Event) -> Result<()> {
+    let mut conn = redis.clone();
+    let json = serde_json::to_string(event)?;
+    conn.xadd_maxlen::<_, _, _, _, ()>(EVENT_STREAM, StreamMaxlen::Approx(max_len), "*", &[("event", json)])
+        .await?;
+    Ok(())
+}
+
+/// Follows the event stream on a connection of its own, as blocking reads
+/// would hold up everything else sharing a multiplexed one.
+pub struct EventReader {
+    conn: redis::aio::MultiplexedConnection,
+    after: String,
+}
+
+impl EventReader {
+    /// Starts after the stream entry `after`, or with the next event
+    /// published when there is none.
+    pub async fn new(client: &redis::Client, after: Option<String>) -> Result<Self> {
+        let mut conn = client.get_multiplexed_async_connection().await?;
+        let after = match after {
+            Some(after) => after,
+            // Resolved now, as reading from `$` each time would skip events
+            // published between reads
+            None => {
+                let latest: StreamRangeReply = conn.xrevrange_count(EVENT_STREAM, "+", "-", 1).await?;
+                latest.ids.first().map_or_else(|| "0-0".to_string(), |entry| entry.id.clone())
+            }
+        };
+        Ok(Self { conn, after })
+    }
+
+    /// The next events, oldest first. Empty when none arrived while waiting.
+    pub async fn next(&mut self) -> Result<Vec<Event>> {
+        let options = StreamReadOptions::default().block(READ_BLOCK_MS).count(READ_COUNT);
+        let reply: Option<StreamReadReply> = self
+            .conn
+            .xread_options(&[EVENT_STREAM], &[&self.after], &options)
+            .await?;
+
+        let mut events = Vec::new();
+        for entry in reply.into_iter().flat_map(|reply| reply.keys).flat_map(|key| key.ids) {
+            self.after = entry.id.clone();
+            let Some(json) = entry.get::<String>("event") else {
+                continue;
+            };
+            let mut event: Event = serde_json::from_str(&json)?;
+            event.id = Some(entry.id);
+            events.push(event);
+        }
+        Ok(events)
+    }
+}
@@ -0,0 +1,1589 @@
+use crate::admin::require_admin;
+use crate::archive::Archiver;
+use crate::auth::{is_valid_tenant, KeyResolver, ResolvedKey};
+use crate::circuit_breaker::{BreakerState, CircuitBreaker};
+use crate::config::Config;
+use crate::crypto::key_fingerprint;
+use crate::eta::{self, Estimates};
+use crate::hooks::{HookContext, Hooks};
+use crate::ip_filter::is_admin_ip;
+use crate::maintenance::pause_end;
+use crate::metrics::{metrics, GaugeGuard};
+use crate::models::{
+    CompletionRequest, ModerationAction, Priority, RequestError, RequestErrorKind, RequestState, RequestStatus,
+    StatusTransition, TraceContext, UpstreamScope,
+};
+use crate::moderation::Moderator;
+use crate::openai_client::{OpenAIClient, UpstreamError};
+use crate::rewrite::RewriteRules;
+use crate::runtime::Runtime;
+use crate::state::{StateManager, DEFAULT_TENANT};
+use crate::tokens;
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures_util::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::time::{timeout, Duration};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+const MAX_METADATA_PAIRS: usize = 16;
+const MAX_METADATA_KEY_LEN: usize = 64;
+const MAX_METADATA_VALUE_LEN: usize = 512;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub state_manager: StateManager,
+    pub openai_client: OpenAIClient,
+    pub circuit_breaker: Arc<CircuitBreaker>,
+    pub key_resolver: Arc<KeyResolver>,
+    pub archiver: Option<Arc<Archiver>>,
+    pub moderator: Arc<Moderator>,
+    pub hooks: Arc<Hooks>,
+    pub rewrite_rules: Arc<RewriteRules>,
+    pub runtime: Arc<Runtime>,
+}
+
+pub async fn health_check() -> &'static str {
+    "OK"
+}
+
+/// Readiness: Redis must be reachable and the upstream circuit breaker must
+/// not be open.
+pub async fn readiness_check(State(app_state): State<Arc<AppState>>) -> Response {
+    let redis_ok = match app_state.state_manager.ping().await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Readiness check failed to reach Redis: {}", e);
+            false
+        }
+    };
+    let breaker = app_state.circuit_breaker.state();
+    let ready = redis_ok && breaker != BreakerState::Open;
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = serde_json::json!({
+        "ready": ready,
+        "redis": redis_ok,
+        "upstream_circuit": breaker,
+    });
+
+    (status, Json(body)).into_response()
+}
+
+pub async fn metrics_handler() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().render(),
+    )
+}
+
+pub async fn create_chat_completion(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(mut request): Json<CompletionRequest>,
+) -> Result<Response, ApiError> {
+    // Extract or generate idempotency key
+    let idempotency_key = headers
+        .get("idempotency-key")
+        .and_then(|h| h.to_str().ok())
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| {
+            let generated_key = Uuid::new_v4().to_string();
+            info!("No idempotency key provided, generated: {}", generated_key);
+            generated_key
+        });
+
+    // Resolve the upstream API key from the Authorization header (required)
+    let resolved = resolve_key(&app_state, &headers).await?;
+    apply_request_defaults(&app_state.config, &mut request)?;
+    apply_model_alias(&app_state.config, &mut request);
+    app_state
+        .rewrite_rules
+        .apply(resolved.mapping_id.as_deref(), resolved.tenant(), &mut request)
+        .map_err(|e| ApiError::InvalidRequest(format!("{:#}", e)))?;
+    enforce_key_policy(&resolved, &request)?;
+    let api_key = resolved.mapping.upstream_key.clone();
+    let state_manager = app_state.state_manager.for_tenant(resolved.tenant());
+
+    let hook_context = HookContext {
+        tenant: resolved.tenant(),
+        request_id: &idempotency_key,
+    };
+    app_state
+        .hooks
+        .before_enqueue(hook_context, &mut request)
+        .await
+        .map_err(|e| ApiError::InvalidRequest(format!("{:#}", e)))?;
+    // Counted on the request as the hooks left it, as they may rewrite or trim messages
+    let estimated_prompt_tokens = check_context_window(&app_state.config, &resolved, &request)?;
+    if let Some(format) = &request.response_format {
+        format
+            .validate(app_state.config.max_response_schema_bytes)
+            .map_err(ApiError::InvalidRequest)?;
+    }
+
+    let metadata = extract_metadata(&headers, &mut request)?;
+    let upstream_url = extract_upstream_url(&app_state.config, &resolved, &headers)?;
+    let scope = extract_scope(&app_state.config, &headers)?;
+    let trace_context = extract_trace_context(&headers);
+
+    if header_flag(&headers, "x-silt-bypass") {
+        enforce_rate_limit(&app_state, &resolved).await?;
+        // Bypassed requests aren't stored, so there is nothing to quarantine
+        if let Some(categories) = moderate(&app_state, &resolved, &request).await? {
+            return Err(flagged_error(&categories));
+        }
+        let request = match resolved.mapping.system_prompt.as_ref().or(app_state.config.system_prompt.as_ref()) {
+            Some(prompt) => prompt.apply(&request),
+            None => request,
+        };
+        // Rotated and routed like queued requests are at dispatch
+        let upstream_key = state_manager
+            .upstream_key(&api_key)
+            .await
+            .map_err(|e| ApiError::InternalError(e.to_string()))?;
+        let route = upstream_url
+            .is_none()
+            .then(|| app_state.config.upstream_route(&request.model))
+            .flatten();
+        let upstream_url = upstream_url.as_deref().or(route.map(|route| route.base_url.as_str()));
+        let api_key = route.and_then(|route| route.api_key.as_deref()).unwrap_or(&upstream_key);
+        let client = app_state
+            .openai_client
+            .for_upstream(upstream_url)
+            .with_scope(&scope)
+            .with_trace_context(trace_context.as_ref());
+        return proxy_realtime(&app_state, hook_context, &client, api_key, &request).await;
+    }
+
+    let priority = match headers.get("x-silt-priority").and_then(|h| h.to_str().ok()) {
+        Some(value) => Priority::parse(value).ok_or_else(|| {
+            ApiError::InvalidRequest(format!(
+                "Invalid x-silt-priority header '{}': expected 'high' or 'low'",
+                value
+            ))
+        })?,
+        None => Priority::default(),
+    };
+
+    let deadline_secs = match headers.get("x-silt-deadline-secs").and_then(|h| h.to_str().ok()) {
+        Some(value) => match value.trim().parse::<u64>() {
+            Ok(secs) if secs > 0 => Some(secs),
+            _ => {
+                return Err(ApiError::InvalidRequest(format!(
+                    "Invalid x-silt-deadline-secs header '{}': expected a positive number of seconds",
+                    value
+                )))
+            }
+        },
+        None => None,
+    };
+
+    let urgency = match headers.get("x-silt-urgency").and_then(|h| h.to_str().ok()) {
+        Some(value) => match app_state.config.urgency_class(value.trim()) {
+            Some(class) => Some(class.name.clone()),
+            None => {
+                let classes: Vec<&str> = app_state.config.urgency_classes.iter().map(|class| class.name.as_str()).collect();
+                return Err(ApiError::InvalidRequest(format!(
+                    "Invalid x-silt-urgency header '{}': expected one of [{}]",
+                    value,
+                    classes.join(", ")
+                )));
+            }
+        },
+        None => None,
+    };
+
+    info!("Received request with idempotency key: {}", idempotency_key);
+
+    // Check if request already exists
+    let existing_state = state_manager.get_request(&idempotency_key).await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+
+    if let Some(existing) = &existing_state {
+        let reused_with_new_body = existing
+            .body_hash
+            .as_ref()
+            .is_some_and(|hash| *hash != request.content_hash());
+        if reused_with_new_body {
+            warn!("Idempotency key {} reused with a different request body", idempotency_key);
+            return Err(ApiError::Conflict(format!(
+                "Idempotency key {} was already used with a different request body",
+                idempotency_key
+            )));
+        }
+    }
+
+    let state = match existing_state {
+        Some(state) if state.status == RequestStatus::Complete => {
+            // Already completed - return cached result
+            info!("Returning cached result for: {}", idempotency_key);
+            return result_response(&state_manager, state).await;
+        }
+        Some(state) if state.status == RequestStatus::Failed => {
+            // Previously failed
+            let error = request_failure(state);
+            error!("Request failed previously: {}", error);
+            return Err(ApiError::BatchFailed(error));
+        }
+        Some(state) if state.status == RequestStatus::Quarantined => {
+            return status_response(&app_state.config, &state_manager, &state, StatusCode::ACCEPTED).await;
+        }
+        Some(state) => {
+            // In progress - wait for completion
+            info!("Request already in progress, waiting: {}", idempotency_key);
+            state
+        }
+        None => {
+            // New request - create it
+            enforce_rate_limit(&app_state, &resolved).await?;
+            info!("Creating new request: {}", idempotency_key);
+            let mut state = RequestState::new(idempotency_key.clone(), request, api_key, priority);
+            state.mapped = resolved.mapping_id.is_some();
+            state.metadata = metadata;
+            state.deadline_at = deadline_secs
+                .map(|secs| state.created_at + chrono::Duration::seconds(secs as i64));
+            state.not_before = resolved
+                .mapping
+                .batch_window_secs
+                .map(|secs| state.created_at + chrono::Duration::seconds(secs as i64));
+            state.system_prompt = resolved.mapping.system_prompt.clone();
+            state.estimated_prompt_tokens = Some(estimated_prompt_tokens);
+            state.upstream_url = upstream_url;
+            state.scope = scope;
+            state.trace_context = trace_context;
+            state.urgency = urgency;
+
+            if let Some(categories) = moderate(&app_state, &resolved, &state.request).await? {
+                warn!("Quarantining request {} flagged for {}", idempotency_key, categories.join(", "));
+                state.status = RequestStatus::Quarantined;
+                state.flagged_categories = Some(categories);
+                let state = state_manager
+                    .quarantine_request(state)
+                    .await
+                    .map_err(|e| ApiError::InternalError(e.to_string()))?;
+                return status_response(&app_state.config, &state_manager, &state, StatusCode::ACCEPTED).await;
+            }
+
+            link_to_identical_request(&app_state.config, &state_manager, &mut state)
+                .await
+                .map_err(|e| ApiError::InternalError(e.to_string()))?;
+
+            if state.duplicate_of.is_some() {
+                let state = state_manager
+                    .create_duplicate_request(state)
+                    .await
+                    .map_err(|e| ApiError::InternalError(e.to_string()))?;
+                if state.status == RequestStatus::Complete {
+                    return result_response(&state_manager, state).await;
+                }
+                state
+            } else {
+                let counted = enforce_tenant_quota(&state_manager).await?;
+
+                let state = match state_manager.create_request(state).await {
+                    Ok(state) => state,
+                    Err(e) => {
+                        // It was never queued, so it doesn't count towards the quota
+                        if counted {
+                            if let Err(e) = state_manager.uncount_daily_request().await {
+                                warn!("Failed to take back a daily request count: {}", e);
+                            }
+                        }
+                        return Err(ApiError::InternalError(e.to_string()));
+                    }
+                };
+
+                metrics()
+                    .requests_submitted_total
+                    .with_label_values(&[state_manager.tenant()])
+                    .inc();
+                if let Some(key_label) = metrics().key_label(&key_fingerprint(&state.api_key)) {
+                    metrics()
+                        .key_requests_submitted_total
+                        .with_label_values(&[state_manager.tenant(), &key_label])
+                        .inc();
+                }
+                maybe_trigger_dispatch(&app_state.config, &state_manager, priority).await;
+                state
+            }
+        }
+    };
+
+    if prefers_async(&headers) {
+        return status_response(&app_state.config, &state_manager, &state, StatusCode::ACCEPTED).await;
+    }
+
+    // Queued requests can be given up on if the client doesn't wait for them
+    let cancel_on_disconnect = match headers.get("x-silt-cancel-on-disconnect") {
+        Some(_) => header_flag(&headers, "x-silt-cancel-on-disconnect"),
+        None => app_state.config.cancel_on_disconnect,
+    };
+    let mut waiter = Waiter::new(&state_manager, &state, cancel_on_disconnect).await?;
+
+    // Wait for completion, for as long as the client and SILT_MAX_WAIT_SECS allow
+    let max_wait = match (preferred_wait(&headers), app_state.config.max_wait_secs) {
+        (Some(wait), 0) => Some(wait),
+        (Some(wait), max) => Some(wait.min(Duration::from_secs(max))),
+        (None, 0) => None,
+        (None, max) => Some(Duration::from_secs(max)),
+    };
+    let response = match max_wait {
+        Some(max_wait) => match timeout(max_wait, wait_for_completion(&state_manager, &idempotency_key)).await {
+            Ok(response) => response,
+            Err(_) => wait_expired(&app_state.config, &state_manager, state, max_wait).await,
+        },
+        None => wait_for_completion(&state_manager, &idempotency_key).await,
+    };
+    waiter.finished();
+    response
+}
+
+/// The request's status once a bounded wait runs out, so the client can
+/// switch to polling its status URL. It keeps running either way.
+async fn wait_expired(
+    config: &Config,
+    state_manager: &StateManager,
+    state: RequestState,
+    waited: Duration,
+) -> Result<Response, ApiError> {
+    info!("Stopped waiting for {} after {:?}", state.request_id, waited);
+    let state = state_manager
+        .get_request(&state.request_id)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?
+        .unwrap_or(state);
+    let status = StatusCode::from_u16(config.wait_timeout_status).unwrap_or(StatusCode::GATEWAY_TIMEOUT);
+    status_response(config, state_manager, &state, status).await
+}
+
+/// A connection waiting on a request's result. Waiters are counted in Redis
+/// so that, when the future is dropped because the client disconnected, a
+/// request still queued can be cancelled once nobody is waiting for it.
+/// Requests already dispatched run to completion for later retrieval.
+struct Waiter {
+    state_manager: StateManager,
+    request_id: String,
+    priority: Priority,
+    cancel_on_disconnect: bool,
+}
+
+impl Waiter {
+    async fn new(state_manager: &StateManager, state: &RequestState, cancel_on_disconnect: bool) -> Result<Self, ApiError> {
+        state_manager
+            .add_waiter(&state.request_id)
+            .await
+            .map_err(|e| ApiError::InternalError(e.to_string()))?;
+        Ok(Self {
+            state_manager: state_manager.clone(),
+            request_id: state.request_id.clone(),
+            priority: state.priority,
+            cancel_on_disconnect,
+        })
+    }
+
+    /// The wait ended with a response, so there is nothing to cancel.
+    fn finished(&mut self) {
+        self.cancel_on_disconnect = false;
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let state_manager = self.state_manager.clone();
+        let request_id = std::mem::take(&mut self.request_id);
+        let priority = self.priority;
+        let cancel = self.cancel_on_disconnect;
+        tokio::spawn(async move {
+            let remaining = match state_manager.remove_waiter(&request_id).await {
+                Ok(remaining) => remaining,
+                Err(e) => {
+                    warn!("Failed to release waiter for {}: {}", request_id, e);
+                    return;
+                }
+            };
+            if !cancel || remaining > 0 {
+                return;
+            }
+            // Claiming it from the queue means it hasn't been dispatched
+            match state_manager.cancel_queued(&request_id, priority).await {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => {
+                    warn!("Failed to cancel {} after its client disconnected: {}", request_id, e);
+                    return;
+                }
+            }
+            info!("Cancelling queued request {}: its client disconnected", request_id);
+            metrics()
+                .requests_cancelled_on_disconnect_total
+                .with_label_values(&[state_manager.tenant()])
+                .inc();
+            let error = RequestError::new(
+                RequestErrorKind::Disconnected,
+                "Cancelled: client disconnected before dispatch",
+            );
+            if let Err(e) = state_manager.fail_request(&request_id, error).await {
+                warn!("Failed to record cancellation of {}: {}", request_id, e);
+            }
+        });
+    }
+}
+
+/// Proxies the upstream model list with the caller's key, so SDK clients
+/// using silt as their base URL can enumerate models. With
+/// `SILT_FILTER_MODELS_BY_ALLOWLIST`, models outside the key mapping's
+/// `allowed_models` are left out.
+pub async fn list_models(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let resolved = resolve_key(&app_state, &headers).await?;
+    let upstream_key = app_state
+        .state_manager
+        .upstream_key(&resolved.mapping.upstream_key)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    let mut models = app_state
+        .openai_client
+        .with_scope(&extract_scope(&app_state.config, &headers)?)
+        .list_models(&upstream_key)
+        .await
+        .map_err(ApiError::Upstream)?;
+
+    if app_state.config.filter_models_by_allowlist {
+        if let (Some(allowed_models), Some(data)) = (
+            &resolved.mapping.allowed_models,
+            models.get_mut("data").and_then(|data| data.as_array_mut()),
+        ) {
+            data.retain(|model| {
+                model
+                    .get("id")
+                    .and_then(|id| id.as_str())
+                    .is_some_and(|id| allowed_models.iter().any(|allowed| allowed == id))
+            });
+        }
+    }
+
+    Ok(Json(models).into_response())
+}
+
+/// `GET /v1/requests/:id` - the request's status and estimates, without the
+/// (potentially large) result.
+pub async fn get_request_status(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(request_id): Path<String>,
+) -> Result<Response, ApiError> {
+    let (state_manager, state) = load_owned_request(&app_state, &headers, &request_id).await?;
+    let response = status_response(&app_state.config, &state_manager, &state, StatusCode::OK).await?;
+    Ok(not_modified_if_matching(&headers, response))
+}
+
+/// `GET /v1/requests/:id/result` - the completion for a finished request.
+pub async fn get_request_result(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(request_id): Path<String>,
+) -> Result<Response, ApiError> {
+    let (state_manager, state) = load_owned_request(&app_state, &headers, &request_id).await?;
+
+    match state.status {
+        RequestStatus::Complete => Ok(not_modified_if_matching(
+            &headers,
+            result_response(&state_manager, state).await?,
+        )),
+        RequestStatus::Failed => Err(ApiError::BatchFailed(request_failure(state))),
+        _ => Err(ApiError::NotReady(format!(
+            "Request {} is still {:?}",
+            request_id, state.status
+        ))),
+    }
+}
+
+/// `DELETE /v1/requests/:id` - cancels a request that is still queued. It
+/// ends `failed`, so anyone waiting on it is released. Requests already in a
+/// batch run to completion.
+pub async fn cancel_request(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(request_id): Path<String>,
+) -> Result<Response, ApiError> {
+    let (state_manager, state) = load_owned_request(&app_state, &headers, &request_id).await?;
+
+    let removed = state.status == RequestStatus::Queued
+        && state_manager
+            .cancel_queued(&request_id, state.priority)
+            .await
+            .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    if !removed {
+        return Err(ApiError::Conflict(format!(
+            "Request {} is no longer queued and cannot be cancelled",
+            request_id
+        )));
+    }
+
+    info!("Cancelling queued request {}", request_id);
+    state_manager
+        .fail_request(&request_id, RequestError::new(RequestErrorKind::Cancelled, "Cancelled by client"))
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    let state = state_manager
+        .get_request(&request_id)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?
+        .unwrap_or(state);
+    status_response(&app_state.config, &state_manager, &state, StatusCode::OK).await
+}
+
+/// Points a new request at an identical one from the same API key instead of
+/// sending it upstream again: any queued, in-flight or completed request with
+/// `SILT_DEDUPE_IDENTICAL_REQUESTS`, or a deterministic request completed
+/// within `SILT_RESULT_CACHE_TTL_SECS`. Leaves `state.duplicate_of` unset when
+/// there is nothing usable to link to.
+async fn link_to_identical_request(
+    config: &Config,
+    state_manager: &StateManager,
+    state: &mut RequestState,
+) -> anyhow::Result<()> {
+    let dedupe = config.dedupe_identical_requests;
+    let cache_ttl = (config.result_cache_ttl_secs > 0 && state.request.is_deterministic())
+        .then(|| chrono::Duration::seconds(config.result_cache_ttl_secs as i64));
+    if !dedupe && cache_ttl.is_none() {
+        return Ok(());
+    }
+
+    let content_hash = dedupe_hash(state);
+
+    let Some(original_id) = state_manager
+        .claim_content_hash(&content_hash, &state.request_id)
+        .await?
+    else {
+        return Ok(());
+    };
+
+    let original = state_manager.get_request(&original_id).await?;
+    let in_flight = original
+        .as_ref()
+        .is_some_and(|original| !matches!(original.status, RequestStatus::Complete | RequestStatus::Failed));
+    let usable = match &original {
+        Some(original) if original.status == RequestStatus::Complete => {
+            dedupe
+                || cache_ttl
+                    .zip(original.completed_at)
+                    .is_some_and(|(ttl, completed_at)| Utc::now() - completed_at < ttl)
+        }
+        Some(_) => in_flight && dedupe,
+        None => false,
+    };
+
+    let Some(original) = original.filter(|_| usable) else {
+        // Keep an in-flight holder so its result is cached once it completes
+        if !in_flight {
+            state_manager.replace_content_hash(&content_hash, &state.request_id).await?;
+        }
+        return Ok(());
+    };
+
+    info!(
+        "Request {} is identical to {}, linking instead of dispatching",
+        state.request_id, original.request_id
+    );
+
+    state.status = original.status;
+    state.batch_id = original.batch_id;
+    state.dispatched_at = original.dispatched_at;
+    state.completed_at = original.completed_at;
+    state.duplicate_of = Some(original.request_id);
+    Ok(())
+}
+
+/// Dedupe hash over the API key, the normalized request body, the key
+/// mapping's system prompt, where it is sent and its urgency, so identical
+/// requests from different keys, prompts or scopes are never linked, nor an
+/// urgent request to a batch that may take all night.
+fn dedupe_hash(state: &RequestState) -> String {
+    let body_hash = state
+        .body_hash
+        .clone()
+        .unwrap_or_else(|| state.request.content_hash());
+    let mut hasher = Sha256::new();
+    hasher.update(key_fingerprint(&state.api_key).as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body_hash.as_bytes());
+    // Mappings sharing an upstream key may add different system prompts
+    if let Some(system_prompt) = &state.system_prompt {
+        hasher.update(b"\nsystem_prompt:");
+        hasher.update(serde_json::to_string(system_prompt).unwrap_or_default().as_bytes());
+    }
+    if let Some(upstream_url) = &state.upstream_url {
+        hasher.update(b"\n");
+        hasher.update(upstream_url.as_bytes());
+    }
+    if let Some(organization) = &state.scope.organization {
+        hasher.update(b"\norganization:");
+        hasher.update(organization.as_bytes());
+    }
+    if let Some(project) = &state.scope.project {
+        hasher.update(b"\nproject:");
+        hasher.update(project.as_bytes());
+    }
+    for (name, value) in &state.scope.headers {
+        hasher.update(format!("\n{}:", name).as_bytes());
+        hasher.update(value.as_bytes());
+    }
+    if let Some(urgency) = &state.urgency {
+        hasher.update(b"\nurgency:");
+        hasher.update(urgency.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Checks the caller's tenant quota before a new request is queued, counting
+/// it towards today's requests if it is accepted. Returns whether it was
+/// counted, so a request that then fails to queue can be taken back.
+async fn enforce_tenant_quota(state_manager: &StateManager) -> Result<bool, ApiError> {
+    let internal = |e: anyhow::Error| ApiError::InternalError(e.to_string());
+
+    let Some(quota) = state_manager
+        .get_tenant_quota(state_manager.tenant())
+        .await
+        .map_err(internal)?
+    else {
+        return Ok(false);
+    };
+
+    if let Some(max_queued) = quota.max_queued_requests {
+        let queued = state_manager.queued_count(Priority::High).await.map_err(internal)?
+            + state_manager.queued_count(Priority::Low).await.map_err(internal)?;
+        if queued >= max_queued {
+            return Err(ApiError::RateLimited(format!(
+                "Tenant queue is full ({} requests waiting for dispatch)",
+                queued
+            )));
+        }
+    }
+
+    if let Some(max_tokens) = quota.max_tokens_per_day {
+        let usage = state_manager.daily_usage().await.map_err(internal)?;
+        if usage.tokens >= max_tokens {
+            return Err(ApiError::RateLimited(format!(
+                "Tenant daily token quota of {} exhausted",
+                max_tokens
+            )));
+        }
+    }
+
+    let count = state_manager.count_daily_request().await.map_err(internal)?;
+    if quota.max_requests_per_day.is_some_and(|max| count > max) {
+        state_manager.uncount_daily_request().await.map_err(internal)?;
+        return Err(ApiError::RateLimited(format!(
+            "Tenant daily request quota of {} exhausted",
+            quota.max_requests_per_day.unwrap_or_default()
+        )));
+    }
+
+    Ok(true)
+}
+
+#[derive(Deserialize)]
+pub struct BatchQuery {
+    tenant: Option<String>,
+}
+
+/// A request in a batch, as reported alongside the upstream batch.
+#[derive(Serialize)]
+struct BatchMember {
+    id: String,
+    /// None once the request's state has expired
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<RequestStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dispatched_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    completed_at: Option<DateTime<Utc>>,
+}
+
+/// `GET /v1/batches/:id` - an upstream batch silt created, fetched from the
+/// upstream it went to with the key it was created with, plus a `silt`
+/// object listing its requests with their statuses and dispatch times and
+/// the batch's progress as of the last poll. Only batches holding one of the
+/// caller's requests are found, and only for as long as silt keeps the
+/// batch's mapping.
+pub async fn get_batch(
+    State(app_state): State<Arc<AppState>>,
+    Path(batch_id): Path<String>,
+    Query(query): Query<BatchQuery>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    let internal = |e: anyhow::Error| ApiError::InternalError(e.to_string());
+    let not_found = || ApiError::NotFound(format!("No batch found with id {}", batch_id));
+    let headers = request.headers();
+
+    // The admin token sees the whole batch in any tenant (`?tenant=`); an API
+    // key sees a batch only if it submitted one of its requests, and only those.
+    let (state_manager, owner) = if require_admin(&app_state, headers).is_ok() {
+        // Only from `SILT_ADMIN_ALLOWED_IPS`, as this route is outside `/admin`
+        if !is_admin_ip(&app_state.config, &request) {
+            return Err(ApiError::Forbidden("Requests from this address are not allowed".to_string()));
+        }
+        let tenant = query.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+        if !is_valid_tenant(tenant) {
+            return Err(ApiError::InvalidRequest(format!("Invalid tenant {}", tenant)));
+        }
+        (app_state.state_manager.for_tenant(tenant), None)
+    } else {
+        let resolved = resolve_key(&app_state, headers).await?;
+        let state_manager = app_state.state_manager.for_tenant(resolved.tenant());
+        (state_manager, Some(resolved.mapping.upstream_key))
+    };
+    let api_key = state_manager
+        .get_batch_api_key(&batch_id)
+        .await
+        .map_err(internal)?
+        .ok_or_else(not_found)?;
+
+    let request_ids = state_manager.get_batch_requests(&batch_id).await.map_err(internal)?;
+    let states = state_manager.get_requests(&request_ids).await.map_err(internal)?;
+    let requests: Vec<BatchMember> = request_ids
+        .into_iter()
+        .zip(states)
+        .filter(|(_, state)| match &owner {
+            Some(owner) => state.as_ref().is_some_and(|state| state.api_key == *owner),
+            None => true,
+        })
+        .map(|(id, state)| BatchMember {
+            id,
+            status: state.as_ref().map(|state| state.status.clone()),
+            created_at: state.as_ref().map(|state| state.created_at),
+            dispatched_at: state.as_ref().and_then(|state| state.dispatched_at),
+            completed_at: state.as_ref().and_then(|state| state.completed_at),
+        })
+        .collect();
+    if owner.is_some() && requests.is_empty() {
+        return Err(not_found());
+    }
+
+    let client = app_state
+        .openai_client
+        .for_upstream(state_manager.get_batch_upstream(&batch_id).await.map_err(internal)?.as_deref())
+        .with_scope(&state_manager.get_batch_scope(&batch_id).await.map_err(internal)?);
+    let mut batch = client.get_batch(&api_key, &batch_id).await.map_err(ApiError::Upstream)?;
+    let last_poll = state_manager.get_batch_progress(&batch_id).await.map_err(internal)?;
+    let urgency = state_manager.get_batch_urgency(&batch_id).await.map_err(internal)?;
+
+    if let Some(batch) = batch.as_object_mut() {
+        batch.insert(
+            "silt".to_string(),
+            serde_json::json!({
+                "requests": requests,
+                "last_poll": last_poll,
+                "urgency": urgency,
+            }),
+        );
+    }
+    Ok(Json(batch).into_response())
+}
+
+/// Loads a request from the caller's tenant, hiding requests submitted with a
+/// different API key. Returns the tenant-scoped state manager alongside it.
+async fn load_owned_request(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    request_id: &str,
+) -> Result<(StateManager, RequestState), ApiError> {
+    let resolved = resolve_key(app_state, headers).await?;
+    let state_manager = app_state.state_manager.for_tenant(resolved.tenant());
+
+    let state = state_manager
+        .get_request(request_id)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+
+    match state {
+        Some(state) if state.api_key == resolved.mapping.upstream_key => Ok((state_manager, state)),
+        _ => Err(ApiError::NotFound(format!("No request found with id {}", request_id))),
+    }
+}
+
+/// Resolves the caller's bearer token to an upstream key, either the token
+/// itself or a key mapping.
+async fn resolve_key(app_state: &AppState, headers: &HeaderMap) -> Result<ResolvedKey, ApiError> {
+    let token = bearer_token(headers).ok_or(ApiError::MissingApiKey)?;
+    app_state
+        .key_resolver
+        .resolve(token)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?
+        .ok_or(ApiError::InvalidApiKey)
+}
+
+/// Fills in `SILT_DEFAULT_MODEL`, `SILT_DEFAULT_MAX_TOKENS` and `SILT_DEFAULT_TEMPERATURE`
+/// where the client left them out, and clamps token limits to
+/// `SILT_MAX_TOKENS_CAP`.
+fn apply_request_defaults(config: &Config, request: &mut CompletionRequest) -> Result<(), ApiError> {
+    if request.model.is_empty() {
+        request.model = config
+            .default_model
+            .clone()
+            .ok_or_else(|| ApiError::InvalidRequest("model is required".to_string()))?;
+    }
+
+    // Newer models take max_completion_tokens instead; don't send both
+    let has_completion_limit = request.extra.contains_key("max_completion_tokens");
+    if request.max_tokens.is_none() && !has_completion_limit {
+        request.max_tokens = config.default_max_tokens;
+    }
+    if let Some(cap) = config.max_tokens_cap {
+        request.max_tokens = request.max_tokens.map(|max_tokens| max_tokens.min(cap));
+        if let Some(limit) = request.extra.get_mut("max_completion_tokens") {
+            if limit.as_u64().is_some_and(|limit| limit > u64::from(cap)) {
+                *limit = cap.into();
+            }
+        }
+    }
+
+    if request.temperature.is_none() {
+        request.temperature = config.default_temperature;
+    }
+    Ok(())
+}
+
+/// Counts the prompt tokens the request will be sent with, system prompt
+/// included, and rejects it when they and its completion limit don't fit the
+/// model's context window. Models without a known window aren't checked.
+fn check_context_window(config: &Config, resolved: &ResolvedKey, request: &CompletionRequest) -> Result<u64, ApiError> {
+    let prompt_tokens = match resolved.mapping.system_prompt.as_ref().or(config.system_prompt.as_ref()) {
+        Some(prompt) => tokens::count_prompt_tokens(&prompt.apply(request)),
+        None => tokens::count_prompt_tokens(request),
+    };
+    let Some(window) = tokens::context_window(&config.model_context_windows, &request.model) else {
+        return Ok(prompt_tokens);
+    };
+
+    let completion_tokens = request
+        .max_tokens
+        .map(u64::from)
+        .or_else(|| request.extra.get("max_completion_tokens").and_then(|limit| limit.as_u64()))
+        .unwrap_or(0);
+    if prompt_tokens + completion_tokens > window {
+        return Err(ApiError::InvalidRequest(format!(
+            "Request needs about {} prompt tokens plus {} completion tokens, more than {}'s context window of {}",
+            prompt_tokens, completion_tokens, request.model, window
+        )));
+    }
+    Ok(prompt_tokens)
+}
+
+/// Runs moderation if the tenant's policy (or `SILT_MODERATION_ACTION`) asks for
+/// it. Flagged requests are rejected here under `reject`; under `quarantine`
+/// their categories are returned for the caller to hold the request.
+async fn moderate(
+    app_state: &AppState,
+    resolved: &ResolvedKey,
+    request: &CompletionRequest,
+) -> Result<Option<Vec<String>>, ApiError> {
+    let action = app_state
+        .state_manager
+        .get_moderation_policy(resolved.tenant())
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?
+        .map(|policy| policy.action)
+        .unwrap_or(app_state.config.moderation_action);
+    if action == ModerationAction::Off {
+        return Ok(None);
+    }
+
+    let upstream_key = app_state
+        .state_manager
+        .upstream_key(&resolved.mapping.upstream_key)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    let flagged = app_state
+        .moderator
+        .check(&upstream_key, request)
+        .await
+        .map_err(ApiError::Upstream)?;
+    match flagged {
+        Some(categories) if action == ModerationAction::Reject => {
+            metrics()
+                .requests_flagged_total
+                .with_label_values(&[resolved.tenant(), "reject"])
+                .inc();
+            Err(flagged_error(&categories))
+        }
+        Some(categories) => {
+            metrics()
+                .requests_flagged_total
+                .with_label_values(&[resolved.tenant(), "quarantine"])
+                .inc();
+            Ok(Some(categories))
+        }
+        None => Ok(None),
+    }
+}
+
+fn flagged_error(categories: &[String]) -> ApiError {
+    if categories.is_empty() {
+        return ApiError::InvalidRequest("Request was flagged by moderation".to_string());
+    }
+    ApiError::InvalidRequest(format!(
+        "Request was flagged by moderation: {}",
+        categories.join(", ")
+    ))
+}
+
+/// Rewrites an aliased model name (`SILT_MODEL_ALIASES`) to the model it stands
+/// for, before the request is checked, stored or sent upstream.
+fn apply_model_alias(config: &Config, request: &mut CompletionRequest) {
+    if let Some(model) = config.model_aliases.get(&request.model) {
+        info!("Rewriting model {} to {}", request.model, model);
+        request.model = model.clone();
+    }
+}
+
+/// Applies a key mapping's model allowlist to a request.
+fn enforce_key_policy(resolved: &ResolvedKey, request: &CompletionRequest) -> Result<(), ApiError> {
+    if resolved.mapping_id.is_none() {
+        return Ok(());
+    }
+    if let Some(allowed_models) = &resolved.mapping.allowed_models {
+        if !allowed_models.contains(&request.model) {
+            return Err(ApiError::Forbidden(format!(
+                "Model {} is not allowed for this API key",
+                request.model
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Counts a request towards its key mapping's per-minute rate limit. Called
+/// once the request is known to be valid and new, so rejected requests and
+/// retries of an idempotency key don't use up the limit.
+async fn enforce_rate_limit(app_state: &AppState, resolved: &ResolvedKey) -> Result<(), ApiError> {
+    let Some(mapping_id) = &resolved.mapping_id else {
+        return Ok(());
+    };
+    if let Some(limit) = resolved.mapping.max_requests_per_minute {
+        let count = app_state
+            .state_manager
+            .count_mapping_request(mapping_id)
+            .await
+            .map_err(|e| ApiError::InternalError(e.to_string()))?;
+        if count > u64::from(limit) {
+            return Err(ApiError::RateLimited(format!(
+                "Rate limit of {} requests per minute exceeded for this API key",
+                limit
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+}
+
+/// `Prefer: respond-async` (RFC 7240) asks for an immediate 202 with a status
+/// URL instead of holding the connection until the batch completes.
+fn prefers_async(headers: &HeaderMap) -> bool {
+    headers.get_all("prefer").iter().any(|value| {
+        value
+            .to_str()
+            .map(|v| v.split(',').any(|pref| pref.trim().eq_ignore_ascii_case("respond-async")))
+            .unwrap_or(false)
+    })
+}
+
+/// `Prefer: wait=<secs>` (RFC 7240) bounds how long a synchronous request is
+/// held open.
+fn preferred_wait(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|pref| {
+            let (name, secs) = pref.split_once('=')?;
+            if !name.trim().eq_ignore_ascii_case("wait") {
+                return None;
+            }
+            secs.trim().parse().ok().map(Duration::from_secs)
+        })
+}
+
+#[derive(Serialize)]
+struct RequestStatusBody<'a> {
+    id: &'a str,
+    object: &'static str,
+    status: &'a RequestStatus,
+    priority: Priority,
+    batch_id: Option<&'a str>,
+    created_at: DateTime<Utc>,
+    dispatched_at: Option<DateTime<Utc>>,
+    completed_at: Option<DateTime<Utc>>,
+    error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimated_prompt_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    urgency: Option<&'a str>,
+    #[serde(flatten)]
+    estimates: Estimates,
+    /// Every status the request has been through, oldest first
+    history: Vec<StatusTransition>,
+    status_url: String,
+    result_url: String,
+}
+
+/// Status document for a request, with ETA headers and a `Location` pointing
+/// at the status endpoint.
+async fn status_response(
+    config: &Config,
+    state_manager: &StateManager,
+    state: &RequestState,
+    status: StatusCode,
+) -> Result<Response, ApiError> {
+    let estimates = match eta::estimate(state_manager, state).await {
+        Ok(estimates) => estimates,
+        Err(e) => {
+            warn!("Failed to estimate ETA for {}: {}", state.request_id, e);
+            Estimates::default()
+        }
+    };
+    let history = match state_manager.get_history(&state.request_id).await {
+        Ok(history) => history,
+        Err(e) => {
+            warn!("Failed to read the history of {}: {}", state.request_id, e);
+            Vec::new()
+        }
+    };
+
+    let status_url = format!("/v1/requests/{}", state.request_id);
+    let body = RequestStatusBody {
+        id: &state.request_id,
+        object: "silt.request",
+        status: &state.status,
+        priority: state.priority,
+        batch_id: state.batch_id.as_deref(),
+        created_at: state.created_at,
+        dispatched_at: state.dispatched_at,
+        completed_at: state.completed_at,
+        error: state.error.as_ref().map(|error| error.message.as_str()),
+        error_kind: state.error.as_ref().map(|error| error.kind.as_str()),
+        estimated_prompt_tokens: state.estimated_prompt_tokens,
+        urgency: state.urgency.as_deref(),
+        estimates: estimates.clone(),
+        history,
+        result_url: format!("{}/result", status_url),
+        status_url: status_url.clone(),
+    };
+
+    let body = serde_json::to_vec(&body).map_err(|e| ApiError::InternalError(e.to_string()))?;
+    let etag = etag_for(&body);
+
+    let mut response = (status, [(header::CONTENT_TYPE, "application/json")], body).into_response();
+    let headers = response.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    if let Ok(location) = HeaderValue::from_str(&status_url) {
+        headers.insert(header::LOCATION, location);
+    }
+    if let Some(at) = estimates.estimated_dispatch_at {
+        if let Ok(value) = HeaderValue::from_str(&at.to_rfc3339()) {
+            headers.insert("x-silt-estimated-dispatch-at", value);
+        }
+    }
+    if let Some(at) = estimates.estimated_completion_at {
+        if let Ok(value) = HeaderValue::from_str(&at.to_rfc3339()) {
+            headers.insert("x-silt-estimated-completion-at", value);
+        }
+    }
+    if state.status == RequestStatus::Queued {
+        if let Some(resumes) = pause_end(&config.pause_windows, Utc::now()) {
+            if let Ok(value) = HeaderValue::from_str(&resumes.to_rfc3339()) {
+                headers.insert("x-silt-dispatch-paused-until", value);
+            }
+        }
+    }
+
+    Ok(response)
+}
+
+/// A completed request's result, loaded from its own key. Results can be
+/// kept for less time than request state, so one may have expired.
+async fn result_response(state_manager: &StateManager, mut state: RequestState) -> Result<Response, ApiError> {
+    state_manager
+        .load_result(&mut state)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    if state.result.is_none() {
+        return Err(ApiError::Gone(format!("The result of request {} has expired", state.request_id)));
+    }
+    Ok(retrieved_response(state_manager, state).await)
+}
+
+/// `completed_response`, recording the retrieval for the audit log and
+/// retention policies.
+async fn retrieved_response(state_manager: &StateManager, state: RequestState) -> Response {
+    if let Err(e) = state_manager.record_retrieval(&state).await {
+        warn!("Failed to record retrieval of {}: {}", state.request_id, e);
+    }
+    completed_response(state)
+}
+
+/// The JSON result of a completed request, with `x-silt-*` headers recording
+/// which batch served it and when it moved through each stage.
+fn completed_response(state: RequestState) -> Response {
+    let body = match serde_json::to_vec(&state.result) {
+        Ok(body) => body,
+        Err(e) => return ApiError::InternalError(e.to_string()).into_response(),
+    };
+    let etag = etag_for(&body);
+
+    let mut response = ([(header::CONTENT_TYPE, "application/json")], body).into_response();
+    let headers = response.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        headers.insert(header::ETAG, etag);
+    }
+
+    let mut insert = |name: &'static str, value: String| {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    };
+
+    if let Some(batch_id) = &state.batch_id {
+        insert("x-silt-batch-id", batch_id.clone());
+    }
+    insert("x-silt-queued-at", state.created_at.to_rfc3339());
+    if let Some(dispatched_at) = state.dispatched_at {
+        insert("x-silt-dispatched-at", dispatched_at.to_rfc3339());
+    }
+    if let Some(completed_at) = state.completed_at {
+        insert("x-silt-completed-at", completed_at.to_rfc3339());
+    }
+
+    response
+}
+
+/// Strong ETag for a response body.
+fn etag_for(body: &[u8]) -> String {
+    let digest = Sha256::digest(body);
+    format!("\"{}\"", hex::encode(&digest[..16]))
+}
+
+/// Replaces a response with an empty 304 when the client's `If-None-Match`
+/// already covers its ETag, so pollers don't re-download unchanged bodies.
+fn not_modified_if_matching(request_headers: &HeaderMap, response: Response) -> Response {
+    let Some(etag) = response.headers().get(header::ETAG).cloned() else {
+        return response;
+    };
+    let Ok(etag_str) = etag.to_str() else {
+        return response;
+    };
+
+    let matches = request_headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag_str);
+
+    if !matches {
+        return response;
+    }
+
+    let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+    not_modified.headers_mut().insert(header::ETAG, etag);
+    not_modified
+}
+
+/// Sends a request straight to the upstream chat completions endpoint,
+/// skipping the queue and Redis entirely.
+async fn proxy_realtime(
+    app_state: &AppState,
+    hook_context: HookContext<'_>,
+    client: &OpenAIClient,
+    api_key: &str,
+    request: &CompletionRequest,
+) -> Result<Response, ApiError> {
+    if request.extra.get("stream").and_then(|v| v.as_bool()) == Some(true) {
+        return Err(ApiError::InvalidRequest(
+            "Streaming is not supported for bypassed requests".to_string(),
+        ));
+    }
+
+    info!("Bypassing batching for realtime request (model: {})", request.model);
+
+    let mut response = client
+        .create_chat_completion(api_key, request)
+        .await
+        .map_err(ApiError::Upstream)?;
+    app_state
+        .hooks
+        .after_result(hook_context, request, &mut response)
+        .await
+        .map_err(|e| {
+            ApiError::BatchFailed(RequestError::new(RequestErrorKind::PostResultHook, format!("{:#}", e)))
+        })?;
+
+    Ok(Json(response).into_response())
+}
+
+/// Batch metadata from the `x-silt-metadata` header (a JSON object) or the
+/// `silt_metadata` body extension, which is stripped before the request is
+/// sent upstream. Limits match the OpenAI Batch API's metadata limits.
+fn extract_metadata(
+    headers: &HeaderMap,
+    request: &mut CompletionRequest,
+) -> Result<Option<BTreeMap<String, String>>, ApiError> {
+    let from_body = request.extra.remove("silt_metadata");
+    let value = match headers.get("x-silt-metadata") {
+        Some(header) => {
+            let raw = header
+                .to_str()
+                .map_err(|_| ApiError::InvalidRequest("Invalid x-silt-metadata header".to_string()))?;
+            serde_json::from_str(raw).map_err(|e| {
+                ApiError::InvalidRequest(format!("x-silt-metadata must be a JSON object: {}", e))
+            })?
+        }
+        None => match from_body {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+    };
+
+    let metadata: BTreeMap<String, String> = serde_json::from_value(value).map_err(|_| {
+        ApiError::InvalidRequest("Metadata must be a JSON object with string values".to_string())
+    })?;
+
+    if metadata.len() > MAX_METADATA_PAIRS {
+        return Err(ApiError::InvalidRequest(format!(
+            "Metadata may have at most {} keys",
+            MAX_METADATA_PAIRS
+        )));
+    }
+    for (key, value) in &metadata {
+        if key.len() > MAX_METADATA_KEY_LEN || value.len() > MAX_METADATA_VALUE_LEN {
+            return Err(ApiError::InvalidRequest(format!(
+                "Metadata keys are limited to {} characters and values to {}",
+                MAX_METADATA_KEY_LEN, MAX_METADATA_VALUE_LEN
+            )));
+        }
+    }
+
+    Ok((!metadata.is_empty()).then_some(metadata))
+}
+
+/// The upstream a request picked with `x-silt-upstream-url`, which must be
+/// one of `SILT_UPSTREAM_URL_ALLOWLIST`. Mapped tokens may only pick the
+/// mapping's `upstream_urls`, as the upstream key sent there is the
+/// operator's rather than the client's own.
+fn extract_upstream_url(
+    config: &Config,
+    resolved: &ResolvedKey,
+    headers: &HeaderMap,
+) -> Result<Option<String>, ApiError> {
+    let Some(header) = headers.get("x-silt-upstream-url") else {
+        return Ok(None);
+    };
+    let url = header
+        .to_str()
+        .map_err(|_| ApiError::InvalidRequest("Invalid x-silt-upstream-url header".to_string()))?
+        .trim()
+        .trim_end_matches('/');
+    if !config.upstream_url_allowlist.iter().any(|allowed| allowed == url) {
+        return Err(ApiError::InvalidRequest(format!(
+            "Upstream {} is not in SILT_UPSTREAM_URL_ALLOWLIST",
+            url
+        )));
+    }
+    let permitted = resolved.mapping_id.is_none()
+        || resolved
+            .mapping
+            .upstream_urls
+            .as_ref()
+            .is_some_and(|urls| urls.iter().any(|allowed| allowed.trim_end_matches('/') == url));
+    if !permitted {
+        return Err(ApiError::Forbidden(format!("This token may not send requests to {}", url)));
+    }
+    Ok(Some(url.to_string()))
+}
+
+/// `OpenAI-Organization` and `OpenAI-Project`, for clients that scope their
+/// usage upstream, and the headers listed in `SILT_PASSTHROUGH_HEADERS`.
+fn extract_scope(config: &Config, headers: &HeaderMap) -> Result<UpstreamScope, ApiError> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .map(|value| {
+                value
+                    .to_str()
+                    .map(|value| value.trim().to_string())
+                    .map_err(|_| ApiError::InvalidRequest(format!("Invalid {} header", name)))
+            })
+            .transpose()
+    };
+    let mut passthrough = BTreeMap::new();
+    for name in &config.passthrough_headers {
+        if let Some(value) = header(name)? {
+            passthrough.insert(name.clone(), value);
+        }
+    }
+    Ok(UpstreamScope {
+        organization: header("openai-organization")?.filter(|value| !value.is_empty()),
+        project: header("openai-project")?.filter(|value| !value.is_empty()),
+        headers: passthrough,
+    })
+}
+
+/// The client's W3C `traceparent` and `tracestate`, to pass on upstream.
+/// Malformed ones are ignored rather than refused, as tracing is best effort.
+fn extract_trace_context(headers: &HeaderMap) -> Option<TraceContext> {
+    let traceparent = headers.get("traceparent")?.to_str().ok()?;
+    let tracestate = headers.get("tracestate").and_then(|value| value.to_str().ok());
+    TraceContext::parse(traceparent, tracestate)
+}
+
+/// Reads a boolean request header such as `x-silt-bypass: true`.
+fn header_flag(headers: &HeaderMap, name: &str) -> bool {
+    headers
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "true" | "1" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Wakes the dispatcher early once the queue reaches `SILT_BATCH_MAX_QUEUE_SIZE`.
+/// Failures are only logged; the request still goes out on the next window.
+async fn maybe_trigger_dispatch(config: &Config, state_manager: &StateManager, priority: Priority) {
+    let max_queue_size = config.batch_max_queue_size;
+    if max_queue_size == 0 {
+        return;
+    }
+
+    match state_manager.queued_count(priority).await {
+        Ok(count) if count >= max_queue_size => {
+            if let Err(e) = state_manager.trigger_dispatch(priority).await {
+                warn!("Failed to trigger early dispatch: {}", e);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Failed to read queue size: {}", e),
+    }
+}
+
+async fn wait_for_completion(
+    state_manager: &StateManager,
+    request_id: &str,
+) -> Result<Response, ApiError> {
+    let _waiting = GaugeGuard::new(&metrics().waiting_requests);
+
+    // Subscribe to completion events
+    let mut pubsub = state_manager
+        .subscribe_to_completion(request_id)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+
+    // Wait for completion with periodic checks
+    loop {
+        // Try to get message with timeout
+        let result = timeout(Duration::from_secs(30), async {
+            let mut stream = pubsub.on_message();
+            stream.next().await
+        })
+        .await;
+
+        match result {
+            Ok(Some(_msg)) => {
+                // Completion event received, fetch the result
+                if let Some(state) = state_manager.get_request(request_id).await
+                    .map_err(|e| ApiError::InternalError(e.to_string()))? {
+                    match state.status {
+                        RequestStatus::Complete => {
+                            info!("Request completed: {}", request_id);
+                            return result_response(state_manager, state).await;
+                        }
+                        RequestStatus::Failed => {
+                            let error = request_failure(state);
+                            error!("Request failed: {}", error);
+                            return Err(ApiError::BatchFailed(error));
+                        }
+                        _ => {
+                            // Still processing, continue waiting
+                            continue;
+                        }
+                    }
+                }
+            }
+            Ok(None) => {
+                warn!("PubSub stream ended unexpectedly");
+                // Reconnect and continue
+                pubsub = state_manager
+                    .subscribe_to_completion(request_id)
+                    .await
+                    .map_err(|e| ApiError::InternalError(e.to_string()))?;
+            }
+            Err(_) => {
+                // Timeout - check status directly
+                if let Some(state) = state_manager.get_request(request_id).await
+                    .map_err(|e| ApiError::InternalError(e.to_string()))? {
+                    match state.status {
+                        RequestStatus::Complete => {
+                            info!("Request completed (via poll): {}", request_id);
+                            return result_response(state_manager, state).await;
+                        }
+                        RequestStatus::Failed => {
+                            let error = request_failure(state);
+                            error!("Request failed (via poll): {}", error);
+                            return Err(ApiError::BatchFailed(error));
+                        }
+                        _ => {
+                            // Still processing, continue waiting
+                            continue;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    MissingApiKey,
+    InvalidApiKey,
+    MissingProxyToken,
+    InvalidProxyToken,
+    Forbidden(String),
+    RateLimited(String),
+    InvalidRequest(String),
+    NotFound(String),
+    NotReady(String),
+    Conflict(String),
+    Gone(String),
+    Overloaded(String),
+    InternalError(String),
+    BatchFailed(RequestError),
+    Upstream(anyhow::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::Upstream(error) => return upstream_error_response(error),
+            ApiError::BatchFailed(error) => return failed_request_response(error),
+            ApiError::MissingApiKey => (
+                StatusCode::UNAUTHORIZED,
+                "Authorization header with Bearer token is required".to_string(),
+            ),
+            ApiError::InvalidApiKey => (StatusCode::UNAUTHORIZED, "Invalid API key".to_string()),
+            ApiError::MissingProxyToken => (
+                StatusCode::UNAUTHORIZED,
+                "x-silt-auth-token header is required".to_string(),
+            ),
+            ApiError::InvalidProxyToken => (StatusCode::UNAUTHORIZED, "Invalid x-silt-auth-token".to_string()),
+            ApiError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
+            ApiError::RateLimited(msg) => (StatusCode::TOO_MANY_REQUESTS, msg),
+            ApiError::InvalidRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            ApiError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            ApiError::NotReady(msg) => (StatusCode::CONFLICT, msg),
+            ApiError::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            ApiError::Gone(msg) => (StatusCode::GONE, msg),
+            ApiError::Overloaded(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg),
+            ApiError::InternalError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+        };
+
+        let body = serde_json::json!({
+            "error": {
+                "message": message,
+                "type": "api_error",
+            }
+        });
+
+        (status, Json(body)).into_response()
+    }
+}
+
+/// The error a failed request ended with, for its client.
+fn request_failure(state: RequestState) -> RequestError {
+    state
+        .error
+        .unwrap_or_else(|| RequestError::new(RequestErrorKind::Other, "Unknown error"))
+}
+
+/// Answers for a failed request with a status matching why it failed: the
+/// upstream's own status for requests it rejected, 502 for other upstream
+/// failures and 409 for requests cancelled before they ran.
+fn failed_request_response(error: RequestError) -> Response {
+    let status = match (error.kind, error.upstream_status) {
+        (_, Some(status)) if (400..500).contains(&status) => {
+            StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY)
+        }
+        (RequestErrorKind::Moderation, _) => StatusCode::BAD_REQUEST,
+        (RequestErrorKind::Cancelled | RequestErrorKind::Disconnected, _) => StatusCode::CONFLICT,
+        (RequestErrorKind::Purged, _) => StatusCode::GONE,
+        (RequestErrorKind::PostResultHook | RequestErrorKind::Other, _) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+
+    let body = serde_json::json!({
+        "error": {
+            "message": format!("Batch processing failed: {}", error.message),
+            "type": error.kind.as_str(),
+            "upstream_status": error.upstream_status,
+            "retryable": error.retryable,
+        }
+    });
+
+    (status, Json(body)).into_response()
+}
+
+/// Relays an upstream error to the client, preserving the upstream's status
+/// code and JSON error body where there is one.
+fn upstream_error_response(error: anyhow::Error) -> Response {
+    let (status, message) = match error.downcast_ref::<UpstreamError>() {
+        Some(UpstreamError::Status { status, body, .. }) => {
+            if let Ok(json) = serde_json::from_str::<serde_json::Value>(body) {
+                return (*status, Json(json)).into_response();
+            }
+            (*status, body.clone())
+        }
+        Some(UpstreamError::CircuitOpen) => (StatusCode::SERVICE_UNAVAILABLE, error.to_string()),
+        _ => (StatusCode::BAD_GATEWAY, error.to_string()),
+    };
+
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": "upstream_error",
+        }
+    });
+
+    (status, Json(body)).into_response()
+}
@@ -0,0 +1,367 @@
+use crate::config::Config;
+use crate::models::{CompletionRequest, CompletionResponse, ResponseFormat};
+use crate::wasm_plugin::WasmPlugin;
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use reqwest::Client;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What a hook is told about the request it is looking at.
+#[derive(Debug, Clone, Copy)]
+pub struct HookContext<'a> {
+    pub tenant: &'a str,
+    pub request_id: &'a str,
+}
+
+/// Runs on each new request before it is queued (or sent straight upstream
+/// when bypassed). Changes to the request are what gets stored and sent;
+/// returning an error rejects the request with 400 and the error as message.
+pub trait PreEnqueueHook: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn before_enqueue<'a>(
+        &'a self,
+        context: HookContext<'a>,
+        request: &'a mut CompletionRequest,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Runs on each result before it is stored. Changes to the response are what
+/// the client receives; returning an error fails the request instead.
+pub trait PostResultHook: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn after_result<'a>(
+        &'a self,
+        context: HookContext<'a>,
+        request: &'a CompletionRequest,
+        response: &'a mut CompletionResponse,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+/// The hooks named in `SILT_PRE_ENQUEUE_HOOKS` and `SILT_POST_RESULT_HOOKS`, run in the
+/// order they are listed.
+///
+/// Built in hooks are `redact_emails` (pre-enqueue) and `require_json_output`
+/// (post-result). In either list, `webhook:<url>` delegates to an external
+/// service and `wasm:<path>` to a WASM plugin, for policies that don't belong
+/// in silt itself.
+#[derive(Default)]
+pub struct Hooks {
+    pre_enqueue: Vec<Arc<dyn PreEnqueueHook>>,
+    post_result: Vec<Arc<dyn PostResultHook>>,
+}
+
+impl Hooks {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let client = Client::builder().timeout(Duration::from_secs(30)).build()?;
+        let mut hooks = Self::default();
+
+        for name in &config.pre_enqueue_hooks {
+            let hook: Arc<dyn PreEnqueueHook> = match name.as_str() {
+                "redact_emails" => Arc::new(RedactEmails),
+                _ => match external_hook(&client, name, "pre_enqueue")? {
+                    Some(hook) => hook.pre_enqueue(),
+                    None => anyhow::bail!(
+                        "Unknown SILT_PRE_ENQUEUE_HOOKS entry '{}': expected redact_emails, webhook:<url> or wasm:<path>",
+                        name
+                    ),
+                },
+            };
+            hooks.pre_enqueue.push(hook);
+        }
+        for name in &config.post_result_hooks {
+            let hook: Arc<dyn PostResultHook> = match name.as_str() {
+                "require_json_output" => Arc::new(RequireJsonOutput),
+                _ => match external_hook(&client, name, "post_result")? {
+                    Some(hook) => hook.post_result(),
+                    None => anyhow::bail!(
+                        "Unknown SILT_POST_RESULT_HOOKS entry '{}': expected require_json_output, webhook:<url> or wasm:<path>",
+                        name
+                    ),
+                },
+            };
+            hooks.post_result.push(hook);
+        }
+        Ok(hooks)
+    }
+
+    pub fn names(&self) -> (Vec<&str>, Vec<&str>) {
+        (
+            self.pre_enqueue.iter().map(|hook| hook.name()).collect(),
+            self.post_result.iter().map(|hook| hook.name()).collect(),
+        )
+    }
+
+    pub fn has_post_result(&self) -> bool {
+        !self.post_result.is_empty()
+    }
+
+    pub async fn before_enqueue(&self, context: HookContext<'_>, request: &mut CompletionRequest) -> Result<()> {
+        for hook in &self.pre_enqueue {
+            hook.before_enqueue(context, request)
+                .await
+                .with_context(|| format!("Rejected by {}", hook.name()))?;
+        }
+        Ok(())
+    }
+
+    pub async fn after_result(
+        &self,
+        context: HookContext<'_>,
+        request: &CompletionRequest,
+        response: &mut CompletionResponse,
+    ) -> Result<()> {
+        for hook in &self.post_result {
+            hook.after_result(context, request, response)
+                .await
+                .with_context(|| format!("Result rejected by {}", hook.name()))?;
+        }
+        Ok(())
+    }
+}
+
+/// A hook defined outside silt, which can run at either stage.
+enum ExternalHook {
+    Webhook(Arc<WebhookHook>),
+    Wasm(Arc<WasmPlugin>),
+}
+
+impl ExternalHook {
+    fn pre_enqueue(self) -> Arc<dyn PreEnqueueHook> {
+        match self {
+            ExternalHook::Webhook(hook) => hook,
+            ExternalHook::Wasm(plugin) => plugin,
+        }
+    }
+
+    fn post_result(self) -> Arc<dyn PostResultHook> {
+        match self {
+            ExternalHook::Webhook(hook) => hook,
+            ExternalHook::Wasm(plugin) => plugin,
+        }
+    }
+}
+
+/// Builds a `webhook:` or `wasm:` hook to run at `stage`, checking that a
+/// plugin exports it.
+fn external_hook(client: &Client, name: &str, stage: &str) -> Result<Option<ExternalHook>> {
+    if let Some(url) = name.strip_prefix("webhook:") {
+        return Ok(Some(ExternalHook::Webhook(Arc::new(WebhookHook::new(client.clone(), url)))));
+    }
+    if let Some(path) = name.strip_prefix("wasm:") {
+        return Ok(Some(ExternalHook::Wasm(Arc::new(WasmPlugin::load(path, stage)?))));
+    }
+    Ok(None)
+}
+
+/// What a webhook or plugin answers: nothing to accept as-is, a replacement
+/// request or response, or a reason to reject.
+#[derive(Default, Deserialize)]
+pub struct HookReply {
+    #[serde(default)]
+    pub reject: Option<String>,
+    #[serde(default)]
+    pub request: Option<CompletionRequest>,
+    #[serde(default)]
+    pub response: Option<CompletionResponse>,
+}
+
+impl HookReply {
+    /// The reply, or the rejection as an error.
+    pub fn accepted(self) -> Result<Self> {
+        match self.reject {
+            Some(reason) => Err(anyhow::anyhow!(reason)),
+            None => Ok(self),
+        }
+    }
+}
+
+/// The JSON a webhook or plugin receives for a request.
+pub fn hook_input(
+    hook: &str,
+    context: HookContext<'_>,
+    request: &CompletionRequest,
+    response: Option<&CompletionResponse>,
+) -> serde_json::Value {
+    let mut input = serde_json::json!({
+        "hook": hook,
+        "tenant": context.tenant,
+        "request_id": context.request_id,
+        "request": request,
+    });
+    if let Some(response) = response {
+        input["response"] = serde_json::json!(response);
+    }
+    input
+}
+
+/// Replaces email addresses in message content with `[email]`.
+struct RedactEmails;
+
+impl PreEnqueueHook for RedactEmails {
+    fn name(&self) -> &str {
+        "redact_emails"
+    }
+
+    fn before_enqueue<'a>(
+        &'a self,
+        _context: HookContext<'a>,
+        request: &'a mut CompletionRequest,
+    ) -> BoxFuture<'a, Result<()>> {
+        for content in request
+            .messages
+            .iter_mut()
+            .filter_map(|message| message.content.as_mut())
+        {
+            content.map_text(|text| text.contains('@').then(|| redact_emails(text)));
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
+
+fn redact_emails(text: &str) -> String {
+    let is_local = |c: char| c.is_ascii_alphanumeric() || "._%+-".contains(c);
+    let is_domain = |c: char| c.is_ascii_alphanumeric() || ".-".contains(c);
+
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('@') {
+        let start = rest[..at]
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| is_local(*c))
+            .last()
+            .map_or(at, |(i, _)| i);
+        let domain = &rest[at + 1..];
+        let domain_len = domain.find(|c: char| !is_domain(c)).unwrap_or(domain.len());
+        let domain = domain[..domain_len].trim_end_matches(['.', '-']);
+
+        if start < at && domain.contains('.') && !domain.starts_with(['.', '-']) {
+            redacted.push_str(&rest[..start]);
+            redacted.push_str("[email]");
+            rest = &rest[at + 1 + domain.len()..];
+        } else {
+            redacted.push_str(&rest[..=at]);
+            rest = &rest[at + 1..];
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// Fails results whose content isn't valid JSON when the request asked for
+/// JSON output through `response_format`.
+struct RequireJsonOutput;
+
+impl PostResultHook for RequireJsonOutput {
+    fn name(&self) -> &str {
+        "require_json_output"
+    }
+
+    fn after_result<'a>(
+        &'a self,
+        _context: HookContext<'a>,
+        request: &'a CompletionRequest,
+        response: &'a mut CompletionResponse,
+    ) -> BoxFuture<'a, Result<()>> {
+        let wants_json = request
+            .response_format
+            .as_ref()
+            .is_some_and(ResponseFormat::wants_json);
+
+        let result = match response.choices.iter().find(|choice| {
+            wants_json
+                && choice
+                    .message
+                    .content
+                    .as_ref()
+                    .is_some_and(|content| serde_json::from_str::<serde_json::Value>(&content.text()).is_err())
+        }) {
+            Some(choice) => Err(anyhow::anyhow!("choice {} is not valid JSON", choice.index)),
+            None => Ok(()),
+        };
+        Box::pin(async move { result })
+    }
+}
+
+/// Delegates to an external service. The hook receives a JSON POST with the
+/// `hook` kind, `tenant`, `request_id`, `request` and (for results)
+/// `response`, and answers 204 to accept as-is, 200 with a replacement
+/// `request` or `response`, or 200 with `{"reject": "reason"}`.
+struct WebhookHook {
+    client: Client,
+    name: String,
+    url: String,
+}
+
+impl WebhookHook {
+    fn new(client: Client, url: &str) -> Self {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        Self {
+            client,
+            name: format!("webhook hook {}", host),
+            url: url.to_string(),
+        }
+    }
+
+    async fn call(&self, body: serde_json::Value) -> Result<HookReply> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        if response.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(HookReply::default());
+        }
+        response.json::<HookReply>().await?.accepted()
+    }
+}
+
+impl PreEnqueueHook for WebhookHook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn before_enqueue<'a>(
+        &'a self,
+        context: HookContext<'a>,
+        request: &'a mut CompletionRequest,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let body = hook_input("pre_enqueue", context, request, None);
+            if let Some(replacement) = self.call(body).await?.request {
+                *request = replacement;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl PostResultHook for WebhookHook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn after_result<'a>(
+        &'a self,
+        context: HookContext<'a>,
+        request: &'a CompletionRequest,
+        response: &'a mut CompletionResponse,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let body = hook_input("post_result", context, request, Some(response));
+            if let Some(replacement) = self.call(body).await?.response {
+                *response = replacement;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,216 @@
+use crate::config::Config;
+use crate::handlers::{ApiError, AppState};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Middleware for every route but the health checks: refuses addresses in
+/// `SILT_DENIED_IPS` and, when `SILT_ALLOWED_IPS` is set, any not in it.
+pub async fn require_allowed_ip(
+    State(app_state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = &app_state.config;
+    if config.allowed_ips.is_empty() && config.denied_ips.is_empty() {
+        return next.run(request).await;
+    }
+
+    match client_ip(config, &request) {
+        Some(ip) if contains(&config.denied_ips, ip) => refuse(ip),
+        Some(ip) if !config.allowed_ips.is_empty() && !contains(&config.allowed_ips, ip) => refuse(ip),
+        Some(_) => next.run(request).await,
+        None => unknown_address(),
+    }
+}
+
+/// Middleware for the `/admin` routes: with `SILT_ADMIN_ALLOWED_IPS` set, only
+/// those addresses reach the admin API, whatever token they present.
+pub async fn require_admin_ip(
+    State(app_state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let allowed = &app_state.config.admin_allowed_ips;
+    if allowed.is_empty() {
+        return next.run(request).await;
+    }
+
+    match client_ip(&app_state.config, &request) {
+        Some(ip) if contains(allowed, ip) => next.run(request).await,
+        Some(ip) => refuse(ip),
+        None => unknown_address(),
+    }
+}
+
+/// Whether a request may use the admin token outside `/admin`, checked as
+/// `require_admin_ip` checks the admin routes.
+pub fn is_admin_ip(config: &Config, request: &Request) -> bool {
+    if config.admin_allowed_ips.is_empty() {
+        return true;
+    }
+    match client_ip(config, request) {
+        Some(ip) if contains(&config.admin_allowed_ips, ip) => true,
+        Some(ip) => {
+            warn!("Refused the admin token from {}", ip);
+            false
+        }
+        None => false,
+    }
+}
+
+/// The address a request came from. Behind a proxy in
+/// `SILT_TRUSTED_PROXIES`, that is the nearest `X-Forwarded-For` entry not
+/// added by a trusted proxy; otherwise the peer itself, as anyone could
+/// have written the header.
+pub fn client_ip(config: &Config, request: &Request) -> Option<IpAddr> {
+    let ConnectInfo(peer) = request.extensions().get::<ConnectInfo<SocketAddr>>()?;
+    Some(forwarded_client(&config.trusted_proxies, peer.ip(), request.headers()))
+}
+
+/// Walks `X-Forwarded-For` back from `peer` for as long as the hop that
+/// added each entry is a trusted proxy.
+fn forwarded_client(trusted_proxies: &[IpNet], peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let mut ip = peer.to_canonical();
+
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for hop in forwarded.into_iter().rev() {
+        if !contains(trusted_proxies, ip) {
+            break;
+        }
+        match hop.trim().parse::<IpAddr>() {
+            Ok(hop) => ip = hop.to_canonical(),
+            // A garbled entry leaves the last proxy as the client
+            Err(_) => break,
+        }
+    }
+    ip
+}
+
+fn contains(networks: &[IpNet], ip: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(&ip))
+}
+
+fn refuse(ip: IpAddr) -> Response {
+    warn!("Refused a request from {}", ip);
+    ApiError::Forbidden("Requests from this address are not allowed".to_string()).into_response()
+}
+
+/// Without the peer address there is nothing to check against, which only
+/// happens when an embedding application serves the router without
+/// connection info.
+fn unknown_address() -> Response {
+    warn!("Refused a request with no peer address; serve the router with `into_make_service_with_connect_info`");
+    ApiError::Forbidden("Requests from this address are not allowed".to_string()).into_response()
+}
+
+/// Parses a CIDR network, or a single address as a network of one.
+pub fn parse_network(value: &str) -> anyhow::Result<IpNet> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| anyhow::anyhow!("'{}' is not an IP address or CIDR network", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn networks(values: &[&str]) -> Vec<IpNet> {
+        values.iter().map(|value| parse_network(value).unwrap()).collect()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let trusted = networks(&["10.0.0.0/24"]);
+        let headers = forwarded_for(&["10.8.0.5"]);
+        assert_eq!(
+            forwarded_client(&trusted, ip("203.0.113.7"), &headers),
+            ip("203.0.113.7")
+        );
+        assert_eq!(forwarded_client(&[], ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn walks_trusted_proxies_to_the_rightmost_untrusted_address() {
+        let trusted = networks(&["10.0.0.0/24", "192.168.1.20"]);
+        // The client spoofed the first entry; the proxies appended the rest
+        let headers = forwarded_for(&["10.8.0.5, 198.51.100.9", "192.168.1.20"]);
+        assert_eq!(forwarded_client(&trusted, ip("10.0.0.1"), &headers), ip("198.51.100.9"));
+
+        let single = forwarded_for(&["198.51.100.9"]);
+        assert_eq!(forwarded_client(&trusted, ip("10.0.0.1"), &single), ip("198.51.100.9"));
+    }
+
+    #[test]
+    fn stops_at_the_last_proxy_on_garbled_or_empty_entries() {
+        let trusted = networks(&["10.0.0.0/24"]);
+        for value in [
+            "198.51.100.9, not-an-ip",
+            "198.51.100.9,",
+            "198.51.100.9, 10.0.0.2:8080",
+            "",
+        ] {
+            let headers = forwarded_for(&[value]);
+            assert_eq!(
+                forwarded_client(&trusted, ip("10.0.0.1"), &headers),
+                ip("10.0.0.1"),
+                "{}",
+                value
+            );
+        }
+        assert_eq!(
+            forwarded_client(&trusted, ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn matches_mapped_ipv4_peers_against_ipv4_networks() {
+        let trusted = networks(&["10.0.0.0/24"]);
+        let headers = forwarded_for(&["::ffff:198.51.100.9"]);
+        assert_eq!(
+            forwarded_client(&trusted, ip("::ffff:10.0.0.1"), &headers),
+            ip("198.51.100.9")
+        );
+    }
+
+    #[test]
+    fn parses_networks_and_single_addresses() {
+        let networks = networks(&["10.0.0.0/8", "192.168.1.20", "2001:db8::/32"]);
+        assert!(contains(&networks, ip("10.255.0.1")));
+        assert!(!contains(&networks, ip("11.0.0.1")));
+        assert!(contains(&networks, ip("192.168.1.20")));
+        assert!(!contains(&networks, ip("192.168.1.21")));
+        assert!(contains(&networks, ip("2001:db8::1")));
+        assert!(!contains(&networks, ip("2001:db9::1")));
+
+        for value in ["", "10.0.0.0/33", "10.0.0", "example.com", "10.0.0.0/8/8"] {
+            assert!(parse_network(value).is_err(), "'{}' should be rejected", value);
+        }
+    }
+}
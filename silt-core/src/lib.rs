@@ -0,0 +1,45 @@
+//! The silt batching proxy as a library. `build_router` gives the API as an
+//! axum `Router` to serve or mount inside another application, and
+//! `BatchWorker::spawn` runs the dispatcher and poller that turn queued
+//! requests into upstream batches; the two only share Redis, so they can
+//! run in the same process or apart.
+
+pub mod admin;
+pub mod alerts;
+pub mod archive;
+pub mod audit;
+pub mod auth;
+pub mod batch_worker;
+pub mod bench;
+pub mod chaos;
+pub mod circuit_breaker;
+pub mod codec;
+pub mod commands;
+pub mod config;
+pub mod crypto;
+pub mod eta;
+pub mod events;
+pub mod handlers;
+pub mod hooks;
+pub mod ip_filter;
+pub mod maintenance;
+pub mod metrics;
+pub mod mock_upstream;
+pub mod models;
+pub mod moderation;
+pub mod openai_client;
+pub mod retry;
+pub mod rewrite;
+pub mod runtime;
+pub mod schedule;
+mod server;
+pub mod state;
+pub mod supervisor;
+pub mod tls;
+pub mod tokens;
+pub mod wasm_plugin;
+
+pub use batch_worker::BatchWorker;
+pub use config::{BenchArgs, Cli, Command, Config, Role};
+pub use handlers::AppState;
+pub use server::{build_router, build_state, connect_state, listen, run_worker, serve, start_services, Services};
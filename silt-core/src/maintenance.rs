@@ -0,0 +1,81 @@
+use crate::schedule::DispatchSchedule;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+
+/// Longest a run of pause windows is followed to find where it ends.
+const MAX_PAUSE_DAYS: i64 = 366;
+
+/// When dispatch resumes, if `at` falls in one of the windows
+/// (`SILT_PAUSE_WINDOWS`): the first minute after it that no window covers,
+/// capped at a year of pause. Whole days and hours a window covers are
+/// skipped at once, as `DispatchSchedule::next_after` does.
+pub fn pause_end(windows: &[DispatchSchedule], at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let paused = |at: DateTime<Utc>| windows.iter().any(|window| window.matches(at));
+    if !paused(at) {
+        return None;
+    }
+
+    let limit = at + Duration::days(MAX_PAUSE_DAYS);
+    let mut end = at.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+    while end < limit {
+        if windows.iter().any(|window| window.matches_whole_day(end)) {
+            end = end.duration_trunc(Duration::days(1)).ok()? + Duration::days(1);
+        } else if windows.iter().any(|window| window.matches_whole_hour(end)) {
+            end = end.duration_trunc(Duration::hours(1)).ok()? + Duration::hours(1);
+        } else if paused(end) {
+            end += Duration::minutes(1);
+        } else {
+            break;
+        }
+    }
+    Some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        // 2026-03-07 is a Saturday
+        Utc.with_ymd_and_hms(2026, 3, day, hour, minute, 0).unwrap()
+    }
+
+    fn windows(expressions: &[&str]) -> Vec<DispatchSchedule> {
+        expressions
+            .iter()
+            .map(|expression| expression.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn not_paused_outside_the_windows() {
+        assert_eq!(pause_end(&windows(&["* 2-3 * * *"]), at(4, 4, 0)), None);
+        assert_eq!(pause_end(&[], at(4, 4, 0)), None);
+    }
+
+    #[test]
+    fn ends_after_the_last_covered_minute() {
+        let windows = windows(&["0-14 9 * * *"]);
+        assert_eq!(pause_end(&windows, at(4, 9, 5)), Some(at(4, 9, 15)));
+    }
+
+    #[test]
+    fn follows_adjacent_windows() {
+        let windows = windows(&["* 22-23 * * *", "* 0-5 * * *"]);
+        assert_eq!(pause_end(&windows, at(4, 22, 30)), Some(at(5, 6, 0)));
+    }
+
+    #[test]
+    fn skips_whole_days() {
+        let windows = windows(&["* * * * 6,0", "* 0-7 * * 1", "0-29 8 * * 1"]);
+        assert_eq!(pause_end(&windows, at(7, 10, 0)), Some(at(9, 8, 30)));
+    }
+
+    #[test]
+    fn caps_an_endless_pause() {
+        let start = at(4, 0, 0);
+        let end = pause_end(&windows(&["* * * * *"]), start).unwrap();
+        assert!(end >= start + Duration::days(MAX_PAUSE_DAYS));
+        assert!(end <= start + Duration::days(MAX_PAUSE_DAYS + 1));
+    }
+}
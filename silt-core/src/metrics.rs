@@ -0,0 +1,359 @@
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use crate::config::Config;
+use std::collections::HashSet;
+use std::sync::{LazyLock, Mutex, OnceLock};
+
+/// Process-wide Prometheus metrics, exposed at `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub requests_submitted_total: IntCounterVec,
+    pub requests_failed_total: IntCounterVec,
+    pub requests_flagged_total: IntCounterVec,
+    pub queued_requests: IntGaugeVec,
+    pub upstream_rate_limited_total: IntCounterVec,
+    pub dispatch_backoff_keys: IntGauge,
+    pub dispatch_backoff_seconds: Gauge,
+    pub upstream_circuit_state: IntGauge,
+    pub upstream_circuit_opened_total: IntCounter,
+    pub chaos_faults_total: IntCounterVec,
+    pub upstream_files_swept_total: IntCounter,
+    pub duplicate_results_total: IntCounterVec,
+    pub waiting_requests: IntGauge,
+    pub oldest_request_age_seconds: IntGaugeVec,
+    pub sla_breached_requests: IntGaugeVec,
+    pub requests_cancelled_on_disconnect_total: IntCounterVec,
+    pub requests_requeued_total: IntCounterVec,
+    pub request_phase_seconds: HistogramVec,
+    pub handler_panics_total: IntCounter,
+    pub requests_shed_total: IntCounterVec,
+    pub task_restarts_total: IntCounterVec,
+    pub key_requests_submitted_total: IntCounterVec,
+    pub key_requests_finished_total: IntCounterVec,
+    pub key_tokens_total: IntCounterVec,
+    pub model_tokens_total: IntCounterVec,
+    pub process_resident_memory_bytes: IntGauge,
+    /// Fingerprints given their own `key` label, the busiest by volume
+    labelled_keys: Mutex<HashSet<String>>,
+}
+
+/// From sub-second uploads to batches taking their full 24 hour window.
+const PHASE_BUCKETS: &[f64] = &[
+    0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 43200.0, 86400.0,
+];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// `SILT_METRICS_MAX_KEYS`; per-key metrics are off until it is set.
+static MAX_KEY_LABELS: OnceLock<usize> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Sets how many API keys get their own label in the per-key metrics. Called
+/// once at startup.
+pub fn init_key_labels(config: &Config) {
+    let _ = MAX_KEY_LABELS.set(config.metrics_max_keys);
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("silt".to_string()), None)
+            .expect("valid metrics registry");
+
+        Self {
+            requests_submitted_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("requests_submitted_total", "Requests accepted into the queue"),
+                    &["tenant"],
+                ),
+            ),
+            requests_failed_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("requests_failed_total", "Requests that ended failed, by the kind of error"),
+                    &["tenant", "kind"],
+                ),
+            ),
+            requests_flagged_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("requests_flagged_total", "Requests flagged by moderation, by the action taken"),
+                    &["tenant", "action"],
+                ),
+            ),
+            queued_requests: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("queued_requests", "Requests waiting for dispatch at the last window tick"),
+                    &["tenant", "priority"],
+                ),
+            ),
+            upstream_rate_limited_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "upstream_rate_limited_total",
+                        "Upstream calls rejected with 429 Too Many Requests",
+                    ),
+                    &["endpoint"],
+                ),
+            ),
+            dispatch_backoff_keys: register(
+                &registry,
+                IntGauge::new(
+                    "dispatch_backoff_keys",
+                    "API keys whose dispatch is currently deferred by an upstream Retry-After",
+                ),
+            ),
+            dispatch_backoff_seconds: register(
+                &registry,
+                Gauge::new(
+                    "dispatch_backoff_seconds",
+                    "Longest remaining Retry-After backoff across API keys",
+                ),
+            ),
+            upstream_circuit_state: register(
+                &registry,
+                IntGauge::new(
+                    "upstream_circuit_state",
+                    "Upstream circuit breaker state (0 = closed, 1 = half-open, 2 = open)",
+                ),
+            ),
+            upstream_circuit_opened_total: register(
+                &registry,
+                IntCounter::new(
+                    "upstream_circuit_opened_total",
+                    "Times the upstream circuit breaker has opened",
+                ),
+            ),
+            chaos_faults_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("chaos_faults_total", "Faults injected by chaos mode, by kind"),
+                    &["kind"],
+                ),
+            ),
+            upstream_files_swept_total: register(
+                &registry,
+                IntCounter::new(
+                    "upstream_files_swept_total",
+                    "Old batch input files deleted from the upstream by the file sweeper",
+                ),
+            ),
+            duplicate_results_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "duplicate_results_total",
+                        "Extra result lines the upstream returned for a custom_id already in the output file",
+                    ),
+                    &["tenant"],
+                ),
+            ),
+            waiting_requests: register(
+                &registry,
+                IntGauge::new(
+                    "waiting_requests",
+                    "Client connections held open waiting for their request's result",
+                ),
+            ),
+            oldest_request_age_seconds: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new(
+                        "oldest_request_age_seconds",
+                        "Time the longest waiting request has spent queued or in flight, at the last SLA check",
+                    ),
+                    &["tenant", "status"],
+                ),
+            ),
+            sla_breached_requests: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new(
+                        "sla_breached_requests",
+                        "Requests queued or in flight for longer than their SLA, at the last SLA check",
+                    ),
+                    &["tenant", "status"],
+                ),
+            ),
+            requests_cancelled_on_disconnect_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "requests_cancelled_on_disconnect_total",
+                        "Queued requests cancelled because their only waiter disconnected",
+                    ),
+                    &["tenant"],
+                ),
+            ),
+            requests_requeued_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "requests_requeued_total",
+                        "Requests put back on the queue after a transient upstream failure",
+                    ),
+                    &["tenant"],
+                ),
+            ),
+            request_phase_seconds: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "request_phase_seconds",
+                        "Time completed requests spent queued, uploading, at the upstream and in result delivery",
+                    )
+                    .buckets(PHASE_BUCKETS.to_vec()),
+                    &["tenant", "phase"],
+                ),
+            ),
+            handler_panics_total: register(
+                &registry,
+                IntCounter::new(
+                    "handler_panics_total",
+                    "HTTP handlers that panicked, answered with a 500",
+                ),
+            ),
+            requests_shed_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "requests_shed_total",
+                        "HTTP requests refused with a 503 because their route group was at its concurrency limit",
+                    ),
+                    &["routes"],
+                ),
+            ),
+            task_restarts_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("task_restarts_total", "Background tasks restarted after a panic"),
+                    &["task"],
+                ),
+            ),
+            key_requests_submitted_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("key_requests_submitted_total", "Requests accepted into the queue, by API key fingerprint"),
+                    &["tenant", "key"],
+                ),
+            ),
+            key_requests_finished_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "key_requests_finished_total",
+                        "Requests that ended complete or failed, by API key fingerprint",
+                    ),
+                    &["tenant", "key", "status"],
+                ),
+            ),
+            key_tokens_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("key_tokens_total", "Tokens used by completed requests, by API key fingerprint"),
+                    &["tenant", "key"],
+                ),
+            ),
+            model_tokens_total: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "model_tokens_total",
+                        "Prompt and completion tokens used by completed requests, by model",
+                    ),
+                    &["tenant", "model", "kind"],
+                ),
+            ),
+            process_resident_memory_bytes: register(
+                &registry,
+                IntGauge::new(
+                    "process_resident_memory_bytes",
+                    "Resident memory of the silt process (Linux only)",
+                ),
+            ),
+            labelled_keys: Mutex::new(HashSet::new()),
+            registry,
+        }
+    }
+
+    /// The `key` label for an API key fingerprint in the per-key metrics, or
+    /// `None` when they are off. The `SILT_METRICS_MAX_KEYS` busiest keys keep
+    /// their fingerprint and the rest share `other`, so a tenant with
+    /// thousands of keys can't flood Prometheus with series.
+    pub fn key_label(&self, fingerprint: &str) -> Option<String> {
+        if MAX_KEY_LABELS.get().copied().unwrap_or(0) == 0 {
+            return None;
+        }
+
+        if self.labelled_keys.lock().unwrap().contains(fingerprint) {
+            Some(fingerprint.to_string())
+        } else {
+            Some("other".to_string())
+        }
+    }
+
+    /// Replaces the keys labelled with their fingerprint, as ranked by the
+    /// batch worker from the day's usage.
+    pub fn set_labelled_keys(&self, fingerprints: HashSet<String>) {
+        *self.labelled_keys.lock().unwrap() = fingerprints;
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        if let Some(bytes) = resident_memory_bytes() {
+            self.process_resident_memory_bytes.set(bytes);
+        }
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Holds a gauge up by one for as long as it lives, so futures dropped
+/// partway (a client disconnecting) still bring it back down.
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// `VmRSS` from `/proc/self/status`, where there is one.
+fn resident_memory_bytes() -> Option<i64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<i64>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+fn register<M: Collector + Clone + 'static>(registry: &Registry, metric: prometheus::Result<M>) -> M {
+    let metric = metric.expect("valid metric definition");
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric registered once");
+    metric
+}
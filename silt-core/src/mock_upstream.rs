@@ -0,0 +1,354 @@
+use crate::config::Config;
+use crate::models::{
+    BatchLine, BatchRequest, BatchRequestCounts, BatchResponse, BatchResultLine, BatchResultResponse, Choice,
+    CompletionRequest, CompletionResponse, FileUploadResponse, Message, ResponseFormat, Usage,
+};
+use crate::tokens;
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tracing::error;
+
+/// Models the mock lists from `GET /models`. Any model is accepted.
+const MOCK_MODELS: [&str; 3] = ["gpt-4o", "gpt-4o-mini", "gpt-4.1-mini"];
+
+/// A batch as the mock tracks it: it completes (or fails) once `ready_at`
+/// passes, when it is next looked at.
+struct MockBatch {
+    batch: BatchResponse,
+    ready_at: Instant,
+    fails: bool,
+}
+
+#[derive(Default)]
+struct MockStore {
+    files: HashMap<String, String>,
+    /// Listing details of uploaded (not output) files
+    uploads: HashMap<String, FileUploadResponse>,
+    batches: HashMap<String, MockBatch>,
+}
+
+struct MockUpstream {
+    latency: Duration,
+    batch_latency: Duration,
+    error_rate: f64,
+    batch_failure_rate: f64,
+    store: Mutex<MockStore>,
+}
+
+/// Starts an in-process stand-in for the OpenAI files, batches and chat
+/// completions endpoints (`SILT_UPSTREAM_MODE=mock`) and returns its base URL.
+/// Batches complete after `SILT_MOCK_BATCH_LATENCY_SECS` with canned answers,
+/// so the whole queue, dispatch, poll and complete flow runs without an
+/// OpenAI key. State is kept in memory, per process.
+pub async fn start(config: &Config) -> Result<String> {
+    let mock = Arc::new(MockUpstream {
+        latency: Duration::from_millis(config.mock_latency_ms),
+        batch_latency: Duration::from_secs(config.mock_batch_latency_secs),
+        error_rate: config.mock_error_rate,
+        batch_failure_rate: config.mock_batch_failure_rate,
+        store: Mutex::new(MockStore::default()),
+    });
+
+    let app = Router::new()
+        .route("/files", post(upload_file).get(list_files))
+        .route("/files/:id", delete(delete_file))
+        .route("/files/:id/content", get(file_content))
+        .route("/batches", post(create_batch))
+        .route("/batches/:id", get(get_batch))
+        .route("/chat/completions", post(chat_completion))
+        .route("/models", get(list_models))
+        .with_state(mock);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Mock upstream stopped: {}", e);
+        }
+    });
+    Ok(format!("http://{}", addr))
+}
+
+impl MockUpstream {
+    /// Waits out `SILT_MOCK_LATENCY_MS`, then fails the call at
+    /// `SILT_MOCK_ERROR_RATE`.
+    async fn respond(&self) -> Result<(), Response> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        if rand::random::<f64>() < self.error_rate {
+            return Err(mock_error(StatusCode::INTERNAL_SERVER_ERROR, "Simulated upstream failure"));
+        }
+        Ok(())
+    }
+}
+
+fn mock_error(status: StatusCode, message: &str) -> Response {
+    let body = json!({ "error": { "message": message, "type": "mock_error" } });
+    (status, Json(body)).into_response()
+}
+
+fn mock_id(prefix: &str) -> String {
+    format!("{}-mock-{}", prefix, uuid::Uuid::new_v4().simple())
+}
+
+async fn upload_file(State(mock): State<Arc<MockUpstream>>, headers: HeaderMap, body: Bytes) -> Response {
+    if let Err(response) = mock.respond().await {
+        return response;
+    }
+
+    let boundary = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split("boundary=").nth(1))
+        .map(|boundary| boundary.trim_matches('"').to_string());
+    let Some((filename, content)) = boundary.and_then(|boundary| multipart_file(&body, &boundary)) else {
+        return mock_error(StatusCode::BAD_REQUEST, "Expected a multipart upload with a file part");
+    };
+
+    let file = FileUploadResponse {
+        id: mock_id("file"),
+        object: "file".to_string(),
+        bytes: content.len() as u64,
+        created_at: Utc::now().timestamp(),
+        filename,
+        purpose: "batch".to_string(),
+    };
+    let mut store = mock.store.lock().unwrap();
+    store.files.insert(file.id.clone(), content);
+    store.uploads.insert(file.id.clone(), file.clone());
+    Json(file).into_response()
+}
+
+/// Lists every upload in one page, oldest first; `purpose` and `limit` are
+/// ignored.
+async fn list_files(State(mock): State<Arc<MockUpstream>>) -> Response {
+    if let Err(response) = mock.respond().await {
+        return response;
+    }
+    let mut files: Vec<_> = mock.store.lock().unwrap().uploads.values().cloned().collect();
+    files.sort_by_key(|file| file.created_at);
+    Json(json!({ "object": "list", "data": files, "has_more": false })).into_response()
+}
+
+async fn delete_file(State(mock): State<Arc<MockUpstream>>, Path(file_id): Path<String>) -> Response {
+    if let Err(response) = mock.respond().await {
+        return response;
+    }
+    let mut store = mock.store.lock().unwrap();
+    store.uploads.remove(&file_id);
+    if store.files.remove(&file_id).is_none() {
+        return mock_error(StatusCode::NOT_FOUND, "No such file");
+    }
+    Json(json!({ "id": file_id, "object": "file", "deleted": true })).into_response()
+}
+
+/// The filename and contents of the `file` part of a multipart body.
+fn multipart_file(body: &[u8], boundary: &str) -> Option<(String, String)> {
+    let body = String::from_utf8_lossy(body);
+    body.split(&format!("--{}", boundary)).find_map(|part| {
+        let (headers, content) = part.split_once("\r\n\r\n")?;
+        if !headers.contains("name=\"file\"") {
+            return None;
+        }
+        let filename = headers
+            .split("filename=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap_or("batch.jsonl");
+        Some((filename.to_string(), content.trim_end_matches("\r\n").to_string()))
+    })
+}
+
+async fn file_content(State(mock): State<Arc<MockUpstream>>, Path(file_id): Path<String>) -> Response {
+    if let Err(response) = mock.respond().await {
+        return response;
+    }
+    match mock.store.lock().unwrap().files.get(&file_id) {
+        Some(content) => content.clone().into_response(),
+        None => mock_error(StatusCode::NOT_FOUND, "No such file"),
+    }
+}
+
+async fn create_batch(State(mock): State<Arc<MockUpstream>>, Json(request): Json<BatchRequest>) -> Response {
+    if let Err(response) = mock.respond().await {
+        return response;
+    }
+
+    let mut store = mock.store.lock().unwrap();
+    if !store.files.contains_key(&request.input_file_id) {
+        return mock_error(StatusCode::BAD_REQUEST, "Unknown input_file_id");
+    }
+    let batch = BatchResponse {
+        id: mock_id("batch"),
+        object: "batch".to_string(),
+        endpoint: request.endpoint,
+        input_file_id: request.input_file_id,
+        output_file_id: None,
+        error_file_id: None,
+        status: "validating".to_string(),
+        created_at: Utc::now().timestamp(),
+        completed_at: None,
+        metadata: request.metadata,
+        request_counts: None,
+        errors: None,
+    };
+    store.batches.insert(
+        batch.id.clone(),
+        MockBatch {
+            batch: batch.clone(),
+            ready_at: Instant::now() + mock.batch_latency,
+            fails: rand::random::<f64>() < mock.batch_failure_rate,
+        },
+    );
+    Json(batch).into_response()
+}
+
+async fn get_batch(State(mock): State<Arc<MockUpstream>>, Path(batch_id): Path<String>) -> Response {
+    if let Err(response) = mock.respond().await {
+        return response;
+    }
+
+    let mut store = mock.store.lock().unwrap();
+    let MockStore { files, batches, .. } = &mut *store;
+    let Some(mock_batch) = batches.get_mut(&batch_id) else {
+        return mock_error(StatusCode::NOT_FOUND, "No such batch");
+    };
+
+    let batch = &mut mock_batch.batch;
+    if batch.completed_at.is_none() {
+        let total = files
+            .get(&batch.input_file_id)
+            .map_or(0, |input| input.lines().count() as u64);
+        if Instant::now() < mock_batch.ready_at {
+            // Lines complete evenly over the batch latency
+            let remaining = mock_batch.ready_at.saturating_duration_since(Instant::now());
+            let done = 1.0 - remaining.as_secs_f64() / mock.batch_latency.as_secs_f64().max(f64::EPSILON);
+            batch.status = "in_progress".to_string();
+            batch.request_counts = Some(BatchRequestCounts {
+                total,
+                completed: (total as f64 * done) as u64,
+                failed: 0,
+            });
+        } else if mock_batch.fails {
+            batch.status = "failed".to_string();
+            batch.completed_at = Some(Utc::now().timestamp());
+            batch.request_counts = Some(BatchRequestCounts { total, completed: 0, failed: total });
+        } else {
+            let input = files.get(&batch.input_file_id).cloned().unwrap_or_default();
+            let output_file_id = mock_id("file");
+            files.insert(output_file_id.clone(), batch_output(&input));
+            batch.status = "completed".to_string();
+            batch.output_file_id = Some(output_file_id);
+            batch.request_counts = Some(BatchRequestCounts { total, completed: total, failed: 0 });
+            batch.completed_at = Some(Utc::now().timestamp());
+        }
+    }
+    Json(batch.clone()).into_response()
+}
+
+/// Result lines answering each line of a batch input file.
+fn batch_output(input: &str) -> String {
+    input
+        .lines()
+        .filter_map(|line| serde_json::from_str::<BatchLine>(line).ok())
+        .map(|line| {
+            let result = BatchResultLine {
+                id: mock_id("batch_req"),
+                custom_id: line.custom_id,
+                response: BatchResultResponse {
+                    status_code: 200,
+                    body: mock_completion(&line.body),
+                },
+            };
+            serde_json::to_string(&result).unwrap_or_default()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+async fn chat_completion(
+    State(mock): State<Arc<MockUpstream>>,
+    Json(request): Json<CompletionRequest>,
+) -> Response {
+    if let Err(response) = mock.respond().await {
+        return response;
+    }
+    Json(mock_completion(&request)).into_response()
+}
+
+async fn list_models(State(mock): State<Arc<MockUpstream>>) -> Response {
+    if let Err(response) = mock.respond().await {
+        return response;
+    }
+    let data: Vec<_> = MOCK_MODELS
+        .iter()
+        .map(|id| json!({ "id": id, "object": "model", "created": 0, "owned_by": "silt-mock" }))
+        .collect();
+    Json(json!({ "object": "list", "data": data })).into_response()
+}
+
+/// A canned answer: valid JSON when the request asked for JSON output,
+/// otherwise an echo of the last message.
+fn mock_completion(request: &CompletionRequest) -> CompletionResponse {
+    let wants_json = request
+        .response_format
+        .as_ref()
+        .is_some_and(ResponseFormat::wants_json);
+    let content = if wants_json {
+        json!({ "mock": true }).to_string()
+    } else {
+        let last = request
+            .messages
+            .last()
+            .and_then(|message| message.content.as_ref())
+            .map(|content| content.text().chars().take(200).collect::<String>())
+            .unwrap_or_default();
+        format!("Mock response to: {}", last)
+    };
+
+    let n = request.n.unwrap_or(1).max(1);
+    let prompt_tokens = tokens::count_prompt_tokens(request) as u32;
+    let completion_tokens = content.split_whitespace().count() as u32 * n;
+    let choices = (0..n)
+        .map(|index| Choice {
+            index,
+            message: Message {
+                role: "assistant".to_string(),
+                content: Some(content.clone().into()),
+                tool_calls: None,
+                tool_call_id: None,
+                extra: HashMap::new(),
+            },
+            finish_reason: Some("stop".to_string()),
+            extra: HashMap::new(),
+        })
+        .collect();
+
+    CompletionResponse {
+        id: mock_id("chatcmpl"),
+        object: "chat.completion".to_string(),
+        created: Utc::now().timestamp(),
+        model: request.model.clone(),
+        choices,
+        usage: Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        },
+        extra: HashMap::new(),
+    }
+}
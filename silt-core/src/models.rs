@@ -0,0 +1,1249 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionRequest {
+    /// Empty when the client omitted it; filled from `SILT_DEFAULT_MODEL`
+    #[serde(default)]
+    pub model: String,
+    pub messages: Vec<Message>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+    #[serde(default)]
+    pub n: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl CompletionRequest {
+    /// SHA-256 of the request body. The request is round-tripped through
+    /// `serde_json::Value` first so object keys hash in a stable order.
+    pub fn content_hash(&self) -> String {
+        let canonical = serde_json::to_value(self)
+            .and_then(|value| serde_json::to_vec(&value))
+            .unwrap_or_default();
+        hex::encode(Sha256::digest(&canonical))
+    }
+
+    /// Whether repeating the request should give the same completion: zero
+    /// temperature or an explicit `seed`.
+    pub fn is_deterministic(&self) -> bool {
+        self.temperature == Some(0.0) || self.extra.get("seed").is_some_and(|seed| !seed.is_null())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub role: String,
+    /// Null on assistant messages that only carry `tool_calls`
+    #[serde(default)]
+    pub content: Option<MessageContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// Set on `tool` messages: the call this message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// A message's content: plain text, or an array of parts for multimodal
+/// requests (text, `image_url`, `input_audio`, ...).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl MessageContent {
+    /// The text of the content, with text parts joined by newlines.
+    pub fn text(&self) -> std::borrow::Cow<'_, str> {
+        match self {
+            MessageContent::Text(text) => text.as_str().into(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| part.text.as_deref())
+                .collect::<Vec<_>>()
+                .join("\n")
+                .into(),
+        }
+    }
+
+    /// Rewrites each piece of text, leaving other parts untouched.
+    pub fn map_text(&mut self, mut rewrite: impl FnMut(&str) -> Option<String>) {
+        match self {
+            MessageContent::Text(text) => {
+                if let Some(rewritten) = rewrite(text) {
+                    *text = rewritten;
+                }
+            }
+            MessageContent::Parts(parts) => {
+                for text in parts.iter_mut().filter_map(|part| part.text.as_mut()) {
+                    if let Some(rewritten) = rewrite(text) {
+                        *text = rewritten;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Parts that aren't text, such as images.
+    pub fn non_text_parts(&self) -> impl Iterator<Item = &ContentPart> {
+        let parts = match self {
+            MessageContent::Text(_) => &[][..],
+            MessageContent::Parts(parts) => parts.as_slice(),
+        };
+        parts.iter().filter(|part| part.text.is_none())
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        MessageContent::Text(text)
+    }
+}
+
+/// One part of multimodal content. Only text is interpreted; other fields
+/// (`image_url`, `input_audio`, `file`, ...) are passed through unchanged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// A function call made by the assistant.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "default_tool_call_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+fn default_tool_call_type() -> String {
+    "function".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON-encoded arguments, as generated by the model
+    pub arguments: String,
+}
+
+/// Output format requested through `response_format`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+/// A structured output schema. Fields silt doesn't know are passed through.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl ResponseFormat {
+    pub fn wants_json(&self) -> bool {
+        !matches!(self, ResponseFormat::Text)
+    }
+
+    /// Checks a `json_schema` format the way the upstream would, so a bad
+    /// schema is rejected at submission rather than failing the whole batch
+    /// line later. Under `strict`, every object must list all of its
+    /// properties as `required` and set `additionalProperties: false`.
+    pub fn validate(&self, max_schema_bytes: usize) -> Result<(), String> {
+        let ResponseFormat::JsonSchema { json_schema } = self else {
+            return Ok(());
+        };
+        let name = &json_schema.name;
+        if name.is_empty()
+            || name.len() > 64
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!(
+                "response_format.json_schema.name '{}' must be 1-64 letters, digits, '_' or '-'",
+                name
+            ));
+        }
+
+        let schema = json_schema
+            .schema
+            .as_ref()
+            .ok_or_else(|| "response_format.json_schema.schema is required".to_string())?;
+        if !schema.is_object() {
+            return Err("response_format.json_schema.schema must be a JSON object".to_string());
+        }
+        let size = serde_json::to_vec(schema).map(|bytes| bytes.len()).unwrap_or_default();
+        if size > max_schema_bytes {
+            return Err(format!(
+                "response_format.json_schema.schema is {} bytes, more than the {} allowed",
+                size, max_schema_bytes
+            ));
+        }
+
+        if json_schema.strict == Some(true) {
+            check_strict_schema(schema, "schema")?;
+        }
+        Ok(())
+    }
+}
+
+fn check_strict_schema(schema: &serde_json::Value, path: &str) -> Result<(), String> {
+    match schema {
+        serde_json::Value::Object(object) => {
+            if let Some(properties) = object.get("properties").and_then(|properties| properties.as_object()) {
+                let required: Vec<&str> = object
+                    .get("required")
+                    .and_then(|required| required.as_array())
+                    .map(|required| required.iter().filter_map(|name| name.as_str()).collect())
+                    .unwrap_or_default();
+                if let Some(missing) = properties.keys().find(|name| !required.contains(&name.as_str())) {
+                    return Err(format!(
+                        "strict response_format: {} must list '{}' in required",
+                        path, missing
+                    ));
+                }
+                if object.get("additionalProperties") != Some(&serde_json::Value::Bool(false)) {
+                    return Err(format!(
+                        "strict response_format: {} must set additionalProperties to false",
+                        path
+                    ));
+                }
+                for (name, property) in properties {
+                    check_strict_schema(property, &format!("{}.properties.{}", path, name))?;
+                }
+            }
+            for (key, value) in object.iter().filter(|(key, _)| *key != "properties") {
+                check_strict_schema(value, &format!("{}.{}", path, key))?;
+            }
+            Ok(())
+        }
+        serde_json::Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, item)| check_strict_schema(item, &format!("{}[{}]", path, i))),
+        _ => Ok(()),
+    }
+}
+
+/// Whether an injected system prompt goes before the client's messages or
+/// replaces the client's own system messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemPromptMode {
+    #[default]
+    Prepend,
+    Replace,
+}
+
+impl std::str::FromStr for SystemPromptMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "prepend" => Ok(SystemPromptMode::Prepend),
+            "replace" => Ok(SystemPromptMode::Replace),
+            other => Err(anyhow::anyhow!(
+                "Invalid system prompt mode '{}': expected 'prepend' or 'replace'",
+                other
+            )),
+        }
+    }
+}
+
+/// A system message added to requests when they are sent upstream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SystemPrompt {
+    pub content: String,
+    #[serde(default)]
+    pub mode: SystemPromptMode,
+}
+
+impl SystemPrompt {
+    /// The request as it should be sent upstream; the stored one is left as
+    /// the client sent it.
+    pub fn apply(&self, request: &CompletionRequest) -> CompletionRequest {
+        let mut request = request.clone();
+        if self.mode == SystemPromptMode::Replace {
+            request.messages.retain(|message| message.role != "system");
+        }
+        request.messages.insert(
+            0,
+            Message {
+                role: "system".to_string(),
+                content: Some(self.content.clone().into()),
+                tool_calls: None,
+                tool_call_id: None,
+                extra: HashMap::new(),
+            },
+        );
+        request
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<Choice>,
+    pub usage: Usage,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Choice {
+    pub index: u32,
+    pub message: Message,
+    pub finish_reason: Option<String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestStatus {
+    Queued,
+    Batching,
+    Processing,
+    Complete,
+    Failed,
+    /// Flagged by moderation and held until an admin releases or rejects it
+    Quarantined,
+}
+
+/// Dispatch lane for a request. High priority requests are batched on a
+/// shorter window into smaller batches.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Low,
+}
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Low => "low",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "high" => Some(Priority::High),
+            "low" => Some(Priority::Low),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestState {
+    pub request_id: String,
+    pub status: RequestStatus,
+    pub batch_id: Option<String>,
+    pub request: CompletionRequest,
+    pub api_key: String,
+    /// Whether the client's token resolved through a key mapping, rather than
+    /// being passed through as the upstream key. Only mapped requests may go
+    /// out on the tenant's key pool.
+    #[serde(default)]
+    pub mapped: bool,
+    #[serde(default)]
+    pub priority: Priority,
+    /// If set and the request has not completed by this time, it is re-run
+    /// against the realtime endpoint and any later batch result is discarded.
+    #[serde(default)]
+    pub deadline_at: Option<DateTime<Utc>>,
+    /// Client supplied metadata attached to the upstream batch. Requests are
+    /// only batched together with others carrying the same metadata.
+    #[serde(default)]
+    pub metadata: Option<BTreeMap<String, String>>,
+    /// Hash of the submitted body, used to reject idempotency key reuse with
+    /// different content.
+    #[serde(default)]
+    pub body_hash: Option<String>,
+    /// Earliest time the dispatcher may send the request, from a key
+    /// mapping's minimum batch window.
+    #[serde(default)]
+    pub not_before: Option<DateTime<Utc>>,
+    /// Set when content deduplication linked this request to an identical
+    /// one; it is never sent upstream and finishes when the original does.
+    #[serde(default)]
+    pub duplicate_of: Option<String>,
+    /// Moderation categories the request was flagged for, when quarantined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flagged_categories: Option<Vec<String>>,
+    /// The key mapping's system prompt, captured at submission and added when
+    /// the request is dispatched; `SILT_SYSTEM_PROMPT` applies when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<SystemPrompt>,
+    /// Prompt tokens counted at submission, system prompt included
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_prompt_tokens: Option<u64>,
+    /// Upstream chosen with `x-silt-upstream-url`, instead of the configured
+    /// one. Requests are only batched with others for the same upstream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_url: Option<String>,
+    /// `OpenAI-Organization`, `OpenAI-Project` and passthrough headers from
+    /// submission. Requests are only batched with others in the same scope.
+    #[serde(default, skip_serializing_if = "UpstreamScope::is_empty")]
+    pub scope: UpstreamScope,
+    /// Trace context of the submitting client request, if it sent one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_context: Option<TraceContext>,
+    /// `SILT_URGENCY_CLASSES` class picked with `x-silt-urgency`. Requests
+    /// are only batched with others of the same class.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub urgency: Option<String>,
+    /// Times the request went back on the queue after a transient failure
+    #[serde(default)]
+    pub requeues: u32,
+    /// Stored under its own key; only set once loaded with
+    /// `StateManager::load_result`
+    pub result: Option<CompletionResponse>,
+    #[serde(default, deserialize_with = "deserialize_request_error")]
+    pub error: Option<RequestError>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// When the dispatcher took the request off the queue to build a batch
+    /// file, before uploading it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_started_at: Option<DateTime<Utc>>,
+    /// When the request left the queue, either in a batch or as a realtime call
+    #[serde(default)]
+    pub dispatched_at: Option<DateTime<Utc>>,
+    /// When the upstream finished the batch that served the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_completed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub completed_at: Option<DateTime<Utc>>,
+    /// When the result was first returned to the client
+    #[serde(default)]
+    pub retrieved_at: Option<DateTime<Utc>>,
+}
+
+impl RequestState {
+    pub fn new(request_id: String, request: CompletionRequest, api_key: String, priority: Priority) -> Self {
+        let now = Utc::now();
+        let body_hash = Some(request.content_hash());
+        Self {
+            request_id,
+            status: RequestStatus::Queued,
+            batch_id: None,
+            request,
+            api_key,
+            mapped: false,
+            priority,
+            deadline_at: None,
+            metadata: None,
+            body_hash,
+            not_before: None,
+            duplicate_of: None,
+            flagged_categories: None,
+            system_prompt: None,
+            estimated_prompt_tokens: None,
+            upstream_url: None,
+            scope: UpstreamScope::default(),
+            trace_context: None,
+            urgency: None,
+            requeues: 0,
+            result: None,
+            error: None,
+            created_at: now,
+            updated_at: now,
+            upload_started_at: None,
+            dispatched_at: None,
+            upstream_completed_at: None,
+            completed_at: None,
+            retrieved_at: None,
+        }
+    }
+
+    /// Time a completed request spent in each phase, in seconds: `queued`
+    /// until the dispatcher took it, `upload` while its batch file went up,
+    /// `upstream` until the upstream finished it and `delivery` until silt
+    /// stored the result. Realtime calls have no upload or delivery phase.
+    pub fn phase_durations(&self) -> Vec<(&'static str, f64)> {
+        let seconds = |from: DateTime<Utc>, to: DateTime<Utc>| (to - from).num_milliseconds().max(0) as f64 / 1000.0;
+        let (Some(dispatched_at), Some(completed_at)) = (self.dispatched_at, self.completed_at) else {
+            return Vec::new();
+        };
+
+        let mut phases = Vec::with_capacity(4);
+        match self.upload_started_at {
+            Some(upload_started_at) => {
+                phases.push(("queued", seconds(self.created_at, upload_started_at)));
+                phases.push(("upload", seconds(upload_started_at, dispatched_at)));
+            }
+            None => phases.push(("queued", seconds(self.created_at, dispatched_at))),
+        }
+        match self.upstream_completed_at {
+            Some(upstream_completed_at) => {
+                phases.push(("upstream", seconds(dispatched_at, upstream_completed_at)));
+                phases.push(("delivery", seconds(upstream_completed_at, completed_at)));
+            }
+            None => phases.push(("upstream", seconds(dispatched_at, completed_at))),
+        }
+        phases
+    }
+}
+
+/// One status change in a request's history, returned by the status
+/// endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusTransition {
+    pub at: DateTime<Utc>,
+    pub status: RequestStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_id: Option<String>,
+    /// What moved the request, e.g. `submitted` or the error it failed with
+    pub cause: String,
+}
+
+/// What ended a request `failed`. Analytics and `silt_requests_failed_total`
+/// count failures by kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestErrorKind {
+    /// The upstream failed the whole batch
+    BatchFailed,
+    BatchExpired,
+    BatchCancelled,
+    /// The upstream answered the request's line in the batch with an error
+    ResultLine,
+    /// The request's result line could not be read
+    MalformedResult,
+    /// A realtime call, on the bypass lane or the realtime dispatch path
+    Realtime,
+    /// The realtime call made when the request missed its deadline
+    DeadlineFallback,
+    /// A `SILT_POST_RESULT_HOOKS` hook rejected the result
+    PostResultHook,
+    Moderation,
+    /// Cancelled through `DELETE /v1/requests/{id}`
+    Cancelled,
+    /// Cancelled when its last waiting client disconnected
+    Disconnected,
+    /// Deduplicated against a request that was purged before finishing
+    Purged,
+    Other,
+}
+
+impl RequestErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RequestErrorKind::BatchFailed => "batch_failed",
+            RequestErrorKind::BatchExpired => "batch_expired",
+            RequestErrorKind::BatchCancelled => "batch_cancelled",
+            RequestErrorKind::ResultLine => "result_line",
+            RequestErrorKind::MalformedResult => "malformed_result",
+            RequestErrorKind::Realtime => "realtime",
+            RequestErrorKind::DeadlineFallback => "deadline_fallback",
+            RequestErrorKind::PostResultHook => "post_result_hook",
+            RequestErrorKind::Moderation => "moderation",
+            RequestErrorKind::Cancelled => "cancelled",
+            RequestErrorKind::Disconnected => "disconnected",
+            RequestErrorKind::Purged => "purged",
+            RequestErrorKind::Other => "other",
+        }
+    }
+}
+
+/// Why a request failed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestError {
+    pub kind: RequestErrorKind,
+    /// Status the upstream answered the request with, when it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_status: Option<u16>,
+    pub message: String,
+    /// Whether sending the request again could succeed
+    #[serde(default)]
+    pub retryable: bool,
+}
+
+impl RequestError {
+    pub fn new(kind: RequestErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            upstream_status: None,
+            message: message.into(),
+            retryable: false,
+        }
+    }
+
+    pub fn with_upstream_status(mut self, status: Option<u16>) -> Self {
+        self.upstream_status = status;
+        self
+    }
+
+    pub fn retryable(mut self, retryable: bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    /// Classifies a message stored before errors were typed.
+    fn from_message(message: String) -> Self {
+        let kind = match message.as_str() {
+            "Batch failed" => RequestErrorKind::BatchFailed,
+            "Batch expired" => RequestErrorKind::BatchExpired,
+            "Batch cancelled" => RequestErrorKind::BatchCancelled,
+            "Original request was purged" => RequestErrorKind::Purged,
+            "Rejected by moderation" => RequestErrorKind::Moderation,
+            "Cancelled by client" => RequestErrorKind::Cancelled,
+            "Cancelled: client disconnected before dispatch" => RequestErrorKind::Disconnected,
+            _ if message.starts_with("Realtime request failed") => RequestErrorKind::Realtime,
+            _ if message.starts_with("Realtime fallback failed") => RequestErrorKind::DeadlineFallback,
+            _ if message.starts_with("Upstream returned") || message.starts_with("Upstream failed the request") => {
+                RequestErrorKind::ResultLine
+            }
+            _ if message.starts_with("Could not parse batch result") => RequestErrorKind::MalformedResult,
+            _ => RequestErrorKind::Other,
+        };
+        Self::new(kind, message)
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Reads a `RequestError`, or the plain message requests were failed with
+/// before errors were typed.
+fn deserialize_request_error<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<RequestError>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Typed(RequestError),
+        Message(String),
+    }
+
+    Ok(Option::<Stored>::deserialize(deserializer)?.map(|stored| match stored {
+        Stored::Typed(error) => error,
+        Stored::Message(message) => RequestError::from_message(message),
+    }))
+}
+
+/// The organization and project a client scoped its usage to, and its
+/// `SILT_PASSTHROUGH_HEADERS`, forwarded on the upstream calls made for its
+/// requests.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UpstreamScope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    /// Passthrough headers by lowercase name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl UpstreamScope {
+    pub fn is_empty(&self) -> bool {
+        self.organization.is_none() && self.project.is_none() && self.headers.is_empty()
+    }
+}
+
+/// W3C trace context (`traceparent` and `tracestate`) of the client request
+/// that submitted a request, sent on the upstream calls made for it so a
+/// distributed trace spans the hours between submission and dispatch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceContext {
+    pub traceparent: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Longest `tracestate` the spec allows; longer ones are dropped.
+    const MAX_TRACESTATE_LEN: usize = 512;
+
+    /// A trace context from a client's headers, or `None` if `traceparent`
+    /// is not a valid version 00 value. `tracestate` is only kept alongside a
+    /// valid `traceparent`.
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let traceparent = traceparent.trim().to_ascii_lowercase();
+        let fields: Vec<&str> = traceparent.split('-').collect();
+        let [version, trace_id, parent_id, flags] = fields[..] else {
+            return None;
+        };
+        let is_hex = |field: &str, len: usize| field.len() == len && field.bytes().all(|b| b.is_ascii_hexdigit());
+        let is_zero = |field: &str| field.bytes().all(|b| b == b'0');
+        if version != "00"
+            || !is_hex(trace_id, 32)
+            || !is_hex(parent_id, 16)
+            || !is_hex(flags, 2)
+            || is_zero(trace_id)
+            || is_zero(parent_id)
+        {
+            return None;
+        }
+
+        let tracestate = tracestate
+            .map(str::trim)
+            .filter(|tracestate| !tracestate.is_empty() && tracestate.len() <= Self::MAX_TRACESTATE_LEN)
+            .map(str::to_string);
+        Some(Self { traceparent, tracestate })
+    }
+}
+
+/// Upstream key and per-client policies for a silt-issued token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyMapping {
+    pub upstream_key: String,
+    /// Tenant whose namespace the token's requests live in; the default
+    /// tenant when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Models the token may request; all models when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_models: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_minute: Option<u32>,
+    /// Minimum time a queued request waits before it may be dispatched, on top
+    /// of the lane's window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_window_secs: Option<u64>,
+    /// Added to the token's requests when they are dispatched, instead of
+    /// `SILT_SYSTEM_PROMPT`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<SystemPrompt>,
+    /// `SILT_UPSTREAM_URL_ALLOWLIST` entries the token may pick with
+    /// `x-silt-upstream-url`, which would otherwise send the mapped upstream
+    /// key to a gateway of the client's choosing; none when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_urls: Option<Vec<String>>,
+}
+
+impl KeyMapping {
+    pub fn passthrough(upstream_key: String) -> Self {
+        Self {
+            upstream_key,
+            tenant: None,
+            allowed_models: None,
+            max_requests_per_minute: None,
+            batch_window_secs: None,
+            system_prompt: None,
+            upstream_urls: None,
+        }
+    }
+}
+
+/// A replacement registered for an upstream key. New batches go out with
+/// `new_key`, while batches already in flight keep polling with the key they
+/// were dispatched with until the old key is retired.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRotation {
+    pub old_key: String,
+    pub new_key: String,
+    pub created_at: DateTime<Utc>,
+    /// Set once the old key's batches have drained and it was retired
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retired_at: Option<DateTime<Utc>>,
+}
+
+/// Limits on a tenant's usage, checked when requests are queued.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TenantQuota {
+    /// Requests waiting for dispatch across both lanes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_queued_requests: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_day: Option<u64>,
+    /// Upstream tokens (prompt and completion) per UTC day. Usage is only known
+    /// once results arrive, so this stops new requests after it is reached
+    /// rather than bounding it exactly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens_per_day: Option<u64>,
+}
+
+/// How long a tenant's finished requests are kept, applied by the retention
+/// sweeper. Nothing outlives the 48 hour state TTL.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Erase finished requests this long after they were submitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+    /// Erase requests this long after their result was first retrieved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result_ttl_after_retrieval_secs: Option<u64>,
+    /// Drop the prompt messages once a request has finished, keeping only the
+    /// result
+    #[serde(default)]
+    pub drop_prompts_on_completion: bool,
+}
+
+/// What happens to requests that moderation flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Don't moderate requests
+    #[default]
+    Off,
+    /// Reject flagged requests with 400
+    Reject,
+    /// Hold flagged requests for review instead of batching them
+    Quarantine,
+}
+
+impl std::str::FromStr for ModerationAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(ModerationAction::Off),
+            "reject" => Ok(ModerationAction::Reject),
+            "quarantine" => Ok(ModerationAction::Quarantine),
+            other => Err(anyhow::anyhow!(
+                "Invalid SILT_MODERATION_ACTION '{}': expected 'off', 'reject' or 'quarantine'",
+                other
+            )),
+        }
+    }
+}
+
+/// A tenant's moderation settings, overriding `SILT_MODERATION_ACTION`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationPolicy {
+    pub action: ModerationAction,
+}
+
+/// A tenant's usage for the current UTC day.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TenantUsage {
+    pub requests: u64,
+    pub tokens: u64,
+}
+
+/// Rollup of the counters kept as requests and batches finish, for one
+/// tenant-day or summed over several.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Analytics {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<NaiveDate>,
+    pub requests_completed: u64,
+    pub requests_failed: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub batches_completed: u64,
+    pub batches_failed: u64,
+    /// Summed over completed batches, for the average
+    pub batch_duration_secs: u64,
+    pub failures_by_class: BTreeMap<String, u64>,
+    pub models: BTreeMap<String, UsageCounts>,
+    /// By upstream API key fingerprint
+    pub keys: BTreeMap<String, UsageCounts>,
+}
+
+/// Upper bounds of the buckets completed requests' end-to-end latency is
+/// counted in, from realtime calls to batches taking their full window. A
+/// last bucket counts anything slower.
+pub const LATENCY_BUCKETS_SECS: [u64; 12] = [1, 5, 15, 60, 300, 900, 1800, 3600, 7200, 14400, 43200, 86400];
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UsageCounts {
+    pub completed: u64,
+    pub failed: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub tokens: u64,
+    /// Estimated cost in millionths of a USD, for models in
+    /// `SILT_MODEL_PRICES`
+    pub cost_micros: u64,
+    /// Completed requests per `LATENCY_BUCKETS_SECS` bucket
+    #[serde(skip)]
+    pub latency_buckets: [u64; LATENCY_BUCKETS_SECS.len() + 1],
+}
+
+impl UsageCounts {
+    pub fn add(&mut self, other: &UsageCounts) {
+        self.completed += other.completed;
+        self.failed += other.failed;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.tokens += other.tokens;
+        self.cost_micros += other.cost_micros;
+        for (count, other) in self.latency_buckets.iter_mut().zip(other.latency_buckets) {
+            *count += other;
+        }
+    }
+
+    /// Analytics counter for a request that took `secs` end to end, e.g.
+    /// `latency_le_300`.
+    pub fn latency_counter(secs: u64) -> String {
+        match LATENCY_BUCKETS_SECS.iter().find(|bound| secs <= **bound) {
+            Some(bound) => format!("latency_le_{}", bound),
+            None => "latency_le_inf".to_string(),
+        }
+    }
+
+    /// Counts a `latency_counter` read back from analytics, ignoring any
+    /// other counter.
+    pub fn set_latency_count(&mut self, counter: &str, value: u64) {
+        let Some(bound) = counter.strip_prefix("latency_le_") else {
+            return;
+        };
+        let bucket = match bound {
+            "inf" => Some(LATENCY_BUCKETS_SECS.len()),
+            _ => LATENCY_BUCKETS_SECS.iter().position(|known| bound == known.to_string()),
+        };
+        if let Some(bucket) = bucket {
+            self.latency_buckets[bucket] = value;
+        }
+    }
+
+    /// Upper bound in seconds of the bucket the `p`th percentile latency
+    /// falls in. None without latencies, or when it is over the last bound.
+    pub fn latency_percentile(&self, p: f64) -> Option<u64> {
+        let total: u64 = self.latency_buckets.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0) * total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.latency_buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return LATENCY_BUCKETS_SECS.get(bucket).copied();
+            }
+        }
+        None
+    }
+}
+
+impl Analytics {
+    pub fn add(&mut self, other: &Analytics) {
+        self.requests_completed += other.requests_completed;
+        self.requests_failed += other.requests_failed;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.batches_completed += other.batches_completed;
+        self.batches_failed += other.batches_failed;
+        self.batch_duration_secs += other.batch_duration_secs;
+        for (class, count) in &other.failures_by_class {
+            *self.failures_by_class.entry(class.clone()).or_default() += count;
+        }
+        for (model, counts) in &other.models {
+            self.models.entry(model.clone()).or_default().add(counts);
+        }
+        for (key, counts) in &other.keys {
+            self.keys.entry(key.clone()).or_default().add(counts);
+        }
+    }
+
+    /// Share of finished requests that failed.
+    pub fn failure_rate(&self) -> f64 {
+        let finished = self.requests_completed + self.requests_failed;
+        if finished == 0 {
+            return 0.0;
+        }
+        self.requests_failed as f64 / finished as f64
+    }
+
+    pub fn average_batch_duration_secs(&self) -> Option<f64> {
+        (self.batches_completed > 0).then(|| self.batch_duration_secs as f64 / self.batches_completed as f64)
+    }
+}
+
+/// A request that ended `failed`, kept in its tenant's `recent_failures` list
+/// for the admin dashboard.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailureRecord {
+    pub failed_at: DateTime<Utc>,
+    pub tenant: String,
+    pub request_id: String,
+    pub model: String,
+    pub api_key_fingerprint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_id: Option<String>,
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<RequestErrorKind>,
+}
+
+/// Audit record of an admin data purge, kept in the `purge_log` list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PurgeRecord {
+    pub purged_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub requests_purged: usize,
+    pub key_mappings_purged: usize,
+    #[serde(default)]
+    pub archived_rows_purged: usize,
+}
+
+// OpenAI Batch API structures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchRequest {
+    pub input_file_id: String,
+    pub endpoint: String,
+    pub completion_window: String,
+    #[serde(default)]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResponse {
+    pub id: String,
+    pub object: String,
+    pub endpoint: String,
+    pub input_file_id: String,
+    pub output_file_id: Option<String>,
+    pub error_file_id: Option<String>,
+    pub status: String,
+    pub created_at: i64,
+    pub completed_at: Option<i64>,
+    pub metadata: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub request_counts: Option<BatchRequestCounts>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errors: Option<BatchErrors>,
+}
+
+impl BatchResponse {
+    /// Whether the batch ended for reasons sending its requests again could
+    /// get past: it expired, or it was refused for the key's rate or token
+    /// limits.
+    pub fn is_transient_failure(&self) -> bool {
+        self.status == "expired"
+            || self.errors.as_ref().is_some_and(|errors| {
+                errors.data.iter().any(|error| {
+                    error
+                        .code
+                        .as_deref()
+                        .is_some_and(|code| code == "token_limit_exceeded" || code.contains("rate_limit"))
+                })
+            })
+    }
+}
+
+/// Why the upstream failed a batch as a whole.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BatchErrors {
+    #[serde(default)]
+    pub data: Vec<BatchErrorData>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchErrorData {
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct BatchRequestCounts {
+    pub total: u64,
+    pub completed: u64,
+    pub failed: u64,
+}
+
+/// How far an in-flight batch has got, as of its last poll. Kept for the
+/// admin dashboard and `GET /v1/batches/:id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchProgress {
+    pub status: String,
+    #[serde(flatten)]
+    pub counts: BatchRequestCounts,
+    pub polled_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchLine {
+    pub custom_id: String,
+    pub method: String,
+    pub url: String,
+    pub body: CompletionRequest,
+}
+
+impl BatchLine {
+    pub fn chat_completion(custom_id: String, body: CompletionRequest) -> Self {
+        Self {
+            custom_id,
+            method: "POST".to_string(),
+            url: "/v1/chat/completions".to_string(),
+            body,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResultLine {
+    pub id: String,
+    pub custom_id: String,
+    pub response: BatchResultResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResultResponse {
+    pub status_code: u16,
+    pub body: CompletionResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileUploadResponse {
+    pub id: String,
+    pub object: String,
+    pub bytes: u64,
+    pub created_at: i64,
+    pub filename: String,
+    pub purpose: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parses_valid_trace_contexts() {
+        let context = TraceContext::parse(TRACEPARENT, Some(" vendor=abc ")).unwrap();
+        assert_eq!(context.traceparent, TRACEPARENT);
+        assert_eq!(context.tracestate.as_deref(), Some("vendor=abc"));
+
+        let upper = TraceContext::parse(&format!(" {} ", TRACEPARENT.to_ascii_uppercase()), None).unwrap();
+        assert_eq!(upper.traceparent, TRACEPARENT);
+        assert_eq!(upper.tracestate, None);
+    }
+
+    #[test]
+    fn rejects_invalid_traceparents() {
+        for traceparent in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+            "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            assert_eq!(
+                TraceContext::parse(traceparent, Some("vendor=abc")),
+                None,
+                "{}",
+                traceparent
+            );
+        }
+    }
+
+    #[test]
+    fn drops_empty_and_oversized_tracestates() {
+        let empty = TraceContext::parse(TRACEPARENT, Some("  ")).unwrap();
+        assert_eq!(empty.tracestate, None);
+
+        let longest = "a".repeat(TraceContext::MAX_TRACESTATE_LEN);
+        let kept = TraceContext::parse(TRACEPARENT, Some(&longest)).unwrap();
+        assert_eq!(kept.tracestate.as_deref(), Some(longest.as_str()));
+
+        let oversized = format!("{}a", longest);
+        let dropped = TraceContext::parse(TRACEPARENT, Some(&oversized)).unwrap();
+        assert_eq!(dropped.tracestate, None);
+    }
+
+    fn latencies(secs: &[u64]) -> UsageCounts {
+        let mut counts = UsageCounts::default();
+        for secs in secs {
+            let bucket = LATENCY_BUCKETS_SECS.iter().position(|bound| secs <= bound);
+            counts.latency_buckets[bucket.unwrap_or(LATENCY_BUCKETS_SECS.len())] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn latency_percentiles_are_bucket_bounds() {
+        let counts = latencies(&[1, 2, 3, 10, 50, 200, 200, 200, 1000, 5000]);
+        assert_eq!(counts.latency_percentile(0.0), Some(1));
+        assert_eq!(counts.latency_percentile(10.0), Some(1));
+        assert_eq!(counts.latency_percentile(11.0), Some(5));
+        assert_eq!(counts.latency_percentile(50.0), Some(60));
+        assert_eq!(counts.latency_percentile(51.0), Some(300));
+        assert_eq!(counts.latency_percentile(80.0), Some(300));
+        assert_eq!(counts.latency_percentile(90.0), Some(1800));
+        assert_eq!(counts.latency_percentile(100.0), Some(7200));
+    }
+
+    #[test]
+    fn latency_percentiles_past_the_last_bound_are_unknown() {
+        assert_eq!(UsageCounts::default().latency_percentile(50.0), None);
+
+        let counts = latencies(&[60, 100_000]);
+        assert_eq!(counts.latency_percentile(50.0), Some(60));
+        assert_eq!(counts.latency_percentile(99.0), None);
+    }
+
+    #[test]
+    fn latency_counters_round_trip() {
+        let mut counts = UsageCounts::default();
+        for (secs, count) in [(0, 1), (300, 2), (301, 3), (86_401, 4)] {
+            counts.set_latency_count(&UsageCounts::latency_counter(secs), count);
+        }
+        counts.set_latency_count("latency_le_2", 9);
+        counts.set_latency_count("completed", 9);
+
+        assert_eq!(UsageCounts::latency_counter(86_401), "latency_le_inf");
+        assert_eq!(counts.latency_buckets[0], 1);
+        assert_eq!(counts.latency_buckets[4], 2);
+        assert_eq!(counts.latency_buckets[5], 3);
+        assert_eq!(counts.latency_buckets[LATENCY_BUCKETS_SECS.len()], 4);
+        assert_eq!(counts.latency_buckets.iter().sum::<u64>(), 10);
+    }
+}
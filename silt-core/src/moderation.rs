@@ -0,0 +1,118 @@
+use crate::config::Config;
+use crate::models::{CompletionRequest, ContentPart};
+use anyhow::Result;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use tracing::debug;
+
+#[derive(Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    #[serde(default)]
+    categories: BTreeMap<String, bool>,
+}
+
+/// Screens incoming messages with an OpenAI-compatible `/moderations`
+/// endpoint: the upstream's own, or a local classifier serving the same API
+/// at `SILT_MODERATION_URL`.
+pub struct Moderator {
+    client: Client,
+    url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl Moderator {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let url = config.moderation_url.clone().unwrap_or_else(|| {
+            let base_url = config
+                .upstream_base_url
+                .as_deref()
+                .unwrap_or("https://api.openai.com/v1");
+            format!("{}/moderations", base_url.trim_end_matches('/'))
+        });
+        Ok(Self {
+            client: Client::builder().timeout(Duration::from_secs(30)).build()?,
+            url,
+            model: config.moderation_model.clone(),
+            api_key: config.moderation_api_key.clone(),
+        })
+    }
+
+    /// The categories a request's messages were flagged for, or `None` if
+    /// they passed. `api_key` is the caller's upstream key, used unless
+    /// `SILT_MODERATION_API_KEY` is set.
+    pub async fn check(&self, api_key: &str, request: &CompletionRequest) -> Result<Option<Vec<String>>> {
+        let input = moderation_input(request);
+        if input.as_array().is_some_and(Vec::is_empty) {
+            return Ok(None);
+        }
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header(
+                "Authorization",
+                format!("Bearer {}", self.api_key.as_deref().unwrap_or(api_key)),
+            )
+            .json(&serde_json::json!({ "model": self.model, "input": input }))
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Moderation request failed: {}", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Moderation request failed ({}): {}", status, body);
+        }
+        let response: ModerationResponse = response.json().await?;
+
+        if !response.results.iter().any(|result| result.flagged) {
+            return Ok(None);
+        }
+        let categories: BTreeSet<String> = response
+            .results
+            .into_iter()
+            .filter(|result| result.flagged)
+            .flat_map(|result| result.categories)
+            .filter(|(_, flagged)| *flagged)
+            .map(|(category, _)| category)
+            .collect();
+        debug!("Moderation flagged categories: {:?}", categories);
+        Ok(Some(categories.into_iter().collect()))
+    }
+}
+
+/// Message text as an array of strings, or as multimodal input objects when a
+/// message carries images (the moderation API doesn't accept a mix).
+fn moderation_input(request: &CompletionRequest) -> serde_json::Value {
+    let texts = request
+        .messages
+        .iter()
+        .filter_map(|message| message.content.as_ref())
+        .map(|content| content.text().into_owned())
+        .filter(|text| !text.is_empty());
+
+    let images: Vec<&ContentPart> = request
+        .messages
+        .iter()
+        .filter_map(|message| message.content.as_ref())
+        .flat_map(|content| content.non_text_parts())
+        .filter(|part| part.kind == "image_url")
+        .collect();
+    if images.is_empty() {
+        return serde_json::json!(texts.collect::<Vec<_>>());
+    }
+
+    let mut input: Vec<serde_json::Value> = texts
+        .map(|text| serde_json::json!({ "type": "text", "text": text }))
+        .collect();
+    input.extend(images.into_iter().map(|part| serde_json::json!(part)));
+    serde_json::Value::Array(input)
+}
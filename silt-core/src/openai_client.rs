@@ -0,0 +1,844 @@
+use crate::chaos::chaos;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::Config;
+use crate::models::{
+    BatchRequest, BatchResponse, BatchResultLine, CompletionRequest,
+    CompletionResponse, FileUploadResponse, TraceContext, UpstreamScope,
+};
+use crate::retry::{with_retry, RetryPolicy};
+use crate::tls;
+use anyhow::Result;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use futures_util::stream::{self, BoxStream, Stream, StreamExt};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Start of the filename silt uploads batch input under, so the file sweeper
+/// can tell its own uploads from other files on the same account.
+pub const UPLOAD_FILENAME_PREFIX: &str = "silt-batch-";
+
+/// `completion_window` of batches for requests without an urgency class.
+pub const DEFAULT_COMPLETION_WINDOW: &str = "24h";
+
+/// A page of `GET /files`.
+#[derive(Deserialize)]
+struct FileList {
+    data: Vec<FileUploadResponse>,
+    #[serde(default)]
+    has_more: bool,
+}
+
+/// Errors returned by upstream calls, distinguishing failures worth retrying
+/// from ones that will fail the same way again.
+#[derive(Debug, thiserror::Error)]
+pub enum UpstreamError {
+    #[error("{operation}: request failed: {source}")]
+    Network {
+        operation: &'static str,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("{operation} ({status}): {body}")]
+    Status {
+        operation: &'static str,
+        status: StatusCode,
+        body: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("Upstream circuit breaker is open, skipping call")]
+    CircuitOpen,
+}
+
+impl UpstreamError {
+    pub fn is_transient(&self) -> bool {
+        match self {
+            UpstreamError::Network { source, .. } => {
+                source.is_timeout() || source.is_connect() || source.is_request() || source.is_body()
+            }
+            UpstreamError::Status { status, .. } => {
+                status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            UpstreamError::CircuitOpen => false,
+        }
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(self, UpstreamError::Status { status, .. } if *status == StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    /// The delay the upstream asked for via a `Retry-After` header, if any.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            UpstreamError::Status { retry_after, .. } => *retry_after,
+            UpstreamError::Network { .. } | UpstreamError::CircuitOpen => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OpenAIClient {
+    client: Client,
+    base_url: String,
+    retry_policy: RetryPolicy,
+    circuit_breaker: Arc<CircuitBreaker>,
+    circuit_failure_threshold: u32,
+    circuit_cooldown: Duration,
+    /// Clients for the other upstreams requests were routed to, built on
+    /// first use
+    routed: Arc<Mutex<HashMap<String, OpenAIClient>>>,
+    /// Organization and project sent with every call
+    scope: UpstreamScope,
+    /// Trace context sent with uploads, batch creation and chat completions
+    trace_context: Option<TraceContext>,
+}
+
+impl OpenAIClient {
+    pub fn new(config: &Config) -> Result<Self> {
+        let base_url = config
+            .upstream_base_url
+            .clone()
+            .unwrap_or_else(|| "https://api.openai.com/v1".to_string());
+        Self::with_base_url(config, base_url)
+    }
+
+    /// A client for the given upstream instead of the configured one.
+    pub fn with_base_url(config: &Config, base_url: String) -> Result<Self> {
+        let mut builder = Client::builder()
+            .timeout(Duration::from_secs(config.upstream_request_timeout_secs))
+            .connect_timeout(Duration::from_secs(config.upstream_connect_timeout_secs))
+            .pool_max_idle_per_host(config.upstream_pool_max_idle_per_host)
+            .pool_idle_timeout(Duration::from_secs(config.upstream_pool_idle_timeout_secs));
+
+        if let Some(tls_config) =
+            tls::build_client_config(&config.upstream_ca_certs, &config.upstream_cert_pins)?
+        {
+            builder = builder.use_preconfigured_tls(tls_config);
+        }
+
+        let client = builder.build()?;
+        let circuit_cooldown = Duration::from_secs(config.upstream_circuit_cooldown_secs);
+
+        Ok(Self {
+            client,
+            base_url,
+            retry_policy: RetryPolicy {
+                max_attempts: config.upstream_retry_max_attempts.max(1),
+                base_delay: Duration::from_millis(config.upstream_retry_base_delay_ms),
+                max_delay: Duration::from_millis(config.upstream_retry_max_delay_ms),
+                jitter: config.upstream_retry_jitter,
+            },
+            circuit_breaker: Arc::new(CircuitBreaker::new(
+                config.upstream_circuit_failure_threshold,
+                circuit_cooldown,
+            )),
+            circuit_failure_threshold: config.upstream_circuit_failure_threshold,
+            circuit_cooldown,
+            routed: Arc::new(Mutex::new(HashMap::new())),
+            scope: UpstreamScope::default(),
+            trace_context: None,
+        })
+    }
+
+    pub fn circuit_breaker(&self) -> Arc<CircuitBreaker> {
+        Arc::clone(&self.circuit_breaker)
+    }
+
+    /// The client for requests routed to `base_url`, or this one when it is
+    /// unset or this client's own. Routed clients share the connection pool,
+    /// timeouts and TLS settings but have their own circuit breaker.
+    pub fn for_upstream(&self, base_url: Option<&str>) -> OpenAIClient {
+        let Some(base_url) = base_url.filter(|base_url| *base_url != self.base_url) else {
+            return self.clone();
+        };
+        let mut routed = self.routed.lock().unwrap();
+        let mut client = routed
+            .entry(base_url.to_string())
+            .or_insert_with(|| OpenAIClient {
+                client: self.client.clone(),
+                base_url: base_url.to_string(),
+                retry_policy: self.retry_policy.clone(),
+                circuit_breaker: Arc::new(CircuitBreaker::for_upstream(
+                    self.circuit_failure_threshold,
+                    self.circuit_cooldown,
+                    base_url,
+                )),
+                circuit_failure_threshold: self.circuit_failure_threshold,
+                circuit_cooldown: self.circuit_cooldown,
+                routed: Arc::clone(&self.routed),
+                scope: UpstreamScope::default(),
+                trace_context: None,
+            })
+            .clone();
+        client.scope = self.scope.clone();
+        client.trace_context = self.trace_context.clone();
+        client
+    }
+
+    /// This client, sending the organization and project a request was
+    /// scoped to with `OpenAI-Organization` and `OpenAI-Project`, along with
+    /// its passthrough headers.
+    pub fn with_scope(&self, scope: &UpstreamScope) -> OpenAIClient {
+        OpenAIClient {
+            scope: scope.clone(),
+            ..self.clone()
+        }
+    }
+
+    /// This client, sending a request's `traceparent` and `tracestate` on the
+    /// calls that carry it upstream, so they join the client's trace.
+    pub fn with_trace_context(&self, trace_context: Option<&TraceContext>) -> OpenAIClient {
+        OpenAIClient {
+            trace_context: trace_context.cloned(),
+            ..self.clone()
+        }
+    }
+
+    fn trace_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let Some(trace_context) = &self.trace_context else {
+            return headers;
+        };
+        if let Ok(value) = HeaderValue::from_str(&trace_context.traceparent) {
+            headers.insert("traceparent", value);
+        }
+        if let Some(value) = trace_context
+            .tracestate
+            .as_deref()
+            .and_then(|value| HeaderValue::from_str(value).ok())
+        {
+            headers.insert("tracestate", value);
+        }
+        headers
+    }
+
+    fn scope_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("openai-organization", &self.scope.organization),
+            ("openai-project", &self.scope.project),
+        ] {
+            if let Some(value) = value.as_deref().and_then(|value| HeaderValue::from_str(value).ok()) {
+                headers.insert(name, value);
+            }
+        }
+        for (name, value) in &self.scope.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
+
+    /// Runs an upstream call through the circuit breaker, counting transient
+    /// failures (after retries) towards opening it.
+    async fn guarded<T>(&self, call: impl Future<Output = Result<T>>) -> Result<T> {
+        if !self.circuit_breaker.allow_request() {
+            return Err(UpstreamError::CircuitOpen.into());
+        }
+
+        let result = call.await;
+        match &result {
+            Ok(_) => self.circuit_breaker.record_success(),
+            Err(e) => {
+                if e.downcast_ref::<UpstreamError>()
+                    .is_some_and(UpstreamError::is_transient)
+                {
+                    self.circuit_breaker.record_failure();
+                }
+            }
+        }
+        result
+    }
+
+    /// Uploads a batch input file, streaming its JSONL from `file`. A body can
+    /// only be sent once, so `file` is called again for each retry.
+    pub async fn upload_batch_file<S>(&self, api_key: &str, file: impl Fn() -> S) -> Result<String>
+    where
+        S: Stream<Item = Result<Vec<u8>>> + Send + 'static,
+    {
+        // Generate unique filename
+        let filename = format!("{}{}.jsonl", UPLOAD_FILENAME_PREFIX, uuid::Uuid::new_v4());
+
+        let upload_response: FileUploadResponse = self
+            .guarded(with_retry(&self.retry_policy, "File upload", || {
+                self.upload_file_once(api_key, &filename, file())
+            }))
+            .await?;
+
+        tracing::info!("File uploaded: {} ({} bytes)", upload_response.id, upload_response.bytes);
+        Ok(upload_response.id)
+    }
+
+    async fn upload_file_once<S>(&self, api_key: &str, filename: &str, content: S) -> Result<FileUploadResponse>
+    where
+        S: Stream<Item = Result<Vec<u8>>> + Send + 'static,
+    {
+        // The multipart form is consumed by the request, so it is rebuilt per attempt
+        let form = reqwest::multipart::Form::new()
+            .text("purpose", "batch")
+            .part(
+                "file",
+                reqwest::multipart::Part::stream(reqwest::Body::wrap_stream(content))
+                    .file_name(filename.to_string())
+                    .mime_str("application/jsonl")?,
+            );
+
+        chaos().upstream_fault("Failed to upload file")?;
+        let url = format!("{}/files", self.base_url);
+        tracing::debug!("POST {}", url);
+
+        let response = self
+            .client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", api_key))
+            .headers(self.scope_headers())
+            .headers(self.trace_headers())
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|source| UpstreamError::Network {
+                operation: "Failed to send file upload request",
+                source,
+            })?;
+
+        tracing::debug!("Upload response status: {}", response.status());
+
+        let response = check_status(response, "upload_file", "Failed to upload file").await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn create_batch(
+        &self,
+        api_key: &str,
+        input_file_id: String,
+        completion_window: &str,
+        metadata: Option<BTreeMap<String, String>>,
+    ) -> Result<BatchResponse> {
+        let batch_request = BatchRequest {
+            input_file_id: input_file_id.clone(),
+            endpoint: "/v1/chat/completions".to_string(),
+            completion_window: completion_window.to_string(),
+            metadata: metadata.map(|m| m.into_iter().collect()),
+        };
+
+        tracing::info!("Creating batch for file: {}", input_file_id);
+
+        let batch_response: BatchResponse = self
+            .guarded(with_retry(&self.retry_policy, "Batch creation", || async {
+                chaos().upstream_fault("Failed to create batch")?;
+                let url = format!("{}/batches", self.base_url);
+                let response = self
+                    .client
+                    .post(&url)
+                    .header("Authorization", format!("Bearer {}", api_key))
+                    .headers(self.scope_headers())
+                    .headers(self.trace_headers())
+                    .header("Content-Type", "application/json")
+                    .json(&batch_request)
+                    .send()
+                    .await
+                    .map_err(|source| UpstreamError::Network {
+                        operation: "Failed to send batch creation request",
+                        source,
+                    })?;
+
+                let response = check_status(response, "create_batch", "Failed to create batch").await?;
+                Ok(response.json().await?)
+            }))
+            .await?;
+
+        tracing::info!("Batch created: {} (status: {})", batch_response.id, batch_response.status);
+        Ok(batch_response)
+    }
+
+    /// Runs a single request against the synchronous chat completions endpoint.
+    pub async fn create_chat_completion(
+        &self,
+        api_key: &str,
+        request: &CompletionRequest,
+    ) -> Result<CompletionResponse> {
+        self.guarded(with_retry(&self.retry_policy, "Chat completion", || async {
+            chaos().upstream_fault("Chat completion failed")?;
+            let response = self
+                .client
+                .post(format!("{}/chat/completions", self.base_url))
+                .header("Authorization", format!("Bearer {}", api_key))
+                .headers(self.scope_headers())
+                .headers(self.trace_headers())
+                .json(request)
+                .send()
+                .await
+                .map_err(|source| UpstreamError::Network {
+                    operation: "Failed to send chat completion request",
+                    source,
+                })?;
+
+            let response = check_status(response, "chat_completions", "Chat completion failed").await?;
+            Ok(response.json().await?)
+        }))
+        .await
+    }
+
+    /// The upstream's `GET /models` list, returned as-is.
+    pub async fn list_models(&self, api_key: &str) -> Result<serde_json::Value> {
+        self.guarded(with_retry(&self.retry_policy, "Model list", || async {
+            chaos().upstream_fault("Failed to list models")?;
+            let response = self
+                .client
+                .get(format!("{}/models", self.base_url))
+                .header("Authorization", format!("Bearer {}", api_key))
+                .headers(self.scope_headers())
+                .send()
+                .await
+                .map_err(|source| UpstreamError::Network {
+                    operation: "Failed to list models",
+                    source,
+                })?;
+
+            let response = check_status(response, "models", "Failed to list models").await?;
+            Ok(response.json().await?)
+        }))
+        .await
+    }
+
+    pub async fn get_batch_status(&self, api_key: &str, batch_id: &str) -> Result<BatchResponse> {
+        Ok(serde_json::from_value(self.get_batch(api_key, batch_id).await?)?)
+    }
+
+    /// A batch as upstream returned it, including fields silt doesn't read.
+    pub async fn get_batch(&self, api_key: &str, batch_id: &str) -> Result<serde_json::Value> {
+        self.guarded(async {
+            chaos().upstream_fault("Failed to get batch status")?;
+            let response = self
+                .client
+                .get(format!("{}/batches/{}", self.base_url, batch_id))
+                .header("Authorization", format!("Bearer {}", api_key))
+                .headers(self.scope_headers())
+                .send()
+                .await
+                .map_err(|source| UpstreamError::Network {
+                    operation: "Failed to get batch status",
+                    source,
+                })?;
+
+            let response = check_status(response, "get_batch", "Failed to get batch status").await?;
+            Ok(response.json().await?)
+        })
+        .await
+    }
+
+    /// Every file uploaded with purpose `batch`, across all pages.
+    pub async fn list_batch_files(&self, api_key: &str) -> Result<Vec<FileUploadResponse>> {
+        let mut files = Vec::new();
+        loop {
+            let after = files.last().map(|file: &FileUploadResponse| file.id.clone());
+            let page: FileList = self
+                .guarded(with_retry(&self.retry_policy, "File list", || async {
+                    chaos().upstream_fault("Failed to list files")?;
+                    let mut query = vec![("purpose", "batch".to_string()), ("limit", "10000".to_string())];
+                    if let Some(after) = &after {
+                        query.push(("after", after.clone()));
+                    }
+                    let response = self
+                        .client
+                        .get(format!("{}/files", self.base_url))
+                        .header("Authorization", format!("Bearer {}", api_key))
+                        .headers(self.scope_headers())
+                        .query(&query)
+                        .send()
+                        .await
+                        .map_err(|source| UpstreamError::Network {
+                            operation: "Failed to list files",
+                            source,
+                        })?;
+
+                    let response = check_status(response, "files", "Failed to list files").await?;
+                    Ok(response.json().await?)
+                }))
+                .await?;
+            let has_more = page.has_more && !page.data.is_empty();
+            files.extend(page.data);
+            if !has_more {
+                return Ok(files);
+            }
+        }
+    }
+
+    pub async fn delete_file(&self, api_key: &str, file_id: &str) -> Result<()> {
+        self.guarded(with_retry(&self.retry_policy, "File deletion", || async {
+            chaos().upstream_fault("Failed to delete file")?;
+            let response = self
+                .client
+                .delete(format!("{}/files/{}", self.base_url, file_id))
+                .header("Authorization", format!("Bearer {}", api_key))
+                .headers(self.scope_headers())
+                .send()
+                .await
+                .map_err(|source| UpstreamError::Network {
+                    operation: "Failed to delete file",
+                    source,
+                })?;
+
+            check_status(response, "delete_file", "Failed to delete file").await?;
+            Ok(())
+        }))
+        .await
+    }
+
+    /// Streams a batch's output file one result at a time, so results can be
+    /// stored as they arrive instead of after the whole file is buffered. A
+    /// download that drops part way is reopened, skipping the lines already
+    /// yielded. Lines are parsed one by one, so a malformed line doesn't
+    /// hold up the rest.
+    pub fn batch_results<'a>(
+        &'a self,
+        api_key: &'a str,
+        output_file_id: &'a str,
+    ) -> impl Stream<Item = Result<BatchResult>> + Send + 'a {
+        let download = ResultDownload {
+            lines: None,
+            position: 0,
+            yielded: 0,
+            interruptions: 0,
+        };
+        stream::unfold(Some(download), move |download| async move {
+            let mut download = download?;
+            loop {
+                if download.lines.is_none() {
+                    match self.open_batch_results(api_key, output_file_id).await {
+                        Ok(response) => {
+                            download.lines = Some(Box::pin(body_lines(response)));
+                            download.position = 0;
+                        }
+                        Err(e) => return Some((Err(e), None)),
+                    }
+                }
+                let next = match download.lines.as_mut() {
+                    Some(lines) => lines.next().await,
+                    None => None,
+                };
+                match next {
+                    Some(Ok(line)) => {
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        download.position += 1;
+                        if download.position <= download.yielded {
+                            continue;
+                        }
+                        download.yielded += 1;
+                        return Some((Ok(BatchResult::parse(line)), Some(download)));
+                    }
+                    Some(Err(source)) if download.interruptions + 1 < self.retry_policy.max_attempts => {
+                        download.interruptions += 1;
+                        tracing::warn!(
+                            "Download of {} dropped after {} results, reopening: {}",
+                            output_file_id,
+                            download.yielded,
+                            source
+                        );
+                        download.lines = None;
+                    }
+                    Some(Err(source)) => {
+                        let error = UpstreamError::Network {
+                            operation: "Failed to read results",
+                            source,
+                        };
+                        return Some((Err(error.into()), None));
+                    }
+                    None => return None,
+                }
+            }
+        })
+    }
+
+    async fn open_batch_results(&self, api_key: &str, output_file_id: &str) -> Result<reqwest::Response> {
+        self.guarded(with_retry(&self.retry_policy, "Result download", || async {
+            chaos().upstream_fault("Failed to retrieve results")?;
+            let response = self
+                .client
+                .get(format!("{}/files/{}/content", self.base_url, output_file_id))
+                .header("Authorization", format!("Bearer {}", api_key))
+                .headers(self.scope_headers())
+                .send()
+                .await
+                .map_err(|source| UpstreamError::Network {
+                    operation: "Failed to retrieve results",
+                    source,
+                })?;
+
+            Ok(check_status(response, "file_content", "Failed to retrieve results").await?)
+        }))
+        .await
+    }
+}
+
+/// One line of a batch output file.
+#[derive(Debug)]
+pub enum BatchResult {
+    Parsed(BatchResultLine),
+    /// A line that isn't a result silt can read, with its `custom_id` when
+    /// that much of it parsed.
+    Malformed {
+        custom_id: Option<String>,
+        line: String,
+        error: serde_json::Error,
+    },
+    /// A line reporting that the upstream could not serve the request, with
+    /// the status it answered or, for requests it never ran (e.g. when the
+    /// batch expired), the error code.
+    Failed {
+        custom_id: String,
+        status_code: Option<u16>,
+        code: Option<String>,
+        message: String,
+    },
+}
+
+impl BatchResult {
+    fn parse(line: String) -> Self {
+        match serde_json::from_str(&line) {
+            Ok(result) => BatchResult::Parsed(result),
+            Err(error) => {
+                let value = serde_json::from_str::<serde_json::Value>(&line).unwrap_or_default();
+                let Some(custom_id) = value["custom_id"].as_str().map(str::to_string) else {
+                    return BatchResult::Malformed { custom_id: None, line, error };
+                };
+
+                let status_code = value["response"]["status_code"].as_u64().map(|status| status as u16);
+                if status_code.is_some_and(|status| !(200..300).contains(&status)) || value["error"].is_object() {
+                    let message = value["response"]["body"]["error"]["message"]
+                        .as_str()
+                        .or(value["error"]["message"].as_str())
+                        .unwrap_or("no error message")
+                        .to_string();
+                    return BatchResult::Failed {
+                        custom_id,
+                        status_code,
+                        code: value["error"]["code"].as_str().map(str::to_string),
+                        message,
+                    };
+                }
+                BatchResult::Malformed { custom_id: Some(custom_id), line, error }
+            }
+        }
+    }
+
+    /// Whether a failed line could succeed if its request were sent again:
+    /// rate limits, server errors and requests an expired batch never ran.
+    pub fn is_transient_failure(status_code: Option<u16>, code: Option<&str>) -> bool {
+        match status_code {
+            Some(status) => status == 429 || status >= 500,
+            None => code == Some("batch_expired"),
+        }
+    }
+}
+
+/// Where a streamed result download has got to, across reopens.
+struct ResultDownload {
+    lines: Option<BoxStream<'static, reqwest::Result<String>>>,
+    /// Non-blank lines read from the current connection
+    position: usize,
+    /// Non-blank lines handed to the caller, across connections
+    yielded: usize,
+    interruptions: u32,
+}
+
+/// Splits a response body into lines as its chunks arrive.
+fn body_lines(response: reqwest::Response) -> impl Stream<Item = reqwest::Result<String>> + Send {
+    let body = response.bytes_stream();
+    stream::unfold(Some((body, Vec::new())), |state| async move {
+        let (mut body, mut buffer) = state?;
+        loop {
+            if let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line = String::from_utf8_lossy(&buffer[..end]).into_owned();
+                buffer.drain(..=end);
+                return Some((Ok(line), Some((body, buffer))));
+            }
+            match body.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => return Some((Err(e), None)),
+                // The last line may not end in a newline
+                None if buffer.is_empty() => return None,
+                None => return Some((Ok(String::from_utf8_lossy(&buffer).into_owned()), None)),
+            }
+        }
+    })
+}
+
+/// Turns a non-success response into an `UpstreamError::Status` carrying the
+/// response body. `endpoint` is a short label used for metrics.
+async fn check_status(
+    response: reqwest::Response,
+    endpoint: &'static str,
+    operation: &'static str,
+) -> Result<reqwest::Response, UpstreamError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after);
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        crate::metrics::metrics()
+            .upstream_rate_limited_total
+            .with_label_values(&[endpoint])
+            .inc();
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    Err(UpstreamError::Status {
+        operation,
+        status,
+        body,
+        retry_after,
+    })
+}
+
+/// Parses a `Retry-After` value, which is either a number of seconds or an
+/// HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let remaining = date.with_timezone(&chrono::Utc) - chrono::Utc::now();
+    Some(remaining.to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completion(content: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "gpt-4o-mini",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": content}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4}
+        })
+    }
+
+    fn parse(line: serde_json::Value) -> BatchResult {
+        BatchResult::parse(line.to_string())
+    }
+
+    #[test]
+    fn parses_successful_lines() {
+        let result = parse(serde_json::json!({
+            "id": "batch_req_1",
+            "custom_id": "req-1",
+            "response": {"status_code": 200, "body": completion("hi")}
+        }));
+        let BatchResult::Parsed(line) = result else {
+            panic!("expected a parsed line, got {:?}", result);
+        };
+        assert_eq!(line.custom_id, "req-1");
+        assert_eq!(
+            line.response.body.choices[0].message.content.as_ref().unwrap().text(),
+            "hi"
+        );
+    }
+
+    #[test]
+    fn reports_upstream_error_statuses_as_failed() {
+        let result = parse(serde_json::json!({
+            "id": "batch_req_1",
+            "custom_id": "req-1",
+            "response": {"status_code": 429, "body": {"error": {"message": "Rate limit reached"}}}
+        }));
+        let BatchResult::Failed {
+            custom_id,
+            status_code,
+            code,
+            message,
+        } = result
+        else {
+            panic!("expected a failed line, got {:?}", result);
+        };
+        assert_eq!(custom_id, "req-1");
+        assert_eq!(status_code, Some(429));
+        assert_eq!(code, None);
+        assert_eq!(message, "Rate limit reached");
+        assert!(BatchResult::is_transient_failure(status_code, code.as_deref()));
+    }
+
+    #[test]
+    fn reports_requests_never_run_as_failed() {
+        let result = parse(serde_json::json!({
+            "id": "batch_req_1",
+            "custom_id": "req-1",
+            "response": null,
+            "error": {"code": "batch_expired", "message": "This request could not be executed before the batch expired"}
+        }));
+        let BatchResult::Failed {
+            status_code,
+            code,
+            message,
+            ..
+        } = result
+        else {
+            panic!("expected a failed line, got {:?}", result);
+        };
+        assert_eq!(status_code, None);
+        assert_eq!(code.as_deref(), Some("batch_expired"));
+        assert!(message.starts_with("This request could not be executed"));
+        assert!(BatchResult::is_transient_failure(status_code, code.as_deref()));
+    }
+
+    #[test]
+    fn failed_lines_without_a_message_still_fail() {
+        let result = parse(serde_json::json!({
+            "custom_id": "req-1",
+            "response": {"status_code": 400, "body": {}}
+        }));
+        let BatchResult::Failed {
+            status_code, message, ..
+        } = result
+        else {
+            panic!("expected a failed line, got {:?}", result);
+        };
+        assert_eq!(status_code, Some(400));
+        assert_eq!(message, "no error message");
+        assert!(!BatchResult::is_transient_failure(status_code, None));
+    }
+
+    #[test]
+    fn keeps_the_custom_id_of_unreadable_successes() {
+        let result = parse(serde_json::json!({
+            "id": "batch_req_1",
+            "custom_id": "req-1",
+            "response": {"status_code": 200, "body": {"unexpected": true}}
+        }));
+        let BatchResult::Malformed { custom_id, line, .. } = result else {
+            panic!("expected a malformed line, got {:?}", result);
+        };
+        assert_eq!(custom_id.as_deref(), Some("req-1"));
+        assert!(line.contains("unexpected"));
+    }
+
+    #[test]
+    fn reports_lines_that_are_not_json() {
+        let result = BatchResult::parse("{\"custom_id\": \"req-1\", trunc".to_string());
+        let BatchResult::Malformed { custom_id, line, .. } = result else {
+            panic!("expected a malformed line, got {:?}", result);
+        };
+        assert_eq!(custom_id, None);
+        assert_eq!(line, "{\"custom_id\": \"req-1\", trunc");
+    }
+}
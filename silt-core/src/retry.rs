@@ -0,0 +1,75 @@
+use crate::openai_client::UpstreamError;
+use anyhow::Result;
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+/// Exponential backoff policy for upstream calls.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of each delay (0.0 - 1.0) that is randomized to avoid
+    /// synchronized retries from many batches at once.
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry (1-based), before jitter is applied.
+    fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    fn delay_for(&self, retry: u32) -> Duration {
+        let delay = self.backoff(retry);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        delay.mul_f64(1.0 - jitter * rand::random::<f64>())
+    }
+}
+
+/// Runs `op` until it succeeds, fails with a non-transient error, or the
+/// policy's attempts are exhausted.
+pub async fn with_retry<T, F, Fut>(policy: &RetryPolicy, operation: &str, mut op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < policy.max_attempts && is_transient(&e) => {
+                // Honor the upstream's Retry-After when it is short enough to wait
+                // inline; longer backoffs are left to the caller to schedule
+                let delay = match retry_after(&e) {
+                    Some(delay) if delay > policy.max_delay => return Err(e),
+                    Some(delay) => delay,
+                    None => policy.delay_for(attempt),
+                };
+                warn!(
+                    "{} failed (attempt {}/{}), retrying in {:?}: {}",
+                    operation, attempt, policy.max_attempts, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn retry_after(error: &anyhow::Error) -> Option<Duration> {
+    error
+        .downcast_ref::<UpstreamError>()
+        .and_then(UpstreamError::retry_after)
+}
+
+fn is_transient(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<UpstreamError>()
+        .map(UpstreamError::is_transient)
+        .unwrap_or(false)
+}
@@ -0,0 +1,242 @@
+use crate::models::CompletionRequest;
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+/// Which requests a rule applies to. Unset fields match everything.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleMatch {
+    /// The requested model, after aliases are applied
+    #[serde(default)]
+    model: Option<String>,
+    /// Key mapping id, as listed by `GET /admin/keys`
+    #[serde(default)]
+    key_id: Option<String>,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReplacementSpec {
+    pattern: String,
+    replacement: String,
+    /// Only rewrite messages with these roles; all messages when unset
+    #[serde(default)]
+    roles: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    #[serde(default, rename = "match")]
+    matches: RuleMatch,
+    #[serde(default)]
+    replace: Vec<ReplacementSpec>,
+    /// Request parameters to set, overriding the client's values
+    #[serde(default)]
+    set: serde_json::Map<String, serde_json::Value>,
+}
+
+struct Replacement {
+    pattern: Regex,
+    replacement: String,
+    roles: Option<Vec<String>>,
+}
+
+struct Rule {
+    matches: RuleMatch,
+    replace: Vec<Replacement>,
+    set: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Request rewrite rules from `SILT_REWRITE_RULES_FILE`, a JSON array evaluated in
+/// order on every incoming request. Each matching rule applies its regex
+/// replacements to message content and then its parameter overrides.
+#[derive(Default)]
+pub struct RewriteRules {
+    rules: Vec<Rule>,
+}
+
+impl RewriteRules {
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read SILT_REWRITE_RULES_FILE {}", path))?;
+        let specs: Vec<RuleSpec> = serde_json::from_str(&contents)
+            .with_context(|| format!("SILT_REWRITE_RULES_FILE {} must be a JSON array of rules", path))?;
+        Self::from_specs(specs)
+    }
+
+    fn from_specs(specs: Vec<RuleSpec>) -> Result<Self> {
+        let rules = specs
+            .into_iter()
+            .map(|spec| {
+                let replace = spec
+                    .replace
+                    .into_iter()
+                    .map(|replacement| {
+                        Ok(Replacement {
+                            pattern: Regex::new(&replacement.pattern).with_context(|| {
+                                format!("Invalid pattern '{}' in SILT_REWRITE_RULES_FILE", replacement.pattern)
+                            })?,
+                            replacement: replacement.replacement,
+                            roles: replacement.roles,
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(Rule {
+                    matches: spec.matches,
+                    replace,
+                    set: spec.set,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Applies every rule matching the request. Fails if an override leaves
+    /// the request invalid.
+    pub fn apply(&self, key_id: Option<&str>, tenant: &str, request: &mut CompletionRequest) -> Result<()> {
+        for rule in &self.rules {
+            let matches = rule.matches.model.as_ref().is_none_or(|model| *model == request.model)
+                && rule.matches.key_id.as_deref().is_none_or(|id| Some(id) == key_id)
+                && rule.matches.tenant.as_ref().is_none_or(|rule_tenant| rule_tenant == tenant);
+            if !matches {
+                continue;
+            }
+
+            for replacement in &rule.replace {
+                for message in &mut request.messages {
+                    let applies = replacement
+                        .roles
+                        .as_ref()
+                        .is_none_or(|roles| roles.contains(&message.role));
+                    if let Some(content) = message.content.as_mut().filter(|_| applies) {
+                        content.map_text(|text| {
+                            match replacement.pattern.replace_all(text, replacement.replacement.as_str()) {
+                                std::borrow::Cow::Owned(rewritten) => Some(rewritten),
+                                std::borrow::Cow::Borrowed(_) => None,
+                            }
+                        });
+                    }
+                }
+            }
+
+            if !rule.set.is_empty() {
+                let mut value = serde_json::to_value(&*request)?;
+                if let Some(fields) = value.as_object_mut() {
+                    fields.extend(rule.set.clone());
+                }
+                *request = serde_json::from_value(value).context("Rewrite rule produced an invalid request")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: serde_json::Value) -> RewriteRules {
+        RewriteRules::from_specs(serde_json::from_value(rules).unwrap()).unwrap()
+    }
+
+    fn request() -> CompletionRequest {
+        serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [
+                {"role": "system", "content": "You are ACME's assistant"},
+                {"role": "user", "content": [{"type": "text", "text": "Ask ACME"}, {"type": "image_url", "image_url": {"url": "https://acme.test/a.png"}}]},
+                {"role": "assistant", "content": null, "tool_calls": [{"id": "call_1", "type": "function", "function": {"name": "acme", "arguments": "{}"}}]}
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn texts(request: &CompletionRequest) -> Vec<String> {
+        request
+            .messages
+            .iter()
+            .map(|message| {
+                message
+                    .content
+                    .as_ref()
+                    .map(|content| content.text().into_owned())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replaces_text_in_the_roles_given() {
+        let rules = rules(serde_json::json!([
+            {"replace": [{"pattern": "ACME", "replacement": "Initech", "roles": ["user"]}]},
+            {"replace": [{"pattern": "(?i)you are", "replacement": "Act as"}]}
+        ]));
+        let mut request = request();
+        rules.apply(None, "default", &mut request).unwrap();
+
+        assert_eq!(texts(&request), vec!["Act as ACME's assistant", "Ask Initech", ""]);
+        // Non-text parts and tool calls are left alone
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            value["messages"][1]["content"][1]["image_url"]["url"],
+            "https://acme.test/a.png"
+        );
+        assert_eq!(value["messages"][2]["tool_calls"][0]["function"]["name"], "acme");
+    }
+
+    #[test]
+    fn applies_only_matching_rules() {
+        let rules = rules(serde_json::json!([
+            {"match": {"model": "gpt-4o-mini"}, "set": {"temperature": 1.0}},
+            {"match": {"key_id": "key-1"}, "set": {"max_tokens": 10}},
+            {"match": {"tenant": "acme", "model": "gpt-4o"}, "set": {"temperature": 0.0}}
+        ]));
+
+        let mut request = request();
+        rules.apply(Some("key-2"), "default", &mut request).unwrap();
+        assert_eq!(request.temperature, None);
+        assert_eq!(request.max_tokens, None);
+
+        rules.apply(Some("key-1"), "acme", &mut request).unwrap();
+        assert_eq!(request.temperature, Some(0.0));
+        assert_eq!(request.max_tokens, Some(10));
+    }
+
+    #[test]
+    fn later_overrides_win_and_keep_unknown_parameters() {
+        let rules = rules(serde_json::json!([
+            {"set": {"temperature": 0.2, "seed": 7}},
+            {"set": {"temperature": 0.9}}
+        ]));
+        let mut request = request();
+        rules.apply(None, "default", &mut request).unwrap();
+
+        assert_eq!(request.temperature, Some(0.9));
+        assert_eq!(request.extra["seed"], serde_json::json!(7));
+    }
+
+    #[test]
+    fn rejects_overrides_that_leave_the_request_invalid() {
+        let rules = rules(serde_json::json!([{"set": {"messages": "not a list"}}]));
+        assert!(rules.apply(None, "default", &mut request()).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_patterns_and_unknown_fields() {
+        let invalid = serde_json::json!([{"replace": [{"pattern": "(", "replacement": ""}]}]);
+        assert!(RewriteRules::from_specs(serde_json::from_value(invalid).unwrap()).is_err());
+        let unknown = serde_json::json!([{"match": {"models": "gpt-4o"}}]);
+        assert!(serde_json::from_value::<Vec<RuleSpec>>(unknown).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use crate::config::Config;
+use crate::models::Priority;
+use crate::state::StateManager;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{interval, Duration};
+use tracing::{info, warn};
+
+/// How often each instance re-reads the runtime settings from Redis, picking
+/// up changes made through another instance's admin API.
+const REFRESH_INTERVAL_SECS: u64 = 5;
+
+/// Tunables that can be changed through `/admin/runtime` while silt runs,
+/// without a restart dropping clients that have waited hours for a batch.
+/// Stored in Redis so every instance follows them; anything unset falls back
+/// to the startup configuration.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeSettings {
+    /// Overrides `SILT_BATCH_WINDOW_SECS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_window_secs: Option<u64>,
+    /// Overrides `SILT_BATCH_HIGH_PRIORITY_WINDOW_SECS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_high_priority_window_secs: Option<u64>,
+    /// Overrides `SILT_BATCH_POLL_INTERVAL_SECS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_poll_interval_secs: Option<u64>,
+    /// Overrides `SILT_BATCH_POLL_MAX_INTERVAL_SECS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_poll_max_interval_secs: Option<u64>,
+    /// Stop dispatching; requests keep queueing and batches already upstream
+    /// keep being polled
+    #[serde(default)]
+    pub paused: bool,
+    /// Overrides for individual upstream API keys, by key fingerprint
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub keys: BTreeMap<String, KeyOverride>,
+}
+
+/// Dispatch settings for one upstream API key.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyOverride {
+    /// Stop dispatching this key's requests
+    #[serde(default)]
+    pub paused: bool,
+    /// Overrides `SILT_DISPATCH_MAX_REQUESTS_PER_KEY`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_window: Option<usize>,
+    /// Overrides `SILT_MAX_INFLIGHT_BATCHES_PER_KEY`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_inflight_batches: Option<usize>,
+    /// Overrides `SILT_MAX_INFLIGHT_TOKENS_PER_KEY`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_inflight_tokens: Option<u64>,
+}
+
+/// The settings in effect: the startup configuration with the current
+/// runtime settings applied. Shared by the API and the batch worker.
+pub struct Runtime {
+    config: Arc<Config>,
+    settings: watch::Sender<RuntimeSettings>,
+}
+
+impl Runtime {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            settings: watch::Sender::new(RuntimeSettings::default()),
+        }
+    }
+
+    pub fn settings(&self) -> RuntimeSettings {
+        self.settings.borrow().clone()
+    }
+
+    /// Notified whenever the settings change.
+    pub fn subscribe(&self) -> watch::Receiver<RuntimeSettings> {
+        self.settings.subscribe()
+    }
+
+    /// Switches to new settings, logging if they differ from the current ones.
+    pub fn apply(&self, settings: RuntimeSettings) {
+        let changed = self.settings.send_if_modified(|current| {
+            if *current == settings {
+                return false;
+            }
+            *current = settings;
+            true
+        });
+        if changed {
+            info!("Runtime settings changed: {:?}", self.settings.borrow());
+        }
+    }
+
+    /// Checks the settings as they would combine with the startup
+    /// configuration, by the same rules as the configuration itself.
+    pub fn validate(&self, settings: &RuntimeSettings) -> Result<(), String> {
+        let window = settings.batch_window_secs.unwrap_or(self.config.batch_window_secs);
+        let high_window = settings
+            .batch_high_priority_window_secs
+            .unwrap_or(self.config.batch_high_priority_window_secs);
+        let poll_interval = settings
+            .batch_poll_interval_secs
+            .unwrap_or(self.config.batch_poll_interval_secs);
+        let poll_max_interval = settings
+            .batch_poll_max_interval_secs
+            .unwrap_or(self.config.batch_poll_max_interval_secs);
+
+        if window == 0 || high_window == 0 || poll_interval == 0 {
+            return Err("batch windows and poll intervals must be at least 1 second".to_string());
+        }
+        if high_window > window {
+            return Err(format!(
+                "batch_high_priority_window_secs ({}) is longer than batch_window_secs ({})",
+                high_window, window
+            ));
+        }
+        if poll_max_interval < poll_interval {
+            return Err(format!(
+                "batch_poll_max_interval_secs ({}) is shorter than batch_poll_interval_secs ({})",
+                poll_max_interval, poll_interval
+            ));
+        }
+        if settings.keys.keys().any(|fingerprint| fingerprint.trim().is_empty()) {
+            return Err("key overrides must be keyed by an API key fingerprint".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn lane_window(&self, priority: Priority) -> Duration {
+        let settings = self.settings.borrow();
+        let secs = match priority {
+            Priority::High => settings
+                .batch_high_priority_window_secs
+                .unwrap_or(self.config.batch_high_priority_window_secs),
+            Priority::Low => settings.batch_window_secs.unwrap_or(self.config.batch_window_secs),
+        };
+        Duration::from_secs(secs)
+    }
+
+    pub fn poll_interval_secs(&self) -> u64 {
+        self.settings
+            .borrow()
+            .batch_poll_interval_secs
+            .unwrap_or(self.config.batch_poll_interval_secs)
+    }
+
+    pub fn poll_max_interval_secs(&self) -> u64 {
+        self.settings
+            .borrow()
+            .batch_poll_max_interval_secs
+            .unwrap_or(self.config.batch_poll_max_interval_secs)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.settings.borrow().paused
+    }
+
+    pub fn is_key_paused(&self, fingerprint: &str) -> bool {
+        self.key_override(fingerprint).is_some_and(|key| key.paused)
+    }
+
+    pub fn max_requests_per_key(&self, fingerprint: &str) -> usize {
+        self.key_override(fingerprint)
+            .and_then(|key| key.max_requests_per_window)
+            .unwrap_or(self.config.dispatch_max_requests_per_key)
+    }
+
+    pub fn max_inflight_batches(&self, fingerprint: &str) -> usize {
+        self.key_override(fingerprint)
+            .and_then(|key| key.max_inflight_batches)
+            .unwrap_or(self.config.max_inflight_batches_per_key)
+    }
+
+    pub fn max_inflight_tokens(&self, fingerprint: &str) -> u64 {
+        self.key_override(fingerprint)
+            .and_then(|key| key.max_inflight_tokens)
+            .unwrap_or(self.config.max_inflight_tokens_per_key)
+    }
+
+    fn key_override(&self, fingerprint: &str) -> Option<KeyOverride> {
+        self.settings.borrow().keys.get(fingerprint).cloned()
+    }
+
+    /// Reads the settings stored in Redis.
+    pub async fn refresh(&self, state: &StateManager) -> Result<()> {
+        let settings = state.get_runtime_settings().await?.unwrap_or_default();
+        self.apply(settings);
+        Ok(())
+    }
+
+    /// Keeps the settings in step with Redis.
+    pub async fn watch(&self, state: StateManager) {
+        let mut ticker = interval(Duration::from_secs(REFRESH_INTERVAL_SECS));
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.refresh(&state).await {
+                warn!("Failed to refresh runtime settings: {}", e);
+            }
+        }
+    }
+}
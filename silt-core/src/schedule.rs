@@ -0,0 +1,290 @@
+use chrono::{DateTime, Datelike, Duration, DurationRound, NaiveTime, Timelike, Utc};
+use std::str::FromStr;
+
+/// Furthest ahead a schedule is searched for its next time, so one that
+/// can never fire (e.g. `0 0 30 2 *`) is reported instead of looping.
+const MAX_SEARCH_DAYS: i64 = 366 * 4;
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct ScheduleError(String);
+
+/// When a lane dispatches (`SILT_BATCH_SCHEDULE`), or the minutes dispatch
+/// is paused (`SILT_PAUSE_WINDOWS`), as a five-field cron expression
+/// evaluated in UTC: minute, hour, day of month, month and day of week.
+/// Fields take `*`, values, ranges (`22-23`), steps (`*/30`, `0-5/2`) and
+/// comma-separated lists of those. As in cron, when both the day of month
+/// and day of week are restricted, a day matching either one counts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DispatchSchedule {
+    expression: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl DispatchSchedule {
+    /// The first scheduled minute strictly after `after`, or `None` if there
+    /// is none in the next few years.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let limit = after + Duration::days(MAX_SEARCH_DAYS);
+        let mut at = after.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+
+        while at <= limit {
+            if !self.months[at.month() as usize] {
+                at = start_of_next_month(at)?;
+            } else if !self.matches_day(at) {
+                at = start_of_day(at) + Duration::days(1);
+            } else if !self.hours[at.hour() as usize] {
+                at = at.duration_trunc(Duration::hours(1)).ok()? + Duration::hours(1);
+            } else if !self.minutes[at.minute() as usize] {
+                at += Duration::minutes(1);
+            } else {
+                return Some(at);
+            }
+        }
+        None
+    }
+
+    /// Whether the minute `at` falls in is one of the schedule's.
+    pub fn matches(&self, at: DateTime<Utc>) -> bool {
+        self.months[at.month() as usize]
+            && self.matches_day(at)
+            && self.hours[at.hour() as usize]
+            && self.minutes[at.minute() as usize]
+    }
+
+    /// Whether every minute of the hour `at` falls in is one of the schedule's.
+    pub fn matches_whole_hour(&self, at: DateTime<Utc>) -> bool {
+        self.matches(at) && self.minutes.iter().all(|minute| *minute)
+    }
+
+    /// Whether every minute of the day `at` falls in is one of the schedule's.
+    pub fn matches_whole_day(&self, at: DateTime<Utc>) -> bool {
+        self.matches_whole_hour(at) && self.hours.iter().all(|hour| *hour)
+    }
+
+    fn matches_day(&self, at: DateTime<Utc>) -> bool {
+        let day_of_month = self.days_of_month[at.day() as usize];
+        let day_of_week = self.days_of_week[at.weekday().num_days_from_sunday() as usize];
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (true, false) => day_of_week,
+            (false, true) => day_of_month,
+            (false, false) => day_of_month || day_of_week,
+        }
+    }
+}
+
+impl std::fmt::Display for DispatchSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl FromStr for DispatchSchedule {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(ScheduleError(format!(
+                "'{}' is not a cron expression: expected minute, hour, day of month, month and day of week",
+                s
+            )));
+        };
+
+        // Fields starting with `*` leave the day unrestricted, as in cron
+        let any_day_of_month = days_of_month.starts_with('*');
+        let any_day_of_week = days_of_week.starts_with('*');
+        let mut days_of_week = parse_field(days_of_week, "day of week", 0, 7)?;
+        // Sunday is both 0 and 7
+        days_of_week[0] |= days_of_week[7];
+
+        Ok(Self {
+            expression: fields.join(" "),
+            minutes: parse_field(minutes, "minute", 0, 59)?,
+            hours: parse_field(hours, "hour", 0, 23)?,
+            days_of_month: parse_field(days_of_month, "day of month", 1, 31)?,
+            months: parse_field(months, "month", 1, 12)?,
+            days_of_week,
+            any_day_of_month,
+            any_day_of_week,
+        })
+    }
+}
+
+/// Which values from `min` to `max` a cron field matches, indexed by value.
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> Result<Vec<bool>, ScheduleError> {
+    let invalid = || ScheduleError(format!("Invalid {} field '{}' in dispatch schedule", name, field));
+    let number = |value: &str| value.parse::<u32>().ok().filter(|value| (min..=max).contains(value));
+
+    let mut matches = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (number(start).ok_or_else(invalid)?, number(end).ok_or_else(invalid)?),
+                // A single value with a step runs to the end, as in `5/15`
+                None if step > 1 => (number(range).ok_or_else(invalid)?, max),
+                None => {
+                    let value = number(range).ok_or_else(invalid)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            matches[value as usize] = true;
+        }
+    }
+    Ok(matches)
+}
+
+fn start_of_day(at: DateTime<Utc>) -> DateTime<Utc> {
+    at.with_time(NaiveTime::MIN).single().unwrap_or(at)
+}
+
+fn start_of_next_month(at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let (year, month) = match at.month() {
+        12 => (at.year() + 1, 1),
+        month => (at.year(), month + 1),
+    };
+    at.date_naive()
+        .with_day(1)?
+        .with_year(year)?
+        .with_month(month)
+        .map(|date| date.and_time(NaiveTime::MIN).and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+    }
+
+    fn matched(field: &str, min: u32, max: u32) -> Vec<u32> {
+        let matches = parse_field(field, "test", min, max).unwrap();
+        (min..=max).filter(|value| matches[*value as usize]).collect()
+    }
+
+    #[test]
+    fn parse_field_accepts_values_ranges_steps_and_lists() {
+        assert_eq!(matched("*", 1, 5), vec![1, 2, 3, 4, 5]);
+        assert_eq!(matched("3", 0, 59), vec![3]);
+        assert_eq!(matched("22-23", 0, 23), vec![22, 23]);
+        assert_eq!(matched("*/15", 0, 59), vec![0, 15, 30, 45]);
+        assert_eq!(matched("0-5/2", 0, 23), vec![0, 2, 4]);
+        assert_eq!(matched("50/5", 0, 59), vec![50, 55]);
+        assert_eq!(matched("1,3-4,*/10", 0, 20), vec![0, 1, 3, 4, 10, 20]);
+        assert_eq!(matched("*/2", 1, 12), vec![1, 3, 5, 7, 9, 11]);
+    }
+
+    #[test]
+    fn parse_field_rejects_invalid_fields() {
+        for field in ["", "60", "-1", "5-2", "*/0", "a", "1-", "1,,2", "*/x", "1-2-3"] {
+            assert!(
+                parse_field(field, "minute", 0, 59).is_err(),
+                "'{}' should be rejected",
+                field
+            );
+        }
+        assert!(parse_field("0", "day of month", 1, 31).is_err());
+    }
+
+    #[test]
+    fn from_str_requires_five_fields() {
+        assert!("* * * *".parse::<DispatchSchedule>().is_err());
+        assert!("* * * * * *".parse::<DispatchSchedule>().is_err());
+        let schedule: DispatchSchedule = "0,30  22-23 * * *".parse().unwrap();
+        assert_eq!(schedule.to_string(), "0,30 22-23 * * *");
+    }
+
+    #[test]
+    fn next_after_is_strictly_after() {
+        let schedule: DispatchSchedule = "0,30 * * * *".parse().unwrap();
+        assert_eq!(schedule.next_after(at(2026, 3, 4, 10, 0)), Some(at(2026, 3, 4, 10, 30)));
+        let within_minute = at(2026, 3, 4, 10, 29) + Duration::seconds(59);
+        assert_eq!(schedule.next_after(within_minute), Some(at(2026, 3, 4, 10, 30)));
+        assert_eq!(schedule.next_after(at(2026, 3, 4, 10, 45)), Some(at(2026, 3, 4, 11, 0)));
+    }
+
+    #[test]
+    fn next_after_rolls_over_days_months_and_years() {
+        let nightly: DispatchSchedule = "0 22 * * *".parse().unwrap();
+        assert_eq!(nightly.next_after(at(2026, 1, 31, 23, 0)), Some(at(2026, 2, 1, 22, 0)));
+        assert_eq!(nightly.next_after(at(2026, 12, 31, 22, 0)), Some(at(2027, 1, 1, 22, 0)));
+
+        let month_start: DispatchSchedule = "0 0 1 * *".parse().unwrap();
+        assert_eq!(
+            month_start.next_after(at(2026, 12, 15, 0, 0)),
+            Some(at(2027, 1, 1, 0, 0))
+        );
+
+        let leap_day: DispatchSchedule = "0 0 29 2 *".parse().unwrap();
+        assert_eq!(leap_day.next_after(at(2026, 3, 1, 0, 0)), Some(at(2028, 2, 29, 0, 0)));
+
+        let thirty_first: DispatchSchedule = "0 0 31 * *".parse().unwrap();
+        assert_eq!(
+            thirty_first.next_after(at(2026, 3, 31, 0, 0)),
+            Some(at(2026, 5, 31, 0, 0))
+        );
+    }
+
+    #[test]
+    fn next_after_gives_up_on_impossible_schedules() {
+        let schedule: DispatchSchedule = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(schedule.next_after(at(2026, 1, 1, 0, 0)), None);
+    }
+
+    #[test]
+    fn days_of_month_and_week_match_either_when_both_restricted() {
+        // 2026-03-04 is a Wednesday
+        let schedule: DispatchSchedule = "0 0 1 * 5".parse().unwrap();
+        assert_eq!(schedule.next_after(at(2026, 3, 4, 0, 0)), Some(at(2026, 3, 6, 0, 0)));
+        assert_eq!(schedule.next_after(at(2026, 3, 27, 0, 0)), Some(at(2026, 4, 1, 0, 0)));
+
+        // A starred day of month leaves only the day of week restricting
+        let fridays: DispatchSchedule = "0 0 */1 * 5".parse().unwrap();
+        assert_eq!(fridays.next_after(at(2026, 3, 6, 0, 0)), Some(at(2026, 3, 13, 0, 0)));
+    }
+
+    #[test]
+    fn seven_is_sunday() {
+        // 2026-03-08 is a Sunday
+        let seven: DispatchSchedule = "0 12 * * 7".parse().unwrap();
+        let zero: DispatchSchedule = "0 12 * * 0".parse().unwrap();
+        assert_eq!(seven.next_after(at(2026, 3, 4, 0, 0)), Some(at(2026, 3, 8, 12, 0)));
+        assert_eq!(zero.next_after(at(2026, 3, 4, 0, 0)), Some(at(2026, 3, 8, 12, 0)));
+        assert!("0 12 * * 8".parse::<DispatchSchedule>().is_err());
+    }
+
+    #[test]
+    fn whole_hours_and_days() {
+        let night: DispatchSchedule = "* 22-23 * * *".parse().unwrap();
+        assert!(night.matches_whole_hour(at(2026, 3, 4, 22, 10)));
+        assert!(!night.matches_whole_hour(at(2026, 3, 4, 21, 10)));
+        assert!(!night.matches_whole_day(at(2026, 3, 4, 22, 10)));
+
+        let weekends: DispatchSchedule = "* * * * 6,0".parse().unwrap();
+        assert!(weekends.matches_whole_day(at(2026, 3, 7, 9, 0)));
+        assert!(!weekends.matches_whole_day(at(2026, 3, 6, 9, 0)));
+
+        let half_hours: DispatchSchedule = "0-29 * * * *".parse().unwrap();
+        assert!(half_hours.matches(at(2026, 3, 4, 9, 29)));
+        assert!(!half_hours.matches_whole_hour(at(2026, 3, 4, 9, 0)));
+    }
+}
@@ -0,0 +1,492 @@
+use crate::admin::{
+    apply_tenant_settings, create_key_mapping, create_key_rotation, dashboard, delete_key_mapping, delete_moderation_policy,
+    delete_retention_policy, delete_runtime_settings, delete_tenant_quota, export_usage, get_analytics, get_key_mapping,
+    get_queues, get_runtime_settings, get_tenant_quota, get_usage, list_audit_events, list_failures, list_key_mappings, list_key_rotations,
+    list_quarantined, list_tenants, purge_data, purge_request, reject_quarantined, release_quarantined, retire_rotated_key, stream_events,
+    update_key_mapping, update_moderation_policy, update_retention_policy, update_runtime_settings,
+    update_tenant_quota,
+};
+use crate::alerts::init_alerts;
+use crate::chaos::init_chaos;
+use crate::archive::Archiver;
+use crate::audit::AuditLog;
+use crate::auth::{require_proxy_auth, KeyResolver};
+use crate::batch_worker::BatchWorker;
+use crate::config::{Config, DispatchMode, UpstreamMode};
+use crate::crypto::{init_key_fingerprints, Cipher};
+use crate::handlers::{
+    ApiError, AppState, cancel_request, create_chat_completion, get_batch, get_request_result, get_request_status,
+    health_check, list_models, metrics_handler, readiness_check,
+};
+use crate::hooks::Hooks;
+use crate::ip_filter::{require_admin_ip, require_allowed_ip};
+use crate::metrics::{init_key_labels, metrics};
+use crate::mock_upstream;
+use crate::moderation::Moderator;
+use crate::models::Priority;
+use crate::openai_client::OpenAIClient;
+use crate::rewrite::RewriteRules;
+use crate::runtime::Runtime;
+use crate::state::StateManager;
+use crate::supervisor::panic_message;
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::ConnectInfo,
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Router,
+};
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use socket2::{Domain, Socket, TcpKeepalive, Type};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::load_shed::LoadShedLayer;
+use tower::{BoxError, ServiceBuilder};
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::trace::TraceLayer;
+use tracing::{error, info, warn};
+
+/// What both the server and a standalone worker run on.
+pub struct Services {
+    pub state_manager: StateManager,
+    pub runtime: Arc<Runtime>,
+    pub openai_client: OpenAIClient,
+    pub archiver: Option<Arc<Archiver>>,
+    pub hooks: Arc<Hooks>,
+}
+
+impl Services {
+    /// A batch worker sharing these services, ready to `spawn`.
+    pub fn worker(&self, config: &Arc<Config>) -> BatchWorker {
+        BatchWorker::new(
+            Arc::clone(config),
+            Arc::clone(&self.runtime),
+            self.state_manager.clone(),
+            self.openai_client.clone(),
+            self.archiver.clone(),
+            Arc::clone(&self.hooks),
+        )
+    }
+}
+
+/// Connects to Redis and the upstream and loads everything configured
+/// around them, logging the configuration as it goes.
+pub async fn start_services(config: &Arc<Config>) -> anyhow::Result<Services> {
+    info!("Configuration loaded: {:#?}", config.masked());
+    info!(
+        "Batch window: {}s (high priority: {}s)",
+        config.batch_window_secs, config.batch_high_priority_window_secs
+    );
+    for priority in [Priority::Low, Priority::High] {
+        if let Some(schedule) = config.lane_schedule(priority) {
+            info!("{} priority requests dispatch on the schedule '{}' (UTC)", priority.as_str(), schedule);
+        }
+    }
+    for window in &config.pause_windows {
+        info!("Dispatch pauses during '{}' (UTC)", window);
+    }
+    if config.batch_max_queue_size > 0 {
+        info!("Early dispatch at {} queued requests", config.batch_max_queue_size);
+    }
+    if config.dispatch_mode == DispatchMode::ServiceTier {
+        info!("Dispatch mode: realtime with service_tier={}", config.service_tier);
+    }
+    info!(
+        "Batch poll interval: {}s (backing off to {}s)",
+        config.batch_poll_interval_secs, config.batch_poll_max_interval_secs
+    );
+    info!("TCP keepalive: {}s", config.tcp_keepalive_secs);
+    info!(
+        "Upstream timeouts: request {}s, connect {}s",
+        config.upstream_request_timeout_secs, config.upstream_connect_timeout_secs
+    );
+    if !config.upstream_ca_certs.is_empty() {
+        info!("Upstream CA certificates: {}", config.upstream_ca_certs.join(", "));
+    }
+
+    init_alerts(config)?;
+    init_chaos(config);
+
+    let state_manager = connect_state(config).await?;
+    apply_tenant_settings(&state_manager, &config.tenants).await?;
+
+    // Settings changed through the admin API outlive restarts, and changes
+    // made through other instances are followed
+    let runtime = Arc::new(Runtime::new(Arc::clone(config)));
+    runtime.refresh(&state_manager).await?;
+    let watched_runtime = Arc::clone(&runtime);
+    let runtime_state = state_manager.clone();
+    tokio::spawn(async move {
+        watched_runtime.watch(runtime_state).await;
+    });
+
+    // Create upstream client (shared so the circuit breaker state is global)
+    let openai_client = match config.upstream_mode {
+        UpstreamMode::OpenAI => OpenAIClient::new(config)?,
+        UpstreamMode::Mock => {
+            let base_url = mock_upstream::start(config).await?;
+            warn!(
+                "Using the mock upstream at {} (batches complete after {}s); nothing reaches OpenAI",
+                base_url, config.mock_batch_latency_secs
+            );
+            OpenAIClient::with_base_url(config, base_url)?
+        }
+    };
+
+    let archiver = Archiver::from_config(config)?.map(Arc::new);
+    if let Some(bucket) = &config.archive_s3_bucket {
+        info!("Archiving completed batches to s3://{}/{}", bucket, config.archive_s3_prefix);
+    }
+
+    let hooks = Arc::new(Hooks::from_config(config)?);
+    let (pre_enqueue_hooks, post_result_hooks) = hooks.names();
+    if !pre_enqueue_hooks.is_empty() || !post_result_hooks.is_empty() {
+        info!(
+            "Request hooks: pre-enqueue [{}], post-result [{}]",
+            pre_enqueue_hooks.join(", "),
+            post_result_hooks.join(", ")
+        );
+    }
+
+    Ok(Services {
+        state_manager,
+        runtime,
+        openai_client,
+        archiver,
+        hooks,
+    })
+}
+
+pub async fn connect_state(config: &Config) -> anyhow::Result<StateManager> {
+    init_key_fingerprints(config);
+    init_key_labels(config);
+    let cipher = Cipher::from_config(config)?;
+    match &cipher {
+        Some(cipher) if cipher.encrypts_payloads() => info!("Encrypting stored API keys, prompts and results"),
+        Some(_) => info!("Encrypting stored API keys"),
+        None => {}
+    }
+    let audit_log = AuditLog::from_config(config).await?;
+    if audit_log.is_enabled() {
+        info!("Audit log: {}", config.audit_log);
+    }
+    let state_manager = StateManager::new(&config.redis_url, cipher, audit_log)
+        .await?
+        .with_codec(config.redis_codec)
+        .with_result_ttl(config.result_ttl_secs)
+        .with_event_stream(config.event_stream_max_len)
+        .with_model_prices(config.model_prices.clone());
+    info!("Connected to Redis at {}", config.redis_url);
+    Ok(state_manager)
+}
+
+/// `silt worker` (or `SILT_ROLE=worker`): dispatches and polls batches
+/// without serving the API, for running alongside API-only instances. The
+/// API hands requests over through Redis alone, so neither side needs the
+/// other in-process.
+pub async fn run_worker(config: Arc<Config>) -> anyhow::Result<()> {
+    info!("Starting OpenAI Batch Proxy worker");
+    let services = start_services(&config).await?;
+    services.worker(&config).spawn();
+
+    tokio::signal::ctrl_c().await?;
+    info!("Shutting down worker");
+    Ok(())
+}
+
+/// Runs the API, and unless `SILT_ROLE=api` the batch workers too.
+pub async fn serve(config: Arc<Config>, with_workers: bool) -> anyhow::Result<()> {
+    info!("Starting OpenAI Batch Proxy");
+    let services = start_services(&config).await?;
+    let app_state = build_state(&config, &services)?;
+
+    if with_workers {
+        services.worker(&config).spawn();
+    } else {
+        info!("Serving the API only (SILT_ROLE=api); batches are dispatched by `silt worker` processes");
+    }
+
+    listen(&config, build_router(app_state)).await
+}
+
+/// The state the API handlers share.
+pub fn build_state(config: &Arc<Config>, services: &Services) -> anyhow::Result<Arc<AppState>> {
+    let key_resolver = Arc::new(KeyResolver::new(config, services.state_manager.clone())?);
+    if key_resolver.requires_mapping() {
+        info!(
+            "Virtual keys required ({} from SILT_VIRTUAL_KEYS_FILE and SILT_VIRTUAL_KEYS)",
+            key_resolver.file_mappings().len()
+        );
+    }
+    if config.admin_token.is_some() {
+        info!("Admin API enabled");
+    }
+    if !config.silt_auth_tokens.is_empty() {
+        info!("Proxy authentication enabled (x-silt-auth-token)");
+    }
+
+    let rewrite_rules = RewriteRules::load(config.rewrite_rules_file.as_deref())?;
+    if let Some(path) = &config.rewrite_rules_file {
+        info!("Loaded {} rewrite rule(s) from {}", rewrite_rules.rule_count(), path);
+    }
+
+    Ok(Arc::new(AppState {
+        config: Arc::clone(config),
+        state_manager: services.state_manager.clone(),
+        openai_client: services.openai_client.clone(),
+        circuit_breaker: services.openai_client.circuit_breaker(),
+        key_resolver,
+        archiver: services.archiver.clone(),
+        moderator: Arc::new(Moderator::from_config(config)?),
+        hooks: Arc::clone(&services.hooks),
+        rewrite_rules: Arc::new(rewrite_rules),
+        runtime: Arc::clone(&services.runtime),
+    }))
+}
+
+/// Every silt route, with its state applied, so it can be served as-is or
+/// merged into a larger application.
+pub fn build_router(app_state: Arc<AppState>) -> Router {
+    let config = &app_state.config;
+    let submit = Router::new().route("/v1/chat/completions", post(create_chat_completion));
+    let status = Router::new()
+        .route("/v1/models", get(list_models))
+        .route("/v1/batches/:id", get(get_batch))
+        .route("/v1/requests/:id", get(get_request_status).delete(cancel_request))
+        .route("/v1/requests/:id/result", get(get_request_result));
+    let api = limit_concurrency(submit, "submit", config.submit_concurrency_limit)
+        .merge(limit_concurrency(status, "status", config.status_concurrency_limit))
+        .route_layer(middleware::from_fn_with_state(Arc::clone(&app_state), require_proxy_auth));
+
+    let admin = Router::new()
+        .route("/admin/keys", get(list_key_mappings).post(create_key_mapping))
+        .route(
+            "/admin/keys/:id",
+            get(get_key_mapping).put(update_key_mapping).delete(delete_key_mapping),
+        )
+        .route("/admin/key-rotations", get(list_key_rotations).post(create_key_rotation))
+        .route("/admin/key-rotations/:fingerprint/retire", post(retire_rotated_key))
+        .route("/admin/tenants", get(list_tenants))
+        .route(
+            "/admin/tenants/:tenant/quota",
+            get(get_tenant_quota).put(update_tenant_quota).delete(delete_tenant_quota),
+        )
+        .route(
+            "/admin/tenants/:tenant/retention",
+            put(update_retention_policy).delete(delete_retention_policy),
+        )
+        .route(
+            "/admin/tenants/:tenant/moderation",
+            put(update_moderation_policy).delete(delete_moderation_policy),
+        )
+        .route("/admin/quarantine", get(list_quarantined))
+        .route("/admin/quarantine/:id", delete(reject_quarantined))
+        .route("/admin/quarantine/:id/release", post(release_quarantined))
+        .route("/admin/queues", get(get_queues))
+        .route("/admin/failures", get(list_failures))
+        .route("/admin/analytics", get(get_analytics))
+        .route("/admin/usage", get(get_usage))
+        .route("/admin/usage/export", get(export_usage))
+        .route("/admin/audit", get(list_audit_events))
+        .route("/admin/events", get(stream_events))
+        .route("/admin/data", delete(purge_data))
+        .route("/admin/requests/:id", delete(purge_request))
+        .route("/admin/ui", get(dashboard))
+        .route(
+            "/admin/runtime",
+            get(get_runtime_settings).put(update_runtime_settings).delete(delete_runtime_settings),
+        );
+
+    let admin = limit_concurrency(admin, "admin", config.admin_concurrency_limit)
+        .route_layer(middleware::from_fn_with_state(Arc::clone(&app_state), require_admin_ip));
+    let filtered = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .merge(api)
+        .merge(admin)
+        .route_layer(middleware::from_fn_with_state(Arc::clone(&app_state), require_allowed_ip));
+
+    // Health checks sit outside every limit and filter, so load balancers
+    // and probes always reach them
+    Router::new()
+        .route("/health", get(health_check))
+        .route("/readyz", get(readiness_check))
+        .merge(filtered)
+        .layer(
+            ServiceBuilder::new()
+                .layer(TraceLayer::new_for_http())
+                .layer(CatchPanicLayer::custom(panic_response)),
+        )
+        .with_state(app_state)
+}
+
+/// Caps how many requests to a group of routes are handled at once, shared
+/// across the group. Requests beyond the limit are refused with a 503 rather
+/// than queued, so one group can't tie up the connections another needs.
+fn limit_concurrency(routes: Router<Arc<AppState>>, group: &'static str, limit: usize) -> Router<Arc<AppState>> {
+    if limit == 0 {
+        return routes;
+    }
+    routes.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(move |_: BoxError| async move {
+                metrics().requests_shed_total.with_label_values(&[group]).inc();
+                ApiError::Overloaded(format!("Too many concurrent {} requests, retry shortly", group))
+            }))
+            .layer(LoadShedLayer::new())
+            .layer(GlobalConcurrencyLimitLayer::new(limit)),
+    )
+}
+
+/// Answers a request whose handler panicked with a 500, rather than dropping
+/// the connection without a response.
+fn panic_response(payload: Box<dyn std::any::Any + Send>) -> Response {
+    error!("Handler panicked: {}", panic_message(payload));
+    metrics().handler_panics_total.inc();
+    ApiError::InternalError("Internal server error".to_string()).into_response()
+}
+
+/// Serves the app on `SILT_SERVER_HOST`:`SILT_SERVER_PORT` with TCP keepalives
+/// set, so connections survive multi-hour waits for a batch.
+///
+/// On SIGTERM or Ctrl-C it stops accepting and drains: open connections
+/// finish the request they are serving, however long its wait, up to
+/// `SILT_DRAIN_TIMEOUT_SECS`. With `SILT_REUSE_PORT`, a new process bound to
+/// the same port takes the new connections meanwhile.
+pub async fn listen(config: &Config, app: Router) -> anyhow::Result<()> {
+    // Bind to address
+    let addr: SocketAddr = format!("{}:{}", config.server_host, config.server_port).parse()?;
+    info!("Binding to {}", addr);
+
+    let listener = bind(addr, config.reuse_port)?;
+
+    info!("Server listening on {}", addr);
+    info!("Ready to accept requests");
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    // Accept connections with TCP keepalive
+    loop {
+        let (socket, remote_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            () = &mut shutdown => break,
+        };
+        // Reap connections that have closed
+        while connections.try_join_next().is_some() {}
+
+        // Configure TCP keepalive
+        let socket_ref = socket2::SockRef::from(&socket);
+        let keepalive = TcpKeepalive::new()
+            .with_time(Duration::from_secs(config.tcp_keepalive_secs))
+            .with_interval(Duration::from_secs(30));
+
+        socket_ref.set_tcp_keepalive(&keepalive)?;
+
+        // Disable Nagle's algorithm for lower latency
+        socket_ref.set_nodelay(true)?;
+
+        // The peer address, for `ip_filter`
+        let tower_service = ServiceBuilder::new()
+            .map_request(move |mut request: hyper::Request<hyper::body::Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(remote_addr));
+                request
+            })
+            .service(app.clone());
+        let mut shutdown_rx = shutdown_rx.clone();
+
+        connections.spawn(async move {
+            let socket = TokioIo::new(socket);
+
+            // Convert tower service to hyper service
+            let hyper_service = TowerToHyperService::new(tower_service);
+
+            // Serve connection with very long timeouts
+            let conn = http1::Builder::new()
+                .keep_alive(true)
+                .serve_connection(socket, hyper_service);
+            tokio::pin!(conn);
+
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = shutdown_rx.changed() => {
+                    // Finish the request in progress, then close
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(err) = result {
+                tracing::error!("Error serving connection from {}: {}", remote_addr, err);
+            }
+        });
+    }
+
+    // Closing the listener sends new connections to any other process on the port
+    drop(listener);
+    let _ = shutdown_tx.send(true);
+    info!("Stopped accepting connections; draining {} open connection(s)", connections.len());
+
+    let drained = async { while connections.join_next().await.is_some() {} };
+    if config.drain_timeout_secs == 0 {
+        drained.await;
+    } else if tokio::time::timeout(Duration::from_secs(config.drain_timeout_secs), drained).await.is_err() {
+        warn!(
+            "Drain timed out after {}s; closing {} connection(s)",
+            config.drain_timeout_secs,
+            connections.len()
+        );
+    }
+    info!("Server stopped");
+    Ok(())
+}
+
+/// A listening socket on `addr`. With `reuse_port`, SO_REUSEPORT lets another
+/// silt process bind the same port alongside this one, for handing over
+/// without refusing connections.
+fn bind(addr: SocketAddr, reuse_port: bool) -> anyhow::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // As the standard library does, so a restart can rebind straight away
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        anyhow::bail!("SILT_REUSE_PORT is only supported on Unix");
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Resolves on SIGTERM (as sent by orchestrators and `kill`) or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = terminate.recv() => info!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => info!("Received Ctrl-C"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Received Ctrl-C");
+    }
+}
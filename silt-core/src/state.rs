@@ -0,0 +1,2122 @@
+use crate::audit::{AuditEvent, AuditEventKind, AuditLog, AuditQuery};
+use crate::chaos::chaos;
+use crate::codec::RedisCodec;
+use crate::config::ModelPrice;
+use crate::crypto::{key_fingerprint, Cipher};
+use crate::events::{self, Event, EventKind, EventReader};
+use crate::models::{
+    Analytics, BatchProgress, CompletionResponse, FailureRecord, KeyMapping, KeyRotation, ModerationPolicy, Priority,
+    PurgeRecord, RequestError, RequestErrorKind, RequestState, RequestStatus, RetentionPolicy, StatusTransition, TenantQuota, TenantUsage, UpstreamScope, Usage, UsageCounts,
+};
+use crate::metrics::metrics;
+use crate::runtime::RuntimeSettings;
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// How many recent batch durations are kept per model for ETA estimates.
+const BATCH_DURATION_SAMPLES: isize = 50;
+
+/// How long the per-day analytics counters are kept.
+pub const ANALYTICS_RETENTION_DAYS: i64 = 90;
+
+/// How many purge audit records are kept.
+const PURGE_LOG_SIZE: isize = 1000;
+
+/// How many failed requests are kept per tenant for the admin dashboard.
+const RECENT_FAILURES_SIZE: isize = 100;
+
+/// How many status changes are kept per request. Requeues are capped, so
+/// only a request stuck in a loop reaches it.
+const HISTORY_MAX_LEN: isize = 100;
+
+/// Claims the right to finish a request (KEYS[1] is its outcome key) as
+/// ARGV[1], `complete` or `failed`, for ARGV[2] seconds. A request finishes
+/// once, except that a late result may still complete one that failed.
+const CLAIM_OUTCOME_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+if current == ARGV[1] or current == 'complete' then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+return 1
+";
+
+/// Claims a request (ARGV[1]) for dispatch if it is still in its lane's queue
+/// (KEYS[1]), marking it (KEYS[2]) for ARGV[2] seconds so it can't be
+/// cancelled while its batch is uploaded.
+const CLAIM_DISPATCH_SCRIPT: &str = r"
+if redis.call('SISMEMBER', KEYS[1], ARGV[1]) == 0 then
+    return 0
+end
+redis.call('SET', KEYS[2], '1', 'EX', ARGV[2])
+return 1
+";
+
+/// Takes a request (ARGV[1]) off its lane's queue (KEYS[1]) unless a
+/// dispatcher has claimed it (KEYS[2]).
+const CANCEL_QUEUED_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[2]) == 1 then
+    return 0
+end
+return redis.call('SREM', KEYS[1], ARGV[1])
+";
+
+/// How long a dispatch claim lasts if its dispatcher dies before releasing it.
+const DISPATCH_CLAIM_TTL_SECS: u64 = 3600;
+
+/// How long results are kept unless `with_result_ttl` says otherwise, the
+/// same as request state.
+const DEFAULT_RESULT_TTL_SECS: u64 = 48 * 3600;
+
+/// Tenant whose state lives under the original, unprefixed keys.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Marks a dispatch trigger from `silt flush`, followed by the lane.
+pub const FLUSH_PREFIX: &str = "flush:";
+
+/// Redis-backed request, queue and batch state.
+///
+/// Request state is namespaced per tenant: `for_tenant` returns a manager
+/// whose keys and completion channels are prefixed with `tenant:{id}:`. Key
+/// mappings, rate limit counters, dispatch schedules and batch duration
+/// history are shared across tenants.
+///
+/// With a cipher configured, upstream API keys are encrypted before they are
+/// written (in request state, batch records and key mappings) and decrypted
+/// when read back, as are request messages and results if payload
+/// encryption is enabled.
+#[derive(Clone)]
+pub struct StateManager {
+    redis: redis::aio::ConnectionManager,
+    client: redis::Client,
+    tenant: String,
+    prefix: String,
+    cipher: Option<Arc<Cipher>>,
+    audit: Arc<AuditLog>,
+    codec: RedisCodec,
+    result_ttl_secs: u64,
+    event_stream_max_len: usize,
+    model_prices: Arc<HashMap<String, ModelPrice>>,
+}
+
+impl StateManager {
+    pub async fn new(redis_url: &str, cipher: Option<Cipher>, audit: AuditLog) -> Result<Self> {
+        let client = redis::Client::open(redis_url)?;
+        let redis = redis::aio::ConnectionManager::new(client.clone()).await?;
+        Ok(Self {
+            redis,
+            client,
+            tenant: DEFAULT_TENANT.to_string(),
+            prefix: String::new(),
+            cipher: cipher.map(Arc::new),
+            audit: Arc::new(audit),
+            codec: RedisCodec::default(),
+            result_ttl_secs: DEFAULT_RESULT_TTL_SECS,
+            event_stream_max_len: 0,
+            model_prices: Arc::new(HashMap::new()),
+        })
+    }
+
+    /// Writes request state and batch mappings with `codec` from now on.
+    /// Values already stored are read in whichever format they were written.
+    pub fn with_codec(mut self, codec: RedisCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Keeps results for `secs`, independently of the 48 hours request state
+    /// is kept for.
+    pub fn with_result_ttl(mut self, secs: u64) -> Self {
+        self.result_ttl_secs = secs;
+        self
+    }
+
+    /// Publishes operator events to a Redis stream of about `max_len`
+    /// entries, for `/admin/events`. Nothing is published with 0.
+    pub fn with_event_stream(mut self, max_len: usize) -> Self {
+        self.event_stream_max_len = max_len;
+        self
+    }
+
+    /// Records the estimated cost of completed requests in analytics, for
+    /// models with a price.
+    pub fn with_model_prices(mut self, prices: HashMap<String, ModelPrice>) -> Self {
+        self.model_prices = Arc::new(prices);
+        self
+    }
+
+    /// A manager scoped to another tenant's keys, sharing the connection.
+    pub fn for_tenant(&self, tenant: &str) -> Self {
+        let prefix = if tenant == DEFAULT_TENANT {
+            String::new()
+        } else {
+            format!("tenant:{}:", tenant)
+        };
+        Self {
+            redis: self.redis.clone(),
+            client: self.client.clone(),
+            tenant: tenant.to_string(),
+            prefix,
+            cipher: self.cipher.clone(),
+            audit: Arc::clone(&self.audit),
+            codec: self.codec,
+            result_ttl_secs: self.result_ttl_secs,
+            event_stream_max_len: self.event_stream_max_len,
+            model_prices: Arc::clone(&self.model_prices),
+        }
+    }
+
+    pub fn tenant(&self) -> &str {
+        &self.tenant
+    }
+
+    fn key(&self, key: impl std::fmt::Display) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// Records a lifecycle event for a request in the audit log, if enabled.
+    async fn audit(&self, kind: AuditEventKind, state: &RequestState, detail: Option<String>) {
+        if !self.audit.is_enabled() {
+            return;
+        }
+        let mut event = AuditEvent::new(kind, &self.tenant, &state.request_id);
+        event.api_key_fingerprint = Some(key_fingerprint(&state.api_key));
+        event.batch_id = state.batch_id.clone();
+        event.detail = detail;
+        self.audit.record(&self.redis, event).await;
+    }
+
+    /// Appends the status a request just moved to onto its history. Like the
+    /// audit log, a failure here never fails the transition itself.
+    async fn record_transition(&self, state: &RequestState, cause: impl Into<String>) {
+        let transition = StatusTransition {
+            at: state.updated_at,
+            status: state.status.clone(),
+            batch_id: state.batch_id.clone(),
+            cause: cause.into(),
+        };
+        let entry = match serde_json::to_string(&transition) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Failed to encode a status change of {}: {}", state.request_id, e);
+                return;
+            }
+        };
+
+        let key = self.history_key(&state.request_id);
+        let mut conn = self.redis.clone();
+        let recorded: redis::RedisResult<()> = redis::pipe()
+            .rpush(&key, entry)
+            .ltrim(&key, -HISTORY_MAX_LEN, -1)
+            .expire(&key, 48 * 3600)
+            .query_async(&mut conn)
+            .await;
+        if let Err(e) = recorded {
+            warn!("Failed to record a status change of {}: {}", state.request_id, e);
+        }
+    }
+
+    fn history_key(&self, request_id: &str) -> String {
+        self.key(format_args!("history:{}", request_id))
+    }
+
+    /// A request's status changes, oldest first.
+    pub async fn get_history(&self, request_id: &str) -> Result<Vec<StatusTransition>> {
+        let mut conn = self.redis.clone();
+        let entries: Vec<String> = conn.lrange(self.history_key(request_id), 0, -1).await?;
+        entries
+            .iter()
+            .map(|entry| Ok(serde_json::from_str(entry)?))
+            .collect()
+    }
+
+    /// Publishes an operator event for this tenant, if the event stream is
+    /// enabled. Failures are logged, as for the audit log.
+    async fn publish_event(&self, event: Event) {
+        if self.event_stream_max_len == 0 {
+            return;
+        }
+        if let Err(e) = events::publish(&self.redis, self.event_stream_max_len, &event).await {
+            warn!("Failed to publish {} event: {}", event.event.as_str(), e);
+        }
+    }
+
+    fn request_event(&self, kind: EventKind, state: &RequestState) -> Event {
+        Event::new(kind, &self.tenant)
+            .request(&state.request_id)
+            .batch(state.batch_id.as_deref())
+    }
+
+    /// Follows the operator event stream, from after the entry `after` or
+    /// from now.
+    pub async fn read_events(&self, after: Option<String>) -> Result<EventReader> {
+        EventReader::new(&self.client, after).await
+    }
+
+    /// Events from the audit log, or `None` if it is not kept in Redis.
+    pub async fn audit_events(&self, query: &AuditQuery) -> Result<Option<Vec<AuditEvent>>> {
+        if !self.audit.is_queryable() {
+            return Ok(None);
+        }
+        Ok(Some(self.audit.query(&self.redis, query).await?))
+    }
+
+    fn encrypt_secret(&self, secret: &str) -> Result<String> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(secret),
+            None => Ok(secret.to_string()),
+        }
+    }
+
+    /// Plaintext values written before encryption was enabled pass through.
+    fn decrypt_secret(&self, stored: &str) -> Result<String> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(stored),
+            None if stored.starts_with(crate::crypto::ENCRYPTED_PREFIX) => {
+                anyhow::bail!("Found an encrypted value but no SILT_ENCRYPTION_KEY is configured")
+            }
+            None => Ok(stored.to_string()),
+        }
+    }
+
+    /// Serializes request state for Redis, leaving out the result, which
+    /// `store_result` keeps under its own key. The API key is encrypted
+    /// whenever a cipher is configured; with `SILT_ENCRYPT_PAYLOADS` the
+    /// messages are replaced by an encrypted string too.
+    fn encode_state(&self, state: &RequestState) -> Result<Vec<u8>> {
+        if self.cipher.is_none() && state.result.is_none() {
+            return self.codec.encode(state);
+        }
+
+        let mut value = serde_json::to_value(state)?;
+        value["result"] = serde_json::Value::Null;
+        if let Some(cipher) = &self.cipher {
+            value["api_key"] = cipher.encrypt(&state.api_key)?.into();
+            if !state.scope.headers.is_empty() {
+                value["scope"] = serde_json::to_value(self.encrypt_scope(&state.scope)?)?;
+            }
+            if cipher.encrypts_payloads() {
+                let messages = serde_json::to_string(&state.request.messages)?;
+                value["request"]["messages"] = cipher.encrypt(&messages)?.into();
+            }
+        }
+        self.codec.encode(&value)
+    }
+
+    /// Encrypted fields are recognised by being strings where JSON is
+    /// expected, so state written with or without payload encryption reads
+    /// back the same. State written before results moved to their own key
+    /// may still carry one inline.
+    fn decode_state(&self, stored: &[u8]) -> Result<RequestState> {
+        let mut value: serde_json::Value = RedisCodec::decode(stored)?;
+        for pointer in ["/request/messages", "/result"] {
+            if let Some(field) = value.pointer_mut(pointer) {
+                if let Some(encrypted) = field.as_str() {
+                    *field = serde_json::from_str(&self.decrypt_secret(encrypted)?)?;
+                }
+            }
+        }
+
+        let mut state: RequestState = serde_json::from_value(value)?;
+        state.api_key = self.decrypt_secret(&state.api_key)?;
+        self.decrypt_scope(&mut state.scope)?;
+        Ok(state)
+    }
+
+    /// Passthrough headers may carry credentials for a gateway, so their
+    /// values are encrypted like API keys.
+    fn encrypt_scope(&self, scope: &UpstreamScope) -> Result<UpstreamScope> {
+        let mut stored = scope.clone();
+        for value in stored.headers.values_mut() {
+            *value = self.encrypt_secret(value)?;
+        }
+        Ok(stored)
+    }
+
+    fn decrypt_scope(&self, scope: &mut UpstreamScope) -> Result<()> {
+        for value in scope.headers.values_mut() {
+            *value = self.decrypt_secret(value)?;
+        }
+        Ok(())
+    }
+
+    fn encode_mapping(&self, mapping: &KeyMapping) -> Result<String> {
+        let mut stored = mapping.clone();
+        stored.upstream_key = self.encrypt_secret(&mapping.upstream_key)?;
+        Ok(serde_json::to_string(&stored)?)
+    }
+
+    fn decode_mapping(&self, json: &str) -> Result<KeyMapping> {
+        let mut mapping: KeyMapping = serde_json::from_str(json)?;
+        mapping.upstream_key = self.decrypt_secret(&mapping.upstream_key)?;
+        Ok(mapping)
+    }
+
+    /// Redis set holding queued request IDs for a lane. The default tenant's
+    /// low lane keeps the original key so queues from earlier versions are
+    /// still dispatched.
+    fn queue_key(&self, priority: Priority) -> String {
+        match priority {
+            Priority::High => self.key("queued_requests:high"),
+            Priority::Low => self.key("queued_requests"),
+        }
+    }
+
+    /// Sorted set of the requests in either lane's queue, scored by when they
+    /// were submitted, so their ages are read without loading them.
+    fn queued_since_key(&self) -> String {
+        self.key("queued_since")
+    }
+
+    /// Every tenant that has submitted requests, starting with the default.
+    pub async fn list_tenants(&self) -> Result<Vec<String>> {
+        let mut conn = self.redis.clone();
+        let mut tenants: Vec<String> = conn.smembers("tenants").await?;
+        tenants.retain(|tenant| tenant != DEFAULT_TENANT);
+        tenants.sort();
+        tenants.insert(0, DEFAULT_TENANT.to_string());
+        Ok(tenants)
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        let mut conn = self.redis.clone();
+        redis::cmd("PING").query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Commands Redis has processed since it started, from `INFO stats`.
+    pub async fn commands_processed(&self) -> Result<u64> {
+        let mut conn = self.redis.clone();
+        let info: String = redis::cmd("INFO").arg("stats").query_async(&mut conn).await?;
+        info.lines()
+            .find_map(|line| line.strip_prefix("total_commands_processed:"))
+            .and_then(|count| count.trim().parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("Redis INFO stats has no total_commands_processed"))
+    }
+
+    pub async fn get_request(&self, request_id: &str) -> Result<Option<RequestState>> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("request:{}", request_id));
+        let data: Option<Vec<u8>> = conn.get(&key).await?;
+
+        match data {
+            Some(stored) => {
+                let mut state = self.decode_state(&stored)?;
+                if let Some(result) = state.result.take() {
+                    // Moves a result stored inline by an earlier version to its own key
+                    self.store_result(request_id, &result).await?;
+                    self.save_keeping_ttl(&state).await?;
+                }
+                Ok(Some(state))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Several requests in one round trip, in the order asked for.
+    pub async fn get_requests(&self, request_ids: &[String]) -> Result<Vec<Option<RequestState>>> {
+        if request_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.redis.clone();
+        let keys: Vec<String> = request_ids
+            .iter()
+            .map(|request_id| self.key(format_args!("request:{}", request_id)))
+            .collect();
+        let data: Vec<Option<Vec<u8>>> = redis::cmd("MGET").arg(&keys).query_async(&mut conn).await?;
+        data.iter()
+            .map(|stored| stored.as_deref().map(|stored| self.decode_state(stored)).transpose())
+            .collect()
+    }
+
+    pub async fn create_request(&self, state: RequestState) -> Result<RequestState> {
+        chaos().redis_write("create_request")?;
+        let mut conn = self.redis.clone();
+
+        let key = self.key(format_args!("request:{}", state.request_id));
+        let json = self.encode_state(&state)?;
+
+        // Set with 48 hour expiry
+        conn.set_ex::<_, _, ()>(&key, json, 48 * 3600).await?;
+
+        if let Some(deadline_at) = state.deadline_at {
+            conn.zadd::<_, _, _, ()>(self.key("request_deadlines"), &state.request_id, deadline_at.timestamp())
+                .await?;
+        }
+
+        // Add to the queued set for its lane
+        conn.sadd::<_, _, ()>(self.queue_key(state.priority), &state.request_id).await?;
+        conn.zadd::<_, _, _, ()>(self.queued_since_key(), &state.request_id, state.created_at.timestamp())
+            .await?;
+
+        if !self.prefix.is_empty() {
+            conn.sadd::<_, _, ()>("tenants", &self.tenant).await?;
+        }
+
+        self.record_transition(&state, "submitted").await;
+        self.audit(AuditEventKind::Submitted, &state, None).await;
+        self.publish_event(self.request_event(EventKind::Enqueued, &state).detail(&state.request.model))
+            .await;
+        Ok(state)
+    }
+
+    /// Stores a request flagged by moderation without queueing it.
+    pub async fn quarantine_request(&self, state: RequestState) -> Result<RequestState> {
+        let mut conn = self.redis.clone();
+
+        let key = self.key(format_args!("request:{}", state.request_id));
+        let json = self.encode_state(&state)?;
+        conn.set_ex::<_, _, ()>(&key, json, 48 * 3600).await?;
+        conn.sadd::<_, _, ()>(self.key("quarantine"), &state.request_id).await?;
+
+        if !self.prefix.is_empty() {
+            conn.sadd::<_, _, ()>("tenants", &self.tenant).await?;
+        }
+
+        let detail = state.flagged_categories.as_ref().map(|categories| categories.join(", "));
+        let cause = match &detail {
+            Some(categories) => format!("flagged by moderation: {}", categories),
+            None => "flagged by moderation".to_string(),
+        };
+        self.record_transition(&state, cause).await;
+        self.audit(AuditEventKind::Quarantined, &state, detail).await;
+        Ok(state)
+    }
+
+    /// This tenant's quarantined requests, oldest first. Ids whose request has
+    /// expired or was already handled are dropped from the set.
+    pub async fn list_quarantined(&self) -> Result<Vec<RequestState>> {
+        let mut conn = self.redis.clone();
+        let quarantine_key = self.key("quarantine");
+        let request_ids: Vec<String> = conn.smembers(&quarantine_key).await?;
+
+        let mut states = Vec::new();
+        for request_id in request_ids {
+            match self.get_request(&request_id).await? {
+                Some(state) if state.status == RequestStatus::Quarantined => states.push(state),
+                _ => conn.srem::<_, _, ()>(&quarantine_key, &request_id).await?,
+            }
+        }
+        states.sort_by_key(|state| state.created_at);
+        Ok(states)
+    }
+
+    /// Queues a quarantined request for batching. Returns `None` if there is no
+    /// such quarantined request.
+    pub async fn release_quarantined(&self, request_id: &str) -> Result<Option<RequestState>> {
+        let mut conn = self.redis.clone();
+        let Some(mut state) = self.get_request(request_id).await? else {
+            return Ok(None);
+        };
+        if state.status != RequestStatus::Quarantined {
+            return Ok(None);
+        }
+
+        state.status = RequestStatus::Queued;
+        state.updated_at = Utc::now();
+        self.save_keeping_ttl(&state).await?;
+
+        if let Some(deadline_at) = state.deadline_at {
+            conn.zadd::<_, _, _, ()>(self.key("request_deadlines"), request_id, deadline_at.timestamp())
+                .await?;
+        }
+        conn.sadd::<_, _, ()>(self.queue_key(state.priority), request_id).await?;
+        conn.zadd::<_, _, _, ()>(self.queued_since_key(), request_id, state.created_at.timestamp())
+            .await?;
+        conn.srem::<_, _, ()>(self.key("quarantine"), request_id).await?;
+
+        self.record_transition(&state, "released from quarantine").await;
+        self.audit(AuditEventKind::Released, &state, None).await;
+        self.publish_event(self.request_event(EventKind::Enqueued, &state).detail("released from quarantine"))
+            .await;
+        Ok(Some(state))
+    }
+
+    /// Fails a quarantined request. Returns false if there is no such
+    /// quarantined request.
+    pub async fn reject_quarantined(&self, request_id: &str) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        match self.get_request(request_id).await? {
+            Some(state) if state.status == RequestStatus::Quarantined => {
+                conn.srem::<_, _, ()>(self.key("quarantine"), request_id).await?;
+                let error = RequestError::new(RequestErrorKind::Moderation, "Rejected by moderation");
+                self.fail_request(request_id, error).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub async fn update_status(
+        &self,
+        request_id: &str,
+        status: RequestStatus,
+        batch_id: Option<String>,
+    ) -> Result<()> {
+        self.update_status_at(request_id, status, batch_id, None).await
+    }
+
+    /// `update_status`, also recording when the dispatcher started uploading
+    /// the batch file the request is in.
+    async fn update_status_at(
+        &self,
+        request_id: &str,
+        status: RequestStatus,
+        batch_id: Option<String>,
+        upload_started_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        chaos().redis_write("update_status")?;
+        let mut conn = self.redis.clone();
+
+        if let Some(mut state) = self.get_request(request_id).await? {
+            // A request finished out of band (e.g. by deadline fallback) keeps its final state
+            if matches!(state.status, RequestStatus::Complete | RequestStatus::Failed) {
+                return Ok(());
+            }
+
+            let now = Utc::now();
+            let changed = state.status != status || state.batch_id != batch_id;
+            let audit_kind = match status {
+                RequestStatus::Queued => Some(AuditEventKind::Requeued),
+                RequestStatus::Batching | RequestStatus::Processing if state.dispatched_at.is_none() => {
+                    Some(AuditEventKind::Dispatched)
+                }
+                _ => None,
+            };
+            match status {
+                RequestStatus::Queued => {
+                    state.upload_started_at = None;
+                    state.dispatched_at = None;
+                }
+                RequestStatus::Batching | RequestStatus::Processing => {
+                    if state.dispatched_at.is_none() {
+                        state.upload_started_at = upload_started_at;
+                    }
+                    state.dispatched_at.get_or_insert(now);
+                }
+                RequestStatus::Complete | RequestStatus::Failed | RequestStatus::Quarantined => {}
+            }
+
+            state.status = status;
+            state.batch_id = batch_id;
+            state.updated_at = now;
+
+            let key = self.key(format_args!("request:{}", request_id));
+            let json = self.encode_state(&state)?;
+            conn.set_ex::<_, _, ()>(&key, json, 48 * 3600).await?;
+
+            if changed {
+                let cause = match (&state.status, &state.batch_id) {
+                    (RequestStatus::Queued, _) => "requeued".to_string(),
+                    (RequestStatus::Batching, Some(batch_id)) => format!("added to batch {}", batch_id),
+                    (RequestStatus::Processing, Some(batch_id)) => format!("batch {} in progress", batch_id),
+                    (RequestStatus::Processing, None) => "sent to the realtime endpoint".to_string(),
+                    _ => "status updated".to_string(),
+                };
+                self.record_transition(&state, cause).await;
+            }
+            if let Some(kind) = audit_kind {
+                self.audit(kind, &state, None).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn complete_request(
+        &self,
+        request_id: &str,
+        result: CompletionResponse,
+        upstream_completed_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        chaos().redis_write("complete_request")?;
+        let mut conn = self.redis.clone();
+
+        if let Some(mut state) = self.get_request(request_id).await? {
+            // Results processed again, e.g. by a poller restarted part way
+            // through a batch, are neither stored nor announced twice
+            if state.status == RequestStatus::Complete
+                || !self.claim_outcome(request_id, RequestStatus::Complete).await?
+            {
+                debug!("Request {} is already complete, skipping its result", request_id);
+                return Ok(());
+            }
+
+            let now = Utc::now();
+            let total_tokens = result.usage.total_tokens;
+            state.status = RequestStatus::Complete;
+            state.error = None;
+            state.updated_at = now;
+            state.upstream_completed_at = upstream_completed_at;
+            state.completed_at = Some(now);
+
+            // The result first, so the request is never complete without one
+            let key = self.key(format_args!("request:{}", request_id));
+            let stored = async {
+                self.store_result(request_id, &result).await?;
+                let json = self.encode_state(&state)?;
+                // Keep completed requests for 48 hours
+                conn.set_ex::<_, _, ()>(&key, json, 48 * 3600).await?;
+                anyhow::Ok(())
+            }
+            .await;
+            if let Err(e) = stored {
+                self.release_outcome(request_id).await;
+                return Err(e);
+            }
+            state.result = Some(result);
+
+            // The outcome is claimed and stored, so a retry would skip the rest:
+            // each remaining step is tried whatever happens to the others
+            after_outcome(request_id, "count the tokens", self.add_daily_tokens(u64::from(total_tokens)).await);
+            after_outcome(request_id, "index the request", self.index_finished(request_id).await);
+            after_outcome(
+                request_id,
+                "clear the deadline",
+                conn.zrem::<_, _, ()>(self.key("request_deadlines"), request_id).await.map_err(Into::into),
+            );
+
+            // Publish completion event
+            let channel = self.key(format_args!("completion:{}", request_id));
+            after_outcome(
+                request_id,
+                "notify waiters",
+                conn.publish::<_, _, ()>(&channel, "complete").await.map_err(Into::into),
+            );
+
+            let cause = match &state.batch_id {
+                Some(batch_id) => format!("result received from batch {}", batch_id),
+                None => "result received".to_string(),
+            };
+            self.record_transition(&state, cause).await;
+            self.audit(AuditEventKind::Completed, &state, Some(format!("{} tokens", total_tokens)))
+                .await;
+            self.publish_event(self.request_event(EventKind::Completed, &state).detail(format!("{} tokens", total_tokens)))
+                .await;
+            let usage = state.result.as_ref().map(|result| &result.usage);
+            after_outcome(request_id, "record analytics", self.record_request_outcome(&state, usage).await);
+            for (phase, seconds) in state.phase_durations() {
+                metrics()
+                    .request_phase_seconds
+                    .with_label_values(&[self.tenant(), phase])
+                    .observe(seconds);
+            }
+
+            after_outcome(request_id, "finish duplicates", self.finish_duplicates(&state).await);
+        }
+
+        Ok(())
+    }
+
+    pub async fn fail_request(
+        &self,
+        request_id: &str,
+        error: RequestError,
+    ) -> Result<()> {
+        chaos().redis_write("fail_request")?;
+        let mut conn = self.redis.clone();
+
+        if let Some(mut state) = self.get_request(request_id).await? {
+            // A finished request keeps its first outcome, so waiters never
+            // hear it failed after it completed
+            if matches!(state.status, RequestStatus::Complete | RequestStatus::Failed)
+                || !self.claim_outcome(request_id, RequestStatus::Failed).await?
+            {
+                debug!("Request {} has already finished, not failing it: {}", request_id, error);
+                return Ok(());
+            }
+
+            let now = Utc::now();
+            state.status = RequestStatus::Failed;
+            state.error = Some(error.clone());
+            state.updated_at = now;
+            state.completed_at = Some(now);
+
+            let key = self.key(format_args!("request:{}", request_id));
+            let stored = match self.encode_state(&state) {
+                Ok(json) => conn.set_ex::<_, _, ()>(&key, json, 48 * 3600).await.map_err(Into::into),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = stored {
+                self.release_outcome(request_id).await;
+                return Err(e);
+            }
+
+            // As for completions, the remaining steps are tried whatever happens
+            after_outcome(request_id, "index the request", self.index_finished(request_id).await);
+            after_outcome(
+                request_id,
+                "clear the deadline",
+                conn.zrem::<_, _, ()>(self.key("request_deadlines"), request_id).await.map_err(Into::into),
+            );
+
+            // Publish completion event (even for failures)
+            let channel = self.key(format_args!("completion:{}", request_id));
+            after_outcome(
+                request_id,
+                "notify waiters",
+                conn.publish::<_, _, ()>(&channel, &error.message).await.map_err(Into::into),
+            );
+
+            if let Err(e) = self.record_failure(&state).await {
+                warn!("Failed to record failure of {}: {}", request_id, e);
+            }
+            self.record_transition(&state, error.message.as_str()).await;
+            self.publish_event(self.request_event(EventKind::Failed, &state).detail(error.kind.as_str()))
+                .await;
+            self.audit(AuditEventKind::Failed, &state, Some(error.message)).await;
+            after_outcome(request_id, "record analytics", self.record_request_outcome(&state, None).await);
+
+            after_outcome(request_id, "finish duplicates", self.finish_duplicates(&state).await);
+        }
+
+        Ok(())
+    }
+
+    fn outcome_key(&self, request_id: &str) -> String {
+        self.key(format_args!("outcome:{}", request_id))
+    }
+
+    /// Claims the right to finish a request as `status` (see
+    /// `CLAIM_OUTCOME_SCRIPT`). Returns false if another worker, or an
+    /// earlier pass over the same results, already finished it.
+    async fn claim_outcome(&self, request_id: &str, status: RequestStatus) -> Result<bool> {
+        let outcome = match status {
+            RequestStatus::Complete => "complete",
+            _ => "failed",
+        };
+        let mut conn = self.redis.clone();
+        let claimed: i64 = redis::Script::new(CLAIM_OUTCOME_SCRIPT)
+            .key(self.outcome_key(request_id))
+            .arg(outcome)
+            .arg(48 * 3600)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(claimed == 1)
+    }
+
+    /// Gives up a claim whose outcome could not be stored, so the request
+    /// can still be finished.
+    async fn release_outcome(&self, request_id: &str) {
+        let mut conn = self.redis.clone();
+        if let Err(e) = conn.del::<_, ()>(self.outcome_key(request_id)).await {
+            warn!("Failed to release the outcome claim on {}: {}", request_id, e);
+        }
+    }
+
+    /// Records `request_id` as the request holding a content hash. Returns the
+    /// current holder instead if an identical request already claimed it.
+    pub async fn claim_content_hash(&self, content_hash: &str, request_id: &str) -> Result<Option<String>> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("content:{}", content_hash));
+
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(request_id)
+            .arg("NX")
+            .arg("EX")
+            .arg(48 * 3600)
+            .query_async(&mut conn)
+            .await?;
+        if claimed.is_some() {
+            return Ok(None);
+        }
+
+        let holder: Option<String> = conn.get(&key).await?;
+        Ok(holder)
+    }
+
+    /// Hands a content hash to a new request, e.g. when the previous holder
+    /// failed or expired.
+    pub async fn replace_content_hash(&self, content_hash: &str, request_id: &str) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("content:{}", content_hash));
+        conn.set_ex::<_, _, ()>(&key, request_id, 48 * 3600).await?;
+        Ok(())
+    }
+
+    /// Stores a request deduplicated against `state.duplicate_of`. It is not
+    /// queued; it is finished alongside the original request.
+    pub async fn create_duplicate_request(&self, state: RequestState) -> Result<RequestState> {
+        let mut conn = self.redis.clone();
+        let original_id = state
+            .duplicate_of
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Request {} is not a duplicate", state.request_id))?;
+
+        if state.status == RequestStatus::Complete {
+            self.copy_result(&original_id, &state.request_id).await?;
+        }
+        let key = self.key(format_args!("request:{}", state.request_id));
+        let json = self.encode_state(&state)?;
+        conn.set_ex::<_, _, ()>(&key, json, 48 * 3600).await?;
+        if matches!(state.status, RequestStatus::Complete | RequestStatus::Failed) {
+            self.index_finished(&state.request_id).await?;
+        }
+
+        let duplicates_key = self.key(format_args!("duplicates:{}", original_id));
+        conn.sadd::<_, _, ()>(&duplicates_key, &state.request_id).await?;
+        conn.expire::<_, ()>(&duplicates_key, 48 * 3600).await?;
+        let detail = format!("duplicate of {}", original_id);
+        self.record_transition(&state, format!("submitted as a {}", detail)).await;
+        self.audit(AuditEventKind::Submitted, &state, Some(detail)).await;
+
+        // The original may have finished between the caller's check and the link above
+        if let Some(original) = self.get_request(&original_id).await? {
+            if matches!(original.status, RequestStatus::Complete | RequestStatus::Failed) {
+                self.finish_duplicates(&original).await?;
+                return Ok(self.get_request(&state.request_id).await?.unwrap_or(state));
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Copies a finished request's outcome to the requests deduplicated
+    /// against it and wakes their waiters.
+    async fn finish_duplicates(&self, original: &RequestState) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let duplicates_key = self.key(format_args!("duplicates:{}", original.request_id));
+        let duplicate_ids: Vec<String> = conn.smembers(&duplicates_key).await?;
+
+        for duplicate_id in duplicate_ids {
+            if let Err(e) = self.finish_duplicate(original, &duplicate_id).await {
+                warn!("Failed to finish {} with {}: {}", duplicate_id, original.request_id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn finish_duplicate(&self, original: &RequestState, duplicate_id: &str) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let Some(mut state) = self.get_request(duplicate_id).await? else {
+            return Ok(());
+        };
+        if matches!(state.status, RequestStatus::Complete | RequestStatus::Failed)
+            || !self.claim_outcome(duplicate_id, original.status.clone()).await?
+        {
+            return Ok(());
+        }
+
+        state.status = original.status.clone();
+        state.batch_id = original.batch_id.clone();
+        state.error = original.error.clone();
+        state.dispatched_at = original.dispatched_at;
+        state.completed_at = original.completed_at;
+        state.updated_at = Utc::now();
+
+        let key = self.key(format_args!("request:{}", duplicate_id));
+        let stored = async {
+            if original.status == RequestStatus::Complete {
+                match &original.result {
+                    Some(result) => self.store_result(duplicate_id, result).await?,
+                    None => self.copy_result(&original.request_id, duplicate_id).await?,
+                }
+            }
+            let json = self.encode_state(&state)?;
+            conn.set_ex::<_, _, ()>(&key, json, 48 * 3600).await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(e) = stored {
+            self.release_outcome(duplicate_id).await;
+            return Err(e);
+        }
+
+        after_outcome(duplicate_id, "index the request", self.index_finished(duplicate_id).await);
+        let channel = self.key(format_args!("completion:{}", duplicate_id));
+        let message = state.error.as_ref().map_or("complete", |error| error.message.as_str());
+        after_outcome(
+            duplicate_id,
+            "notify waiters",
+            conn.publish::<_, _, ()>(&channel, message).await.map_err(Into::into),
+        );
+
+        let (kind, event_kind) = match state.status {
+            RequestStatus::Failed => (AuditEventKind::Failed, EventKind::Failed),
+            _ => (AuditEventKind::Completed, EventKind::Completed),
+        };
+        let detail = format!("from {}", original.request_id);
+        self.record_transition(&state, format!("finished with {}", original.request_id)).await;
+        self.publish_event(self.request_event(event_kind, &state).detail(&detail)).await;
+        self.audit(kind, &state, Some(detail)).await;
+        // Duplicates never reach the upstream, so they count without tokens
+        after_outcome(duplicate_id, "record analytics", self.record_request_outcome(&state, None).await);
+
+        Ok(())
+    }
+
+    fn result_key(&self, request_id: &str) -> String {
+        self.key(format_args!("result:{}", request_id))
+    }
+
+    /// Stores a result under its own key, so reading a request's status
+    /// never loads it, for `SILT_RESULT_TTL_SECS`. Encrypted with
+    /// `SILT_ENCRYPT_PAYLOADS`.
+    async fn store_result(&self, request_id: &str, result: &CompletionResponse) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let stored = match &self.cipher {
+            Some(cipher) if cipher.encrypts_payloads() => cipher.encrypt(&serde_json::to_string(result)?)?.into_bytes(),
+            _ => self.codec.encode(result)?,
+        };
+        conn.set_ex::<_, _, ()>(self.result_key(request_id), stored, self.result_ttl_secs)
+            .await?;
+        Ok(())
+    }
+
+    /// A completed request's result, or `None` once it has expired.
+    pub async fn get_result(&self, request_id: &str) -> Result<Option<CompletionResponse>> {
+        let mut conn = self.redis.clone();
+        let data: Option<Vec<u8>> = conn.get(self.result_key(request_id)).await?;
+        let Some(stored) = data else {
+            return Ok(None);
+        };
+        if stored.starts_with(crate::crypto::ENCRYPTED_PREFIX.as_bytes()) {
+            let json = self.decrypt_secret(std::str::from_utf8(&stored)?)?;
+            return Ok(Some(serde_json::from_str(&json)?));
+        }
+        Ok(Some(RedisCodec::decode(&stored)?))
+    }
+
+    /// Fills in a completed request's result, which `get_request` leaves out.
+    pub async fn load_result(&self, state: &mut RequestState) -> Result<()> {
+        if state.status == RequestStatus::Complete && state.result.is_none() {
+            state.result = self.get_result(&state.request_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn copy_result(&self, from_request_id: &str, to_request_id: &str) -> Result<()> {
+        if let Some(result) = self.get_result(from_request_id).await? {
+            self.store_result(to_request_id, &result).await?;
+        }
+        Ok(())
+    }
+
+    /// Sorted set of this tenant's finished requests, scored by when they
+    /// finished, so retention sweeps don't walk the keyspace.
+    fn finished_key(&self) -> String {
+        self.key("finished_requests")
+    }
+
+    /// Adds a request that just finished to the tenant's index, dropping
+    /// entries old enough for their state to have expired.
+    async fn index_finished(&self, request_id: &str) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let now = Utc::now().timestamp();
+        conn.zadd::<_, _, _, ()>(self.finished_key(), request_id, now).await?;
+        conn.zrembyscore::<_, _, _, ()>(self.finished_key(), "-inf", now - 48 * 3600).await?;
+        Ok(())
+    }
+
+    /// IDs of this tenant's finished requests, oldest first.
+    pub async fn list_finished_requests(&self) -> Result<Vec<String>> {
+        let mut conn = self.redis.clone();
+        Ok(conn.zrange(self.finished_key(), 0, -1).await?)
+    }
+
+    /// IDs of every request stored for this tenant. Walks the keyspace with
+    /// SCAN, so it is meant for rare admin operations such as purges.
+    pub async fn list_request_ids(&self) -> Result<Vec<String>> {
+        let mut conn = self.redis.clone();
+        let prefix = self.key("request:");
+
+        let mut request_ids = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}*", prefix))
+                .arg("COUNT")
+                .arg(1000)
+                .query_async(&mut conn)
+                .await?;
+            request_ids.extend(keys.iter().map(|key| key[prefix.len()..].to_string()));
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(request_ids)
+    }
+
+    /// Erases a request: its state (prompt and result), queue and deadline
+    /// entries, failure record and dedupe links. Requests deduplicated against it are failed
+    /// rather than left waiting. Returns false if there was no such request.
+    pub async fn purge_request(&self, request_id: &str) -> Result<bool> {
+        let Some(state) = self.get_request(request_id).await? else {
+            return Ok(false);
+        };
+        let mut conn = self.redis.clone();
+
+        let key = self.key(format_args!("request:{}", request_id));
+        conn.del::<_, ()>(&key).await?;
+        conn.del::<_, ()>(self.result_key(request_id)).await?;
+        conn.del::<_, ()>(self.history_key(request_id)).await?;
+        conn.del::<_, ()>(self.outcome_key(request_id)).await?;
+        for priority in [Priority::High, Priority::Low] {
+            conn.srem::<_, _, ()>(self.queue_key(priority), request_id).await?;
+        }
+        conn.zrem::<_, _, ()>(self.queued_since_key(), request_id).await?;
+        conn.zrem::<_, _, ()>(self.finished_key(), request_id).await?;
+        conn.zrem::<_, _, ()>(self.key("request_deadlines"), request_id).await?;
+        conn.srem::<_, _, ()>(self.key("quarantine"), request_id).await?;
+        self.remove_failures(|record| record.request_id == request_id).await?;
+        if let Some(original_id) = &state.duplicate_of {
+            let original_duplicates_key = self.key(format_args!("duplicates:{}", original_id));
+            conn.srem::<_, _, ()>(&original_duplicates_key, request_id).await?;
+        }
+
+        let duplicates_key = self.key(format_args!("duplicates:{}", request_id));
+        let duplicate_ids: Vec<String> = conn.smembers(&duplicates_key).await?;
+        for duplicate_id in duplicate_ids {
+            let Some(duplicate) = self.get_request(&duplicate_id).await? else {
+                continue;
+            };
+            if !matches!(duplicate.status, RequestStatus::Complete | RequestStatus::Failed) {
+                let error = RequestError::new(RequestErrorKind::Purged, "Original request was purged");
+                self.fail_request(&duplicate_id, error).await?;
+            }
+        }
+        conn.del::<_, ()>(&duplicates_key).await?;
+
+        self.audit(AuditEventKind::Purged, &state, None).await;
+        Ok(true)
+    }
+
+    /// Audits a batch output file carrying more than one line for a request.
+    pub async fn record_duplicate_result(&self, request_id: &str, detail: String) -> Result<()> {
+        if let Some(state) = self.get_request(request_id).await? {
+            self.audit(AuditEventKind::DuplicateResult, &state, Some(detail)).await;
+        }
+        Ok(())
+    }
+
+    /// Audits a result being returned to the client and records the first
+    /// time it was.
+    pub async fn record_retrieval(&self, state: &RequestState) -> Result<()> {
+        self.audit(AuditEventKind::Retrieved, state, None).await;
+        if state.retrieved_at.is_some() {
+            return Ok(());
+        }
+        // The caller's copy may be stale, so only the stored state's
+        // `retrieved_at` is changed
+        if let Some(mut stored) = self.get_request(&state.request_id).await? {
+            if stored.retrieved_at.is_none() {
+                stored.retrieved_at = Some(Utc::now());
+                self.save_keeping_ttl(&stored).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a finished request's prompt messages, keeping its result.
+    pub async fn drop_prompt(&self, request_id: &str) -> Result<()> {
+        if let Some(mut state) = self.get_request(request_id).await? {
+            state.request.messages.clear();
+            self.save_keeping_ttl(&state).await?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites request state without extending its expiry.
+    async fn save_keeping_ttl(&self, state: &RequestState) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("request:{}", state.request_id));
+        redis::cmd("SET")
+            .arg(&key)
+            .arg(self.encode_state(state)?)
+            .arg("XX")
+            .arg("KEEPTTL")
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn record_failure(&self, state: &RequestState) -> Result<()> {
+        let record = FailureRecord {
+            failed_at: state.updated_at,
+            tenant: self.tenant.clone(),
+            request_id: state.request_id.clone(),
+            model: state.request.model.clone(),
+            api_key_fingerprint: key_fingerprint(&state.api_key),
+            batch_id: state.batch_id.clone(),
+            error: state.error.as_ref().map(|error| error.message.clone()).unwrap_or_default(),
+            error_kind: state.error.as_ref().map(|error| error.kind),
+        };
+        let mut conn = self.redis.clone();
+        let key = self.key("recent_failures");
+        conn.lpush::<_, _, ()>(&key, serde_json::to_string(&record)?).await?;
+        conn.ltrim::<_, ()>(&key, 0, RECENT_FAILURES_SIZE - 1).await?;
+        Ok(())
+    }
+
+    /// This tenant's failed requests, newest first.
+    pub async fn recent_failures(&self, limit: usize) -> Result<Vec<FailureRecord>> {
+        let mut conn = self.redis.clone();
+        let records: Vec<String> = conn
+            .lrange(self.key("recent_failures"), 0, limit.clamp(1, RECENT_FAILURES_SIZE as usize) as isize - 1)
+            .await?;
+        Ok(records
+            .iter()
+            .filter_map(|record| serde_json::from_str(record).ok())
+            .collect())
+    }
+
+    /// Drops this tenant's failure records for an API key, or all of them.
+    pub async fn purge_failures(&self, api_key_fingerprint: Option<&str>) -> Result<()> {
+        self.remove_failures(|record| {
+            api_key_fingerprint.is_none_or(|fingerprint| record.api_key_fingerprint == fingerprint)
+        })
+        .await
+    }
+
+    async fn remove_failures(&self, matches: impl Fn(&FailureRecord) -> bool) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = self.key("recent_failures");
+        let records: Vec<String> = conn.lrange(&key, 0, -1).await?;
+        for record in records {
+            if serde_json::from_str::<FailureRecord>(&record).is_ok_and(|record| matches(&record)) {
+                conn.lrem::<_, _, ()>(&key, 0, &record).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn record_purge(&self, record: &PurgeRecord) -> Result<()> {
+        let mut conn = self.redis.clone();
+        conn.lpush::<_, _, ()>("purge_log", serde_json::to_string(record)?).await?;
+        conn.ltrim::<_, ()>("purge_log", 0, PURGE_LOG_SIZE - 1).await?;
+        Ok(())
+    }
+
+    pub async fn get_key_mapping(&self, mapping_id: &str) -> Result<Option<KeyMapping>> {
+        let mut conn = self.redis.clone();
+        let data: Option<String> = conn.hget("key_mappings", mapping_id).await?;
+        data.map(|json| self.decode_mapping(&json)).transpose()
+    }
+
+    pub async fn list_key_mappings(&self) -> Result<Vec<(String, KeyMapping)>> {
+        let mut conn = self.redis.clone();
+        let entries: HashMap<String, String> = conn.hgetall("key_mappings").await?;
+        entries
+            .into_iter()
+            .map(|(id, json)| Ok((id, self.decode_mapping(&json)?)))
+            .collect()
+    }
+
+    pub async fn put_key_mapping(&self, mapping_id: &str, mapping: &KeyMapping) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let json = self.encode_mapping(mapping)?;
+        conn.hset::<_, _, _, ()>("key_mappings", mapping_id, json).await?;
+        Ok(())
+    }
+
+    /// Returns false if there was no such mapping.
+    pub async fn delete_key_mapping(&self, mapping_id: &str) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let removed: usize = conn.hdel("key_mappings", mapping_id).await?;
+        Ok(removed > 0)
+    }
+
+    pub async fn get_key_rotation(&self, fingerprint: &str) -> Result<Option<KeyRotation>> {
+        let mut conn = self.redis.clone();
+        let data: Option<String> = conn.hget("key_rotations", fingerprint).await?;
+        data.map(|json| self.decode_rotation(&json)).transpose()
+    }
+
+    /// Registered key rotations, by the old key's fingerprint.
+    pub async fn list_key_rotations(&self) -> Result<HashMap<String, KeyRotation>> {
+        let mut conn = self.redis.clone();
+        let entries: HashMap<String, String> = conn.hgetall("key_rotations").await?;
+        entries
+            .into_iter()
+            .map(|(fingerprint, json)| Ok((fingerprint, self.decode_rotation(&json)?)))
+            .collect()
+    }
+
+    pub async fn put_key_rotation(&self, rotation: &KeyRotation) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let mut stored = rotation.clone();
+        stored.old_key = self.encrypt_secret(&rotation.old_key)?;
+        stored.new_key = self.encrypt_secret(&rotation.new_key)?;
+        conn.hset::<_, _, _, ()>("key_rotations", key_fingerprint(&rotation.old_key), serde_json::to_string(&stored)?)
+            .await?;
+        Ok(())
+    }
+
+    /// The key requests made with `api_key` are sent upstream with, after any
+    /// registered rotations.
+    pub async fn upstream_key(&self, api_key: &str) -> Result<String> {
+        let rotations = self.list_key_rotations().await?;
+        Ok(rotated_key(&rotations, api_key).to_string())
+    }
+
+    fn decode_rotation(&self, json: &str) -> Result<KeyRotation> {
+        let mut rotation: KeyRotation = serde_json::from_str(json)?;
+        rotation.old_key = self.decrypt_secret(&rotation.old_key)?;
+        rotation.new_key = self.decrypt_secret(&rotation.new_key)?;
+        Ok(rotation)
+    }
+
+    /// Counts a request against a key mapping's per-minute limit and returns
+    /// the count for the current minute.
+    pub async fn count_mapping_request(&self, mapping_id: &str) -> Result<u64> {
+        let mut conn = self.redis.clone();
+        let minute = Utc::now().timestamp() / 60;
+        let key = format!("rate_limit:{}:{}", mapping_id, minute);
+        let count: u64 = conn.incr(&key, 1).await?;
+        if count == 1 {
+            conn.expire::<_, ()>(&key, 120).await?;
+        }
+        Ok(count)
+    }
+
+    pub async fn get_tenant_quota(&self, tenant: &str) -> Result<Option<TenantQuota>> {
+        let mut conn = self.redis.clone();
+        let data: Option<String> = conn.hget("tenant_quotas", tenant).await?;
+        data.map(|json| serde_json::from_str(&json).map_err(Into::into))
+            .transpose()
+    }
+
+    pub async fn list_tenant_quotas(&self) -> Result<Vec<(String, TenantQuota)>> {
+        let mut conn = self.redis.clone();
+        let entries: HashMap<String, String> = conn.hgetall("tenant_quotas").await?;
+        entries
+            .into_iter()
+            .map(|(tenant, json)| Ok((tenant, serde_json::from_str(&json)?)))
+            .collect()
+    }
+
+    pub async fn put_tenant_quota(&self, tenant: &str, quota: &TenantQuota) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let json = serde_json::to_string(quota)?;
+        conn.hset::<_, _, _, ()>("tenant_quotas", tenant, json).await?;
+        Ok(())
+    }
+
+    /// Returns false if the tenant had no quota.
+    pub async fn delete_tenant_quota(&self, tenant: &str) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let removed: usize = conn.hdel("tenant_quotas", tenant).await?;
+        Ok(removed > 0)
+    }
+
+    pub async fn get_retention_policy(&self, tenant: &str) -> Result<Option<RetentionPolicy>> {
+        let mut conn = self.redis.clone();
+        let data: Option<String> = conn.hget("tenant_retention", tenant).await?;
+        data.map(|json| serde_json::from_str(&json).map_err(Into::into))
+            .transpose()
+    }
+
+    pub async fn list_retention_policies(&self) -> Result<Vec<(String, RetentionPolicy)>> {
+        let mut conn = self.redis.clone();
+        let entries: HashMap<String, String> = conn.hgetall("tenant_retention").await?;
+        entries
+            .into_iter()
+            .map(|(tenant, json)| Ok((tenant, serde_json::from_str(&json)?)))
+            .collect()
+    }
+
+    pub async fn put_retention_policy(&self, tenant: &str, policy: &RetentionPolicy) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let json = serde_json::to_string(policy)?;
+        conn.hset::<_, _, _, ()>("tenant_retention", tenant, json).await?;
+        Ok(())
+    }
+
+    /// Returns false if the tenant had no retention policy.
+    pub async fn delete_retention_policy(&self, tenant: &str) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let removed: usize = conn.hdel("tenant_retention", tenant).await?;
+        Ok(removed > 0)
+    }
+
+    pub async fn get_moderation_policy(&self, tenant: &str) -> Result<Option<ModerationPolicy>> {
+        let mut conn = self.redis.clone();
+        let data: Option<String> = conn.hget("tenant_moderation", tenant).await?;
+        data.map(|json| serde_json::from_str(&json).map_err(Into::into))
+            .transpose()
+    }
+
+    pub async fn list_moderation_policies(&self) -> Result<Vec<(String, ModerationPolicy)>> {
+        let mut conn = self.redis.clone();
+        let entries: HashMap<String, String> = conn.hgetall("tenant_moderation").await?;
+        entries
+            .into_iter()
+            .map(|(tenant, json)| Ok((tenant, serde_json::from_str(&json)?)))
+            .collect()
+    }
+
+    pub async fn put_moderation_policy(&self, tenant: &str, policy: &ModerationPolicy) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let json = serde_json::to_string(policy)?;
+        conn.hset::<_, _, _, ()>("tenant_moderation", tenant, json).await?;
+        Ok(())
+    }
+
+    /// Returns false if the tenant had no moderation policy.
+    pub async fn delete_moderation_policy(&self, tenant: &str) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let removed: usize = conn.hdel("tenant_moderation", tenant).await?;
+        Ok(removed > 0)
+    }
+
+    pub async fn get_runtime_settings(&self) -> Result<Option<RuntimeSettings>> {
+        let mut conn = self.redis.clone();
+        let data: Option<String> = conn.get("runtime_settings").await?;
+        data.map(|json| serde_json::from_str(&json).map_err(Into::into))
+            .transpose()
+    }
+
+    pub async fn put_runtime_settings(&self, settings: &RuntimeSettings) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let json = serde_json::to_string(settings)?;
+        conn.set::<_, _, ()>("runtime_settings", json).await?;
+        Ok(())
+    }
+
+    /// Returns false if no runtime settings were stored.
+    pub async fn delete_runtime_settings(&self) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let removed: usize = conn.del("runtime_settings").await?;
+        Ok(removed > 0)
+    }
+
+    /// Counts a queued request towards this tenant's daily usage and returns
+    /// the new count for today.
+    pub async fn count_daily_request(&self) -> Result<u64> {
+        let mut conn = self.redis.clone();
+        let key = self.usage_key("requests");
+        let count: u64 = conn.incr(&key, 1).await?;
+        conn.expire::<_, ()>(&key, 48 * 3600).await?;
+        Ok(count)
+    }
+
+    /// Takes back a request counted by `count_daily_request` that was then
+    /// rejected or failed to queue.
+    pub async fn uncount_daily_request(&self) -> Result<()> {
+        let mut conn = self.redis.clone();
+        conn.decr::<_, _, ()>(self.usage_key("requests"), 1).await?;
+        Ok(())
+    }
+
+    async fn add_daily_tokens(&self, tokens: u64) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = self.usage_key("tokens");
+        conn.incr::<_, _, ()>(&key, tokens).await?;
+        conn.expire::<_, ()>(&key, 48 * 3600).await?;
+        Ok(())
+    }
+
+    pub async fn daily_usage(&self) -> Result<TenantUsage> {
+        let mut conn = self.redis.clone();
+        let requests: Option<u64> = conn.get(self.usage_key("requests")).await?;
+        let tokens: Option<u64> = conn.get(self.usage_key("tokens")).await?;
+        Ok(TenantUsage {
+            requests: requests.unwrap_or(0),
+            tokens: tokens.unwrap_or(0),
+        })
+    }
+
+    /// Daily usage counters are shared keys, named by tenant and UTC date.
+    fn usage_key(&self, counter: &str) -> String {
+        format!("usage:{}:{}:{}", self.tenant, Utc::now().format("%Y-%m-%d"), counter)
+    }
+
+    /// When the longest-queued request was submitted, and how many queued
+    /// requests were submitted before `before`.
+    pub async fn queued_since(&self, before: DateTime<Utc>) -> Result<(Option<DateTime<Utc>>, usize)> {
+        let mut conn = self.redis.clone();
+        let oldest: Vec<(String, i64)> = conn.zrange_withscores(self.queued_since_key(), 0, 0).await?;
+        let older: usize = conn
+            .zcount(self.queued_since_key(), "-inf", format!("({}", before.timestamp()))
+            .await?;
+        let oldest = oldest.first().and_then(|(_, at)| DateTime::from_timestamp(*at, 0));
+        Ok((oldest, older))
+    }
+
+    pub async fn queued_count(&self, priority: Priority) -> Result<usize> {
+        let mut conn = self.redis.clone();
+        let count: usize = conn.scard(self.queue_key(priority)).await?;
+        Ok(count)
+    }
+
+    /// Asks the dispatcher for this lane to run before its next window tick.
+    pub async fn trigger_dispatch(&self, priority: Priority) -> Result<()> {
+        let mut conn = self.redis.clone();
+        conn.publish::<_, _, ()>("dispatch_trigger", priority.as_str()).await?;
+        Ok(())
+    }
+
+    /// Asks every running dispatcher to send this lane's queues now. Returns
+    /// how many dispatchers received the request.
+    pub async fn request_flush(&self, priority: Priority) -> Result<usize> {
+        let mut conn = self.redis.clone();
+        let receivers: usize = conn
+            .publish("dispatch_trigger", format!("{}{}", FLUSH_PREFIX, priority.as_str()))
+            .await?;
+        Ok(receivers)
+    }
+
+    pub async fn subscribe_to_dispatch_triggers(&self) -> Result<redis::aio::PubSubStream> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe("dispatch_trigger").await?;
+        Ok(pubsub.into_on_message())
+    }
+
+    /// Requests whose deadline has passed without completing.
+    pub async fn get_expired_deadlines(&self) -> Result<Vec<String>> {
+        let mut conn = self.redis.clone();
+        let request_ids: Vec<String> = conn
+            .zrangebyscore(self.key("request_deadlines"), "-inf", Utc::now().timestamp())
+            .await?;
+        Ok(request_ids)
+    }
+
+    /// Claims an expired deadline for fallback handling. Returns false if
+    /// another worker already claimed it.
+    pub async fn claim_deadline(&self, request_id: &str) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let removed: usize = conn.zrem(self.key("request_deadlines"), request_id).await?;
+        Ok(removed > 0)
+    }
+
+    /// Takes a request out of its lane's queue, or off the requeue schedule,
+    /// so it will not be batched. Returns false if it was no longer queued.
+    pub async fn remove_from_queue(&self, request_id: &str, priority: Priority) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let removed: usize = conn.srem(self.queue_key(priority), request_id).await?;
+        if removed > 0 {
+            conn.zrem::<_, _, ()>(self.queued_since_key(), request_id).await?;
+            return Ok(true);
+        }
+        let unscheduled: usize = conn.zrem(self.key("requeue_schedule"), request_id).await?;
+        Ok(unscheduled > 0)
+    }
+
+    fn dispatch_claim_key(&self, request_id: &str) -> String {
+        self.key(format_args!("dispatching:{}", request_id))
+    }
+
+    /// Claims queued requests for a batch about to be uploaded, so a cancel
+    /// can't fail one that is going out anyway. Returns those still queued;
+    /// the rest were cancelled or dispatched since they were read.
+    pub async fn claim_for_dispatch(&self, request_ids: &[String], priority: Priority) -> Result<Vec<String>> {
+        let mut conn = self.redis.clone();
+        let mut claimed = Vec::with_capacity(request_ids.len());
+        for request_id in request_ids {
+            let result: i64 = redis::Script::new(CLAIM_DISPATCH_SCRIPT)
+                .key(self.queue_key(priority))
+                .key(self.dispatch_claim_key(request_id))
+                .arg(request_id)
+                .arg(DISPATCH_CLAIM_TTL_SECS)
+                .invoke_async(&mut conn)
+                .await?;
+            if result == 1 {
+                claimed.push(request_id.clone());
+            }
+        }
+        Ok(claimed)
+    }
+
+    /// Gives up dispatch claims on requests whose batch wasn't created, so
+    /// they can be cancelled again.
+    pub async fn release_dispatch_claims(&self, request_ids: &[String]) -> Result<()> {
+        let mut conn = self.redis.clone();
+        for request_id in request_ids {
+            conn.del::<_, ()>(self.dispatch_claim_key(request_id)).await?;
+        }
+        Ok(())
+    }
+
+    /// Takes a request out of its lane's queue, or off the requeue schedule,
+    /// for a client that no longer wants it. Returns false if it was no
+    /// longer queued or a dispatcher has claimed it.
+    pub async fn cancel_queued(&self, request_id: &str, priority: Priority) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let removed: i64 = redis::Script::new(CANCEL_QUEUED_SCRIPT)
+            .key(self.queue_key(priority))
+            .key(self.dispatch_claim_key(request_id))
+            .arg(request_id)
+            .invoke_async(&mut conn)
+            .await?;
+        if removed > 0 {
+            conn.zrem::<_, _, ()>(self.queued_since_key(), request_id).await?;
+            return Ok(true);
+        }
+        let unscheduled: usize = conn.zrem(self.key("requeue_schedule"), request_id).await?;
+        Ok(unscheduled > 0)
+    }
+
+    /// Puts a request back on its lane's queue after a transient failure.
+    pub async fn requeue(&self, request_id: &str, priority: Priority) -> Result<()> {
+        chaos().redis_write("requeue")?;
+        let mut conn = self.redis.clone();
+        conn.sadd::<_, _, ()>(self.queue_key(priority), request_id).await?;
+        if let Some(state) = self.get_request(request_id).await? {
+            conn.zadd::<_, _, _, ()>(self.queued_since_key(), request_id, state.created_at.timestamp())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Returns a request that failed transiently to `queued`, to be put back
+    /// on its lane's queue by `requeue_due` once `retry_at` has passed.
+    /// Returns false if it had already finished.
+    pub async fn schedule_requeue(&self, request_id: &str, retry_at: DateTime<Utc>, error: &str) -> Result<bool> {
+        chaos().redis_write("schedule_requeue")?;
+        let mut conn = self.redis.clone();
+
+        let Some(mut state) = self.get_request(request_id).await? else {
+            return Ok(false);
+        };
+        if matches!(state.status, RequestStatus::Complete | RequestStatus::Failed) {
+            return Ok(false);
+        }
+
+        state.status = RequestStatus::Queued;
+        state.batch_id = None;
+        state.dispatched_at = None;
+        state.requeues += 1;
+        state.updated_at = Utc::now();
+
+        let key = self.key(format_args!("request:{}", request_id));
+        let json = self.encode_state(&state)?;
+        conn.set_ex::<_, _, ()>(&key, json, 48 * 3600).await?;
+        conn.zadd::<_, _, _, ()>(self.key("requeue_schedule"), request_id, retry_at.timestamp())
+            .await?;
+
+        let detail = format!("requeue {} at {} after: {}", state.requeues, retry_at.to_rfc3339(), error);
+        self.record_transition(&state, format!("retry {} at {} after: {}", state.requeues, retry_at.to_rfc3339(), error))
+            .await;
+        let event = self.request_event(EventKind::Requeued, &state).detail(format!("retry at {}", retry_at.to_rfc3339()));
+        self.publish_event(event).await;
+        self.audit(AuditEventKind::Requeued, &state, Some(detail)).await;
+        Ok(true)
+    }
+
+    /// Puts scheduled requests whose time has come back on their lane's
+    /// queue. Returns how many were requeued.
+    pub async fn requeue_due(&self) -> Result<usize> {
+        let mut conn = self.redis.clone();
+        let schedule_key = self.key("requeue_schedule");
+        let request_ids: Vec<String> = conn
+            .zrangebyscore(&schedule_key, "-inf", Utc::now().timestamp())
+            .await?;
+
+        let mut requeued = 0;
+        for request_id in request_ids {
+            // Queue before unscheduling, so a crash in between cannot strand it
+            if let Some(state) = self.get_request(&request_id).await? {
+                if state.status == RequestStatus::Queued {
+                    self.requeue(&request_id, state.priority).await?;
+                    requeued += 1;
+                }
+            }
+            conn.zrem::<_, _, ()>(&schedule_key, &request_id).await?;
+        }
+        Ok(requeued)
+    }
+
+    pub async fn get_queued_requests(&self, priority: Priority) -> Result<Vec<String>> {
+        let mut conn = self.redis.clone();
+        let request_ids: Vec<String> = conn.smembers(self.queue_key(priority)).await?;
+        Ok(request_ids)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn move_to_batching(
+        &self,
+        request_ids: &[String],
+        batch_id: &str,
+        api_key: &str,
+        upstream_url: Option<&str>,
+        scope: &UpstreamScope,
+        urgency: Option<&str>,
+        priority: Priority,
+        model_tokens: &HashMap<String, u64>,
+        upload_started_at: DateTime<Utc>,
+    ) -> Result<()> {
+        chaos().redis_write("move_to_batching")?;
+        let mut conn = self.redis.clone();
+
+        // Store the organization and project the batch was created under, and its
+        // urgency class, before its requests show as batching, so it is always
+        // polled and downloaded with the same headers. Unscoped batches store
+        // no scope.
+        if !scope.is_empty() {
+            let batch_scope = self.key(format_args!("batch_scope:{}", batch_id));
+            conn.set_ex::<_, _, ()>(&batch_scope, self.codec.encode(&self.encrypt_scope(scope)?)?, 48 * 3600)
+                .await?;
+        }
+        if let Some(urgency) = urgency {
+            let batch_urgency = self.key(format_args!("batch_urgency:{}", batch_id));
+            conn.set_ex::<_, _, ()>(&batch_urgency, urgency, 48 * 3600).await?;
+        }
+
+        // Remove from queued set
+        for request_id in request_ids {
+            conn.srem::<_, _, ()>(self.queue_key(priority), request_id).await?;
+            conn.zrem::<_, _, ()>(self.queued_since_key(), request_id).await?;
+            conn.del::<_, ()>(self.dispatch_claim_key(request_id)).await?;
+            self.update_status_at(
+                request_id,
+                RequestStatus::Batching,
+                Some(batch_id.to_string()),
+                Some(upload_started_at),
+            ).await?;
+        }
+
+        // Store batch -> request mapping
+        let batch_key = self.key(format_args!("batch:{}", batch_id));
+        conn.set_ex::<_, _, ()>(&batch_key, self.codec.encode(&request_ids)?, 48 * 3600).await?;
+
+        // Store batch -> API key mapping
+        let batch_api_key = self.key(format_args!("batch_api_key:{}", batch_id));
+        conn.set_ex::<_, _, ()>(&batch_api_key, self.encrypt_secret(api_key)?, 48 * 3600)
+            .await?;
+
+        // Batches on the configured upstream have no mapping
+        if let Some(upstream_url) = upstream_url {
+            let batch_upstream = self.key(format_args!("batch_upstream:{}", batch_id));
+            conn.set_ex::<_, _, ()>(&batch_upstream, upstream_url, 48 * 3600).await?;
+        }
+
+        // Add to processing batches set
+        conn.sadd::<_, _, ()>(self.key("processing_batches"), batch_id).await?;
+
+        // Upstream queue limits are per key, so in-flight tracking spans tenants
+        let inflight_key = inflight_key(api_key);
+        let estimated_tokens: u64 = model_tokens.values().sum();
+        conn.hset::<_, _, _, ()>(&inflight_key, batch_id, estimated_tokens).await?;
+        conn.expire::<_, ()>(&inflight_key, 48 * 3600).await?;
+        let model_tokens_key = inflight_model_tokens_key(api_key);
+        conn.hset::<_, _, _, ()>(&model_tokens_key, batch_id, serde_json::to_string(model_tokens)?)
+            .await?;
+        conn.expire::<_, ()>(&model_tokens_key, 48 * 3600).await?;
+
+        let daily_key = daily_batches_key(api_key);
+        conn.incr::<_, _, ()>(&daily_key, 1).await?;
+        conn.expire::<_, ()>(&daily_key, 48 * 3600).await?;
+
+        let event = Event::new(EventKind::BatchCreated, &self.tenant)
+            .batch(Some(batch_id))
+            .detail(format!("{} request(s)", request_ids.len()));
+        self.publish_event(event).await;
+        Ok(())
+    }
+
+    pub async fn get_batch_api_key(&self, batch_id: &str) -> Result<Option<String>> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("batch_api_key:{}", batch_id));
+        let api_key: Option<String> = conn.get(&key).await?;
+        api_key.map(|stored| self.decrypt_secret(&stored)).transpose()
+    }
+
+    /// The upstream a batch was created on, if not the configured one.
+    pub async fn get_batch_upstream(&self, batch_id: &str) -> Result<Option<String>> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("batch_upstream:{}", batch_id));
+        Ok(conn.get(&key).await?)
+    }
+
+    pub async fn get_batch_scope(&self, batch_id: &str) -> Result<UpstreamScope> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("batch_scope:{}", batch_id));
+        let data: Option<Vec<u8>> = conn.get(&key).await?;
+        let mut scope: UpstreamScope = data.map(|stored| RedisCodec::decode(&stored)).transpose()?.unwrap_or_default();
+        self.decrypt_scope(&mut scope)?;
+        Ok(scope)
+    }
+
+    /// The urgency class of a batch's requests, if they had one.
+    pub async fn get_batch_urgency(&self, batch_id: &str) -> Result<Option<String>> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("batch_urgency:{}", batch_id));
+        Ok(conn.get(&key).await?)
+    }
+
+    pub async fn get_batch_requests(&self, batch_id: &str) -> Result<Vec<String>> {
+        let mut conn = self.redis.clone();
+        let batch_key = self.key(format_args!("batch:{}", batch_id));
+        let data: Option<Vec<u8>> = conn.get(&batch_key).await?;
+
+        match data {
+            Some(stored) => {
+                let request_ids: Vec<String> = RedisCodec::decode(&stored)?;
+                Ok(request_ids)
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Records when the dispatcher for a lane will next run.
+    pub async fn set_next_dispatch(&self, priority: Priority, at: DateTime<Utc>, ttl_secs: u64) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = format!("next_dispatch_at:{}", priority.as_str());
+        conn.set_ex::<_, _, ()>(&key, at.timestamp(), ttl_secs.max(1)).await?;
+        Ok(())
+    }
+
+    pub async fn get_next_dispatch(&self, priority: Priority) -> Result<Option<DateTime<Utc>>> {
+        let mut conn = self.redis.clone();
+        let key = format!("next_dispatch_at:{}", priority.as_str());
+        let timestamp: Option<i64> = conn.get(&key).await?;
+        Ok(timestamp.and_then(|ts| DateTime::from_timestamp(ts, 0)))
+    }
+
+    /// This tenant's analytics counters for a UTC day.
+    fn analytics_key(&self, date: NaiveDate) -> String {
+        self.key(format_args!("analytics:{}", date.format("%Y-%m-%d")))
+    }
+
+    /// Counts a finished request in today's analytics, with its token usage
+    /// if it was sent upstream.
+    async fn record_request_outcome(&self, state: &RequestState, usage: Option<&Usage>) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = self.analytics_key(Utc::now().date_naive());
+        let model = &state.request.model;
+
+        let fingerprint = key_fingerprint(&state.api_key);
+
+        let key_label = metrics().key_label(&fingerprint);
+        if let Some(key_label) = &key_label {
+            let status = if state.status == RequestStatus::Failed { "failed" } else { "complete" };
+            metrics()
+                .key_requests_finished_total
+                .with_label_values(&[self.tenant(), key_label, status])
+                .inc();
+        }
+
+        let mut pipe = redis::pipe();
+        if state.status == RequestStatus::Failed {
+            let class = state.error.as_ref().map_or(RequestErrorKind::Other, |error| error.kind).as_str();
+            metrics()
+                .requests_failed_total
+                .with_label_values(&[self.tenant(), class])
+                .inc();
+            pipe.hincr(&key, "requests_failed", 1)
+                .hincr(&key, format!("model:{}:failed", model), 1)
+                .hincr(&key, format!("key:{}:failed", fingerprint), 1)
+                .hincr(&key, format!("error:{}", class), 1);
+        } else {
+            let latency_secs = (state.completed_at.unwrap_or_else(Utc::now) - state.created_at).num_seconds();
+            let latency = UsageCounts::latency_counter(latency_secs.max(0) as u64);
+            pipe.hincr(&key, "requests_completed", 1)
+                .hincr(&key, format!("model:{}:completed", model), 1)
+                .hincr(&key, format!("key:{}:completed", fingerprint), 1)
+                .hincr(&key, format!("model:{}:{}", model, latency), 1)
+                .hincr(&key, format!("key:{}:{}", fingerprint, latency), 1);
+        }
+        if let Some(usage) = usage {
+            if let Some(key_label) = &key_label {
+                metrics()
+                    .key_tokens_total
+                    .with_label_values(&[self.tenant(), key_label])
+                    .inc_by(u64::from(usage.total_tokens));
+            }
+            for (kind, tokens) in [("prompt", usage.prompt_tokens), ("completion", usage.completion_tokens)] {
+                metrics()
+                    .model_tokens_total
+                    .with_label_values(&[self.tenant(), model, kind])
+                    .inc_by(u64::from(tokens));
+            }
+            pipe.hincr(&key, "prompt_tokens", usage.prompt_tokens)
+                .hincr(&key, "completion_tokens", usage.completion_tokens)
+                .hincr(&key, "total_tokens", usage.total_tokens)
+                .hincr(&key, format!("model:{}:prompt_tokens", model), usage.prompt_tokens)
+                .hincr(&key, format!("model:{}:completion_tokens", model), usage.completion_tokens)
+                .hincr(&key, format!("model:{}:tokens", model), usage.total_tokens)
+                .hincr(&key, format!("key:{}:tokens", fingerprint), usage.total_tokens);
+            if let Some(price) = self.model_prices.get(model) {
+                let cost = price.cost_micros(usage.prompt_tokens, usage.completion_tokens);
+                pipe.hincr(&key, format!("model:{}:cost_micros", model), cost)
+                    .hincr(&key, format!("key:{}:cost_micros", fingerprint), cost);
+            }
+        }
+        pipe.expire(&key, ANALYTICS_RETENTION_DAYS * 24 * 3600);
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Counts an upstream batch ending in today's analytics.
+    pub async fn record_batch_outcome(&self, completed: bool, duration_secs: Option<i64>) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = self.analytics_key(Utc::now().date_naive());
+
+        let mut pipe = redis::pipe();
+        if completed {
+            pipe.hincr(&key, "batches_completed", 1);
+            if let Some(duration_secs) = duration_secs {
+                pipe.hincr(&key, "batch_duration_secs", duration_secs.max(0));
+            }
+        } else {
+            pipe.hincr(&key, "batches_failed", 1);
+        }
+        pipe.expire(&key, ANALYTICS_RETENTION_DAYS * 24 * 3600);
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    pub async fn analytics(&self, date: NaiveDate) -> Result<Analytics> {
+        let mut conn = self.redis.clone();
+        let counters: HashMap<String, u64> = conn.hgetall(self.analytics_key(date)).await?;
+
+        let mut analytics = Analytics {
+            date: Some(date),
+            ..Default::default()
+        };
+        for (field, value) in counters {
+            match field.as_str() {
+                "requests_completed" => analytics.requests_completed = value,
+                "requests_failed" => analytics.requests_failed = value,
+                "prompt_tokens" => analytics.prompt_tokens = value,
+                "completion_tokens" => analytics.completion_tokens = value,
+                "total_tokens" => analytics.total_tokens = value,
+                "batches_completed" => analytics.batches_completed = value,
+                "batches_failed" => analytics.batches_failed = value,
+                "batch_duration_secs" => analytics.batch_duration_secs = value,
+                _ => {
+                    if let Some(class) = field.strip_prefix("error:") {
+                        analytics.failures_by_class.insert(class.to_string(), value);
+                        continue;
+                    }
+                    let (counts, counter) = if let Some((model, counter)) =
+                        field.strip_prefix("model:").and_then(|rest| rest.rsplit_once(':'))
+                    {
+                        (analytics.models.entry(model.to_string()).or_default(), counter)
+                    } else if let Some((key, counter)) =
+                        field.strip_prefix("key:").and_then(|rest| rest.rsplit_once(':'))
+                    {
+                        (analytics.keys.entry(key.to_string()).or_default(), counter)
+                    } else {
+                        continue;
+                    };
+                    match counter {
+                        "completed" => counts.completed = value,
+                        "failed" => counts.failed = value,
+                        "prompt_tokens" => counts.prompt_tokens = value,
+                        "completion_tokens" => counts.completion_tokens = value,
+                        "tokens" => counts.tokens = value,
+                        "cost_micros" => counts.cost_micros = value,
+                        _ => counts.set_latency_count(counter, value),
+                    }
+                }
+            }
+        }
+        Ok(analytics)
+    }
+
+    /// Keeps a rolling window of recent upstream batch durations per model.
+    pub async fn record_batch_duration(&self, model: &str, duration_secs: i64) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = format!("batch_durations:{}", model);
+        conn.lpush::<_, _, ()>(&key, duration_secs).await?;
+        conn.ltrim::<_, ()>(&key, 0, BATCH_DURATION_SAMPLES - 1).await?;
+        Ok(())
+    }
+
+    /// Median of the recent batch durations for a model, if any were recorded.
+    pub async fn typical_batch_duration(&self, model: &str) -> Result<Option<i64>> {
+        let mut conn = self.redis.clone();
+        let key = format!("batch_durations:{}", model);
+        let mut durations: Vec<i64> = conn.lrange(&key, 0, -1).await?;
+        if durations.is_empty() {
+            return Ok(None);
+        }
+        durations.sort_unstable();
+        Ok(Some(durations[durations.len() / 2]))
+    }
+
+    /// Records a batch's progress, publishing a `batch_status` event when its
+    /// upstream status differs from the last poll's.
+    pub async fn set_batch_progress(&self, batch_id: &str, progress: &BatchProgress) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("batch_progress:{}", batch_id));
+        let previous: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(serde_json::to_string(progress)?)
+            .arg("EX")
+            .arg(48 * 3600)
+            .arg("GET")
+            .query_async(&mut conn)
+            .await?;
+
+        let previous_status = previous
+            .and_then(|json| serde_json::from_str::<BatchProgress>(&json).ok())
+            .map(|previous| previous.status);
+        if previous_status.as_deref() != Some(progress.status.as_str()) {
+            let event = Event::new(EventKind::BatchStatus, &self.tenant)
+                .batch(Some(batch_id))
+                .detail(&progress.status);
+            self.publish_event(event).await;
+        }
+        Ok(())
+    }
+
+    pub async fn get_batch_progress(&self, batch_id: &str) -> Result<Option<BatchProgress>> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("batch_progress:{}", batch_id));
+        let data: Option<String> = conn.get(&key).await?;
+        Ok(data.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    /// Remembers an upstream key that batch files are uploaded with, so the
+    /// file sweeper can find uploads orphaned by a crash. Shared across
+    /// tenants and stored encrypted like other keys.
+    pub async fn record_upload_key(&self, api_key: &str) -> Result<()> {
+        let mut conn = self.redis.clone();
+        conn.hset::<_, _, _, ()>("upload_keys", key_fingerprint(api_key), self.encrypt_secret(api_key)?)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_upload_keys(&self) -> Result<Vec<String>> {
+        let mut conn = self.redis.clone();
+        let stored: HashMap<String, String> = conn.hgetall("upload_keys").await?;
+        let mut keys = Vec::new();
+        for (fingerprint, value) in stored {
+            match self.decrypt_secret(&value) {
+                Ok(key) => keys.push(key),
+                Err(e) => warn!("Skipping stored upload key {}: {}", fingerprint, e),
+            }
+        }
+        Ok(keys)
+    }
+
+    pub async fn forget_upload_key(&self, fingerprint: &str) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let removed: usize = conn.hdel("upload_keys", fingerprint).await?;
+        Ok(removed > 0)
+    }
+
+    /// Claims the next file sweep for `ttl_secs`, so only one worker runs it.
+    pub async fn claim_file_sweep(&self, ttl_secs: u64) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg("file_sweep_lock")
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl_secs.max(1))
+            .query_async(&mut conn)
+            .await?;
+        Ok(claimed.is_some())
+    }
+
+    pub async fn get_processing_batches(&self) -> Result<Vec<String>> {
+        let mut conn = self.redis.clone();
+        let batch_ids: Vec<String> = conn.smembers(self.key("processing_batches")).await?;
+        Ok(batch_ids)
+    }
+
+    pub async fn remove_processing_batch(&self, batch_id: &str, api_key: &str) -> Result<()> {
+        chaos().redis_write("remove_processing_batch")?;
+        let mut conn = self.redis.clone();
+        conn.srem::<_, _, ()>(self.key("processing_batches"), batch_id).await?;
+        conn.hdel::<_, _, ()>(inflight_key(api_key), batch_id).await?;
+        conn.hdel::<_, _, ()>(inflight_model_tokens_key(api_key), batch_id).await?;
+        Ok(())
+    }
+
+    /// Batches created for an API key so far today (UTC).
+    pub async fn daily_batch_count(&self, api_key: &str) -> Result<u64> {
+        let mut conn = self.redis.clone();
+        let count: Option<u64> = conn.get(daily_batches_key(api_key)).await?;
+        Ok(count.unwrap_or(0))
+    }
+
+    /// Batches in flight upstream for an API key and their estimated input
+    /// tokens.
+    pub async fn inflight_batches(&self, api_key: &str) -> Result<(usize, u64)> {
+        let mut conn = self.redis.clone();
+        let tokens: Vec<u64> = conn.hvals(inflight_key(api_key)).await?;
+        Ok((tokens.len(), tokens.iter().sum()))
+    }
+
+    /// Estimated input tokens in flight upstream for an API key, per model.
+    pub async fn inflight_model_tokens(&self, api_key: &str) -> Result<HashMap<String, u64>> {
+        let mut conn = self.redis.clone();
+        let batches: Vec<String> = conn.hvals(inflight_model_tokens_key(api_key)).await?;
+        let mut totals = HashMap::new();
+        for batch in batches {
+            let model_tokens: HashMap<String, u64> = serde_json::from_str(&batch)?;
+            for (model, tokens) in model_tokens {
+                *totals.entry(model).or_default() += tokens;
+            }
+        }
+        Ok(totals)
+    }
+
+    /// Counts a connection waiting on a request's result, across instances.
+    pub async fn add_waiter(&self, request_id: &str) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("waiters:{}", request_id));
+        conn.incr::<_, _, ()>(&key, 1).await?;
+        conn.expire::<_, ()>(&key, 48 * 3600).await?;
+        Ok(())
+    }
+
+    /// Returns how many connections are still waiting on the request.
+    pub async fn remove_waiter(&self, request_id: &str) -> Result<i64> {
+        let mut conn = self.redis.clone();
+        let key = self.key(format_args!("waiters:{}", request_id));
+        let remaining: i64 = conn.decr(&key, 1).await?;
+        if remaining <= 0 {
+            conn.del::<_, ()>(&key).await?;
+        }
+        Ok(remaining)
+    }
+
+    pub async fn subscribe_to_completion(&self, request_id: &str) -> Result<redis::aio::PubSub> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        let channel = self.key(format_args!("completion:{}", request_id));
+        pubsub.subscribe(&channel).await?;
+        Ok(pubsub)
+    }
+}
+
+/// The key to send in place of `api_key`, following rotations registered on
+/// top of one another. A cycle stops once every rotation has been followed.
+pub fn rotated_key<'a>(rotations: &'a HashMap<String, KeyRotation>, api_key: &'a str) -> &'a str {
+    let mut current = api_key;
+    for _ in 0..rotations.len() {
+        match rotations.get(&key_fingerprint(current)) {
+            Some(rotation) => current = &rotation.new_key,
+            None => break,
+        }
+    }
+    current
+}
+
+/// Logs a step that failed after a request's outcome was claimed and stored.
+/// It isn't returned, as a retry would find the request finished and skip
+/// whatever was left to do.
+fn after_outcome(request_id: &str, step: &str, result: Result<()>) {
+    if let Err(e) = result {
+        warn!("Failed to {} for finished request {}: {}", step, request_id, e);
+    }
+}
+
+/// Redis hash of an API key's in-flight batch ids to estimated tokens.
+fn inflight_key(api_key: &str) -> String {
+    format!("inflight_batches:{}", key_fingerprint(api_key))
+}
+
+/// Redis hash of an API key's in-flight batch ids to their estimated tokens
+/// per model, as JSON.
+fn inflight_model_tokens_key(api_key: &str) -> String {
+    format!("inflight_model_tokens:{}", key_fingerprint(api_key))
+}
+
+/// Counter of batches created for an API key on the current UTC day.
+fn daily_batches_key(api_key: &str) -> String {
+    format!("daily_batches:{}:{}", key_fingerprint(api_key), Utc::now().format("%Y-%m-%d"))
+}
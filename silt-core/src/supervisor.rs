@@ -0,0 +1,59 @@
+use crate::metrics::metrics;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+use tracing::{error, warn};
+
+/// Wait before the first restart of a panicked task, doubled on each panic
+/// in a row.
+const RESTART_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between restarts.
+const RESTART_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// A task that ran this long before panicking starts the backoff over.
+const HEALTHY_RUN: Duration = Duration::from_secs(600);
+
+/// Runs a task built by `task`, building and starting it again with backoff
+/// whenever it panics, so one bad batch or request can't stop the
+/// dispatcher or a poller for good. Returns once the task ends without
+/// panicking, or is cancelled.
+///
+/// Restarts are counted in `silt_task_restarts_total` by `name`.
+pub async fn supervise<F, Fut>(name: &'static str, task: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut delay = RESTART_BASE_DELAY;
+    loop {
+        let started = Instant::now();
+        match tokio::spawn(task()).await {
+            Ok(()) => return,
+            Err(e) if e.is_panic() => {
+                if started.elapsed() >= HEALTHY_RUN {
+                    delay = RESTART_BASE_DELAY;
+                }
+                error!("Task {} panicked, restarting in {:?}: {}", name, delay, panic_message(e.into_panic()));
+                metrics().task_restarts_total.with_label_values(&[name]).inc();
+                sleep(delay).await;
+                delay = (delay * 2).min(RESTART_MAX_DELAY);
+            }
+            Err(_) => {
+                warn!("Task {} was cancelled", name);
+                return;
+            }
+        }
+    }
+}
+
+/// The message a panic was raised with, when it was a string.
+pub fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// Builds a rustls client config for upstream connections when custom root
+/// CAs or certificate pins are configured. Returns `None` when neither is set
+/// so the HTTP client keeps its default TLS setup.
+pub fn build_client_config(ca_cert_paths: &[String], cert_pins: &[String]) -> Result<Option<ClientConfig>> {
+    if ca_cert_paths.is_empty() && cert_pins.is_empty() {
+        return Ok(None);
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    // Start from the public web roots, then add any private CAs on top
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    for path in ca_cert_paths {
+        let certs = CertificateDer::pem_file_iter(path)
+            .with_context(|| format!("Failed to read CA certificate file {}", path))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to parse CA certificate file {}", path))?;
+
+        if certs.is_empty() {
+            return Err(anyhow!("No certificates found in CA certificate file {}", path));
+        }
+
+        for cert in certs {
+            roots
+                .add(cert)
+                .with_context(|| format!("Invalid CA certificate in {}", path))?;
+        }
+
+        tracing::info!("Loaded upstream CA certificates from {}", path);
+    }
+
+    let webpki_verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), Arc::clone(&provider))
+        .build()
+        .map_err(|e| anyhow!("Failed to build certificate verifier: {}", e))?;
+
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| anyhow!("Failed to configure TLS protocol versions: {}", e))?;
+
+    let config = if cert_pins.is_empty() {
+        builder
+            .with_webpki_verifier(webpki_verifier)
+            .with_no_client_auth()
+    } else {
+        let pins = cert_pins
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<Result<Vec<_>>>()?;
+
+        tracing::info!("Pinning upstream TLS to {} certificate fingerprint(s)", pins.len());
+
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                inner: webpki_verifier,
+                pins,
+            }))
+            .with_no_client_auth()
+    };
+
+    Ok(Some(config))
+}
+
+/// Parses a SHA-256 certificate fingerprint in hex, with or without colon
+/// separators (the format printed by `openssl x509 -fingerprint -sha256`),
+/// or in base64 (as piped through `openssl dgst -sha256 -binary | base64`).
+fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+    let normalized: String = pin.chars().filter(|c| *c != ':').collect();
+    let bytes = hex::decode(&normalized)
+        .or_else(|e| BASE64.decode(pin).map_err(|_| e))
+        .map_err(|e| anyhow!("Invalid certificate pin {}: {}", pin, e))?;
+
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("Invalid certificate pin {}: expected a SHA-256 fingerprint", pin))
+}
+
+/// Verifies the certificate chain as usual and additionally requires the
+/// leaf certificate to match one of the configured fingerprints.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        let fingerprint: [u8; 32] = Sha256::digest(end_entity.as_ref()).into();
+        if self.pins.contains(&fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            tracing::error!(
+                "Upstream certificate fingerprint {} does not match any configured pin",
+                hex::encode(fingerprint)
+            );
+            Err(rustls::Error::General(
+                "upstream certificate does not match pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT: [u8; 32] = [
+        0x5e, 0x8f, 0x16, 0x06, 0x2e, 0xa3, 0xcd, 0x2c, 0x4a, 0x0d, 0x54, 0x78, 0x76, 0xba, 0xa6, 0xf3, 0x8c, 0xab,
+        0xf6, 0x25, 0xa5, 0x5a, 0x7e, 0x8b, 0xd0, 0x2a, 0x9c, 0x66, 0x1e, 0x41, 0x46, 0xa6,
+    ];
+
+    #[test]
+    fn parses_hex_with_and_without_colons() {
+        let bare = hex::encode(FINGERPRINT);
+        assert_eq!(parse_pin(&bare).unwrap(), FINGERPRINT);
+        assert_eq!(parse_pin(&bare.to_ascii_uppercase()).unwrap(), FINGERPRINT);
+
+        let colons = FINGERPRINT
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(parse_pin(&colons).unwrap(), FINGERPRINT);
+    }
+
+    #[test]
+    fn parses_base64() {
+        assert_eq!(parse_pin(&BASE64.encode(FINGERPRINT)).unwrap(), FINGERPRINT);
+    }
+
+    #[test]
+    fn rejects_fingerprints_of_the_wrong_length() {
+        assert!(parse_pin(&hex::encode(&FINGERPRINT[..20])).is_err());
+        assert!(parse_pin(&hex::encode([FINGERPRINT.as_slice(), &[0]].concat())).is_err());
+        assert!(parse_pin(&BASE64.encode(&FINGERPRINT[..31])).is_err());
+        assert!(parse_pin("").is_err());
+    }
+
+    #[test]
+    fn rejects_input_that_is_neither_hex_nor_base64() {
+        let mut not_hex = hex::encode(FINGERPRINT);
+        not_hex.replace_range(..2, "zz");
+        assert!(parse_pin(&not_hex).is_err());
+        assert!(parse_pin("AB:CD:...").is_err());
+        assert!(parse_pin("sha256 fingerprint").is_err());
+    }
+}
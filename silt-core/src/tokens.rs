@@ -0,0 +1,63 @@
+use crate::models::CompletionRequest;
+use std::collections::HashMap;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::{
+    cl100k_base_singleton, o200k_base_singleton, o200k_harmony_singleton, p50k_base_singleton,
+    p50k_edit_singleton, r50k_base_singleton, CoreBPE,
+};
+
+/// Tokens counted for each image, roughly a high detail image of one tile.
+const IMAGE_TOKEN_ESTIMATE: u64 = 765;
+
+/// Tokens the chat format adds around every message.
+const TOKENS_PER_MESSAGE: u64 = 3;
+
+/// Tokens the chat format adds to prime the reply.
+const REPLY_PRIMING_TOKENS: u64 = 3;
+
+fn encoding(model: &str) -> &'static CoreBPE {
+    match get_tokenizer(model) {
+        Some(Tokenizer::Cl100kBase) => cl100k_base_singleton(),
+        Some(Tokenizer::O200kHarmony) => o200k_harmony_singleton(),
+        Some(Tokenizer::P50kBase) => p50k_base_singleton(),
+        Some(Tokenizer::P50kEdit) => p50k_edit_singleton(),
+        Some(Tokenizer::R50kBase | Tokenizer::Gpt2) => r50k_base_singleton(),
+        // Current OpenAI models, and the best guess for models tiktoken doesn't know
+        Some(Tokenizer::O200kBase) | None => o200k_base_singleton(),
+    }
+}
+
+/// Prompt tokens for a request, counted with the model's tokenizer plus the
+/// chat format's per-message overhead. Images and other non-text parts count
+/// a flat amount each, so this is an estimate rather than the billed figure.
+pub fn count_prompt_tokens(request: &CompletionRequest) -> u64 {
+    let bpe = encoding(&request.model);
+    let count = |text: &str| bpe.encode_ordinary(text).len() as u64;
+
+    let messages: u64 = request
+        .messages
+        .iter()
+        .map(|message| {
+            let content = message.content.as_ref().map_or(0, |content| {
+                count(&content.text()) + content.non_text_parts().count() as u64 * IMAGE_TOKEN_ESTIMATE
+            });
+            let tool_calls: u64 = message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|call| count(&call.function.name) + count(&call.function.arguments))
+                .sum();
+            TOKENS_PER_MESSAGE + count(&message.role) + content + tool_calls
+        })
+        .sum();
+    messages + REPLY_PRIMING_TOKENS
+}
+
+/// The model's context window from `SILT_MODEL_CONTEXT_WINDOWS`, or tiktoken's
+/// table of OpenAI models. `None` for models neither knows.
+pub fn context_window(overrides: &HashMap<String, u64>, model: &str) -> Option<u64> {
+    overrides
+        .get(model)
+        .copied()
+        .or_else(|| tiktoken_rs::model::get_context_size(model).map(|size| size as u64))
+}
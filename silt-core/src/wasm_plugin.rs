@@ -0,0 +1,139 @@
+use crate::hooks::{hook_input, HookContext, HookReply, PostResultHook, PreEnqueueHook};
+use crate::models::{CompletionRequest, CompletionResponse};
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use wasmtime::{Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Instructions a plugin may execute per call before it is stopped.
+const FUEL_PER_CALL: u64 = 1_000_000_000;
+
+/// Linear memory a plugin may grow to.
+const MAX_MEMORY_BYTES: usize = 64 * 1024 * 1024;
+
+/// A hook compiled from an operator-provided WebAssembly module (`wasm:<path>`
+/// in `SILT_PRE_ENQUEUE_HOOKS` or `SILT_POST_RESULT_HOOKS`).
+///
+/// Plugins are core WASM modules with no imports. They export `memory`,
+/// `alloc(len: i32) -> i32`, and `pre_enqueue` and/or `post_result`, each
+/// `(ptr: i32, len: i32) -> i64`. The input is the same JSON a webhook hook
+/// receives, written into memory returned by `alloc`; the returned `i64` packs
+/// the output's pointer (high 32 bits) and length (low 32 bits), and the
+/// output is a webhook-style reply. A zero length accepts the input as-is.
+///
+/// Every call gets a fresh instance with its own memory, bounded fuel and a
+/// memory cap, so a misbehaving plugin fails the call rather than silt.
+#[derive(Clone)]
+pub struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    instance: InstancePre<StoreLimits>,
+}
+
+impl WasmPlugin {
+    /// Compiles the plugin at `path`, failing unless it exports the `stage`
+    /// it is configured for (`pre_enqueue` or `post_result`).
+    pub fn load(path: &str, stage: &str) -> Result<Self> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::from_file(&engine, path).with_context(|| format!("Failed to load WASM plugin {}", path))?;
+
+        for export in ["memory", "alloc", stage] {
+            if module.get_export(export).is_none() {
+                anyhow::bail!("WASM plugin {} does not export {}", path, export);
+            }
+        }
+        let instance = Linker::new(&engine)
+            .instantiate_pre(&module)
+            .with_context(|| format!("WASM plugin {} must not have imports", path))?;
+
+        let file_name = std::path::Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string());
+        Ok(Self {
+            name: format!("wasm plugin {}", file_name),
+            engine,
+            instance,
+        })
+    }
+
+    /// Runs `export` on the input in a fresh instance, on a blocking thread.
+    async fn call(&self, export: &'static str, input: serde_json::Value) -> Result<HookReply> {
+        let plugin = self.clone();
+        let input = serde_json::to_vec(&input)?;
+        tokio::task::spawn_blocking(move || plugin.call_blocking(export, &input)).await?
+    }
+
+    fn call_blocking(&self, export: &str, input: &[u8]) -> Result<HookReply> {
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY_BYTES).build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FUEL_PER_CALL)?;
+
+        let instance = self.instance.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("{} does not export memory", self.name))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let hook = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, export)
+            .with_context(|| format!("{} does not export {}", self.name, export))?;
+
+        let len = i32::try_from(input.len()).context("Hook input is too large")?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+
+        let packed = hook.call(&mut store, (ptr, len))? as u64;
+        let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        if out_len == 0 {
+            return Ok(HookReply::default());
+        }
+        let mut output = vec![0; out_len];
+        memory.read(&store, out_ptr, &mut output)?;
+        serde_json::from_slice::<HookReply>(&output)
+            .with_context(|| format!("{} returned invalid JSON", self.name))?
+            .accepted()
+    }
+}
+
+impl PreEnqueueHook for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn before_enqueue<'a>(
+        &'a self,
+        context: HookContext<'a>,
+        request: &'a mut CompletionRequest,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let input = hook_input("pre_enqueue", context, request, None);
+            if let Some(replacement) = self.call("pre_enqueue", input).await?.request {
+                *request = replacement;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl PostResultHook for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn after_result<'a>(
+        &'a self,
+        context: HookContext<'a>,
+        request: &'a CompletionRequest,
+        response: &'a mut CompletionResponse,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let input = hook_input("post_result", context, request, Some(response));
+            if let Some(replacement) = self.call("post_result", input).await?.response {
+                *response = replacement;
+            }
+            Ok(())
+        })
+    }
+}